@@ -0,0 +1,1230 @@
+//! Command-line handling for the ray tracer binary
+//!
+//! Parses the program arguments into `CliOptions` and implements the non-default
+//! modes (such as `--verify`) as plain functions so they can be tested without a process.
+
+use raytracer_rust::error::RayTracerError;
+use nalgebra::Vector3;
+use raytracer_rust::imgcomparator::{file_to_image, save_exr_channels, save_image, save_linear_image, Image};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use raytracer_rust::raytracer::{Config, ParsedConfigState, RayTracer, RenderStats, SampleAovs};
+use serde::Serialize;
+
+/// Scene rendered when no `--scene` argument is given
+pub const DEFAULT_SCENE: &str = "final_avec_bonus.scene";
+
+/// Color drawn over object boundaries by `--outline`
+pub const OUTLINE_COLOR: u32 = 0xFF00FF;
+
+/// Channel values grouped into each bar of the `--histogram` chart
+const HISTOGRAM_BUCKET: usize = 16;
+
+/// Length in characters of the longest `--histogram` bar
+const HISTOGRAM_BAR_WIDTH: usize = 40;
+
+/// Options collected from the command line
+#[derive(Debug, PartialEq)]
+pub struct CliOptions {
+    /// Scene file to load
+    pub scene: String,
+    /// Reference image to compare the render against instead of saving it
+    pub verify: Option<String>,
+    /// Number of differing pixels still accepted by `--verify`
+    pub tolerance: u128,
+    /// Open the interactive preview window (needs the `window` feature)
+    pub window: bool,
+    /// Only load the scene and report its contents, without rendering
+    pub dry_run: bool,
+    /// JSON file receiving the render statistics
+    pub stats: Option<String>,
+    /// Draw object silhouettes over the rendered image
+    pub outline: bool,
+    /// Extra `(width, height)` renders of the same view, saved next to the main output
+    pub resolutions: Vec<(u32, u32)>,
+    /// Render a turntable of this many frames instead of the single image
+    pub frames: Option<u32>,
+    /// Longest side allowed for the render; larger scenes are scaled down keeping
+    /// their aspect ratio
+    pub max_size: Option<u32>,
+    /// Random seed overriding the scene's `seed`
+    pub seed: Option<u64>,
+    /// Longest the main render may take before it is abandoned with an error
+    pub timeout: Option<Duration>,
+    /// Directory whose `*.test` scenes are all rendered and compared against their
+    /// `*.png` references, writing `report.md` there
+    pub report: Option<String>,
+    /// View file whose `camera` (and optional `size`) replace the scene's
+    pub view: Option<String>,
+    /// Scene files whose objects and lights are added to the scene (`--layer`, repeatable)
+    pub layers: Vec<String>,
+    /// Directory caching parsed scenes between runs (see `ParsedConfigState::set_cache_dir`)
+    pub scene_cache: Option<PathBuf>,
+    /// Print the BVH leaf and AABB of every object instead of rendering
+    pub list_objects: bool,
+    /// Print an ASCII histogram of the rendered image's channel values
+    pub histogram: bool,
+    /// Convert the scene to a binary `.rtscene` file at this path instead of rendering
+    pub to_binary: Option<String>,
+    /// Image receiving a heatmap of the rays traced per pixel
+    pub heatmap: Option<String>,
+    /// Image receiving the object index seen through each pixel (see
+    /// `RayTracer::render_segmentation`)
+    pub segmentation: Option<String>,
+    /// OpenEXR file receiving the linear image with per-pixel sample counts and variance
+    pub sample_aovs: Option<String>,
+    /// Object names to render, leaving out every other object (`--only`, repeatable)
+    pub only: Vec<String>,
+    /// Object names left out of the render (`--hide`, repeatable)
+    pub hide: Vec<String>,
+    /// Encoding forced on the output image, replacing the extension of the scene's `output`
+    pub output_format: Option<OutputFormat>,
+    /// Reference image to re-render the scene against, replacing it if they differ
+    /// and the update is confirmed
+    pub validate_reference: Option<String>,
+    /// Confirm `--validate-reference` updates without prompting
+    pub yes: bool,
+}
+
+impl Default for CliOptions {
+    fn default() -> Self {
+        CliOptions {
+            scene: DEFAULT_SCENE.to_string(),
+            verify: None,
+            tolerance: 0,
+            window: false,
+            dry_run: false,
+            stats: None,
+            outline: false,
+            resolutions: Vec::new(),
+            frames: None,
+            max_size: None,
+            seed: None,
+            timeout: None,
+            report: None,
+            view: None,
+            layers: Vec::new(),
+            scene_cache: None,
+            list_objects: false,
+            histogram: false,
+            to_binary: None,
+            heatmap: None,
+            segmentation: None,
+            sample_aovs: None,
+            only: Vec::new(),
+            hide: Vec::new(),
+            output_format: None,
+            validate_reference: None,
+            yes: false,
+        }
+    }
+}
+
+/// Image encodings accepted by `--output-format`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Ppm,
+    Png,
+    Bmp,
+}
+
+impl OutputFormat {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_ascii_lowercase().as_str() {
+            "ppm" => Ok(OutputFormat::Ppm),
+            "png" => Ok(OutputFormat::Png),
+            "bmp" => Ok(OutputFormat::Bmp),
+            _ => Err(format!("Invalid value for --output-format: '{value}' (expected ppm, png or bmp)")),
+        }
+    }
+
+    /// File extension `save_image` encodes in this format
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Ppm => "ppm",
+            OutputFormat::Png => "png",
+            OutputFormat::Bmp => "bmp",
+        }
+    }
+}
+
+/// Parses program arguments (without the program name)
+///
+/// # Returns
+/// * `Ok(CliOptions)` - Parsed options, defaults for anything not given
+/// * `Err(String)` - Unknown flag, missing or invalid value
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<CliOptions, String> {
+    let mut options = CliOptions::default();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--scene" => options.scene = next_value(&mut args, &arg)?,
+            "--verify" => options.verify = Some(next_value(&mut args, &arg)?),
+            "--validate-reference" => options.validate_reference = Some(next_value(&mut args, &arg)?),
+            "--yes" => options.yes = true,
+            "--tolerance" => {
+                options.tolerance = next_value(&mut args, &arg)?
+                    .parse::<u128>()
+                    .map_err(|e| format!("Invalid value for --tolerance: {e}"))?;
+            }
+            "--window" => options.window = true,
+            "--dry-run" => options.dry_run = true,
+            "--outline" => options.outline = true,
+            "--list-objects" => options.list_objects = true,
+            "--histogram" => options.histogram = true,
+            "--to-binary" => options.to_binary = Some(next_value(&mut args, &arg)?),
+            "--heatmap" => options.heatmap = Some(next_value(&mut args, &arg)?),
+            "--segmentation" => options.segmentation = Some(next_value(&mut args, &arg)?),
+            "--sample-aovs" => {
+                let path = next_value(&mut args, &arg)?;
+                if !Path::new(&path).extension().is_some_and(|ext| ext.eq_ignore_ascii_case("exr")) {
+                    return Err(format!("--sample-aovs writes OpenEXR: expected a .exr path, got {path}"));
+                }
+                options.sample_aovs = Some(path);
+            }
+            "--only" => options.only.push(next_value(&mut args, &arg)?),
+            "--hide" => options.hide.push(next_value(&mut args, &arg)?),
+            "--output-format" => options.output_format = Some(OutputFormat::parse(&next_value(&mut args, &arg)?)?),
+            "--frames" => {
+                let frames = next_value(&mut args, &arg)?
+                    .parse::<u32>()
+                    .map_err(|e| format!("Invalid value for --frames: {e}"))?;
+                if frames == 0 {
+                    return Err("--frames must be at least 1".to_string());
+                }
+                options.frames = Some(frames);
+            }
+            "--max-size" => {
+                let max_size = next_value(&mut args, &arg)?
+                    .parse::<u32>()
+                    .map_err(|e| format!("Invalid value for --max-size: {e}"))?;
+                if max_size == 0 {
+                    return Err("--max-size must be at least 1".to_string());
+                }
+                options.max_size = Some(max_size);
+            }
+            "--seed" => {
+                options.seed = Some(
+                    next_value(&mut args, &arg)?
+                        .parse::<u64>()
+                        .map_err(|e| format!("Invalid value for --seed: {e}"))?,
+                );
+            }
+            "--timeout" => {
+                let seconds = next_value(&mut args, &arg)?
+                    .parse::<f64>()
+                    .map_err(|e| format!("Invalid value for --timeout: {e}"))?;
+                if !(seconds > 0.0 && seconds.is_finite()) {
+                    return Err("--timeout must be a positive number of seconds".to_string());
+                }
+                options.timeout = Some(Duration::from_secs_f64(seconds));
+            }
+            "--resolutions" => options.resolutions = parse_resolutions(&next_value(&mut args, &arg)?)?,
+            "--stats" => options.stats = Some(next_value(&mut args, &arg)?),
+            "--report" => options.report = Some(next_value(&mut args, &arg)?),
+            "--view" => options.view = Some(next_value(&mut args, &arg)?),
+            "--layer" => options.layers.push(next_value(&mut args, &arg)?),
+            "--scene-cache" => options.scene_cache = Some(PathBuf::from(next_value(&mut args, &arg)?)),
+            _ => return Err(format!("Unknown argument: {arg}")),
+        }
+    }
+
+    Ok(options)
+}
+
+/// Parses a comma-separated list of `WIDTHxHEIGHT` sizes, e.g. `160x120,640x480`
+pub fn parse_resolutions(value: &str) -> Result<Vec<(u32, u32)>, String> {
+    value
+        .split(',')
+        .map(|size| {
+            let (width, height) = size
+                .split_once('x')
+                .ok_or_else(|| format!("Invalid resolution '{size}': expected WIDTHxHEIGHT"))?;
+            let parse = |v: &str| match v.parse::<u32>() {
+                Ok(n) if n > 0 => Ok(n),
+                _ => Err(format!("Invalid resolution '{size}': expected WIDTHxHEIGHT")),
+            };
+            Ok((parse(width)?, parse(height)?))
+        })
+        .collect()
+}
+
+/// Scales `config`'s size down so its longest side is `max_size`, keeping the aspect
+/// ratio (and so the camera framing). Sizes already within the limit are left alone.
+pub fn cap_size(config: &mut Config, max_size: u32) {
+    let longest = config.width.max(config.height);
+    if longest <= max_size {
+        return;
+    }
+    let scale = |side: u32| {
+        let scaled = (side as u64 * max_size as u64 + longest as u64 / 2) / longest as u64;
+        scaled.max(1) as u32
+    };
+    config.width = scale(config.width);
+    config.height = scale(config.height);
+}
+
+/// Rewrites the extension of `config`'s output file for `--output-format`, so
+/// `save_image` encodes the render (and the extra sizes and frames named after it) in
+/// `format`. `out.png` becomes `out.ppm`; a path without extension gets one.
+pub fn apply_output_format(config: &mut Config, format: OutputFormat) {
+    config.output_file = Path::new(&config.output_file)
+        .with_extension(format.extension())
+        .to_string_lossy()
+        .into_owned();
+}
+
+/// Applies `--only` and `--hide` to `config`'s objects (see `Config::select_objects`),
+/// returning a warning for every given name that no object carries
+pub fn select_objects(config: &mut Config, only: &[String], hide: &[String]) -> Vec<String> {
+    let warnings = only
+        .iter()
+        .chain(hide)
+        .filter(|name| {
+            !(0..config.get_scene_objects().len()).any(|index| config.object_name(index) == Some(name.as_str()))
+        })
+        .map(|name| format!("No object is named '{}'", name))
+        .collect();
+    config.select_objects(only, hide);
+    warnings
+}
+
+/// Settles the seed the render will use: `seed` (from `--seed`) if given, else the
+/// scene's own `seed`, else one derived from the clock. The result is stored in
+/// `config` and returned so it can be reported and the render reproduced later.
+pub fn apply_seed(config: &mut Config, seed: Option<u64>) -> u64 {
+    let seed = seed.or(config.seed).unwrap_or_else(|| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+    });
+    config.seed = Some(seed);
+    seed
+}
+
+/// Lists the objects of `ray_tracer`'s scene grouped by BVH leaf node, one object per
+/// line with its kind and AABB, e.g. `node 2` followed by `  #0 sphere (-1.000, ...)`
+pub fn object_listing(ray_tracer: &RayTracer) -> String {
+    let mut listing = String::new();
+    let mut current_node = None;
+    for leaf in ray_tracer.bvh_leaves() {
+        if current_node != Some(leaf.node_index) {
+            listing.push_str(&format!("node {}\n", leaf.node_index));
+            current_node = Some(leaf.node_index);
+        }
+        listing.push_str(&format!(
+            "  #{} {} ({:.3}, {:.3}, {:.3}) .. ({:.3}, {:.3}, {:.3})\n",
+            leaf.object_index,
+            leaf.object_kind,
+            leaf.min.x,
+            leaf.min.y,
+            leaf.min.z,
+            leaf.max.x,
+            leaf.max.y,
+            leaf.max.z
+        ));
+    }
+    listing
+}
+
+/// ASCII chart of `Image::histogram` for `--histogram`: per channel, the number of
+/// clipped pixels (values 0 and 255) followed by one bar per range of 16 values,
+/// scaled to the channel's fullest range
+pub fn histogram_chart(image: &Image) -> String {
+    let mut chart = String::new();
+    for (name, counts) in ["red", "green", "blue"].iter().zip(image.histogram()) {
+        chart.push_str(&format!("{name}: {} at 0, {} at 255\n", counts[0], counts[255]));
+        let buckets: Vec<u32> = counts.chunks(HISTOGRAM_BUCKET).map(|chunk| chunk.iter().sum()).collect();
+        let fullest = buckets.iter().copied().max().unwrap_or(0).max(1);
+        for (index, &count) in buckets.iter().enumerate() {
+            let start = index * HISTOGRAM_BUCKET;
+            let bar = "#".repeat((count as usize * HISTOGRAM_BAR_WIDTH).div_ceil(fullest as usize));
+            chart.push_str(&format!(
+                "  {:3}-{:3} {:<width$} {}\n",
+                start,
+                start + HISTOGRAM_BUCKET - 1,
+                bar,
+                count,
+                width = HISTOGRAM_BAR_WIDTH
+            ));
+        }
+    }
+    chart
+}
+
+/// Saves the linear framebuffer from `RayTracer::render_linear` to `path` (`.exr` or `.pfm`)
+pub fn save_linear(linear: &[Vector3<f32>], width: u32, height: u32, path: &str) -> Result<(), RayTracerError> {
+    let pixels: Vec<[f32; 3]> = linear.iter().map(|color| [color.x, color.y, color.z]).collect();
+    save_linear_image(width, height, &pixels, path)
+}
+
+/// Saves the linear framebuffer of a `RayTracer::render_scene_frame` to `path` as
+/// OpenEXR, with its sample counts and variances as the extra `samples` and `variance`
+/// channels
+pub fn save_sample_aovs(
+    linear: &[Vector3<f32>],
+    width: u32,
+    height: u32,
+    aovs: &SampleAovs,
+    path: &str,
+) -> Result<(), RayTracerError> {
+    let channel = |index: usize| -> Vec<f32> { linear.iter().map(|color| color[index]).collect() };
+    let (red, green, blue) = (channel(0), channel(1), channel(2));
+    let counts: Vec<f32> = aovs.counts.iter().map(|&count| count as f32).collect();
+    save_exr_channels(
+        width,
+        height,
+        &[("R", &red), ("G", &green), ("B", &blue), ("samples", &counts), ("variance", &aovs.variance)],
+        path,
+    )
+}
+
+/// `out.png` rendered at 160x120 becomes `out_160x120.png`
+pub fn sized_output_path(path: &str, width: u32, height: u32) -> String {
+    let path = std::path::Path::new(path);
+    let stem = path.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+    let name = match path.extension() {
+        Some(ext) => format!("{stem}_{width}x{height}.{}", ext.to_string_lossy()),
+        None => format!("{stem}_{width}x{height}"),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+fn next_value<I: Iterator<Item = String>>(args: &mut I, flag: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("Missing value for {flag}"))
+}
+
+/// Result of rendering a scene and comparing it against a reference image
+#[derive(Debug, PartialEq)]
+pub struct VerifyReport {
+    /// Number of pixels differing by more than 1 on any channel
+    pub diff_pixels: u128,
+    /// Whether `diff_pixels` is within the requested tolerance
+    pub passed: bool,
+}
+
+/// Renders `scene` and compares the result against the image at `reference`
+///
+/// # Arguments
+/// * `scene` - Path to the scene file
+/// * `reference` - Path to the expected image
+/// * `tolerance` - Number of differing pixels still considered a pass
+///
+/// # Returns
+/// * `Ok(VerifyReport)` - Comparison outcome
+/// * `Err(RayTracerError)` - Loading, rendering or comparison (e.g. size mismatch) failed
+pub fn verify(scene: &str, reference: &str, tolerance: u128) -> Result<VerifyReport, RayTracerError> {
+    let expected = file_to_image(reference)?;
+    let rendered = render_for_reference(scene, &expected)?;
+    let (diff_pixels, _) = Image::compare(&rendered, &expected)?;
+
+    Ok(VerifyReport {
+        diff_pixels,
+        passed: diff_pixels <= tolerance,
+    })
+}
+
+/// What `validate_reference` did with the reference image
+#[derive(Debug, PartialEq)]
+pub enum ReferenceUpdate {
+    /// The render is within the tolerance of the reference, which was left alone
+    Unchanged,
+    /// The render differed in `diff_pixels` pixels and replaced the reference
+    Updated { diff_pixels: u128 },
+    /// The render differed in `diff_pixels` pixels but the update was not confirmed
+    Declined { diff_pixels: u128 },
+}
+
+/// Renders `scene` as `verify` does and, when more than `tolerance` pixels differ from
+/// the image at `reference`, asks `confirm` (given the differing pixel count) whether
+/// to overwrite the reference with the render. The reference is only written when
+/// `confirm` returns true, so golden images never change by accident.
+///
+/// # Returns
+/// * `Ok(ReferenceUpdate)` - Whether the reference was kept or replaced
+/// * `Err(RayTracerError)` - Loading, rendering, comparing or saving failed
+pub fn validate_reference(
+    scene: &str,
+    reference: &str,
+    tolerance: u128,
+    confirm: impl FnOnce(u128) -> bool,
+) -> Result<ReferenceUpdate, RayTracerError> {
+    let expected = file_to_image(reference)?;
+    let rendered = render_for_reference(scene, &expected)?;
+    let (diff_pixels, _) = Image::compare(&rendered, &expected)?;
+    if diff_pixels <= tolerance {
+        return Ok(ReferenceUpdate::Unchanged);
+    }
+    if !confirm(diff_pixels) {
+        return Ok(ReferenceUpdate::Declined { diff_pixels });
+    }
+    save_image(&rendered, reference)?;
+    Ok(ReferenceUpdate::Updated { diff_pixels })
+}
+
+/// Renders `scene` for comparison against `expected`. A scene without `size` is
+/// rendered at the reference's size rather than the defaults.
+fn render_for_reference(scene: &str, expected: &Image) -> Result<Image, RayTracerError> {
+    let mut parsed_config = ParsedConfigState::new();
+    let mut config = parsed_config.load_config_file(scene)?;
+    if !parsed_config.size_was_set() {
+        config.width = expected.width;
+        config.height = expected.height;
+    }
+    RayTracer::new(config).render()
+}
+
+/// Comparison of one scene against its reference, as listed by `compare_directory`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SceneReport {
+    /// Path of the `.test` scene file
+    pub scene: String,
+    /// Number of pixels differing by more than 1 on any channel
+    pub diff_pixels: u128,
+    /// PSNR of the render against the reference in dB, infinite for identical images
+    pub psnr: f64,
+    /// Whether `diff_pixels` is within the tolerance
+    pub passed: bool,
+    /// Where the difference image was written
+    pub diff_image: Option<String>,
+    /// Why the scene could not be compared at all (it then counts as failed)
+    pub error: Option<String>,
+}
+
+/// Renders every `*.test` scene under `dir` (recursively, in path order) and compares
+/// it against the `.png` next to it, like `verify`. Each difference image is saved as
+/// `<scene>_diff.png`. A scene that fails to load, render or compare is reported as
+/// failed with its error instead of stopping the batch.
+///
+/// # Returns
+/// * `Ok(Vec<SceneReport>)` - One entry per scene
+/// * `Err(RayTracerError::Io)` - `dir` could not be listed
+pub fn compare_directory(dir: &Path, tolerance: u128) -> Result<Vec<SceneReport>, RayTracerError> {
+    let mut scenes = Vec::new();
+    collect_scenes(dir, &mut scenes)?;
+    scenes.sort();
+
+    Ok(scenes
+        .iter()
+        .map(|scene| {
+            let path = scene.to_string_lossy().into_owned();
+            compare_scene(scene, tolerance).unwrap_or_else(|e| SceneReport {
+                scene: path,
+                diff_pixels: 0,
+                psnr: 0.0,
+                passed: false,
+                diff_image: None,
+                error: Some(e.to_string()),
+            })
+        })
+        .collect())
+}
+
+fn collect_scenes(dir: &Path, scenes: &mut Vec<std::path::PathBuf>) -> Result<(), RayTracerError> {
+    let display = dir.to_string_lossy();
+    let entries = std::fs::read_dir(dir).map_err(|e| RayTracerError::io(&display, e))?;
+    for entry in entries {
+        let path = entry.map_err(|e| RayTracerError::io(&display, e))?.path();
+        if path.is_dir() {
+            collect_scenes(&path, scenes)?;
+        } else if path.extension().is_some_and(|ext| ext == "test") {
+            scenes.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn compare_scene(scene: &Path, tolerance: u128) -> Result<SceneReport, RayTracerError> {
+    let scene_path = scene.to_string_lossy().into_owned();
+    let expected = file_to_image(&scene.with_extension("png").to_string_lossy())?;
+    let rendered = render_for_reference(&scene_path, &expected)?;
+    let (diff_pixels, diff) = Image::compare(&rendered, &expected)?;
+    let psnr = Image::psnr(&rendered, &expected)?;
+
+    let stem = scene.file_stem().unwrap_or_default().to_string_lossy();
+    let diff_image = scene.with_file_name(format!("{stem}_diff.png")).to_string_lossy().into_owned();
+    save_image(&diff, &diff_image)?;
+
+    Ok(SceneReport {
+        scene: scene_path,
+        diff_pixels,
+        psnr,
+        passed: diff_pixels <= tolerance,
+        diff_image: Some(diff_image),
+        error: None,
+    })
+}
+
+/// Writes `reports` to `path` as JSON when it ends in `.json`, as a markdown table otherwise
+pub fn write_report(reports: &[SceneReport], path: &str) -> Result<(), RayTracerError> {
+    let content = if path.ends_with(".json") {
+        serde_json::to_string_pretty(reports).map_err(|e| RayTracerError::io(path, e))?
+    } else {
+        report_markdown(reports)
+    };
+    std::fs::write(path, content).map_err(|e| RayTracerError::io(path, e))
+}
+
+/// `reports` as a markdown table followed by a pass count
+pub fn report_markdown(reports: &[SceneReport]) -> String {
+    let mut table = String::from("| Scene | Result | Differing pixels | PSNR (dB) | Diff image |\n");
+    table.push_str("|---|---|---|---|---|\n");
+    for report in reports {
+        let result = match (&report.error, report.passed) {
+            (Some(error), _) => format!("error: {error}"),
+            (None, true) => "pass".to_string(),
+            (None, false) => "FAIL".to_string(),
+        };
+        table.push_str(&format!(
+            "| {} | {} | {} | {:.2} | {} |\n",
+            report.scene,
+            result,
+            report.diff_pixels,
+            report.psnr,
+            report.diff_image.as_deref().unwrap_or("-")
+        ));
+    }
+    let passed = reports.iter().filter(|report| report.passed).count();
+    table.push_str(&format!("\n{passed}/{} scene(s) passed\n", reports.len()));
+    table
+}
+
+/// Parsed scene and the figures printed by `--dry-run`
+pub struct DryRunReport {
+    pub config: Config,
+    pub objects: usize,
+    pub lights: usize,
+    /// Output path after resolution against the scene file's directory
+    pub output_path: String,
+}
+
+/// Loads `scene` and adds the objects and lights of every `layers` file to it (see
+/// `ParsedConfigState::apply_layer_file`), then applies the `view` file's camera and
+/// size over it when given. With `scene_cache`, the scene itself is looked up in (and
+/// stored to) that parsed-scene cache.
+///
+/// # Returns
+/// * `Ok((ParsedConfigState, Config))` - The parser (holding the load warnings) and the scene
+/// * `Err(RayTracerError)` - The scene, a layer or the view file failed to load
+pub fn load_scene(
+    scene: &str,
+    layers: &[String],
+    view: Option<&str>,
+    scene_cache: Option<&Path>,
+) -> Result<(ParsedConfigState, Config), RayTracerError> {
+    let mut parsed_config = ParsedConfigState::new();
+    parsed_config.set_cache_dir(scene_cache.map(Path::to_path_buf));
+    let mut config = parsed_config.load_config_file(scene)?;
+    for layer in layers {
+        parsed_config.apply_layer_file(layer, &mut config)?;
+    }
+    if let Some(view) = view {
+        parsed_config.apply_view_file(view, &mut config)?;
+    }
+    Ok((parsed_config, config))
+}
+
+/// Loads `scene` (with its `layers` and optional `view` file) and summarizes it
+/// without building the BVH or rendering
+///
+/// # Returns
+/// * `Ok(DryRunReport)` - The parsed configuration and its summary
+/// * `Err(RayTracerError)` - The scene failed to load
+pub fn dry_run(scene: &str, layers: &[String], view: Option<&str>) -> Result<DryRunReport, RayTracerError> {
+    let (_, config) = load_scene(scene, layers, view, None)?;
+    Ok(DryRunReport {
+        objects: config.get_scene_objects().len(),
+        lights: config.get_lights().len(),
+        output_path: config.output_file.clone(),
+        config,
+    })
+}
+
+/// Renders `frames` views orbiting the scene (see `Config::turntable_camera`), 360/frames
+/// degrees apart, saving them as `frame_000.png`, `frame_001.png`... in `output_dir`
+///
+/// # Returns
+/// * `Ok(Vec<String>)` - Paths of the written frames, in order
+/// * `Err(RayTracerError::Io)` - A frame could not be saved
+pub fn render_turntable(
+    ray_tracer: &RayTracer,
+    frames: u32,
+    output_dir: &Path,
+) -> Result<Vec<String>, RayTracerError> {
+    let config = ray_tracer.config();
+    (0..frames)
+        .map(|frame| {
+            let camera = config.turntable_camera(frame as f32 * 360.0 / frames as f32);
+            let image = ray_tracer.render_to_image_with_camera(&camera, config.width, config.height);
+            let path = output_dir
+                .join(format!("frame_{frame:03}.png"))
+                .to_string_lossy()
+                .into_owned();
+            save_image(&image, &path)?;
+            Ok(path)
+        })
+        .collect()
+}
+
+/// Writes `stats` as pretty-printed JSON to `path`
+pub fn write_stats(stats: &RenderStats, path: &str) -> Result<(), RayTracerError> {
+    let json = serde_json::to_string_pretty(stats).map_err(|e| RayTracerError::io(path, e))?;
+    std::fs::write(path, json).map_err(|e| RayTracerError::io(path, e))
+}
+
+// ==========================================================
+// TESTS
+// ==========================================================
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args_defaults() {
+        assert_eq!(parse_args(args(&[])).unwrap(), CliOptions::default());
+    }
+
+    #[test]
+    fn test_parse_args_verify() {
+        let options =
+            parse_args(args(&["--scene", "a.test", "--verify", "a.png", "--tolerance", "5"]))
+                .unwrap();
+        assert_eq!(options.scene, "a.test");
+        assert_eq!(options.verify, Some("a.png".to_string()));
+        assert_eq!(options.tolerance, 5);
+    }
+
+    #[test]
+    fn test_parse_args_window() {
+        assert!(parse_args(args(&["--window"])).unwrap().window);
+    }
+
+    #[test]
+    fn test_parse_args_dry_run() {
+        assert!(parse_args(args(&["--dry-run"])).unwrap().dry_run);
+    }
+
+    #[test]
+    fn test_object_listing_groups_by_node() {
+        assert!(parse_args(args(&["--list-objects"])).unwrap().list_objects);
+
+        let config = ParsedConfigState::new()
+            .load_config_str("sphere 0 0 0 1\nsphere 4 0 0 0.5\n")
+            .unwrap();
+        let listing = object_listing(&RayTracer::new(config));
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("node ") && lines[2].starts_with("node "));
+        assert!(listing.contains("  #0 sphere (-1.000, -1.000, -1.000) .. (1.000, 1.000, 1.000)\n"));
+        assert!(listing.contains("  #1 sphere (3.500, -0.500, -0.500) .. (4.500, 0.500, 0.500)\n"));
+    }
+
+    #[test]
+    fn test_histogram_chart_shows_clipping() {
+        assert!(parse_args(args(&["--histogram"])).unwrap().histogram);
+        assert_eq!(parse_args(args(&["--to-binary", "big.rtscene"])).unwrap().to_binary.as_deref(), Some("big.rtscene"));
+        assert_eq!(parse_args(args(&["--heatmap", "work.png"])).unwrap().heatmap.as_deref(), Some("work.png"));
+        assert!(parse_args(args(&["--heatmap"])).is_err());
+        assert_eq!(
+            parse_args(args(&["--segmentation", "ids.png"])).unwrap().segmentation.as_deref(),
+            Some("ids.png")
+        );
+        assert_eq!(parse_args(args(&["--sample-aovs", "aovs.exr"])).unwrap().sample_aovs.as_deref(), Some("aovs.exr"));
+        assert!(parse_args(args(&["--sample-aovs", "aovs.png"])).is_err());
+
+        let image = Image::new(4, 1, vec![0x000000, 0x0000FF, 0x00FF00, 0xFF0000]);
+        let chart = histogram_chart(&image);
+        assert_eq!(chart.lines().count(), 3 * 17);
+        assert!(chart.contains("red: 3 at 0, 1 at 255\n"));
+        assert!(chart.contains(&format!("    0- 15 {} 3\n", "#".repeat(40))));
+        assert!(chart.contains(&format!("  240-255 {:<40} 1\n", "#".repeat(14))));
+    }
+
+    #[test]
+    fn test_dry_run_reports_without_rendering() {
+        let report = dry_run("test_file/jalon3/tp31.test", &[], None).unwrap();
+        assert_eq!(report.config.width, 640);
+        assert_eq!(report.objects, 1);
+        assert_eq!(report.lights, 0);
+        assert_eq!(report.output_path, "test_file/jalon3/tp31.png");
+
+        // A render at this size would not finish; a dry run returns immediately
+        let dir = std::env::temp_dir().join(format!("raytracer_dry_run_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let scene = dir.join("huge.scene");
+        std::fs::write(&scene, "size 100000 100000\noutput huge.png\nsphere 0 0 0 1\n").unwrap();
+        let report = dry_run(scene.to_str().unwrap(), &[], None).unwrap();
+        assert_eq!(report.objects, 1);
+        assert!(!dir.join("huge.png").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_view_file_overrides_scene_camera() {
+        assert_eq!(parse_args(args(&["--view", "close.view"])).unwrap().view.as_deref(), Some("close.view"));
+
+        let dir = std::env::temp_dir().join(format!("raytracer_view_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let scene = dir.join("scene.test");
+        std::fs::write(&scene, "size 320 240\ncamera 0 0 10 0 0 0 0 1 0 45\nsphere 0 0 0 1\n").unwrap();
+        let view = dir.join("close.view");
+        std::fs::write(&view, "# close-up\nsize 64 48\ncamera 3 1 2 0 0 0 0 1 0 30\n").unwrap();
+        let scene = scene.to_str().unwrap();
+        let view = view.to_str().unwrap();
+
+        let (_, config) = load_scene(scene, &[], Some(view), None).unwrap();
+        assert_eq!(config.camera.position(), Vector3::new(3.0, 1.0, 2.0));
+        assert_eq!(config.camera.fov(), 30.0);
+        assert_eq!((config.width, config.height), (64, 48));
+        assert_eq!(config.get_scene_objects().len(), 1);
+        let (_, config) = load_scene(scene, &[], None, None).unwrap();
+        assert_eq!(config.camera.position(), Vector3::new(0.0, 0.0, 10.0));
+
+        // Views only hold shot setup
+        let bad_view = dir.join("bad.view");
+        std::fs::write(&bad_view, "camera 3 1 2 0 0 0 0 1 0 30\nsphere 0 0 0 1\n").unwrap();
+        assert!(matches!(
+            load_scene(scene, &[], bad_view.to_str(), None),
+            Err(RayTracerError::Parse { line: 2, .. })
+        ));
+        std::fs::write(&bad_view, "size 64 48\n").unwrap();
+        assert!(load_scene(scene, &[], bad_view.to_str(), None).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_layers_add_objects_and_lights() {
+        let options = parse_args(args(&["--layer", "props.test", "--layer", "lights.test"])).unwrap();
+        assert_eq!(options.layers, ["props.test", "lights.test"]);
+        assert!(parse_args(args(&["--layer"])).is_err());
+
+        let dir = std::env::temp_dir().join(format!("raytracer_layers_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let scene = dir.join("scene.test");
+        std::fs::write(&scene, "size 64 48\ncamera 0 0 10 0 0 0 0 1 0 45\nsphere 0 0 0 1\n").unwrap();
+        let props = dir.join("props.test");
+        std::fs::write(&props, "camera 9 9 9 0 0 0 0 1 0 30\nsphere 2 0 0 1\nsphere -2 0 0 1\n").unwrap();
+        let lights = dir.join("lights.test");
+        std::fs::write(&lights, "point 0 5 5 1 1 1\n").unwrap();
+        let layers = [props.to_str().unwrap().to_string(), lights.to_str().unwrap().to_string()];
+
+        let (parsed_config, config) = load_scene(scene.to_str().unwrap(), &layers, None, None).unwrap();
+        assert_eq!(config.get_scene_objects().len(), 3);
+        assert_eq!(config.get_lights().len(), 1);
+        assert_eq!(config.camera.position(), Vector3::new(0.0, 0.0, 10.0));
+        assert_eq!((config.width, config.height), (64, 48));
+        // Layers without a size warn like any scene, naming their file
+        assert!(parsed_config
+            .warnings()
+            .iter()
+            .any(|warning| warning.starts_with(&layers[1]) && warning.contains("No size given")));
+
+        let missing = [dir.join("missing.test").to_str().unwrap().to_string()];
+        assert!(matches!(
+            load_scene(scene.to_str().unwrap(), &missing, None, None),
+            Err(RayTracerError::Io { .. })
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_max_size_keeps_aspect_and_framing() {
+        let options = parse_args(args(&["--max-size", "800"])).unwrap();
+        assert_eq!(options.max_size, Some(800));
+        assert!(parse_args(args(&["--max-size", "0"])).is_err());
+
+        let scene = "size 1600 1200
+camera 0 0 5 0 0 0 0 1 0 45
+sphere 1 0.5 0 0.8
+sphere -1.5 -0.5 0 0.5
+";
+        let full = ParsedConfigState::new().load_config_str(scene).unwrap();
+        let mut capped = ParsedConfigState::new().load_config_str(scene).unwrap();
+        cap_size(&mut capped, 800);
+        assert_eq!((capped.width, capped.height), (800, 600));
+
+        // Fraction of the frame covered by each object and where its center sits, in
+        // image-relative coordinates
+        let framing = |config: Config| {
+            let (width, height) = (config.width as usize, config.height as usize);
+            let ids = RayTracer::new(config).render_object_ids().unwrap();
+            (0..2u32)
+                .map(|object| {
+                    let pixels: Vec<usize> = (0..ids.len()).filter(|&i| ids[i] == object).collect();
+                    let n = pixels.len() as f32;
+                    let cx = pixels.iter().map(|&i| (i % width) as f32 + 0.5).sum::<f32>() / n / width as f32;
+                    let cy = pixels.iter().map(|&i| (i / width) as f32 + 0.5).sum::<f32>() / n / height as f32;
+                    (n / ids.len() as f32, cx, cy)
+                })
+                .collect::<Vec<_>>()
+        };
+        for (a, b) in framing(full).iter().zip(framing(capped)) {
+            assert!((a.0 - b.0).abs() < 1e-3, "coverage {} vs {}", a.0, b.0);
+            assert!((a.1 - b.1).abs() < 1e-3 && (a.2 - b.2).abs() < 1e-3);
+        }
+
+        let mut small = ParsedConfigState::new().load_config_str("size 640 480\n").unwrap();
+        cap_size(&mut small, 800);
+        assert_eq!((small.width, small.height), (640, 480));
+    }
+
+    #[test]
+    fn test_only_and_hide_select_named_objects() {
+        let options = parse_args(args(&["--only", "foo", "--hide", "bar", "--hide", "baz"])).unwrap();
+        assert_eq!(options.only, ["foo"]);
+        assert_eq!(options.hide, ["bar", "baz"]);
+        assert!(parse_args(args(&["--only"])).is_err());
+
+        let scene = "size 30 10
+camera 0 0 10 0 0 0 0 1 0 30
+ambient .5 .5 .5
+diffuse .5 .5 .5
+sphere -4 0 0 1
+name foo
+sphere 0 0 0 1
+name bar
+sphere 4 0 0 1
+";
+        // Pixels at the centers of the left, middle and right spheres
+        let visible = |only: &[&str], hide: &[&str]| {
+            let mut config = ParsedConfigState::new().load_config_str(scene).unwrap();
+            let only: Vec<String> = only.iter().map(|name| name.to_string()).collect();
+            let hide: Vec<String> = hide.iter().map(|name| name.to_string()).collect();
+            assert!(select_objects(&mut config, &only, &hide).is_empty());
+            let image = RayTracer::new(config).render().unwrap();
+            [7, 15, 22].map(|x| image.data[5 * 30 + x] & 0xFFFFFF != 0)
+        };
+
+        assert_eq!(visible(&[], &[]), [true, true, true]);
+        assert_eq!(visible(&["foo"], &[]), [false, true, false]);
+        assert_eq!(visible(&[], &["foo"]), [true, false, true]);
+        assert_eq!(visible(&["foo", "bar"], &["bar"]), [false, true, false]);
+
+        let mut config = ParsedConfigState::new().load_config_str(scene).unwrap();
+        let warnings = select_objects(&mut config, &["qux".to_string()], &[]);
+        assert_eq!(warnings, ["No object is named 'qux'"]);
+        assert!(config.get_scene_objects().is_empty());
+    }
+
+    #[test]
+    fn test_output_format_overrides_the_scene_extension() {
+        let options = parse_args(args(&["--output-format", "PPM"])).unwrap();
+        assert_eq!(options.output_format, Some(OutputFormat::Ppm));
+        assert!(parse_args(args(&["--output-format", "gif"])).is_err());
+        assert!(parse_args(args(&["--output-format"])).is_err());
+
+        let dir = std::env::temp_dir().join("raytracer_output_format");
+        std::fs::create_dir_all(&dir).unwrap();
+        let scene = dir.join("scene.test");
+        std::fs::write(&scene, "size 8 6\noutput x.png\nambient 0.3 0.3 0.3\nsphere 0 0 -3 1\n").unwrap();
+        let mut config = ParsedConfigState::new().load_config_file(scene.to_str().unwrap()).unwrap();
+        apply_output_format(&mut config, OutputFormat::Ppm);
+        assert_eq!(Path::new(&config.output_file), dir.join("x.ppm"));
+
+        let ray_tracer = RayTracer::new(config);
+        let image = ray_tracer.render().unwrap();
+        save_image(&image, ray_tracer.get_output_path()).unwrap();
+        let bytes = std::fs::read(ray_tracer.get_output_path()).unwrap();
+        // Binary PPM: "P6", width, height and maximum value, then raw RGB triples
+        let header = b"P6\n8 6 255\n";
+        assert_eq!(&bytes[..header.len()], header);
+        assert_eq!(bytes.len(), header.len() + 8 * 6 * 3);
+        let (diff, _) = Image::compare(&file_to_image(ray_tracer.get_output_path()).unwrap(), &image).unwrap();
+        assert_eq!(diff, 0);
+
+        let mut bare = ParsedConfigState::new().load_config_str("size 8 6\n").unwrap();
+        bare.output_file = "render".to_string();
+        apply_output_format(&mut bare, OutputFormat::Bmp);
+        assert_eq!(bare.output_file, "render.bmp");
+    }
+
+    #[test]
+    fn test_parse_resolutions() {
+        let options = parse_args(args(&["--resolutions", "100x75,200x150"])).unwrap();
+        assert_eq!(options.resolutions, vec![(100, 75), (200, 150)]);
+        assert!(parse_resolutions("100").is_err());
+        assert!(parse_resolutions("0x10").is_err());
+        assert!(parse_resolutions("100x75,").is_err());
+    }
+
+    #[test]
+    fn test_sized_output_path() {
+        assert_eq!(sized_output_path("renders/out.png", 160, 120), "renders/out_160x120.png");
+        assert_eq!(sized_output_path("out", 1, 2), "out_1x2");
+    }
+
+    #[test]
+    fn test_parse_args_frames() {
+        assert_eq!(parse_args(args(&["--frames", "36"])).unwrap().frames, Some(36));
+        assert!(parse_args(args(&["--frames", "0"])).is_err());
+    }
+
+    #[test]
+    fn test_turntable_frames() {
+        // Four differently colored spheres around the origin, seen from 6 units away
+        let scene = "size 32 24
+camera 0 1 6 0 0 0 0 1 0 45
+ambient 0.2 0.2 0.2
+point 0 10 0 1 1 1
+diffuse 0.8 0 0
+sphere 2 0 0 0.5
+diffuse 0 0.8 0
+sphere -2 0 0 0.5
+diffuse 0 0 0.8
+sphere 0 0 2 0.5
+diffuse 0.8 0.8 0
+sphere 0 0 -2 0.5
+";
+        let config = ParsedConfigState::new().load_config_str(scene).unwrap();
+        let expected_positions = [
+            (0.0, Vector3::new(0.0, 1.0, 6.0)),
+            (90.0, Vector3::new(6.0, 1.0, 0.0)),
+            (180.0, Vector3::new(0.0, 1.0, -6.0)),
+            (270.0, Vector3::new(-6.0, 1.0, 0.0)),
+        ];
+        for (angle, expected) in expected_positions {
+            let camera = config.turntable_camera(angle);
+            assert!((camera.position() - expected).norm() < 1e-4, "{angle}: {:?}", camera.position());
+            assert_eq!(camera.look_at(), Vector3::zeros());
+        }
+
+        let dir = std::env::temp_dir().join(format!("raytracer_turntable_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths = render_turntable(&RayTracer::new(config), 4, &dir).unwrap();
+        assert_eq!(paths.len(), 4);
+        assert!(paths[3].ends_with("frame_003.png"));
+
+        let images: Vec<Image> = paths.iter().map(|p| file_to_image(p).unwrap()).collect();
+        for i in 0..4 {
+            for j in i + 1..4 {
+                assert_ne!(images[i], images[j], "frames {i} and {j} are identical");
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_args_outline() {
+        assert!(parse_args(args(&["--outline"])).unwrap().outline);
+    }
+
+    #[test]
+    fn test_parse_args_stats() {
+        let options = parse_args(args(&["--stats", "stats.json"])).unwrap();
+        assert_eq!(options.stats, Some("stats.json".to_string()));
+        assert!(parse_args(args(&["--stats"])).is_err());
+    }
+
+    #[test]
+    fn test_sample_aovs_keep_the_linear_colors() {
+        let path = std::env::temp_dir().join(format!("raytracer_sample_aovs_{}.exr", std::process::id()));
+        let path = path.to_str().unwrap();
+        let linear = [Vector3::new(2.5, 0.5, 0.0), Vector3::new(0.0, 0.25, 1.0)];
+        let aovs = SampleAovs {
+            counts: vec![9, 1],
+            variance: vec![0.1, 0.0],
+        };
+        save_sample_aovs(&linear, 2, 1, &aovs, path).unwrap();
+
+        // Over-bright values survive, unlike in the 8-bit display image
+        let decoded = image::open(path).unwrap().to_rgb32f();
+        let rgb: Vec<f32> = decoded.pixels().flat_map(|pixel| pixel.0).collect();
+        assert_eq!(rgb, [2.5, 0.5, 0.0, 0.0, 0.25, 1.0]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_args_scene_cache() {
+        let options = parse_args(args(&["--scene-cache", "cache"])).unwrap();
+        assert_eq!(options.scene_cache, Some(PathBuf::from("cache")));
+        assert!(parse_args(args(&["--scene-cache"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_report() {
+        let options = parse_args(args(&["--report", "test_file", "--tolerance", "3"])).unwrap();
+        assert_eq!(options.report, Some("test_file".to_string()));
+        assert_eq!(options.tolerance, 3);
+    }
+
+    #[test]
+    fn test_parse_args_seed() {
+        assert_eq!(parse_args(args(&["--seed", "1234"])).unwrap().seed, Some(1234));
+        assert!(parse_args(args(&["--seed", "-1"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_timeout() {
+        assert_eq!(parse_args(args(&["--timeout", "2.5"])).unwrap().timeout, Some(Duration::from_millis(2500)));
+        for invalid in ["0", "-1", "inf", "soon"] {
+            assert!(parse_args(args(&["--timeout", invalid])).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_apply_seed_precedence() {
+        let load = |scene: &str| ParsedConfigState::new().load_config_str(scene).unwrap();
+
+        let mut config = load("seed 7\n");
+        assert_eq!(apply_seed(&mut config, Some(3)), 3);
+        assert_eq!(config.seed, Some(3));
+
+        let mut config = load("seed 7\n");
+        assert_eq!(apply_seed(&mut config, None), 7);
+
+        let mut config = load("size 4 4\n");
+        let generated = apply_seed(&mut config, None);
+        assert_eq!(config.seed, Some(generated));
+    }
+
+    #[test]
+    fn test_same_seed_renders_identically() {
+        // Jittered (anti-aliased) passes over a glossy mirror: every random stream is used
+        let scene = "size 32 24\ncamera 0 1 5 0 0 0 0 1 0 45\nmaxdepth 2\nambient 0.1 0.1 0.1\n\
+                     point 2 4 3 0.8 0.8 0.8\ndiffuse 0.8 0.2 0.2\nsphere -0.8 0.3 0 0.7\n\
+                     diffuse 0.1 0.1 0.1\nspecular 0.8 0.8 0.8\nroughness 0.4\nsphere 0.8 0.3 0 0.7\n";
+        let render = |seed: u64| {
+            let mut config = ParsedConfigState::new().load_config_str(scene).unwrap();
+            apply_seed(&mut config, Some(seed));
+            RayTracer::new(config).render_progressive(4, |_| {}).unwrap()
+        };
+
+        assert_eq!(render(11).data, render(11).data);
+        assert_ne!(render(11).data, render(12).data);
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        assert!(parse_args(args(&["--verify"])).is_err());
+        assert!(parse_args(args(&["--tolerance", "many"])).is_err());
+        assert!(parse_args(args(&["--bogus"])).is_err());
+    }
+
+    #[test]
+    fn test_validate_reference_only_overwrites_when_confirmed() {
+        let options = parse_args(args(&["--validate-reference", "tp31.png", "--yes"])).unwrap();
+        assert_eq!(options.validate_reference.as_deref(), Some("tp31.png"));
+        assert!(options.yes);
+        assert!(!parse_args(args(&["--validate-reference", "tp31.png"])).unwrap().yes);
+
+        let dir = std::env::temp_dir().join(format!("raytracer_validate_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let scene = dir.join("tp31.test");
+        let reference = dir.join("tp31.png");
+        std::fs::copy("test_file/jalon3/tp31.test", &scene).unwrap();
+        // A stale reference: another scene's image
+        std::fs::copy("test_file/jalon3/tp32.png", &reference).unwrap();
+        let (scene, reference) = (scene.to_str().unwrap(), reference.to_str().unwrap());
+        let stale = std::fs::read(reference).unwrap();
+
+        let declined = validate_reference(scene, reference, 0, |_| false).unwrap();
+        assert!(matches!(declined, ReferenceUpdate::Declined { diff_pixels } if diff_pixels > 0));
+        assert_eq!(std::fs::read(reference).unwrap(), stale);
+
+        let mut asked = None;
+        let updated = validate_reference(scene, reference, 0, |diff_pixels| {
+            asked = Some(diff_pixels);
+            true
+        })
+        .unwrap();
+        assert_eq!(updated, ReferenceUpdate::Updated { diff_pixels: asked.unwrap() });
+        assert!(verify(scene, reference, 0).unwrap().passed);
+
+        // An up-to-date reference is kept without asking
+        let unchanged = validate_reference(scene, reference, 0, |_| panic!("nothing to confirm")).unwrap();
+        assert_eq!(unchanged, ReferenceUpdate::Unchanged);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_verify_error_kinds() {
+        assert!(matches!(
+            verify("test_file/does_not_exist.test", "test_file/jalon3/tp31.png", 0),
+            Err(RayTracerError::Io { .. })
+        ));
+        assert!(matches!(
+            verify("test_file/jalon3/tp31.test", "test_file/does_not_exist.png", 0),
+            Err(RayTracerError::Io { .. })
+        ));
+    }
+
+    #[test]
+    fn test_verify_matching_reference() {
+        let report = verify("test_file/jalon3/tp31.test", "test_file/jalon3/tp31.png", 0).unwrap();
+        assert_eq!(report.diff_pixels, 0);
+        assert!(report.passed);
+    }
+
+    #[test]
+    fn test_compare_directory_reports_each_scene() {
+        let dir = std::env::temp_dir().join(format!("raytracer_report_{}", std::process::id()));
+        let nested = dir.join("nested");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::copy("test_file/jalon3/tp31.test", dir.join("match.test")).unwrap();
+        std::fs::copy("test_file/jalon3/tp31.png", dir.join("match.png")).unwrap();
+        std::fs::copy("test_file/jalon3/tp31.test", nested.join("mismatch.test")).unwrap();
+        std::fs::copy("test_file/jalon3/tp32.png", nested.join("mismatch.png")).unwrap();
+        std::fs::write(dir.join("broken.test"), "sphere 0 0\n").unwrap();
+
+        let reports = compare_directory(&dir, 0).unwrap();
+        let expected_diff = verify("test_file/jalon3/tp31.test", "test_file/jalon3/tp32.png", 0)
+            .unwrap()
+            .diff_pixels;
+
+        let names: Vec<_> = reports
+            .iter()
+            .map(|report| Path::new(&report.scene).file_stem().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, ["broken", "match", "mismatch"]);
+
+        let (broken, matching, mismatched) = (&reports[0], &reports[1], &reports[2]);
+        assert!(!broken.passed && broken.error.is_some());
+        assert!(matching.passed);
+        assert_eq!(matching.diff_pixels, 0);
+        assert_eq!(matching.psnr, f64::INFINITY);
+        assert!(!mismatched.passed);
+        assert_eq!(mismatched.diff_pixels, expected_diff);
+        assert!(mismatched.psnr.is_finite());
+        assert!(Path::new(mismatched.diff_image.as_ref().unwrap()).exists());
+
+        let markdown = report_markdown(&reports);
+        assert!(markdown.contains(&format!("| FAIL | {expected_diff} |")));
+        assert!(markdown.ends_with("1/3 scene(s) passed\n"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_verify_without_size_uses_reference_dimensions() {
+        let dir = std::env::temp_dir().join(format!("raytracer_no_size_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let original = std::fs::read_to_string("test_file/jalon3/tp31.test").unwrap();
+        let scene = dir.join("tp31.test");
+        std::fs::write(&scene, original.replace("size 640 480\n", "")).unwrap();
+
+        let report = verify(scene.to_str().unwrap(), "test_file/jalon3/tp31.png", 0).unwrap();
+        assert!(report.passed);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_verify_mismatched_reference() {
+        let report = verify("test_file/jalon3/tp31.test", "test_file/jalon3/tp32.png", 0).unwrap();
+        assert!(report.diff_pixels > 0);
+        assert!(!report.passed);
+
+        let tolerant = verify(
+            "test_file/jalon3/tp31.test",
+            "test_file/jalon3/tp32.png",
+            report.diff_pixels,
+        )
+        .unwrap();
+        assert!(tolerant.passed);
+    }
+}
@@ -0,0 +1,54 @@
+//! Error type shared by scene loading, rendering and image I/O
+//!
+//! Callers can match on the variant to tell a missing file from a malformed scene or
+//! mismatched images; `Display` gives the human-readable message.
+
+use std::fmt;
+use std::time::Duration;
+
+/// Errors returned by the ray tracer's public API
+#[derive(Debug, Clone, PartialEq)]
+pub enum RayTracerError {
+    /// A file could not be opened, read, decoded or written
+    Io { path: String, message: String },
+    /// A scene line could not be parsed (`line` is 1-based)
+    Parse { line: usize, msg: String },
+    /// Every line parsed but the scene as a whole is inconsistent
+    /// (e.g. a mesh without `endmesh`)
+    InvalidScene(String),
+    /// Two images (or an image and a per-pixel buffer) do not have the same size
+    DimensionMismatch {
+        expected: (u32, u32),
+        actual: (u32, u32),
+    },
+    /// The renderer was asked for something it cannot produce
+    Render(String),
+    /// A render was still running when its timeout (see `RayTracer::with_timeout`)
+    /// ran out, and was abandoned
+    Timeout(Duration),
+}
+
+impl RayTracerError {
+    pub fn io(path: &str, error: impl fmt::Display) -> Self {
+        RayTracerError::Io {
+            path: path.to_string(),
+            message: error.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for RayTracerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RayTracerError::Io { path, message } => write!(f, "{}: {}", path, message),
+            RayTracerError::Parse { line, msg } => write!(f, "line {}: {}", line, msg),
+            RayTracerError::InvalidScene(msg) | RayTracerError::Render(msg) => write!(f, "{}", msg),
+            RayTracerError::DimensionMismatch { .. } => write!(f, "Images have different dimensions"),
+            RayTracerError::Timeout(timeout) => {
+                write!(f, "Render did not finish within the {:.1}s timeout", timeout.as_secs_f64())
+            }
+        }
+    }
+}
+
+impl std::error::Error for RayTracerError {}
@@ -3,6 +3,8 @@
 //! This module provides utilities for loading, comparing, and saving images.
 //! Images are represented in RGB format with 8 bits per channel, packed into u32.
 
+use crate::error::RayTracerError;
+use image::codecs::pnm::{PnmEncoder, PnmSubtype, SampleEncoding};
 use image::GenericImageView;
 use std::path::Path;
 
@@ -10,6 +12,18 @@ use std::path::Path;
 const RED_SHIFT: u32 = 16;
 const GREEN_SHIFT: u32 = 8;
 const CHANNEL_MASK: u32 = 0xFF;
+const ALPHA_MASK: u32 = 0xFF00_0000;
+
+/// Synthetic image patterns used to exercise the image pipeline without rendering
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TestPattern {
+    /// Alternating black and white squares of `cell` pixels, starting with black at (0, 0)
+    Checkerboard { cell: u32 },
+    /// Red ramps from 0 to 255 left-to-right, green from 0 to 255 top-to-bottom, blue is 0
+    Gradient,
+    /// White one-pixel lines every `spacing` pixels (starting at 0) over a black background
+    Grid { spacing: u32 },
+}
 
 /// Represents an RGB image with packed pixel data
 ///
@@ -39,6 +53,76 @@ impl Image {
         }
     }
 
+    /// Paints `color` over every pixel whose id differs from its right or bottom
+    /// neighbour, outlining the regions of an id buffer
+    ///
+    /// # Arguments
+    /// * `ids` - One id per pixel in row-major order (e.g. object ids from the renderer)
+    /// * `color` - Packed RGB outline color
+    ///
+    /// # Returns
+    /// * `Err(RayTracerError::Render)` - `ids` does not have one entry per pixel
+    pub fn overlay_outline(&mut self, ids: &[u32], color: u32) -> Result<(), RayTracerError> {
+        if ids.len() != self.data.len() {
+            return Err(RayTracerError::Render(
+                "Id buffer size does not match the image".to_string(),
+            ));
+        }
+        let (width, height) = (self.width as usize, self.height as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let id = ids[y * width + x];
+                let right_differs = x + 1 < width && ids[y * width + x + 1] != id;
+                let below_differs = y + 1 < height && ids[(y + 1) * width + x] != id;
+                if right_differs || below_differs {
+                    self.data[y * width + x] = (self.data[y * width + x] & 0xFF00_0000) | color;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Generates an image filled with a synthetic test pattern
+    ///
+    /// # Arguments
+    /// * `width` - Width of the image in pixels
+    /// * `height` - Height of the image in pixels
+    /// * `kind` - Pattern to generate
+    pub fn test_pattern(width: u32, height: u32, kind: TestPattern) -> Self {
+        let mut data = Vec::with_capacity((width * height) as usize);
+
+        for y in 0..height {
+            for x in 0..width {
+                let pixel = match kind {
+                    TestPattern::Checkerboard { cell } => {
+                        let cell = cell.max(1);
+                        if ((x / cell) + (y / cell)) % 2 == 0 {
+                            0x000000
+                        } else {
+                            0xFFFFFF
+                        }
+                    }
+                    TestPattern::Gradient => {
+                        let r = ramp(x, width);
+                        let g = ramp(y, height);
+                        pack_rgb(r, g, 0)
+                    }
+                    TestPattern::Grid { spacing } => {
+                        let spacing = spacing.max(1);
+                        if x % spacing == 0 || y % spacing == 0 {
+                            0xFFFFFF
+                        } else {
+                            0x000000
+                        }
+                    }
+                };
+                data.push(pixel);
+            }
+        }
+
+        Image::new(width, height, data)
+    }
+
     /// Compares two images and returns a difference image
     ///
     /// For each pixel, calculates the absolute difference for each RGB channel.
@@ -50,11 +134,9 @@ impl Image {
     ///
     /// # Returns
     /// * `Ok(Image)` - Difference image where each channel contains the absolute difference
-    /// * `Err(String)` - Error message if dimensions don't match
-    pub fn compare(img1: &Image, img2: &Image) -> Result<(u128, Image), String> {
-        if img1.height != img2.height || img1.width != img2.width {
-            return Err("Images have different dimensions".to_string());
-        }
+    /// * `Err(RayTracerError::DimensionMismatch)` - The images differ in size
+    pub fn compare(img1: &Image, img2: &Image) -> Result<(u128, Image), RayTracerError> {
+        Self::check_same_size(img1, img2)?;
 
         let mut diff_pixels: Vec<u32> = Vec::with_capacity(img1.data.len());
         let mut total_diff: u128 = 0;
@@ -87,6 +169,165 @@ impl Image {
 
         Ok((total_diff, Image::new(img1.width, img1.height, diff_pixels)))
     }
+
+    /// Converts the image to grayscale using Rec. 709 luma weights
+    ///
+    /// Each pixel becomes `0.2126 R + 0.7152 G + 0.0722 B`, rounded and packed into all
+    /// three channels; the alpha byte is kept.
+    pub fn to_grayscale(&self) -> Image {
+        let data = self
+            .data
+            .iter()
+            .map(|&pixel| {
+                let y = luma(pixel);
+                (pixel & ALPHA_MASK) | pack_rgb(y, y, y)
+            })
+            .collect();
+        Image::new(self.width, self.height, data)
+    }
+
+    /// Compares two images by luminance only
+    ///
+    /// Like `compare`, but each pixel is reduced to its Rec. 709 luma first, so renders
+    /// that differ in hue at equal brightness match and subtle brightness changes stand out.
+    ///
+    /// # Returns
+    /// * `Ok((u128, Image))` - Number of pixels whose luma differs by more than 1, and a
+    ///   grayscale image of the absolute luma differences
+    /// * `Err(RayTracerError::DimensionMismatch)` - The images differ in size
+    pub fn compare_luma(img1: &Image, img2: &Image) -> Result<(u128, Image), RayTracerError> {
+        Self::check_same_size(img1, img2)?;
+
+        let mut total_diff: u128 = 0;
+        let diff_pixels = img1
+            .data
+            .iter()
+            .zip(&img2.data)
+            .map(|(p1, p2)| {
+                let d = luma(*p1).abs_diff(luma(*p2));
+                if d <= 1 {
+                    0
+                } else {
+                    total_diff += 1;
+                    pack_rgb(d, d, d)
+                }
+            })
+            .collect();
+
+        Ok((total_diff, Image::new(img1.width, img1.height, diff_pixels)))
+    }
+
+    fn check_same_size(img1: &Image, img2: &Image) -> Result<(), RayTracerError> {
+        if img1.height != img2.height || img1.width != img2.width {
+            return Err(RayTracerError::DimensionMismatch {
+                expected: (img1.width, img1.height),
+                actual: (img2.width, img2.height),
+            });
+        }
+        Ok(())
+    }
+
+    /// Computes the peak signal-to-noise ratio between two images, in decibels
+    ///
+    /// The mean squared error is taken over all three channels of every pixel.
+    ///
+    /// # Returns
+    /// * `Ok(f64)` - PSNR in dB, `f64::INFINITY` for identical images
+    /// * `Err(RayTracerError::DimensionMismatch)` - The images differ in size
+    pub fn psnr(img1: &Image, img2: &Image) -> Result<f64, RayTracerError> {
+        Self::check_same_size(img1, img2)?;
+
+        let mut squared_error: u64 = 0;
+        for (p1, p2) in img1.data.iter().zip(&img2.data) {
+            let (r1, g1, b1) = extract_rgb(*p1);
+            let (r2, g2, b2) = extract_rgb(*p2);
+            for (c1, c2) in [(r1, r2), (g1, g2), (b1, b2)] {
+                let d = c1 as i64 - c2 as i64;
+                squared_error += (d * d) as u64;
+            }
+        }
+
+        if squared_error == 0 {
+            return Ok(f64::INFINITY);
+        }
+        let mse = squared_error as f64 / (img1.data.len() as f64 * 3.0);
+        Ok(10.0 * (255.0 * 255.0 / mse).log10())
+    }
+
+    /// Counts how many pixels take each channel value
+    ///
+    /// Spikes at 0 or 255 reveal clipped shadows or highlights when tuning exposure
+    /// and grading.
+    ///
+    /// # Returns
+    /// Red, green and blue histograms, each indexed by channel value
+    pub fn histogram(&self) -> [[u32; 256]; 3] {
+        let mut histogram = [[0; 256]; 3];
+        for &pixel in &self.data {
+            let (r, g, b) = extract_rgb(pixel);
+            histogram[0][r as usize] += 1;
+            histogram[1][g as usize] += 1;
+            histogram[2][b as usize] += 1;
+        }
+        histogram
+    }
+
+    /// Resizes the image by picking, for each output pixel, the source pixel under
+    /// its center
+    ///
+    /// Keeps hard edges and exact colors, e.g. to upscale renders for inspection.
+    /// Resizing an empty image gives a black one.
+    pub fn resize_nearest(&self, width: u32, height: u32) -> Image {
+        let mut data = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let sx = (x as u64 * self.width as u64 / width as u64) as usize;
+                let sy = (y as u64 * self.height as u64 / height as u64) as usize;
+                data.push(self.data.get(sy * self.width as usize + sx).copied().unwrap_or(0));
+            }
+        }
+        Image::new(width, height, data)
+    }
+
+    /// Resizes the image by blending, for each output pixel, the four source pixels
+    /// around its center, weighted by distance
+    ///
+    /// Pixel centers are aligned, so upscaling keeps the edge pixels' colors and blends
+    /// between them; the alpha byte comes from the nearest of the four. Resizing an
+    /// empty image gives a black one.
+    pub fn resize_bilinear(&self, width: u32, height: u32) -> Image {
+        if self.data.is_empty() {
+            return Image::new(width, height, vec![0; (width * height) as usize]);
+        }
+        // Source coordinate of an output pixel center, clamped to the source pixel centers
+        let source = |pos: u32, size: u32, source_size: u32| {
+            let t = (pos as f32 + 0.5) * source_size as f32 / size as f32 - 0.5;
+            let t = t.clamp(0.0, (source_size - 1) as f32);
+            let low = t.floor() as usize;
+            (low, (low + 1).min(source_size as usize - 1), t - low as f32)
+        };
+        let mut data = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            let (y0, y1, ty) = source(y, height, self.height);
+            for x in 0..width {
+                let (x0, x1, tx) = source(x, width, self.width);
+                let at = |sx: usize, sy: usize| self.data[sy * self.width as usize + sx];
+                let corners = [at(x0, y0), at(x1, y0), at(x0, y1), at(x1, y1)];
+                let weights = [(1.0 - tx) * (1.0 - ty), tx * (1.0 - ty), (1.0 - tx) * ty, tx * ty];
+                let mut rgb = [0.0f32; 3];
+                for (pixel, weight) in corners.iter().zip(weights) {
+                    let (r, g, b) = extract_rgb(*pixel);
+                    rgb[0] += r as f32 * weight;
+                    rgb[1] += g as f32 * weight;
+                    rgb[2] += b as f32 * weight;
+                }
+                let nearest = corners[(tx >= 0.5) as usize + 2 * (ty >= 0.5) as usize];
+                let [r, g, b] = rgb.map(|c| c.round() as u32);
+                data.push((nearest & ALPHA_MASK) | pack_rgb(r, g, b));
+            }
+        }
+        Image::new(width, height, data)
+    }
 }
 
 /// Extracts RGB channels from a packed pixel value
@@ -118,6 +359,23 @@ fn pack_rgb(r: u32, g: u32, b: u32) -> u32 {
     (r << RED_SHIFT) | (g << GREEN_SHIFT) | b
 }
 
+/// Rec. 709 luma of a packed pixel, in `0..=255`
+#[inline]
+fn luma(pixel: u32) -> u32 {
+    let (r, g, b) = extract_rgb(pixel);
+    (0.2126 * r as f32 + 0.7152 * g as f32 + 0.0722 * b as f32).round() as u32
+}
+
+/// Maps a coordinate in `0..size` linearly onto a channel value in `0..=255`
+#[inline]
+fn ramp(pos: u32, size: u32) -> u32 {
+    if size <= 1 {
+        0
+    } else {
+        (pos * 255 + (size - 1) / 2) / (size - 1)
+    }
+}
+
 /// Loads an image from a file
 ///
 /// # Arguments
@@ -125,9 +383,9 @@ fn pack_rgb(r: u32, g: u32, b: u32) -> u32 {
 ///
 /// # Returns
 /// * `Ok(Image)` - Successfully loaded image
-/// * `Err(String)` - Error message if loading fails
-pub fn file_to_image(path: &str) -> Result<Image, String> {
-    let img = image::open(Path::new(path)).map_err(|e| e.to_string())?;
+/// * `Err(RayTracerError::Io)` - The file could not be read or decoded
+pub fn file_to_image(path: &str) -> Result<Image, RayTracerError> {
+    let img = image::open(Path::new(path)).map_err(|e| RayTracerError::io(path, e))?;
     let (width, height) = img.dimensions();
     let mut data = Vec::with_capacity((width * height) as usize);
 
@@ -141,7 +399,10 @@ pub fn file_to_image(path: &str) -> Result<Image, String> {
     Ok(Image::new(width, height, data))
 }
 
-/// Saves an image to a file
+/// Saves an image to a file, encoded according to the extension of `path`
+///
+/// `.ppm` paths are written as binary PPM (`P6`); the `image` crate alone would write
+/// them as PAM, which many PPM readers reject.
 ///
 /// # Arguments
 /// * `img` - Image to save
@@ -149,8 +410,8 @@ pub fn file_to_image(path: &str) -> Result<Image, String> {
 ///
 /// # Returns
 /// * `Ok(())` - Image saved successfully
-/// * `Err(String)` - Error message if saving fails
-pub fn save_image(img: &Image, path: &str) -> Result<(), String> {
+/// * `Err(RayTracerError::Io)` - The file could not be encoded or written
+pub fn save_image(img: &Image, path: &str) -> Result<(), RayTracerError> {
     let mut imgbuf = image::RgbImage::new(img.width, img.height);
 
     for y in 0..img.height {
@@ -161,7 +422,173 @@ pub fn save_image(img: &Image, path: &str) -> Result<(), String> {
         }
     }
 
-    imgbuf.save(path).map_err(|e| e.to_string())
+    let is_ppm = Path::new(path)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("ppm"));
+    if is_ppm {
+        let file = std::fs::File::create(path).map_err(|e| RayTracerError::io(path, e))?;
+        let encoder = PnmEncoder::new(std::io::BufWriter::new(file))
+            .with_subtype(PnmSubtype::Pixmap(SampleEncoding::Binary));
+        return imgbuf.write_with_encoder(encoder).map_err(|e| RayTracerError::io(path, e));
+    }
+    imgbuf.save(path).map_err(|e| RayTracerError::io(path, e))
+}
+
+/// Saves linear floating-point RGB pixels, e.g. a renderer's framebuffer before tone
+/// mapping and quantization
+///
+/// `.pfm` paths are written as a Portable Float Map and `.exr` paths as OpenEXR (see
+/// `save_exr_channels`); other extensions go through the `image` crate, so use a float
+/// format.
+///
+/// # Arguments
+/// * `width` - Width of the image in pixels
+/// * `height` - Height of the image in pixels
+/// * `pixels` - Row-major linear RGB values, top row first
+/// * `path` - Destination file path
+///
+/// # Returns
+/// * `Ok(())` - Image saved successfully
+/// * `Err(RayTracerError::Io)` - The file could not be encoded or written
+pub fn save_linear_image(width: u32, height: u32, pixels: &[[f32; 3]], path: &str) -> Result<(), RayTracerError> {
+    let extension = Path::new(path).extension();
+    if extension.is_some_and(|extension| extension.eq_ignore_ascii_case("pfm")) {
+        return std::fs::write(path, encode_pfm(width, height, pixels)).map_err(|e| RayTracerError::io(path, e));
+    }
+    if extension.is_some_and(|extension| extension.eq_ignore_ascii_case("exr")) {
+        let channel = |index: usize| -> Vec<f32> { pixels.iter().map(|pixel| pixel[index]).collect() };
+        let (red, green, blue) = (channel(0), channel(1), channel(2));
+        return save_exr_channels(width, height, &[("R", &red), ("G", &green), ("B", &blue)], path);
+    }
+
+    let samples = pixels.iter().flatten().copied().collect();
+    let imgbuf = image::Rgb32FImage::from_raw(width, height, samples)
+        .ok_or_else(|| RayTracerError::io(path, "pixel count does not match the image size"))?;
+    imgbuf.save(path).map_err(|e| RayTracerError::io(path, e))
+}
+
+/// Portable Float Map: a text header, then little-endian RGB floats with the bottom
+/// row first
+fn encode_pfm(width: u32, height: u32, pixels: &[[f32; 3]]) -> Vec<u8> {
+    let mut bytes = format!("PF\n{} {}\n-1.0\n", width, height).into_bytes();
+    for row in pixels.chunks(width as usize).rev() {
+        for value in row.iter().flatten() {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    bytes
+}
+
+/// Saves any number of named float channels as an uncompressed OpenEXR file
+///
+/// Readers show `R`, `G` and `B` channels as the image and keep the others (e.g.
+/// per-pixel sample counts) as extra layers.
+///
+/// # Arguments
+/// * `width` - Width of the image in pixels
+/// * `height` - Height of the image in pixels
+/// * `channels` - Channel names with one row-major value per pixel each
+/// * `path` - Destination file path
+///
+/// # Returns
+/// * `Ok(())` - Image saved successfully
+/// * `Err(RayTracerError::Io)` - A channel is empty-named, repeated or of the wrong
+///   size, or the file could not be written
+pub fn save_exr_channels(width: u32, height: u32, channels: &[(&str, &[f32])], path: &str) -> Result<(), RayTracerError> {
+    let pixel_count = width as usize * height as usize;
+    for (index, (name, values)) in channels.iter().enumerate() {
+        if name.is_empty() || name.contains('\0') || channels[..index].iter().any(|(other, _)| other == name) {
+            return Err(RayTracerError::io(path, format!("invalid or repeated channel name '{}'", name)));
+        }
+        if values.len() != pixel_count {
+            return Err(RayTracerError::io(path, format!("channel '{}' does not match the image size", name)));
+        }
+    }
+    std::fs::write(path, encode_exr(width, height, channels)).map_err(|e| RayTracerError::io(path, e))
+}
+
+/// Single-part scanline OpenEXR with 32-bit float channels, one uncompressed scanline
+/// per block. The format requires channels sorted by name, in the header and the data.
+fn encode_exr(width: u32, height: u32, channels: &[(&str, &[f32])]) -> Vec<u8> {
+    const FLOAT: i32 = 2;
+    let mut sorted: Vec<&(&str, &[f32])> = channels.iter().collect();
+    sorted.sort_by_key(|(name, _)| *name);
+
+    let mut bytes = vec![0x76, 0x2F, 0x31, 0x01, 2, 0, 0, 0];
+    let mut attribute = |name: &str, kind: &str, value: &[u8]| {
+        for text in [name, kind] {
+            bytes.extend_from_slice(text.as_bytes());
+            bytes.push(0);
+        }
+        bytes.extend_from_slice(&(value.len() as i32).to_le_bytes());
+        bytes.extend_from_slice(value);
+    };
+    let mut channel_list = Vec::new();
+    for (name, _) in &sorted {
+        channel_list.extend_from_slice(name.as_bytes());
+        channel_list.push(0);
+        channel_list.extend_from_slice(&FLOAT.to_le_bytes());
+        // pLinear and reserved bytes, then x and y sampling
+        channel_list.extend_from_slice(&[0; 4]);
+        channel_list.extend_from_slice(&1i32.to_le_bytes());
+        channel_list.extend_from_slice(&1i32.to_le_bytes());
+    }
+    channel_list.push(0);
+    let window: Vec<u8> = [0, 0, width as i32 - 1, height as i32 - 1]
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect();
+    attribute("channels", "chlist", &channel_list);
+    attribute("compression", "compression", &[0]);
+    attribute("dataWindow", "box2i", &window);
+    attribute("displayWindow", "box2i", &window);
+    attribute("lineOrder", "lineOrder", &[0]);
+    attribute("pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+    attribute("screenWindowCenter", "v2f", &[0; 8]);
+    attribute("screenWindowWidth", "float", &1.0f32.to_le_bytes());
+    bytes.push(0);
+
+    // Offset table, then one block per scanline: y, data size and each channel's row
+    let row_size = sorted.len() * width as usize * 4;
+    let table_end = bytes.len() + height as usize * 8;
+    for y in 0..height as usize {
+        bytes.extend_from_slice(&((table_end + y * (8 + row_size)) as u64).to_le_bytes());
+    }
+    for y in 0..height as usize {
+        bytes.extend_from_slice(&(y as i32).to_le_bytes());
+        bytes.extend_from_slice(&(row_size as i32).to_le_bytes());
+        for (_, values) in &sorted {
+            for value in &values[y * width as usize..(y + 1) * width as usize] {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+    }
+    bytes
+}
+
+/// Saves a single-channel 16-bit image (such as a depth map) as a grayscale file
+///
+/// Only the low 16 bits of each pixel are written.
+///
+/// # Arguments
+/// * `img` - Image whose pixels hold values in `0..=65535`
+/// * `path` - Destination file path (must be a format supporting 16-bit luma, e.g. PNG)
+///
+/// # Returns
+/// * `Ok(())` - Image saved successfully
+/// * `Err(RayTracerError::Io)` - The file could not be encoded or written
+pub fn save_depth_image(img: &Image, path: &str) -> Result<(), RayTracerError> {
+    let mut imgbuf: image::ImageBuffer<image::Luma<u16>, Vec<u16>> =
+        image::ImageBuffer::new(img.width, img.height);
+
+    for y in 0..img.height {
+        for x in 0..img.width {
+            let value = img.data[(y * img.width + x) as usize] & 0xFFFF;
+            imgbuf.put_pixel(x, y, image::Luma([value as u16]));
+        }
+    }
+
+    imgbuf.save(path).map_err(|e| RayTracerError::io(path, e))
 }
 
 // ==========================================================
@@ -188,6 +615,78 @@ mod tests {
         assert_eq!(diff_img.width, 3);
     }
 
+    #[test]
+    fn test_resize_upscales_2x2() {
+        // Black and white columns over a red and blue bottom row
+        let image = Image::new(2, 2, vec![0x000000, 0xFFFFFF, 0xFF0000, 0x0000FF]);
+
+        let nearest = image.resize_nearest(4, 4);
+        assert_eq!((nearest.width, nearest.height), (4, 4));
+        assert_eq!(&nearest.data[0..4], &[0x000000, 0x000000, 0xFFFFFF, 0xFFFFFF]);
+        assert_eq!(&nearest.data[4..8], &nearest.data[0..4]);
+        assert_eq!(&nearest.data[12..16], &[0xFF0000, 0xFF0000, 0x0000FF, 0x0000FF]);
+
+        let bilinear = image.resize_bilinear(4, 4);
+        // Corners keep the source colors; inner pixels blend a quarter and three quarters
+        assert_eq!(&bilinear.data[0..4], &[0x000000, 0x404040, 0xBFBFBF, 0xFFFFFF]);
+        assert_eq!(bilinear.data[15], 0x0000FF);
+        assert_eq!(bilinear.data[4], 0x400000);
+
+        // The center of a 3x3 upscale lies midway between all four source pixels
+        let center = image.resize_bilinear(3, 3).data[4];
+        assert_eq!(extract_rgb(center), (128, 64, 128));
+        assert_eq!(image.resize_nearest(2, 2), image);
+    }
+
+    #[test]
+    fn test_exr_channels_round_trip() {
+        let path = std::env::temp_dir().join(format!("raytracer_channels_{}.exr", std::process::id()));
+        let path = path.to_str().unwrap();
+        let red = [1.0, 0.0, 0.25, 2.0, 0.0, 0.5];
+        let green = [0.0, 1.0, 0.25, 0.0, 0.0, 0.5];
+        let blue = [0.0, 0.0, 0.25, 0.0, 1.0, 0.5];
+        let samples = [1.0, 9.0, 1.0, 1.0, 9.0, 9.0];
+        // Given out of order: the file sorts them
+        let channels: [(&str, &[f32]); 4] = [("samples", &samples), ("R", &red), ("G", &green), ("B", &blue)];
+        save_exr_channels(3, 2, &channels, path).unwrap();
+
+        // Any EXR reader sees the RGB image
+        let decoded = image::open(path).unwrap().to_rgb32f();
+        assert_eq!(decoded.dimensions(), (3, 2));
+        let rgb: Vec<f32> = decoded.pixels().flat_map(|pixel| pixel.0).collect();
+        let expected: Vec<f32> = (0..6).flat_map(|i| [red[i], green[i], blue[i]]).collect();
+        assert_eq!(rgb, expected);
+
+        // The extra channel follows B, G and R in the second scanline block
+        let bytes = std::fs::read(path).unwrap();
+        let header_end = bytes.windows(8).position(|w| w == b"samples\0").unwrap();
+        assert!(header_end > 0);
+        let second_block = u64::from_le_bytes(bytes[bytes.len() - 2 * (8 + 4 * 12) - 8..][..8].try_into().unwrap());
+        let row: Vec<f32> = bytes[second_block as usize + 8 + 3 * 12..][..12]
+            .chunks(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        assert_eq!(row, &samples[3..6]);
+
+        assert!(save_exr_channels(3, 2, &[("R", &red[..5])], path).is_err());
+        assert!(save_exr_channels(3, 2, &[("R", &red), ("R", &red)], path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_encode_pfm_writes_rows_bottom_up() {
+        let pixels = [[1.0, 0.0, 0.0], [0.0, 2.5, 0.0], [0.0, 0.0, 0.25], [4.0, 4.0, 4.0]];
+        let bytes = encode_pfm(2, 2, &pixels);
+
+        let header = b"PF\n2 2\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header);
+        let values: Vec<f32> = bytes[header.len()..]
+            .chunks(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        assert_eq!(values, [0.0, 0.0, 0.25, 4.0, 4.0, 4.0, 1.0, 0.0, 0.0, 0.0, 2.5, 0.0]);
+    }
+
     #[test]
     fn test_compare_dimension_mismatch() {
         // Arrange: Different sizes
@@ -197,12 +696,16 @@ mod tests {
         // Act
         let result = Image::compare(&img1, &img2);
 
-        // Assert: Should be an Error
-        assert!(result.is_err());
+        // Assert: Should be a dimension mismatch error
+        let err = result.unwrap_err();
         assert_eq!(
-            result.err(),
-            Some("Images have different dimensions".to_string())
+            err,
+            RayTracerError::DimensionMismatch {
+                expected: (2, 2),
+                actual: (3, 3)
+            }
         );
+        assert_eq!(err.to_string(), "Images have different dimensions");
     }
 
     #[test]
@@ -241,4 +744,120 @@ mod tests {
         // Result: 0x01FFFF
         assert_eq!(img.data[0], 0x01FFFF);
     }
+
+    #[test]
+    fn test_histogram_counts_channel_values() {
+        // Each red and green value appears once per row or column, blue is always 0
+        let [red, green, blue] = Image::test_pattern(256, 256, TestPattern::Gradient).histogram();
+        assert!(red.iter().all(|&count| count == 256));
+        assert!(green.iter().all(|&count| count == 256));
+        assert_eq!(blue[0], 256 * 256);
+        assert_eq!(blue[1..].iter().sum::<u32>(), 0);
+
+        let black = Image::new(4, 2, vec![0xFF00_0000; 8]).histogram();
+        for channel in black {
+            assert_eq!(channel[0], 8);
+            assert_eq!(channel[1..].iter().sum::<u32>(), 0);
+        }
+    }
+
+    #[test]
+    fn test_grayscale_uses_rec709_luma() {
+        let img = Image::new(3, 1, vec![0xFFFF_0000, 0x00FF00, 0xFFFFFF]);
+        let gray = img.to_grayscale();
+        // 0.2126 * 255 = 54.2, 0.7152 * 255 = 182.4
+        assert_eq!(gray.data, vec![0xFF36_3636, 0xB6B6B6, 0xFFFFFF]);
+    }
+
+    #[test]
+    fn test_compare_luma_distinguishes_red_from_green() {
+        let black = Image::new(2, 1, vec![0x000000; 2]);
+        let red = Image::new(2, 1, vec![0xFF0000; 2]);
+        let green = Image::new(2, 1, vec![0x00FF00; 2]);
+
+        // Per channel, both are a full-scale difference on every pixel
+        let (red_diff, _) = Image::compare(&red, &black).unwrap();
+        let (green_diff, _) = Image::compare(&green, &black).unwrap();
+        assert_eq!(red_diff, green_diff);
+
+        // In luminance, green is far brighter than red
+        let (_, red_luma) = Image::compare_luma(&red, &black).unwrap();
+        let (_, green_luma) = Image::compare_luma(&green, &black).unwrap();
+        assert_eq!(red_luma.data[0], 0x363636);
+        assert_eq!(green_luma.data[0], 0xB6B6B6);
+        let (count, _) = Image::compare_luma(&red, &green).unwrap();
+        assert_eq!(count, 2);
+
+        // Different hues of equal luma match: pure blue and a dark gray are both 18
+        let blue = Image::new(1, 1, vec![0x0000FF]);
+        let gray = Image::new(1, 1, vec![0x121212]);
+        assert_eq!(Image::compare(&blue, &gray).unwrap().0, 1);
+        assert_eq!(Image::compare_luma(&blue, &gray).unwrap().0, 0);
+    }
+
+    #[test]
+    fn test_psnr() {
+        let img1 = Image::new(2, 1, vec![0x000000, 0x000000]);
+        let img2 = Image::new(2, 1, vec![0x000000, 0x0A0A0A]);
+
+        assert_eq!(Image::psnr(&img1, &img1).unwrap(), f64::INFINITY);
+        // MSE = 6 * 100 / 6 = 50
+        let expected = 10.0 * (255.0f64 * 255.0 / 50.0).log10();
+        assert!((Image::psnr(&img1, &img2).unwrap() - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_pattern_checkerboard() {
+        let img = Image::test_pattern(8, 8, TestPattern::Checkerboard { cell: 2 });
+
+        assert_eq!(img.data.len(), 64);
+        // Top-left cell is black, its right neighbour is white
+        assert_eq!(img.data[0], 0x000000);
+        assert_eq!(img.data[2], 0xFFFFFF);
+        // Bottom-right corner: cell (3, 3) -> even -> black
+        assert_eq!(img.data[63], 0x000000);
+        // Center pixel (4, 4): cell (2, 2) -> black
+        assert_eq!(img.data[4 * 8 + 4], 0x000000);
+        // Pixel (4, 2): cell (2, 1) -> white
+        assert_eq!(img.data[2 * 8 + 4], 0xFFFFFF);
+    }
+
+    #[test]
+    fn test_pattern_gradient() {
+        let img = Image::test_pattern(5, 3, TestPattern::Gradient);
+
+        // Corners: black, red, green, yellow
+        assert_eq!(img.data[0], 0x000000);
+        assert_eq!(img.data[4], 0xFF0000);
+        assert_eq!(img.data[2 * 5], 0x00FF00);
+        assert_eq!(img.data[2 * 5 + 4], 0xFFFF00);
+        // Center (2, 1): halfway on both axes -> R = G = 128
+        assert_eq!(img.data[5 + 2], 0x808000);
+    }
+
+    #[test]
+    fn test_pattern_grid() {
+        let img = Image::test_pattern(9, 9, TestPattern::Grid { spacing: 4 });
+
+        assert_eq!(img.data[0], 0xFFFFFF);
+        assert_eq!(img.data[8 * 9 + 8], 0xFFFFFF);
+        // Center (4, 4) sits on a line, (2, 2) and (6, 6) sit inside cells
+        assert_eq!(img.data[4 * 9 + 4], 0xFFFFFF);
+        assert_eq!(img.data[2 * 9 + 2], 0x000000);
+        assert_eq!(img.data[6 * 9 + 6], 0x000000);
+    }
+
+    #[test]
+    fn test_pattern_save_load_roundtrip() {
+        let img = Image::test_pattern(16, 12, TestPattern::Gradient);
+        let path = std::env::temp_dir().join("raytracer_test_pattern_roundtrip.png");
+        let path = path.to_str().unwrap();
+
+        save_image(&img, path).expect("Failed to save pattern");
+        let loaded = file_to_image(path).expect("Failed to load pattern");
+        let (diff, _) = Image::compare(&img, &loaded).unwrap();
+
+        assert_eq!(loaded, img);
+        assert_eq!(diff, 0);
+    }
 }
@@ -0,0 +1,32 @@
+//! Ray tracer library: scene loading, rendering and image I/O
+//!
+//! The `raytracer_rust` binary is a thin command-line front end over this crate.
+//! The public API is:
+//! - [`raytracer`]: [`ParsedConfigState`] parses a scene file or string into a [`Config`],
+//!   and [`RayTracer`] renders it into an [`Image`]. Scene building blocks ([`Shape`],
+//!   [`Light`], [`Camera`], materials, transforms) live under [`raytracer::config`].
+//! - [`imgcomparator`]: the packed RGB [`Image`], PNG loading/saving and image comparison.
+//! - [`error`]: [`RayTracerError`], returned by every fallible public function.
+//!
+//! Anything not reachable from these modules (BVH plumbing, ray counters, the scene
+//! parser's helpers) is an implementation detail and may change without notice.
+//!
+//! ```
+//! use raytracer_rust::{ParsedConfigState, RayTracer};
+//!
+//! let config = ParsedConfigState::new()
+//!     .load_config_str("size 8 6\ncamera 0 0 5 0 0 0 0 1 0 45\nambient 1 0 0\nsphere 0 0 0 1\n")
+//!     .unwrap();
+//! let image = RayTracer::new(config).render().unwrap();
+//! assert_eq!((image.width, image.height), (8, 6));
+//! ```
+
+pub mod error;
+pub mod imgcomparator;
+pub mod raytracer;
+
+pub use error::RayTracerError;
+pub use imgcomparator::Image;
+pub use raytracer::config::light::Light;
+pub use raytracer::config::shape::Shape;
+pub use raytracer::{Camera, Config, ParsedConfigState, RayTracer, RenderStats};
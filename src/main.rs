@@ -1,27 +1,261 @@
-// Image and all functions are in imgcomparator module
-mod imgcomparator;
-mod raytracer;
+mod cli;
+#[cfg(feature = "window")]
+mod preview;
 
-use raytracer::ParsedConfigState;
+use raytracer_rust::imgcomparator;
+use raytracer_rust::raytracer;
+use std::process;
 
 fn main() {
-    let mut parsed_config = ParsedConfigState::new();
-    let config = parsed_config.load_config_file("final_avec_bonus.scene").expect("Failed to load configuration");
+    let options = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}");
+            process::exit(2);
+        }
+    };
+
+    if let Some(reference) = &options.verify {
+        match cli::verify(&options.scene, reference, options.tolerance) {
+            Ok(report) => {
+                println!(
+                    "{} differing pixel(s) against {} (tolerance {})",
+                    report.diff_pixels, reference, options.tolerance
+                );
+                if report.passed {
+                    println!("Verification passed.");
+                    return;
+                }
+                eprintln!("Verification failed.");
+                process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("Error during verification: {e}");
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(reference) = &options.validate_reference {
+        let confirm = |diff_pixels: u128| options.yes || confirm_overwrite(reference, diff_pixels);
+        match cli::validate_reference(&options.scene, reference, options.tolerance, confirm) {
+            Ok(cli::ReferenceUpdate::Unchanged) => println!("{reference} is up to date."),
+            Ok(cli::ReferenceUpdate::Updated { diff_pixels }) => {
+                println!("{reference} updated ({diff_pixels} differing pixel(s)).")
+            }
+            Ok(cli::ReferenceUpdate::Declined { diff_pixels }) => {
+                eprintln!("{reference} differs in {diff_pixels} pixel(s); left unchanged.");
+                process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("Error while validating the reference: {e}");
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(dir) = &options.report {
+        let reports = match cli::compare_directory(std::path::Path::new(dir), options.tolerance) {
+            Ok(reports) => reports,
+            Err(e) => {
+                eprintln!("Error during comparison: {e}");
+                process::exit(1);
+            }
+        };
+        print!("{}", cli::report_markdown(&reports));
+        let path = std::path::Path::new(dir).join("report.md");
+        if let Err(e) = cli::write_report(&reports, &path.to_string_lossy()) {
+            eprintln!("{e}");
+        }
+        if reports.iter().any(|report| !report.passed) {
+            process::exit(1);
+        }
+        return;
+    }
+
+    if options.dry_run {
+        match cli::dry_run(&options.scene, &options.layers, options.view.as_deref()) {
+            Ok(report) => {
+                report.config.println_config();
+                println!("{} object(s), {} light(s)", report.objects, report.lights);
+                println!("Output would be written to {}", report.output_path);
+                return;
+            }
+            Err(e) => {
+                eprintln!("Error loading scene: {e}");
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(path) = &options.to_binary {
+        match raytracer::ParsedConfigState::new().convert_to_binary(&options.scene, path) {
+            Ok(()) => {
+                println!("Binary scene written to {path}");
+                return;
+            }
+            Err(e) => {
+                eprintln!("Error converting scene: {e}");
+                process::exit(1);
+            }
+        }
+    }
+
+    let (parsed_config, mut config) =
+        cli::load_scene(&options.scene, &options.layers, options.view.as_deref(), options.scene_cache.as_deref())
+            .expect("Failed to load configuration");
+    for warning in parsed_config.warnings() {
+        eprintln!("Warning: {warning}");
+    }
+    if let Some(max_size) = options.max_size {
+        cli::cap_size(&mut config, max_size);
+    }
+    if let Some(format) = options.output_format {
+        cli::apply_output_format(&mut config, format);
+    }
+    for warning in cli::select_objects(&mut config, &options.only, &options.hide) {
+        eprintln!("Warning: {warning}");
+    }
+    let seed = cli::apply_seed(&mut config, options.seed);
+    println!("Seed: {seed}");
     println!("Configuration loaded successfully.");
     let ray_tracer = raytracer::RayTracer::new(config);
+
+    if options.list_objects {
+        print!("{}", cli::object_listing(&ray_tracer));
+        return;
+    }
+
+    if options.window {
+        run_preview(ray_tracer);
+        return;
+    }
+
+    if let Some(frames) = options.frames {
+        let output_dir = std::path::Path::new(ray_tracer.get_output_path())
+            .parent()
+            .unwrap_or(std::path::Path::new(""))
+            .to_path_buf();
+        println!("Rendering {frames} turntable frame(s)...");
+        match cli::render_turntable(&ray_tracer, frames, &output_dir) {
+            Ok(paths) => println!("Frames saved: {} ... {}", paths[0], paths[paths.len() - 1]),
+            Err(e) => {
+                eprintln!("Error during turntable rendering: {e}");
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
     println!("Starting rendering...");
-    let start_time = std::time::Instant::now();
-    let image = ray_tracer.render();
-    let duration = start_time.elapsed();
-    println!("Rendering completed in: {:?}", duration);
-    match image {
-        Ok(img) => {
+    let rendered = match options.timeout {
+        Some(timeout) => ray_tracer.with_timeout(timeout, raytracer::RayTracer::render_scene_frame),
+        None => ray_tracer.render_scene_frame(),
+    };
+    match rendered {
+        Ok((raytracer::Frame { image: mut img, linear, aovs }, stats)) => {
+            println!("Rendering completed in: {:?}", stats.elapsed);
+            println!(
+                "Rays cast: {} (primary {}, shadow {}, reflection {}, refraction {})",
+                stats.total_rays(),
+                stats.primary_rays,
+                stats.shadow_rays,
+                stats.reflection_rays,
+                stats.refraction_rays
+            );
+            if let Some(path) = &options.stats {
+                match cli::write_stats(&stats, path) {
+                    Ok(()) => println!("Render statistics written to {path}"),
+                    Err(e) => eprintln!("{e}"),
+                }
+            }
+            if options.outline {
+                ray_tracer
+                    .render_object_ids()
+                    .and_then(|ids| img.overlay_outline(&ids, cli::OUTLINE_COLOR))
+                    .expect("The scene rendered, so its id buffer does too");
+            }
             imgcomparator::save_image(&img, ray_tracer.get_output_path())
                 .expect("Failed to save image");
-            println!("Image rendered and saved to output.png");
+            println!("Image rendered and saved to {}", ray_tracer.get_output_path());
+            if options.histogram {
+                print!("{}", cli::histogram_chart(&img));
+            }
+            if let Some(path) = &options.heatmap {
+                let saved = ray_tracer
+                    .render_heatmap()
+                    .and_then(|heatmap| imgcomparator::save_image(&heatmap, path));
+                match saved {
+                    Ok(()) => println!("Ray heatmap saved to {path}"),
+                    Err(e) => eprintln!("{e}"),
+                }
+            }
+            if let Some(path) = &options.segmentation {
+                let saved = ray_tracer
+                    .render_segmentation()
+                    .and_then(|segmentation| imgcomparator::save_image(&segmentation, path));
+                match saved {
+                    Ok(()) => println!("Segmentation pass saved to {path}"),
+                    Err(e) => eprintln!("{e}"),
+                }
+            }
+            if let Some(path) = &options.sample_aovs {
+                match cli::save_sample_aovs(&linear, img.width, img.height, &aovs, path) {
+                    Ok(()) => println!("Sample AOVs saved to {path}"),
+                    Err(e) => eprintln!("{e}"),
+                }
+            }
+            if let Some(path) = &ray_tracer.config().linear_output {
+                match cli::save_linear(&linear, img.width, img.height, path) {
+                    Ok(()) => println!("Linear framebuffer saved to {path}"),
+                    Err(e) => eprintln!("{e}"),
+                }
+            }
+            match ray_tracer.render_multi_res(&options.resolutions) {
+                Ok(extras) => {
+                    for extra in extras {
+                        let path = cli::sized_output_path(ray_tracer.get_output_path(), extra.width, extra.height);
+                        imgcomparator::save_image(&extra, &path).expect("Failed to save image");
+                        println!("{}x{} render saved to {}", extra.width, extra.height, path);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Error during rendering: {e}");
+                    process::exit(1);
+                }
+            }
         }
         Err(e) => {
             eprintln!("Error during rendering: {e}");
+            process::exit(1);
         }
     }
 }
+
+/// Asks on the terminal whether to overwrite `reference`; anything but `y` or `yes`
+/// (including end of input) declines.
+fn confirm_overwrite(reference: &str, diff_pixels: u128) -> bool {
+    use std::io::Write;
+    print!("{reference} differs in {diff_pixels} pixel(s). Overwrite it with the new render? [y/N] ");
+    let _ = std::io::stdout().flush();
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+#[cfg(feature = "window")]
+fn run_preview(ray_tracer: raytracer::RayTracer) {
+    if let Err(e) = preview::run(ray_tracer) {
+        eprintln!("Error in preview window: {e}");
+        process::exit(1);
+    }
+}
+
+#[cfg(not(feature = "window"))]
+fn run_preview(_ray_tracer: raytracer::RayTracer) {
+    eprintln!("--window is not available: rebuild with `--features window`");
+    process::exit(2);
+}
@@ -0,0 +1,343 @@
+//! Interactive preview window (`--window`, requires the `window` feature)
+//!
+//! Renders the scene at reduced resolution into a winit window and re-renders whenever
+//! the camera changes:
+//! - left mouse drag orbits around the look-at point
+//! - right mouse drag pans the look-at point
+//! - mouse wheel zooms in and out
+
+use raytracer_rust::imgcomparator::Image;
+use raytracer_rust::raytracer::{Camera, FovAxis, RayTracer};
+use nalgebra::Vector3;
+use std::num::NonZeroU32;
+use std::rc::Rc;
+use winit::application::ApplicationHandler;
+use winit::dpi::PhysicalSize;
+use winit::event::{ElementState, MouseButton, MouseScrollDelta, WindowEvent};
+use winit::event_loop::{ActiveEventLoop, EventLoop};
+use winit::window::{Window, WindowId};
+
+/// The preview renders at 1/PREVIEW_DIVISOR of the scene resolution on each axis
+const PREVIEW_DIVISOR: u32 = 4;
+/// Degrees of orbit per pixel of mouse drag
+const ORBIT_SPEED: f32 = 0.4;
+/// Fraction of the orbit distance panned per pixel of mouse drag
+const PAN_SPEED: f32 = 0.002;
+/// Zoom factor applied per wheel line
+const ZOOM_STEP: f32 = 0.9;
+/// Pitch is kept away from the poles so the view basis never degenerates
+const MAX_PITCH_DEGREES: f32 = 89.0;
+
+/// Camera orbiting a target point, expressed in a frame built from the scene camera
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrbitCamera {
+    pub target: Vector3<f32>,
+    pub distance: f32,
+    /// Rotation around the up axis, in degrees
+    pub yaw: f32,
+    /// Elevation above the plane perpendicular to up, in degrees
+    pub pitch: f32,
+    up: Vector3<f32>,
+    forward_ref: Vector3<f32>,
+    right_ref: Vector3<f32>,
+    fov: f32,
+    fov_axis: FovAxis,
+}
+
+impl OrbitCamera {
+    /// Starts an orbit reproducing exactly the given camera
+    pub fn from_camera(camera: &Camera) -> Self {
+        let up = camera.up().normalize();
+        let offset = camera.position() - camera.look_at();
+        let distance = offset.norm();
+        let direction = offset / distance;
+
+        let horizontal = direction - up * direction.dot(&up);
+        let forward_ref = if horizontal.norm() > 1e-6 {
+            horizontal.normalize()
+        } else {
+            // Looking straight along up: any perpendicular axis will do
+            up.cross(&Vector3::x()).try_normalize(1e-6).unwrap_or_else(|| up.cross(&Vector3::z()).normalize())
+        };
+        let right_ref = up.cross(&forward_ref);
+
+        OrbitCamera {
+            target: camera.look_at(),
+            distance,
+            yaw: 0.0,
+            pitch: direction.dot(&up).clamp(-1.0, 1.0).asin().to_degrees(),
+            up,
+            forward_ref,
+            right_ref,
+            fov: camera.fov(),
+            fov_axis: camera.fov_axis(),
+        }
+    }
+
+    pub fn orbit(&mut self, delta_yaw: f32, delta_pitch: f32) {
+        self.yaw = (self.yaw + delta_yaw) % 360.0;
+        self.pitch = (self.pitch + delta_pitch).clamp(-MAX_PITCH_DEGREES, MAX_PITCH_DEGREES);
+    }
+
+    /// Moves the target within the view plane by `dx`, `dy` (in units of the orbit distance)
+    pub fn pan(&mut self, dx: f32, dy: f32) {
+        let camera = self.camera();
+        let forward = camera.direction();
+        let right = forward.cross(&self.up).normalize();
+        let view_up = right.cross(&forward);
+        self.target += (view_up * dy - right * dx) * self.distance;
+    }
+
+    pub fn zoom(&mut self, factor: f32) {
+        self.distance = (self.distance * factor).max(1e-3);
+    }
+
+    pub fn camera(&self) -> Camera {
+        let (yaw, pitch) = (self.yaw.to_radians(), self.pitch.to_radians());
+        let horizontal = self.forward_ref * yaw.cos() + self.right_ref * yaw.sin();
+        let offset = (horizontal * pitch.cos() + self.up * pitch.sin()) * self.distance;
+        Camera::new(self.target + offset, self.target, self.up, self.fov).with_fov_axis(self.fov_axis)
+    }
+}
+
+/// Window-independent viewer state: camera, preview size and mouse interaction
+#[derive(Debug)]
+pub struct PreviewState {
+    pub orbit: OrbitCamera,
+    pub width: u32,
+    pub height: u32,
+    dragging: Option<MouseButton>,
+    cursor: Option<(f64, f64)>,
+    dirty: bool,
+}
+
+impl PreviewState {
+    /// Preview of `camera` at the scene size reduced by `PREVIEW_DIVISOR`
+    pub fn new(camera: &Camera, scene_width: u32, scene_height: u32) -> Self {
+        PreviewState {
+            orbit: OrbitCamera::from_camera(camera),
+            width: (scene_width / PREVIEW_DIVISOR).max(1),
+            height: (scene_height / PREVIEW_DIVISOR).max(1),
+            dragging: None,
+            cursor: None,
+            dirty: true,
+        }
+    }
+
+    pub fn mouse_button(&mut self, button: MouseButton, pressed: bool) {
+        self.dragging = if pressed { Some(button) } else { None };
+    }
+
+    pub fn cursor_moved(&mut self, x: f64, y: f64) {
+        if let (Some(button), Some((last_x, last_y))) = (self.dragging, self.cursor) {
+            let (dx, dy) = ((x - last_x) as f32, (y - last_y) as f32);
+            match button {
+                MouseButton::Left => self.orbit.orbit(-dx * ORBIT_SPEED, dy * ORBIT_SPEED),
+                MouseButton::Right => self.orbit.pan(dx * PAN_SPEED, dy * PAN_SPEED),
+                _ => {}
+            }
+            self.dirty = true;
+        }
+        self.cursor = Some((x, y));
+    }
+
+    pub fn scroll(&mut self, lines: f32) {
+        self.orbit.zoom(ZOOM_STEP.powf(lines));
+        self.dirty = true;
+    }
+
+    /// Returns whether the camera changed since the last call
+    pub fn take_dirty(&mut self) -> bool {
+        std::mem::replace(&mut self.dirty, false)
+    }
+
+    pub fn render(&self, ray_tracer: &RayTracer) -> Image {
+        ray_tracer.render_to_image_with_camera(&self.orbit.camera(), self.width, self.height)
+    }
+}
+
+struct PreviewApp {
+    ray_tracer: RayTracer,
+    state: PreviewState,
+    frame: Option<Image>,
+    window: Option<Rc<Window>>,
+    surface: Option<softbuffer::Surface<Rc<Window>, Rc<Window>>>,
+    error: Option<String>,
+}
+
+impl PreviewApp {
+    fn redraw(&mut self) -> Result<(), String> {
+        let (Some(window), Some(surface)) = (&self.window, &mut self.surface) else {
+            return Ok(());
+        };
+        if self.state.take_dirty() || self.frame.is_none() {
+            self.frame = Some(self.state.render(&self.ray_tracer));
+        }
+        let Some(frame) = &self.frame else {
+            return Ok(());
+        };
+
+        let size = window.inner_size();
+        let (Some(width), Some(height)) = (NonZeroU32::new(size.width), NonZeroU32::new(size.height))
+        else {
+            return Ok(());
+        };
+        surface.resize(width, height).map_err(|e| e.to_string())?;
+        let mut buffer = surface.buffer_mut().map_err(|e| e.to_string())?;
+
+        // Nearest-neighbour upscale of the low resolution frame to the window size
+        for y in 0..size.height {
+            let src_y = (y * frame.height / size.height) as usize;
+            for x in 0..size.width {
+                let src_x = (x * frame.width / size.width) as usize;
+                buffer[(y * size.width + x) as usize] =
+                    frame.data[src_y * frame.width as usize + src_x] & 0xFFFFFF;
+            }
+        }
+        buffer.present().map_err(|e| e.to_string())
+    }
+
+    fn fail(&mut self, event_loop: &ActiveEventLoop, error: String) {
+        self.error = Some(error);
+        event_loop.exit();
+    }
+}
+
+impl ApplicationHandler for PreviewApp {
+    fn resumed(&mut self, event_loop: &ActiveEventLoop) {
+        if self.window.is_some() {
+            return;
+        }
+        let config = self.ray_tracer.config();
+        let attributes = Window::default_attributes()
+            .with_title("RayTracer preview")
+            .with_inner_size(PhysicalSize::new(config.width, config.height));
+
+        let created = event_loop
+            .create_window(attributes)
+            .map_err(|e| e.to_string())
+            .and_then(|window| {
+                let window = Rc::new(window);
+                let context = softbuffer::Context::new(window.clone()).map_err(|e| e.to_string())?;
+                let surface =
+                    softbuffer::Surface::new(&context, window.clone()).map_err(|e| e.to_string())?;
+                Ok((window, surface))
+            });
+        match created {
+            Ok((window, surface)) => {
+                window.request_redraw();
+                self.window = Some(window);
+                self.surface = Some(surface);
+            }
+            Err(e) => self.fail(event_loop, e),
+        }
+    }
+
+    fn window_event(&mut self, event_loop: &ActiveEventLoop, _id: WindowId, event: WindowEvent) {
+        match event {
+            WindowEvent::CloseRequested => event_loop.exit(),
+            WindowEvent::RedrawRequested => {
+                if let Err(e) = self.redraw() {
+                    self.fail(event_loop, e);
+                }
+            }
+            WindowEvent::MouseInput { state, button, .. } => {
+                self.state.mouse_button(button, state == ElementState::Pressed);
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                self.state.cursor_moved(position.x, position.y);
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                let lines = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => y,
+                    MouseScrollDelta::PixelDelta(position) => position.y as f32 / 40.0,
+                };
+                self.state.scroll(lines);
+            }
+            WindowEvent::Resized(_) => self.state.dirty = true,
+            _ => {}
+        }
+        if self.state.dirty {
+            if let Some(window) = &self.window {
+                window.request_redraw();
+            }
+        }
+    }
+}
+
+/// Opens the preview window and blocks until it is closed
+pub fn run(ray_tracer: RayTracer) -> Result<(), String> {
+    let config = ray_tracer.config();
+    let state = PreviewState::new(&config.camera, config.width, config.height);
+    let mut app = PreviewApp {
+        ray_tracer,
+        state,
+        frame: None,
+        window: None,
+        surface: None,
+        error: None,
+    };
+
+    let event_loop = EventLoop::new().map_err(|e| e.to_string())?;
+    event_loop.run_app(&mut app).map_err(|e| e.to_string())?;
+
+    match app.error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+// ==========================================================
+// TESTS
+// ==========================================================
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use raytracer_rust::raytracer::ParsedConfigState;
+
+    fn scene_camera() -> (Camera, u32, u32) {
+        let config = ParsedConfigState::new()
+            .load_config_str("size 640 480\ncamera 0 0 10 0 0 0 0 1 0 45\nsphere 0 0 0 1\n")
+            .unwrap();
+        let (width, height) = (config.width, config.height);
+        (config.camera, width, height)
+    }
+
+    #[test]
+    fn test_preview_state_reproduces_scene_camera() {
+        let (camera, width, height) = scene_camera();
+        let state = PreviewState::new(&camera, width, height);
+
+        assert_eq!((state.width, state.height), (160, 120));
+        let preview_camera = state.orbit.camera();
+        assert!((preview_camera.position() - camera.position()).norm() < 1e-4);
+        assert_eq!(preview_camera.look_at(), camera.look_at());
+    }
+
+    #[test]
+    fn test_preview_orbit_keeps_distance() {
+        let (camera, width, height) = scene_camera();
+        let mut state = PreviewState::new(&camera, width, height);
+        assert!(state.take_dirty());
+
+        state.mouse_button(MouseButton::Left, true);
+        state.cursor_moved(0.0, 0.0);
+        state.cursor_moved(-225.0, 0.0);
+        assert!(state.take_dirty());
+
+        // 225 px * 0.4 deg/px = 90 degrees around the Y axis
+        let position = state.orbit.camera().position();
+        assert!((position.norm() - 10.0).abs() < 1e-4);
+        assert!((position - Vector3::new(10.0, 0.0, 0.0)).norm() < 1e-3);
+    }
+
+    #[test]
+    fn test_preview_renders_reduced_resolution() {
+        let config = ParsedConfigState::new()
+            .load_config_str("size 64 32\ncamera 0 0 10 0 0 0 0 1 0 45\nsphere 0 0 0 1\n")
+            .unwrap();
+        let state = PreviewState::new(&config.camera, config.width, config.height);
+        let image = state.render(&RayTracer::new(config));
+        assert_eq!((image.width, image.height), (16, 8));
+    }
+}
@@ -0,0 +1,395 @@
+//! Binary `.rtscene` scene format, loaded by `ParsedConfigState::load_config_file` from
+//! files with that extension.
+//!
+//! A binary scene holds the whole scene: its render settings and every object. Triangles
+//! are stored as three indices into a shared vertex table and one into a material table,
+//! so a large mesh costs 17 bytes per face, and loading is a single read with no text
+//! parsing. Object order is kept, so hit tie-breaks and object ids survive a round trip.
+//!
+//! Numbers are little-endian, a vector is three `f32`s, a flag is a `u8` that is 0 or 1
+//! and a face is three `u32` vertex indices, a `u32` material index and a two-sided
+//! flag. In order:
+//!
+//! * Header: `MAGIC`, then `FORMAT_VERSION` as a `u32`
+//! * Settings: the `Config` without its objects, serialized with bincode. It covers the
+//!   size, camera, lights, object names and every render setting, so `FORMAT_VERSION`
+//!   is bumped whenever the serialized fields of `Config` change
+//! * Materials: a `u32` count, then for each the diffuse and specular colors,
+//!   shininess, anisotropy and roughness as `f32`s, an ambient flag followed by the
+//!   ambient color when set, and the pattern as a `u8` (0 none, 1 bands followed by the
+//!   `u16` band count and two colors, 2 checker followed by two colors)
+//! * Vertices: a `u32` count, then one vector each
+//! * Meshes: a `u32` count, then for each its name as a `u32` byte length and UTF-8
+//!   bytes, and its triangles as a `u32` count and one face each
+//! * Objects: a `u32` count, then for each a `u8` kind: 0 triangle followed by its face,
+//!   1 mesh instance followed by the `u32` mesh index and its bincode `Transform`, 2 any
+//!   other shape serialized with bincode
+
+use crate::error::RayTracerError;
+use crate::raytracer::config::material::{Material, Pattern};
+use crate::raytracer::config::shape::{Mesh, Shape};
+use crate::raytracer::config::transform::Transform;
+use crate::raytracer::config::Config;
+use nalgebra::Vector3;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::num::NonZeroU16;
+use std::sync::Arc;
+
+/// File extension of binary scenes
+pub const BINARY_EXTENSION: &str = "rtscene";
+
+const MAGIC: &[u8; 8] = b"RTSCENE\0";
+
+/// Bumped whenever the layout of `.rtscene` files changes
+const FORMAT_VERSION: u32 = 14;
+
+/// Triangle as stored: vertex indices, material index and two-sided flag
+type Face = ([u32; 3], u32, bool);
+
+/// Object as stored, borrowing from the scene being written
+enum Object<'a> {
+    Triangle(Face),
+    Instance(u32, &'a Transform),
+    Shape(&'a Shape),
+}
+
+/// Vertex and material tables shared by every face of a scene
+#[derive(Default)]
+struct Tables {
+    vertices: Vec<Vector3<f32>>,
+    vertex_indices: HashMap<[u32; 3], u32>,
+    materials: Vec<Material>,
+}
+
+impl Tables {
+    /// Face of `shape`, adding its vertices and material to the tables. `None` if it is
+    /// not a triangle.
+    fn face(&mut self, shape: &Shape) -> Option<Face> {
+        let Shape::Triangle { v0, v1, v2, material, twosided, .. } = shape else {
+            return None;
+        };
+        let corners = [v0, v1, v2].map(|v| {
+            *self.vertex_indices.entry(v.map(f32::to_bits).into()).or_insert_with(|| {
+                self.vertices.push(*v);
+                (self.vertices.len() - 1) as u32
+            })
+        });
+        // Scenes have few materials and consecutive faces usually share one
+        let material = match self.materials.iter().rposition(|known| known == material) {
+            Some(index) => index as u32,
+            None => {
+                self.materials.push(*material);
+                (self.materials.len() - 1) as u32
+            }
+        };
+        Some((corners, material, *twosided))
+    }
+
+    /// Triangle of a face read back, checking its indices against the tables
+    fn triangle(&self, (corners, material, twosided): Face) -> io::Result<Shape> {
+        let vertex = |index: u32| {
+            self.vertices
+                .get(index as usize)
+                .copied()
+                .ok_or_else(|| invalid_data(format!("vertex index {} out of range", index)))
+        };
+        let material = *self
+            .materials
+            .get(material as usize)
+            .ok_or_else(|| invalid_data(format!("material index {} out of range", material)))?;
+        Ok(Shape::Triangle {
+            v0: vertex(corners[0])?,
+            v1: vertex(corners[1])?,
+            v2: vertex(corners[2])?,
+            material,
+            node_index: 0,
+            twosided,
+        })
+    }
+}
+
+/// Writes `config` to `path` as a binary scene. Output paths are stored as they are
+/// and, as for a text scene, resolved against the directory of the file on load.
+///
+/// # Returns
+/// * `Err(RayTracerError::Io)` - The file could not be written
+pub fn save_binary(config: &Config, path: &str) -> Result<(), RayTracerError> {
+    let mut tables = Tables::default();
+    let mut meshes: Vec<(&str, Vec<Face>)> = Vec::new();
+    // Instances of one mesh share it, so it is written once
+    let mut mesh_indices: HashMap<*const Mesh, u32> = HashMap::new();
+    let objects: Vec<Object> = config
+        .get_scene_objects()
+        .iter()
+        .map(|object| match object {
+            Shape::Instance { mesh, transform, .. } => {
+                let index = *mesh_indices.entry(Arc::as_ptr(mesh)).or_insert_with(|| {
+                    let faces = mesh.triangles().iter().filter_map(|triangle| tables.face(triangle)).collect();
+                    meshes.push((&mesh.name, faces));
+                    (meshes.len() - 1) as u32
+                });
+                Object::Instance(index, transform)
+            }
+            _ => tables.face(object).map_or(Object::Shape(object), Object::Triangle),
+        })
+        .collect();
+
+    let file = File::create(path).map_err(|e| RayTracerError::io(path, e))?;
+    let mut writer = BufWriter::new(file);
+    let mut write = || -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        write_u32(&mut writer, FORMAT_VERSION)?;
+        write_bincode(&mut writer, config)?;
+        write_u32(&mut writer, tables.materials.len() as u32)?;
+        for material in &tables.materials {
+            write_material(&mut writer, material)?;
+        }
+        write_u32(&mut writer, tables.vertices.len() as u32)?;
+        for vertex in &tables.vertices {
+            write_vector(&mut writer, vertex)?;
+        }
+        write_u32(&mut writer, meshes.len() as u32)?;
+        for (name, faces) in &meshes {
+            write_u32(&mut writer, name.len() as u32)?;
+            writer.write_all(name.as_bytes())?;
+            write_u32(&mut writer, faces.len() as u32)?;
+            for face in faces {
+                write_face(&mut writer, face)?;
+            }
+        }
+        write_u32(&mut writer, objects.len() as u32)?;
+        for object in &objects {
+            match object {
+                Object::Triangle(face) => {
+                    write_u8(&mut writer, 0)?;
+                    write_face(&mut writer, face)?;
+                }
+                Object::Instance(mesh, transform) => {
+                    write_u8(&mut writer, 1)?;
+                    write_u32(&mut writer, *mesh)?;
+                    write_bincode(&mut writer, transform)?;
+                }
+                Object::Shape(shape) => {
+                    write_u8(&mut writer, 2)?;
+                    write_bincode(&mut writer, shape)?;
+                }
+            }
+        }
+        writer.flush()
+    };
+    write().map_err(|e| RayTracerError::io(path, e))
+}
+
+/// Reads a binary scene written by `save_binary`
+///
+/// # Returns
+/// * `Err(RayTracerError::InvalidScene)` - The file is not a binary scene, was written
+///   by another format version, or is corrupt
+/// * `Err(RayTracerError::Io)` - The file could not be read
+pub fn load_binary(path: &str) -> Result<Config, RayTracerError> {
+    let file = File::open(path).map_err(|e| RayTracerError::io(path, e))?;
+    let mut reader = BufReader::new(file);
+    let mut magic = [0; 8];
+    if reader.read_exact(&mut magic).is_err() || &magic != MAGIC {
+        return Err(RayTracerError::InvalidScene(format!("'{}' is not a binary scene", path)));
+    }
+    let version = read_u32(&mut reader)
+        .map_err(|_| RayTracerError::InvalidScene(format!("'{}' is not a binary scene", path)))?;
+    if version != FORMAT_VERSION {
+        return Err(RayTracerError::InvalidScene(format!(
+            "'{}' is a version {} binary scene, expected version {}",
+            path, version, FORMAT_VERSION
+        )));
+    }
+    read_scene(&mut reader)
+        .map_err(|e| RayTracerError::InvalidScene(format!("Corrupt binary scene '{}': {}", path, e)))
+}
+
+/// Everything after the format version
+fn read_scene(reader: &mut impl Read) -> io::Result<Config> {
+    let mut config: Config = read_bincode(reader)?;
+    let materials = (0..read_u32(reader)?)
+        .map(|_| read_material(reader))
+        .collect::<io::Result<_>>()?;
+    let vertices = (0..read_u32(reader)?)
+        .map(|_| read_vector(reader))
+        .collect::<io::Result<_>>()?;
+    let tables = Tables {
+        vertices,
+        vertex_indices: HashMap::new(),
+        materials,
+    };
+    let meshes = (0..read_u32(reader)?)
+        .map(|_| {
+            let mut name = vec![0; read_u32(reader)? as usize];
+            reader.read_exact(&mut name)?;
+            let name = String::from_utf8(name).map_err(|e| invalid_data(e.to_string()))?;
+            let triangles = (0..read_u32(reader)?)
+                .map(|_| tables.triangle(read_face(reader)?))
+                .collect::<io::Result<_>>()?;
+            Mesh::new(name, triangles).map(Arc::new).map_err(invalid_data)
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let objects = (0..read_u32(reader)?)
+        .map(|_| match read_u8(reader)? {
+            0 => tables.triangle(read_face(reader)?),
+            1 => {
+                let index = read_u32(reader)?;
+                let mesh = meshes
+                    .get(index as usize)
+                    .ok_or_else(|| invalid_data(format!("mesh index {} out of range", index)))?;
+                Ok(Shape::Instance {
+                    mesh: Arc::clone(mesh),
+                    transform: Box::new(read_bincode(reader)?),
+                    node_index: 0,
+                })
+            }
+            2 => read_bincode(reader),
+            kind => Err(invalid_data(format!("unknown object kind {}", kind))),
+        })
+        .collect::<io::Result<_>>()?;
+    config.set_scene_objects(objects);
+    Ok(config)
+}
+
+fn write_face(writer: &mut impl Write, (corners, material, twosided): &Face) -> io::Result<()> {
+    for index in corners {
+        write_u32(writer, *index)?;
+    }
+    write_u32(writer, *material)?;
+    write_flag(writer, *twosided)
+}
+
+fn read_face(reader: &mut impl Read) -> io::Result<Face> {
+    let corners = [read_u32(reader)?, read_u32(reader)?, read_u32(reader)?];
+    Ok((corners, read_u32(reader)?, read_flag(reader)?))
+}
+
+fn write_material(writer: &mut impl Write, material: &Material) -> io::Result<()> {
+    write_vector(writer, &material.diffuse_color)?;
+    write_vector(writer, &material.specular_color)?;
+    write_f32(writer, material.shininess)?;
+    write_f32(writer, material.anisotropy)?;
+    write_f32(writer, material.roughness)?;
+    write_flag(writer, material.ambient.is_some())?;
+    if let Some(ambient) = &material.ambient {
+        write_vector(writer, ambient)?;
+    }
+    match &material.pattern {
+        None => write_u8(writer, 0),
+        Some(Pattern::Bands { count, color1, color2 }) => {
+            write_u8(writer, 1)?;
+            writer.write_all(&count.get().to_le_bytes())?;
+            write_vector(writer, color1)?;
+            write_vector(writer, color2)
+        }
+        Some(Pattern::Checker { color1, color2 }) => {
+            write_u8(writer, 2)?;
+            write_vector(writer, color1)?;
+            write_vector(writer, color2)
+        }
+    }
+}
+
+fn read_material(reader: &mut impl Read) -> io::Result<Material> {
+    let diffuse_color = read_vector(reader)?;
+    let specular_color = read_vector(reader)?;
+    let shininess = read_f32(reader)?;
+    let anisotropy = read_f32(reader)?;
+    let roughness = read_f32(reader)?;
+    let ambient = if read_flag(reader)? { Some(read_vector(reader)?) } else { None };
+    let pattern = match read_u8(reader)? {
+        0 => None,
+        1 => {
+            let mut count = [0; 2];
+            reader.read_exact(&mut count)?;
+            let count = NonZeroU16::new(u16::from_le_bytes(count))
+                .ok_or_else(|| invalid_data("band count of zero".to_string()))?;
+            Some(Pattern::Bands {
+                count,
+                color1: read_vector(reader)?,
+                color2: read_vector(reader)?,
+            })
+        }
+        2 => Some(Pattern::Checker {
+            color1: read_vector(reader)?,
+            color2: read_vector(reader)?,
+        }),
+        kind => return Err(invalid_data(format!("unknown pattern {}", kind))),
+    };
+    Ok(Material {
+        diffuse_color,
+        specular_color,
+        shininess,
+        anisotropy,
+        roughness,
+        ambient,
+        pattern,
+    })
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_bincode(writer: &mut impl Write, value: &impl Serialize) -> io::Result<()> {
+    bincode::serialize_into(writer, value).map_err(io::Error::other)
+}
+
+fn read_bincode<T: DeserializeOwned>(reader: &mut impl Read) -> io::Result<T> {
+    bincode::deserialize_from(reader).map_err(|e| invalid_data(e.to_string()))
+}
+
+fn write_u8(writer: &mut impl Write, value: u8) -> io::Result<()> {
+    writer.write_all(&[value])
+}
+
+fn write_flag(writer: &mut impl Write, value: bool) -> io::Result<()> {
+    write_u8(writer, value as u8)
+}
+
+fn write_u32(writer: &mut impl Write, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_f32(writer: &mut impl Write, value: f32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_vector(writer: &mut impl Write, vector: &Vector3<f32>) -> io::Result<()> {
+    vector.iter().try_for_each(|&component| write_f32(writer, component))
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_flag(reader: &mut impl Read) -> io::Result<bool> {
+    match read_u8(reader)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        value => Err(invalid_data(format!("flag of {}", value))),
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_f32(reader: &mut impl Read) -> io::Result<f32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes))
+}
+
+fn read_vector(reader: &mut impl Read) -> io::Result<Vector3<f32>> {
+    Ok(Vector3::new(read_f32(reader)?, read_f32(reader)?, read_f32(reader)?))
+}
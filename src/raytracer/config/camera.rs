@@ -1,14 +1,128 @@
-use nalgebra::Vector3;
+use nalgebra::{Rotation3, Unit, Vector3};
+use serde::{Deserialize, Serialize};
 
+/// Image axis spanned by the camera's `fov` angle.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FovAxis {
+    /// `fov` covers the image height (the historical behaviour)
+    #[default]
+    Vertical,
+    /// `fov` covers the image width
+    Horizontal,
+    /// `fov` covers the image diagonal, as with photographic lenses
+    Diagonal,
+}
+
+/// How primary rays leave the camera.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Projection {
+    /// Rays fan out from the camera position across `fov` (the historical behaviour)
+    #[default]
+    Perspective,
+    /// Parallel rays along the view direction, starting on an image plane through the
+    /// camera position that is `height` world units tall. `fov` and `fov_axis` are unused.
+    Orthographic { height: f32 },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Camera {
     pub(crate) position: Vector3<f32>,
     pub(crate) look_at: Vector3<f32>,
     pub(crate) up: Vector3<f32>,
     pub(crate) fov: f32,
+    pub(crate) fov_axis: FovAxis,
+    pub(crate) projection: Projection,
 }
 
 impl Camera {
+    /// Camera at `position` looking at `look_at`, with a vertical field of view of `fov` degrees
+    pub fn new(position: Vector3<f32>, look_at: Vector3<f32>, up: Vector3<f32>, fov: f32) -> Self {
+        Camera {
+            position,
+            look_at,
+            up,
+            fov,
+            fov_axis: FovAxis::Vertical,
+            projection: Projection::Perspective,
+        }
+    }
+
+    /// Same camera with `fov` measured along `fov_axis` instead
+    pub fn with_fov_axis(mut self, fov_axis: FovAxis) -> Self {
+        self.fov_axis = fov_axis;
+        self
+    }
+
+    /// Same camera with the given projection
+    pub fn with_projection(mut self, projection: Projection) -> Self {
+        self.projection = projection;
+        self
+    }
+
+    pub fn position(&self) -> Vector3<f32> {
+        self.position
+    }
+
+    pub fn look_at(&self) -> Vector3<f32> {
+        self.look_at
+    }
+
+    pub fn up(&self) -> Vector3<f32> {
+        self.up
+    }
+
+    pub fn fov(&self) -> f32 {
+        self.fov
+    }
+
+    pub fn fov_axis(&self) -> FovAxis {
+        self.fov_axis
+    }
+
+    pub fn projection(&self) -> Projection {
+        self.projection
+    }
+
+    /// Moves the camera to `position`, still looking at the same point
+    pub fn set_position(&mut self, position: Vector3<f32>) {
+        self.position = position;
+    }
+
+    /// Turns the camera to look at `look_at` from where it stands
+    pub fn set_look_at(&mut self, look_at: Vector3<f32>) {
+        self.look_at = look_at;
+    }
+
+    /// Swings the camera by `angle_degrees` around the camera's up axis through
+    /// `center`, carrying its look-at point along, so a camera aimed at `center` keeps
+    /// facing it. Positive angles turn counter-clockwise seen from above.
+    pub fn orbit(&mut self, center: Vector3<f32>, angle_degrees: f32) {
+        let axis = Unit::new_normalize(self.up);
+        let rotation = Rotation3::from_axis_angle(&axis, angle_degrees.to_radians());
+        self.position = center + rotation * (self.position - center);
+        self.look_at = center + rotation * (self.look_at - center);
+    }
+
     pub fn direction(&self) -> Vector3<f32> {
         (self.look_at - self.position).normalize()
     }
+
+    /// Half-width and half-height of the view plane for an image of the given
+    /// `width / height` aspect ratio: at unit distance for a perspective camera, in world
+    /// units for an orthographic one.
+    pub fn view_half_extents(&self, aspect: f32) -> (f32, f32) {
+        if let Projection::Orthographic { height } = self.projection {
+            return (height / 2.0 * aspect, height / 2.0);
+        }
+        let fovrad = self.fov * std::f32::consts::PI / 180.0;
+        let half_fov = (fovrad / 2.0).tan();
+        match self.fov_axis {
+            FovAxis::Vertical => (half_fov * aspect, half_fov),
+            FovAxis::Horizontal => (half_fov, half_fov / aspect),
+            FovAxis::Diagonal => {
+                let half_height = half_fov / (1.0 + aspect * aspect).sqrt();
+                (half_height * aspect, half_height)
+            }
+        }
+    }
 }
@@ -1,37 +1,223 @@
-use crate::raytracer::config::camera::Camera;
-use crate::raytracer::config::light::Light;
-use crate::raytracer::config::shape::Shape;
+use crate::error::RayTracerError;
+use crate::raytracer::config::binary_scene::{self, BINARY_EXTENSION};
+use crate::raytracer::config::camera::{Camera, FovAxis, Projection};
+use crate::raytracer::config::environment::EnvironmentMap;
+use crate::raytracer::config::grade::{ClampMode, ColorGrade};
+use crate::raytracer::config::light::{AmbientLight, Light};
+use crate::raytracer::config::lut::Lut;
+use crate::raytracer::config::material::{Material, Pattern, Transmission};
+use crate::raytracer::config::sampling::{AaCombine, AaPattern};
+use crate::raytracer::config::scene_cache;
+use crate::raytracer::config::shape::{Mesh, Shape, PLANE_AABB_SIZE};
+use crate::raytracer::config::transform::Transform;
 
-use nalgebra::Vector3;
+use bvh::aabb::Bounded;
+use nalgebra::{Rotation3, Vector3};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, Cursor};
+use std::num::NonZeroU16;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 const COMMENT_CHAR: char = '#';
 const DEFAULT_DIFFUSE_COLOR: Vector3<f32> = Vector3::new(0.0, 0.0, 0.0);
 const DEFAULT_SPECULAR_COLOR: Vector3<f32> = Vector3::new(0.0, 0.0, 0.0);
 const DEFAULT_SHININESS: f32 = 0.0;
+const DEFAULT_SHADOW_BIAS: f32 = 1e-6;
+/// Pixel centers
+pub const DEFAULT_PIXEL_OFFSET: (f32, f32) = (0.5, 0.5);
+/// Highest accepted `maxdepth`. Reflections are traced recursively, one stack frame per
+/// bounce, so an unbounded depth between facing mirrors could overflow the stack.
+pub const MAX_DEPTH: u32 = 256;
 
+/// Newest scene format understood by this parser, as given by a scene's `version` line
+pub const LATEST_FORMAT_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize)]
 pub struct Config {
     pub width: u32,
     pub height: u32,
+    /// Output image path. When loaded from a file, relative paths are resolved against
+    /// the scene file's directory rather than the current working directory.
     pub output_file: String,
+    /// Where `save_linear` writes the linear framebuffer (before grading, clamping and
+    /// quantization) alongside `output_file`; resolved like `output_file`
+    pub linear_output: Option<String>,
     pub camera: Camera,
-    pub ambient: Vector3<f32>,
+    /// Set by `ambient` (uniform) or `ambient_hemi` (sky/ground), whichever comes last
+    pub ambient: AmbientLight,
     pub maxdepth: u32,
+    /// Reflections allowed along one ray path, on top of the `maxdepth` bound on all
+    /// bounces. With `max_refract`, this keeps surfaces that both reflect and refract
+    /// from doubling the ray count at every bounce.
+    pub max_reflect: u32,
+    /// Refractions allowed along one ray path; see `max_reflect`
+    pub max_refract: u32,
+    /// Distance over which reflections fade: a reflected ray hitting an object at
+    /// distance `d` carries back `exp(-d / falloff)` of its color. `None` keeps
+    /// reflections at full strength whatever the distance.
+    pub reflection_falloff: Option<f32>,
     pub maxverts: u32,
+    /// Number of lights shaded per hit via importance sampling, 0 to shade every light
+    pub light_samples: u32,
+    /// Lift/gamma/gain grading applied to each pixel before quantization
+    pub grade: ColorGrade,
+    /// How graded colors brighter than 1 are brought into range
+    pub clamp_mode: ClampMode,
+    /// Hits closer than this along a ray are ignored
+    pub clip_near: f32,
+    /// Hits farther than this along a ray are ignored
+    pub clip_far: f32,
+    /// Seen by rays leaving the scene instead of black
+    pub environment: Option<EnvironmentMap>,
+    /// Environment samples per hit for image-based diffuse lighting, 0 to disable
+    pub ibl_samples: u32,
+    /// Mixed into every random stream (light sampling, IBL, glossy reflections,
+    /// progressive jitter), so equal seeds give byte-identical renders. `None` when
+    /// the scene has no `seed` line, which renders like seed 0.
+    pub seed: Option<u64>,
+    /// Samples averaged in silhouette pixels (see `RayTracer::render`), 0 to sample
+    /// every pixel once at its center
+    pub edge_aa_samples: u32,
+    /// Where in a silhouette pixel its `edge_aa` samples are taken, set by `aa_pattern`
+    pub aa_pattern: AaPattern,
+    /// How the `edge_aa` samples of a silhouette pixel make its color, set by `aa_combine`
+    pub aa_combine: AaCombine,
+    /// Where in each pixel its primary ray passes, in pixels from the pixel's top-left
+    /// corner. Defaults to the center, `(0.5, 0.5)`; `(0, 0)` samples corners as some
+    /// reference renderers do.
+    pub pixel_offset: (f32, f32),
+    /// Strength of the edge-aware denoiser run over each render, after `edge_aa` (see
+    /// `RayTracer::render_to_image_with_camera`), `None` to leave the samples unfiltered
+    pub denoise: Option<f32>,
+    /// Lower shininess where a highlight would be narrower than a couple of pixels, so
+    /// very sharp highlights do not alias (see `RayTracer::stable_shininess`)
+    pub specular_aa: bool,
+    /// Shadow-ray hits closer than this to the shaded point are ignored, for point and
+    /// directional lights alike. Raise it when large coordinates cause shadow acne.
+    pub shadow_bias: f32,
+    /// Filter patterns over each primary ray's pixel footprint (see `Ray::spread`)
+    /// instead of point-sampling them, so distant fine patterns do not alias
+    pub texture_filter: bool,
+    /// Tone curve or 3D LUT applied to display colors after `clamp_mode`, set by `lut`
+    pub lut: Option<Lut>,
+    /// Left out of the serialized settings: the scene cache and binary scenes store the
+    /// objects in layouts of their own (see `set_scene_objects`)
+    #[serde(skip)]
     scene_objects: Vec<Shape>,
+    /// Names given with `name`, keyed by index into `scene_objects`
+    object_names: BTreeMap<usize, String>,
     lights: Vec<Light>,
 }
 
 impl Config {
+    /// Axis-aligned `(min, max)` corners enclosing every bounded object. Planes are
+    /// infinite and left out; `None` when the scene has no bounded object.
+    pub fn bounding_box(&self) -> Option<(Vector3<f32>, Vector3<f32>)> {
+        self.scene_objects
+            .iter()
+            .filter(|shape| !matches!(shape, Shape::Plane { .. }))
+            .map(|shape| {
+                let aabb = shape.aabb();
+                (aabb.min.coords, aabb.max.coords)
+            })
+            .reduce(|(min_a, max_a), (min_b, max_b)| (min_a.inf(&min_b), max_a.sup(&max_b)))
+    }
+
+    /// The scene camera orbited by `angle_degrees` around the scene's up axis through
+    /// the center of `bounding_box` (or the camera's look-at point for unbounded
+    /// scenes), keeping its distance and height and looking at that center.
+    pub fn turntable_camera(&self, angle_degrees: f32) -> Camera {
+        let center = self
+            .bounding_box()
+            .map_or(self.camera.look_at, |(min, max)| (min + max) / 2.0);
+        let mut camera = self.camera.clone();
+        camera.orbit(center, angle_degrees);
+        camera.set_look_at(center);
+        camera
+    }
+
+    /// Moves the scene camera to `position`; see `Camera::set_position`.
+    pub fn set_camera_position(&mut self, position: Vector3<f32>) {
+        self.camera.set_position(position);
+    }
+
+    /// Aims the scene camera at `look_at`; see `Camera::set_look_at`.
+    pub fn set_camera_look_at(&mut self, look_at: Vector3<f32>) {
+        self.camera.set_look_at(look_at);
+    }
+
+    /// Orbits the scene camera by `angle_degrees` around `center`; see `Camera::orbit`.
+    /// Unlike `turntable_camera`, the camera is changed in place, so successive calls
+    /// accumulate into a flythrough.
+    pub fn orbit(&mut self, center: Vector3<f32>, angle_degrees: f32) {
+        self.camera.orbit(center, angle_degrees);
+    }
+
+    /// Appends `other`'s objects and lights after this scene's own.
+    ///
+    /// Every singleton setting (size, output file, camera, ambient, environment,
+    /// render limits, grading...) is kept from `self` and `other`'s are dropped, so
+    /// merge into the config whose view and lighting setup should win, e.g.
+    /// `rig.merge(geometry)` to light swappable geometry with a shared rig. Scene files
+    /// can be layered the same way with `ParsedConfigState::apply_layer_file`. The BVH,
+    /// and with it every object's node index, is built over the merged objects by
+    /// `RayTracer::new`.
+    pub fn merge(&mut self, other: Config) {
+        let offset = self.scene_objects.len();
+        self.object_names
+            .extend(other.object_names.into_iter().map(|(index, name)| (index + offset, name)));
+        self.scene_objects.extend(other.scene_objects);
+        self.lights.extend(other.lights);
+    }
+
+    /// Puts back the objects of a config read from a scene cache or binary scene, which
+    /// store them apart from the serialized settings. Names stay keyed by index, so
+    /// `objects` must be in their original order.
+    pub(crate) fn set_scene_objects(&mut self, objects: Vec<Shape>) {
+        self.scene_objects = objects;
+    }
+
     pub fn get_scene_objects(&self) -> &Vec<Shape> {
         &self.scene_objects
     }
 
-    pub fn get_scene_objects_mut(&mut self) -> &mut Vec<Shape> {
+    /// The objects for editing in place. A slice rather than the vector, so objects can
+    /// be changed but not added, removed or reordered under their names' indices.
+    pub fn get_scene_objects_mut(&mut self) -> &mut [Shape] {
         &mut self.scene_objects
     }
 
+    /// Name given to the object at `index` in the object list, if any
+    pub fn object_name(&self, index: usize) -> Option<&str> {
+        self.object_names.get(&index).map(String::as_str)
+    }
+
+    /// Drops the objects left out by a selection: with `only` non-empty, every object
+    /// not named in it (unnamed ones included); then every object named in `hide`.
+    /// Object order is kept. Returns the number of objects removed.
+    pub fn select_objects(&mut self, only: &[String], hide: &[String]) -> usize {
+        let objects = std::mem::take(&mut self.scene_objects);
+        let mut names = std::mem::take(&mut self.object_names);
+        let total = objects.len();
+        for (index, object) in objects.into_iter().enumerate() {
+            let name = names.remove(&index);
+            let selected = match &name {
+                Some(name) => (only.is_empty() || only.contains(name)) && !hide.contains(name),
+                None => only.is_empty(),
+            };
+            if selected {
+                if let Some(name) = name {
+                    self.object_names.insert(self.scene_objects.len(), name);
+                }
+                self.scene_objects.push(object);
+            }
+        }
+        total - self.scene_objects.len()
+    }
+
     pub fn get_lights(&self) -> &Vec<Light> {
         &self.lights
     }
@@ -40,61 +226,104 @@ impl Config {
         println!("Config:");
         println!(" Size: {}x{}", self.width, self.height);
         println!(" Output file: {}", self.output_file);
+        if let Some(linear_output) = &self.linear_output {
+            println!(" Linear output file: {}", linear_output);
+        }
         println!(
-            " Camera: position({:?}), look_at({:?}), up({:?}), fov({})",
-            self.camera.position, self.camera.look_at, self.camera.up, self.camera.fov
+            " Camera: position({:?}), look_at({:?}), up({:?}), fov({}, {:?}), {:?}",
+            self.camera.position,
+            self.camera.look_at,
+            self.camera.up,
+            self.camera.fov,
+            self.camera.fov_axis,
+            self.camera.projection
         );
-        println!(" Ambient light: {:?}", self.ambient);
+        if self.ambient.is_uniform() {
+            println!(" Ambient light: {:?}", self.ambient.sky);
+        } else {
+            println!(" Hemisphere ambient: sky({:?}), ground({:?})", self.ambient.sky, self.ambient.ground);
+        }
         for (i, obj) in self.scene_objects.iter().enumerate() {
             match obj {
                 Shape::Sphere {
                     center,
                     radius,
-                    diffuse_color,
-                    specular_color,
-                    shininess,
+                    material,
+                    inward,
                     ..
                 } => {
+                    let radius = if *inward { -radius } else { *radius };
                     println!(
                         " Object {}: Sphere - center({:?}), radius({}), diffuse_color({:?}), specular_color({:?}), shininess({})",
-                        i, center, radius, diffuse_color, specular_color, shininess
+                        i, center, radius, material.diffuse_color, material.specular_color, material.shininess
                     );
                 }
                 Shape::Plane {
                     point,
                     normal,
-                    diffuse_color,
-                    specular_color,
-                    shininess,
+                    material,
                     ..
                 } => {
                     println!(
                         " Object {}: Plane - point({:?}), normal({:?}), diffuse_color({:?}), specular_color({:?}), shininess({})",
-                        i, point, normal, diffuse_color, specular_color, shininess
+                        i, point, normal, material.diffuse_color, material.specular_color, material.shininess
                     );
                 }
                 Shape::Triangle {
                     v0,
                     v1,
                     v2,
-                    diffuse_color,
-                    specular_color,
-                    shininess,
+                    material,
                     ..
                 } => {
                     println!(
                         " Object {}: Triangle - v0({:?}), v1({:?}), v2({:?}), diffuse_color({:?}), specular_color({:?}), shininess({})",
-                        i, v0, v1, v2, diffuse_color, specular_color, shininess
+                        i, v0, v1, v2, material.diffuse_color, material.specular_color, material.shininess
+                    );
+                }
+                Shape::BoundedPlane {
+                    point,
+                    normal,
+                    half_size,
+                    material,
+                    ..
+                } => {
+                    println!(
+                        " Object {}: Bounded plane - point({:?}), normal({:?}), half_size({}), diffuse_color({:?}), specular_color({:?}), shininess({})",
+                        i, point, normal, half_size, material.diffuse_color, material.specular_color, material.shininess
+                    );
+                }
+                Shape::Capsule {
+                    a,
+                    b,
+                    radius,
+                    material,
+                    ..
+                } => {
+                    println!(
+                        " Object {}: Capsule - a({:?}), b({:?}), radius({}), diffuse_color({:?}), specular_color({:?}), shininess({})",
+                        i, a, b, radius, material.diffuse_color, material.specular_color, material.shininess
+                    );
+                }
+                Shape::Instance {
+                    mesh, transform, ..
+                } => {
+                    println!(
+                        " Object {}: Instance - mesh({}, {} triangles), transform({:?})",
+                        i,
+                        mesh.name,
+                        mesh.triangles().len(),
+                        transform.matrix()
                     );
                 }
             }
         }
         for (i, light) in self.lights.iter().enumerate() {
             match light {
-                Light::Point { position, color } => {
+                Light::Point { position, color, cutoff } => {
                     println!(
-                        " Light {}: Point - position({:?}), color({:?})",
-                        i, position, color
+                        " Light {}: Point - position({:?}), color({:?}), cutoff({:?})",
+                        i, position, color, cutoff
                     );
                 }
                 Light::Directional { direction, color } => {
@@ -108,43 +337,385 @@ impl Config {
     }
 }
 
+/// Resolves `path` against the directory containing `scene_path`; absolute paths are
+/// returned unchanged.
+fn resolve_relative_to(scene_path: &str, path: &str) -> String {
+    if Path::new(path).is_absolute() {
+        return path.to_string();
+    }
+    match Path::new(scene_path).parent() {
+        Some(dir) => dir.join(path).to_string_lossy().into_owned(),
+        None => path.to_string(),
+    }
+}
+
+/// Resolves the output paths of a scene loaded from `scene_path` against its directory
+fn resolve_outputs(scene_path: &str, config: &mut Config) {
+    config.output_file = resolve_relative_to(scene_path, &config.output_file);
+    if let Some(linear_output) = &mut config.linear_output {
+        *linear_output = resolve_relative_to(scene_path, linear_output);
+    }
+}
+
+/// Material in effect at the start of a scene and after `reset_material`
+fn default_material() -> Material {
+    Material {
+        diffuse_color: DEFAULT_DIFFUSE_COLOR,
+        specular_color: DEFAULT_SPECULAR_COLOR,
+        shininess: DEFAULT_SHININESS,
+        anisotropy: 0.0,
+        roughness: 0.0,
+        ambient: None,
+        pattern: None,
+    }
+}
+
+/// Whether `material` has a specular color but no shininess. Shininess 0 makes the
+/// highlight `n.h` unraised, spread over everything facing a light, which is rarely meant.
+fn has_flat_highlight(material: &Material) -> bool {
+    material.shininess == 0.0 && material.specular_color.iter().any(|c| *c > 0.0)
+}
+
 pub struct ParsedConfigState {
-    diffuse_color: Vector3<f32>,
-    specular_color: Vector3<f32>,
-    shininess: f32,
+    material: Material,
+    /// Set by `transparency` and `ior`, applied to the spheres that follow
+    transmission: Transmission,
+    /// Set by `twosided`, applied to the triangles that follow. A directive of its own
+    /// rather than a material property: `usematerial` and `reset_material` keep it.
+    twosided: bool,
     vertices: Vec<Vector3<f32>>,
+    meshes: HashMap<String, Arc<Mesh>>,
+    /// Name and triangles of the mesh currently being defined between `mesh` and `endmesh`
+    current_mesh: Option<(String, Vec<Shape>)>,
+    /// Path of the scene file being loaded, used to resolve relative asset paths
+    scene_path: Option<String>,
+    /// Materials defined with `material NAME ...`, inline or through `mtllib`
+    materials: HashMap<String, Material>,
+    /// Color inputs are divided by this: 1 for `colorspace float`, 255 for `colorspace byte`
+    color_divisor: f32,
+    /// Whether light colors may exceed 1, set by `hdr on`
+    hdr: bool,
+    /// Whether the last loaded scene had a `size` line
+    size_set: bool,
+    /// Non-fatal problems found while loading the last scene
+    warnings: Vec<String>,
+    /// Planar UV density given to the planes that follow, set by `uv_scale`
+    uv_scale: f32,
+    /// Rotation taking the `scene_up` axis to +Y, applied to the geometry that follows
+    scene_rotation: Rotation3<f32>,
+    /// Group transforms opened by `push_transform`, each composed with the ones below
+    /// it; the last one places the objects that follow
+    transform_stack: Vec<Transform>,
+    /// Set by `name`, given to the objects of the next line that defines any
+    pending_name: Option<String>,
+    /// Directory of the parsed-scene cache used by `load_config_file`, `None` to disable it
+    cache_dir: Option<PathBuf>,
+    /// Whether the last `load_config_file` was answered from the cache
+    loaded_from_cache: bool,
+    /// Files read while loading the last scene, which invalidate its cache entry
+    sources: Vec<String>,
+    /// Format version of the scene being loaded: its `version` line, 1 without one
+    format_version: u32,
+    /// Whether a line other than a blank or comment was parsed, so `version` is misplaced
+    seen_content: bool,
+}
+
+impl Default for ParsedConfigState {
+    fn default() -> Self {
+        ParsedConfigState::new()
+    }
 }
 
 impl ParsedConfigState {
     pub fn new() -> Self {
         ParsedConfigState {
-            diffuse_color: DEFAULT_DIFFUSE_COLOR,
-            specular_color: DEFAULT_SPECULAR_COLOR,
-            shininess: DEFAULT_SHININESS,
+            material: default_material(),
+            transmission: Transmission::OPAQUE,
+            twosided: false,
             vertices: Vec::new(),
+            meshes: HashMap::new(),
+            current_mesh: None,
+            scene_path: None,
+            materials: HashMap::new(),
+            color_divisor: 1.0,
+            hdr: false,
+            size_set: false,
+            warnings: Vec::new(),
+            uv_scale: 1.0,
+            scene_rotation: Rotation3::identity(),
+            transform_stack: Vec::new(),
+            pending_name: None,
+            cache_dir: None,
+            loaded_from_cache: false,
+            sources: Vec::new(),
+            format_version: 1,
+            seen_content: false,
+        }
+    }
+
+    /// Whether the last loaded scene set its dimensions with `size`.
+    ///
+    /// When it did not, the config holds the 800x600 defaults and callers comparing
+    /// against a reference image may substitute the reference's dimensions instead.
+    pub fn size_was_set(&self) -> bool {
+        self.size_set
+    }
+
+    /// Non-fatal problems found while loading the last scene, e.g. a missing `size`
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Format version of the last loaded scene, from its `version` line (1 without one).
+    ///
+    /// Version 2 adds `reset_material` and rejects lone unknown words, which version 1
+    /// silently ignores.
+    pub fn format_version(&self) -> u32 {
+        self.format_version
+    }
+
+    /// Caches parsed scenes in `dir`: `load_config_file` then reuses the config stored
+    /// for a scene file, skipping the parse, until the scene, its material libraries,
+    /// environment map or LUT, or the executable change. Scenes with mesh instances are not
+    /// cached. The parser's own state (materials, meshes) is not restored on a hit.
+    pub fn set_cache_dir(&mut self, dir: Option<PathBuf>) {
+        self.cache_dir = dir;
+    }
+
+    /// Whether the last `load_config_file` was answered from the scene cache
+    pub fn loaded_from_cache(&self) -> bool {
+        self.loaded_from_cache
+    }
+
+    /// Loads a scene file: a text scene, or a binary scene (see `save_binary`) when the
+    /// extension is `.rtscene`. Binary scenes always count as having a `size`.
+    pub fn load_config_file(&mut self, file_path: &str) -> Result<Config, RayTracerError> {
+        self.scene_path = Some(file_path.to_string());
+        if Path::new(file_path).extension().is_some_and(|ext| ext == BINARY_EXTENSION) {
+            self.size_set = true;
+            self.warnings.clear();
+            self.loaded_from_cache = false;
+            let mut config = binary_scene::load_binary(file_path)?;
+            resolve_outputs(file_path, &mut config);
+            return Ok(config);
+        }
+        let cache_path = self
+            .cache_dir
+            .as_deref()
+            .map(|dir| scene_cache::cache_path(dir, file_path));
+        self.loaded_from_cache = false;
+        if let Some((mut config, objects, size_set, warnings, format_version)) =
+            cache_path.as_deref().and_then(scene_cache::load)
+        {
+            config.set_scene_objects(objects);
+            self.size_set = size_set;
+            self.warnings = warnings;
+            self.format_version = format_version;
+            self.loaded_from_cache = true;
+            resolve_outputs(file_path, &mut config);
+            return Ok(config);
+        }
+
+        let file = File::open(file_path).map_err(|e| RayTracerError::io(file_path, e))?;
+        let mut config = self.load_config_reader(io::BufReader::new(file))?;
+        // Instances share their mesh through an `Arc`, which cache entries cannot hold
+        let cacheable = !config
+            .get_scene_objects()
+            .iter()
+            .any(|object| matches!(object, Shape::Instance { .. }));
+        if let Some(cache_path) = cache_path.filter(|_| cacheable) {
+            self.sources.push(file_path.to_string());
+            let entry = (
+                &config,
+                config.get_scene_objects().as_slice(),
+                self.size_set,
+                self.warnings.as_slice(),
+                self.format_version,
+            );
+            if let Err(e) = scene_cache::store(&cache_path, &self.sources, entry) {
+                self.warnings.push(format!("Scene cache '{}' not written: {}", cache_path.display(), e));
+            }
+        }
+        resolve_outputs(file_path, &mut config);
+        Ok(config)
+    }
+
+    /// Converts the text scene `scene_path` into the binary scene `binary_path`. Output
+    /// paths are stored as written in the text scene, so they are resolved against the
+    /// binary scene's directory when it is loaded.
+    pub fn convert_to_binary(&mut self, scene_path: &str, binary_path: &str) -> Result<(), RayTracerError> {
+        self.scene_path = Some(scene_path.to_string());
+        let file = File::open(scene_path).map_err(|e| RayTracerError::io(scene_path, e))?;
+        let config = self.load_config_reader(io::BufReader::new(file))?;
+        binary_scene::save_binary(&config, binary_path)
+    }
+
+    /// Parses a scene given directly as text, using the same rules as `load_config_file`
+    pub fn load_config_str(&mut self, content: &str) -> Result<Config, RayTracerError> {
+        self.load_config_reader(Cursor::new(content))
+    }
+
+    /// Applies a view file to an already loaded scene: its `camera` line replaces the
+    /// scene's camera, and an optional `size` (or `fov_axis`) line overrides the scene's.
+    /// Any other keyword is an error, so shot setup stays separate from scene content.
+    pub fn apply_view_file(&mut self, file_path: &str, config: &mut Config) -> Result<(), RayTracerError> {
+        let content = std::fs::read_to_string(file_path).map_err(|e| RayTracerError::io(file_path, e))?;
+        let mut has_camera = false;
+        for (index, line) in content.lines().enumerate() {
+            let keyword = line.split_whitespace().next().unwrap_or("");
+            match keyword {
+                "" => {}
+                _ if keyword.starts_with(COMMENT_CHAR) => {}
+                "camera" | "size" | "fov_axis" => {
+                    has_camera |= keyword == "camera";
+                    self.parse_line(line, config)
+                        .map_err(|msg| RayTracerError::Parse { line: index + 1, msg })?;
+                }
+                _ => {
+                    return Err(RayTracerError::Parse {
+                        line: index + 1,
+                        msg: format!("'{}' is not allowed in a view file (only camera, size and fov_axis)", keyword),
+                    })
+                }
+            }
         }
+        if !has_camera {
+            return Err(RayTracerError::InvalidScene(format!(
+                "View file '{}' has no camera line",
+                file_path
+            )));
+        }
+        Ok(())
     }
-    pub fn load_config_file(&mut self, file_path: &str) -> Result<Config, String> {
-        let file = File::open(file_path).map_err(|e| e.to_string())?;
-        let reader = io::BufReader::new(file);
+
+    /// Loads another scene file as a layer of `config`, appending its objects and lights
+    /// with `Config::merge`. The layer is parsed on its own, so materials and `name`s
+    /// do not leak between files, and its global settings (camera, size, output,
+    /// ambient...) are dropped in favour of `config`'s; use a view file to override
+    /// those. The layer's warnings are added to this parser's, prefixed with its path.
+    pub fn apply_layer_file(&mut self, file_path: &str, config: &mut Config) -> Result<(), RayTracerError> {
+        let mut layer_parser = ParsedConfigState::new();
+        layer_parser.cache_dir = self.cache_dir.clone();
+        let layer = layer_parser.load_config_file(file_path)?;
+        self.warnings.extend(
+            layer_parser
+                .warnings
+                .into_iter()
+                .map(|warning| format!("{}: {}", file_path, warning)),
+        );
+        config.merge(layer);
+        Ok(())
+    }
+
+    fn load_config_reader<R: BufRead>(&mut self, reader: R) -> Result<Config, RayTracerError> {
+        self.size_set = false;
+        self.warnings.clear();
+        self.format_version = 1;
+        self.seen_content = false;
+        self.sources.clear();
+        self.transform_stack.clear();
+        self.pending_name = None;
         let mut config = Config {
             width: 800,
             height: 600,
             output_file: "output.png".to_string(),
+            linear_output: None,
             camera: Camera {
                 position: Vector3::zeros(),
                 look_at: Vector3::z(),
                 up: Vector3::y(),
                 fov: 60.0,
+                fov_axis: FovAxis::Vertical,
+                projection: Projection::Perspective,
             },
-            ambient: Vector3::repeat(0.0),
+            ambient: AmbientLight::uniform(Vector3::zeros()),
             maxdepth: 1,
+            max_reflect: MAX_DEPTH,
+            max_refract: MAX_DEPTH,
+            reflection_falloff: None,
             maxverts: 0,
+            light_samples: 0,
+            grade: ColorGrade::identity(),
+            clamp_mode: ClampMode::Clip,
+            clip_near: 0.0,
+            clip_far: f32::INFINITY,
+            environment: None,
+            ibl_samples: 0,
+            seed: None,
+            edge_aa_samples: 0,
+            aa_pattern: AaPattern::Jitter,
+            aa_combine: AaCombine::Mean,
+            pixel_offset: DEFAULT_PIXEL_OFFSET,
+            denoise: None,
+            specular_aa: true,
+            shadow_bias: DEFAULT_SHADOW_BIAS,
+            texture_filter: true,
+            lut: None,
             scene_objects: Vec::new(),
+            object_names: BTreeMap::new(),
             lights: Vec::new(),
         };
-        for line in reader.lines() {
-            self.parse_line(&line.map_err(|e| e.to_string())?, &mut config)?;
+        for (index, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| {
+                RayTracerError::io(self.scene_path.as_deref().unwrap_or("<scene>"), e)
+            })?;
+            let objects_before = config.scene_objects.len();
+            self.parse_line(&line, &mut config)
+                .map_err(|msg| RayTracerError::Parse { line: index + 1, msg })?;
+            if config.scene_objects.len() > objects_before {
+                if let Some(name) = self.pending_name.take() {
+                    for object in objects_before..config.scene_objects.len() {
+                        config.object_names.insert(object, name.clone());
+                    }
+                }
+            }
+        }
+        if let Some((name, _)) = &self.current_mesh {
+            return Err(RayTracerError::InvalidScene(format!(
+                "Mesh '{}' is missing its endmesh",
+                name
+            )));
+        }
+        if !self.transform_stack.is_empty() {
+            return Err(RayTracerError::InvalidScene(format!(
+                "{} push_transform(s) are missing their pop_transform",
+                self.transform_stack.len()
+            )));
+        }
+        if config.clip_far <= config.clip_near {
+            return Err(RayTracerError::InvalidScene(
+                "clip_far must be greater than clip_near".to_string(),
+            ));
+        }
+        if config.ibl_samples > 0 && config.environment.is_none() {
+            return Err(RayTracerError::InvalidScene("ibl requires an envmap".to_string()));
+        }
+        if let Some(name) = &self.pending_name {
+            self.warnings.push(format!("name '{}' is not followed by any object", name));
+        }
+        if !self.size_set {
+            self.warnings.push(format!(
+                "No size given, using the default {}x{}",
+                config.width, config.height
+            ));
+        }
+        let flat_highlights = config
+            .scene_objects
+            .iter()
+            .filter(|object| match object {
+                Shape::Instance { mesh, .. } => mesh
+                    .triangles()
+                    .iter()
+                    .any(|triangle| triangle.material().is_some_and(has_flat_highlight)),
+                _ => object.material().is_some_and(has_flat_highlight),
+            })
+            .count();
+        if flat_highlights > 0 {
+            self.warnings.push(format!(
+                "{} object(s) have a specular color but shininess 0, which lights their whole \
+                 side facing each light; set a shininess such as 32 for a focused highlight",
+                flat_highlights
+            ));
         }
         Ok(config)
     }
@@ -153,37 +724,184 @@ impl ParsedConfigState {
         if line.trim().is_empty() || line.trim_start().starts_with(COMMENT_CHAR) {
             return Ok(());
         }
+        let first_line = !self.seen_content;
+        self.seen_content = true;
         let parts: Vec<&str> = line.split(' ').map(|s| s.trim()).collect();
+        if parts.len() == 1 {
+            match parts[0] {
+                "endmesh" => return self.end_mesh(),
+                "push_transform" => return self.push_transform(&[]),
+                "pop_transform" => return self.pop_transform(),
+                "reset_material" => {
+                    self.require_version("reset_material", 2)?;
+                    self.material = default_material();
+                    self.transmission = Transmission::OPAQUE;
+                    return Ok(());
+                }
+                keyword if self.format_version >= 2 => {
+                    return Err(format!("Unknown configuration key: {}", keyword));
+                }
+                _ => {}
+            }
+        }
         if parts.len() >= 2 {
             let param = &line[parts[0].len()..].trim();
             match parts[0] {
+                "version" => {
+                    if !first_line {
+                        return Err("version must be the first line of the scene".to_string());
+                    }
+                    let version = param.parse::<u32>().map_err(|e| e.to_string())?;
+                    if version == 0 || version > LATEST_FORMAT_VERSION {
+                        return Err(format!(
+                            "Unsupported scene format version {} (this parser reads versions 1 to {})",
+                            version, LATEST_FORMAT_VERSION
+                        ));
+                    }
+                    self.format_version = version;
+                }
                 "size" => {
                     let (width, height) = self.parse_size(param)?;
                     config.width = width;
                     config.height = height;
+                    self.size_set = true;
                 }
                 "output" => {
                     let output_file = self.parse_output(param)?;
                     config.output_file = output_file;
                 }
+                "save_linear" => {
+                    config.linear_output = Some(self.parse_output(param)?);
+                }
                 "camera" => {
                     let camera = self.parse_camera(param)?;
-                    config.camera = camera;
+                    // fov_axis and ortho may be given before or after the camera line
+                    config.camera = Camera {
+                        fov_axis: config.camera.fov_axis,
+                        projection: config.camera.projection,
+                        ..camera
+                    };
+                }
+                "colorspace" => {
+                    self.color_divisor = match *param {
+                        "float" => 1.0,
+                        "byte" => 255.0,
+                        _ => return Err(format!("Invalid colorspace '{}': expected float or byte", param)),
+                    };
+                }
+                "hdr" => {
+                    self.hdr = match *param {
+                        "on" => true,
+                        "off" => false,
+                        _ => return Err(format!("Invalid hdr '{}': expected on or off", param)),
+                    };
+                }
+                "fov_axis" => {
+                    config.camera.fov_axis = Self::parse_fov_axis(param)?;
+                }
+                "ortho" => {
+                    let height = Self::parse_f32(param)?;
+                    if height <= 0.0 {
+                        return Err("ortho view height must be greater than zero".to_string());
+                    }
+                    config.camera.projection = Projection::Orthographic { height };
                 }
                 "ambient" => {
-                    config.ambient = self.parse_ambient(param)?;
+                    config.ambient = AmbientLight::uniform(self.parse_ambient(param)?);
+                }
+                "clip_near" => {
+                    config.clip_near = Self::parse_f32(param)?;
+                    if config.clip_near < 0.0 {
+                        return Err("clip_near must not be negative".to_string());
+                    }
+                }
+                "shadow_bias" => {
+                    config.shadow_bias = Self::parse_f32(param)?;
+                    if config.shadow_bias <= 0.0 {
+                        return Err("shadow_bias must be greater than zero".to_string());
+                    }
+                }
+                "clip_far" => {
+                    config.clip_far = Self::parse_f32(param)?;
+                }
+                "envmap" => {
+                    let path = match &self.scene_path {
+                        Some(scene_path) => resolve_relative_to(scene_path, param),
+                        None => param.to_string(),
+                    };
+                    config.environment = Some(EnvironmentMap::load(&path)?);
+                    self.sources.push(path);
+                }
+                "lut" => {
+                    let path = match &self.scene_path {
+                        Some(scene_path) => resolve_relative_to(scene_path, param),
+                        None => param.to_string(),
+                    };
+                    config.lut = Some(Lut::load(&path)?);
+                    self.sources.push(path);
+                }
+                "skydome" => {
+                    let (dome, samples) = self.parse_skydome(param)?;
+                    config.environment = Some(dome);
+                    config.ibl_samples = samples;
+                }
+                "ibl" => {
+                    config.ibl_samples = param.parse::<u32>().map_err(|e| e.to_string())?;
+                    if config.ibl_samples == 0 {
+                        return Err("ibl sample count must be greater than zero".to_string());
+                    }
+                }
+                "ambient_hemi" => {
+                    config.ambient = self.parse_ambient_hemi(param)?;
                 }
                 "sphere" => {
+                    self.check_not_in_mesh("sphere")?;
                     let sphere = self.parse_sphere(param)?;
-                    config.scene_objects.push(sphere);
+                    self.push_object(sphere, config)?;
+                }
+                "capsule" => {
+                    self.check_not_in_mesh("capsule")?;
+                    let capsule = self.parse_capsule(param)?;
+                    self.push_object(capsule, config)?;
                 }
                 "tri" => {
                     let triangle = self.parse_triangle(param)?;
-                    config.scene_objects.push(triangle);
+                    self.add_triangles(vec![triangle], config)?;
+                }
+                "tristrip" => {
+                    let triangles = self.parse_triangle_strip(param)?;
+                    self.add_triangles(triangles, config)?;
+                }
+                "trifan" => {
+                    let triangles = self.parse_triangle_fan(param)?;
+                    self.add_triangles(triangles, config)?;
                 }
                 "plane" => {
+                    self.check_not_in_mesh("plane")?;
                     let plane = self.parse_plane(param)?;
-                    config.scene_objects.push(plane);
+                    self.push_object(plane, config)?;
+                }
+                "plane_bounded" => {
+                    self.check_not_in_mesh("plane_bounded")?;
+                    let plane = self.parse_plane_bounded(param)?;
+                    self.push_object(plane, config)?;
+                }
+                "grid_repeat" => {
+                    self.check_not_in_mesh("grid_repeat")?;
+                    let (counts, spacing) = Self::parse_grid_repeat(param)?;
+                    self.grid_repeat(counts, spacing, config)?;
+                }
+                "mesh" => {
+                    self.begin_mesh(param)?;
+                }
+                "push_transform" => {
+                    let clauses: Vec<&str> = param.split(' ').collect();
+                    self.push_transform(&clauses)?;
+                }
+                "instance" => {
+                    self.check_not_in_mesh("instance")?;
+                    let instance = self.parse_instance(param)?;
+                    self.push_object(instance, config)?;
                 }
                 "point" => {
                     let light = self.parse_point_light(param)?;
@@ -194,39 +912,198 @@ impl ParsedConfigState {
                     config.lights.push(light);
                 }
                 "diffuse" => {
-                    self.diffuse_color = self.parse_simple_vec3(param)?;
-                    ParsedConfigState::check_rgb_values(
-                        self.diffuse_color.x,
-                        self.diffuse_color.y,
-                        self.diffuse_color.z,
-                    )?;
-                    if (self.diffuse_color.x + config.ambient.x) > 1.0
-                        || (self.diffuse_color.y + config.ambient.y) > 1.0
-                        || (self.diffuse_color.z + config.ambient.z) > 1.0
-                    {
-                        return Err(
-                            "Sum of diffuse color and ambient light components must not exceed 1.0"
-                                .to_string(),
-                        );
+                    self.material.diffuse_color = self.parse_color(param)?;
+                    ParsedConfigState::check_reflectance("diffuse", self.material.diffuse_color)?;
+                    let ambient = self.material.ambient.unwrap_or(config.ambient.brightest());
+                    ParsedConfigState::check_diffuse_ambient_sum(self.material.diffuse_color, ambient)?;
+                }
+                "material_ambient" => {
+                    if *param == "none" {
+                        self.material.ambient = None;
+                    } else {
+                        let ambient = self.parse_ambient(param)?;
+                        ParsedConfigState::check_diffuse_ambient_sum(self.material.diffuse_color, ambient)?;
+                        self.material.ambient = Some(ambient);
                     }
                 }
                 "specular" => {
-                    self.specular_color = self.parse_simple_vec3(param)?;
-                    if self.specular_color.x < 0.0
-                        || self.specular_color.y < 0.0
-                        || self.specular_color.z < 0.0
+                    self.material.specular_color = self.parse_color(param)?;
+                    if self.material.specular_color.x < 0.0
+                        || self.material.specular_color.y < 0.0
+                        || self.material.specular_color.z < 0.0
                     {
                         return Err("Specular color components must be non-negative".to_string());
                     }
                 }
+                "bands" => {
+                    self.material.pattern = if *param == "none" {
+                        None
+                    } else {
+                        Some(self.parse_bands(param)?)
+                    };
+                }
+                "checker" => {
+                    self.material.pattern = if *param == "none" {
+                        None
+                    } else {
+                        Some(self.parse_checker(param)?)
+                    };
+                }
+                "scene_up" => {
+                    self.scene_rotation = Self::parse_scene_up(param)?;
+                }
+                "uv_scale" => {
+                    self.uv_scale = Self::parse_f32(param)?;
+                    if self.uv_scale <= 0.0 {
+                        return Err("uv_scale must be greater than zero".to_string());
+                    }
+                }
+                "name" => {
+                    if param.contains(' ') {
+                        return Err(format!("Invalid name '{}': expected a single word", param));
+                    }
+                    self.pending_name = Some(param.to_string());
+                }
+                "material" => {
+                    let (name, material) = self.parse_material_definition(param)?;
+                    self.materials.insert(name, material);
+                }
+                "mtllib" => {
+                    self.load_material_library(param)?;
+                }
+                "usematerial" => {
+                    let material = *self
+                        .materials
+                        .get(*param)
+                        .ok_or_else(|| format!("Unknown material: {}", param))?;
+                    let ambient = material.ambient.unwrap_or(config.ambient.brightest());
+                    ParsedConfigState::check_diffuse_ambient_sum(material.diffuse_color, ambient)?;
+                    self.material = material;
+                }
                 "shininess" => {
-                    self.shininess = param.parse::<f32>().map_err(|e| e.to_string())?;
-                    if self.shininess < 0.0 {
+                    self.material.shininess = Self::parse_f32(param)?;
+                    if self.material.shininess < 0.0 {
                         return Err("Shininess must be non-negative".to_string());
                     }
                 }
+                "anisotropy" => {
+                    self.material.anisotropy = Self::parse_anisotropy(param)?;
+                }
+                "roughness" => {
+                    self.material.roughness = Self::parse_roughness(param)?;
+                }
+                "twosided" => {
+                    self.twosided = match *param {
+                        "on" => true,
+                        "off" => false,
+                        _ => return Err(format!("Invalid twosided '{}': expected on or off", param)),
+                    };
+                }
+                "transparency" => {
+                    self.transmission.transparency = Self::parse_transparency(param)?;
+                }
+                "ior" => {
+                    self.transmission.ior = Self::parse_ior(param)?;
+                }
+                "max_reflect" => {
+                    config.max_reflect = param.parse::<u32>().map_err(|e| e.to_string())?;
+                }
+                "max_refract" => {
+                    config.max_refract = param.parse::<u32>().map_err(|e| e.to_string())?;
+                }
+                "reflection_falloff" => {
+                    let falloff = Self::parse_f32(param)?;
+                    if falloff <= 0.0 {
+                        return Err("reflection_falloff must be greater than zero".to_string());
+                    }
+                    config.reflection_falloff = Some(falloff);
+                }
                 "maxdepth" => {
                     config.maxdepth = param.parse::<u32>().map_err(|e| e.to_string())?;
+                    if config.maxdepth > MAX_DEPTH {
+                        return Err(format!("maxdepth must be at most {}", MAX_DEPTH));
+                    }
+                }
+                "light_samples" => {
+                    config.light_samples = param.parse::<u32>().map_err(|e| e.to_string())?;
+                    if config.light_samples == 0 {
+                        return Err("light_samples must be greater than zero".to_string());
+                    }
+                }
+                "edge_aa" => {
+                    config.edge_aa_samples = param.parse::<u32>().map_err(|e| e.to_string())?;
+                    if config.edge_aa_samples == 0 {
+                        return Err("edge_aa samples must be greater than zero".to_string());
+                    }
+                }
+                "aa_pattern" => {
+                    config.aa_pattern = match *param {
+                        "jitter" => AaPattern::Jitter,
+                        "grid" => AaPattern::Grid,
+                        "rgss" => AaPattern::RotatedGrid,
+                        "poisson" => AaPattern::Poisson,
+                        _ => {
+                            return Err(format!(
+                                "Invalid aa_pattern '{}': expected jitter, grid, rgss or poisson",
+                                param
+                            ))
+                        }
+                    };
+                }
+                "aa_combine" => {
+                    config.aa_combine = match *param {
+                        "mean" => AaCombine::Mean,
+                        "median" => AaCombine::Median,
+                        "clamped" => AaCombine::Clamped,
+                        _ => {
+                            return Err(format!(
+                                "Invalid aa_combine '{}': expected mean, median or clamped",
+                                param
+                            ))
+                        }
+                    };
+                }
+                "pixel_offset" => {
+                    config.pixel_offset = Self::parse_pixel_offset(param)?;
+                }
+                "denoise" => {
+                    let strength = Self::parse_f32(param)?;
+                    if strength <= 0.0 {
+                        return Err("denoise strength must be greater than zero".to_string());
+                    }
+                    config.denoise = Some(strength);
+                }
+                "specular_aa" => {
+                    config.specular_aa = match *param {
+                        "on" => true,
+                        "off" => false,
+                        _ => return Err(format!("Invalid specular_aa '{}': expected on or off", param)),
+                    };
+                }
+                "texture_filter" => {
+                    config.texture_filter = match *param {
+                        "on" => true,
+                        "off" => false,
+                        _ => return Err(format!("Invalid texture_filter '{}': expected on or off", param)),
+                    };
+                }
+                "seed" => {
+                    config.seed = Some(param.parse::<u64>().map_err(|e| e.to_string())?);
+                }
+                "clamp_mode" => {
+                    config.clamp_mode = match *param {
+                        "clip" => ClampMode::Clip,
+                        "preserve_hue" => ClampMode::PreserveHue,
+                        _ => {
+                            return Err(format!(
+                                "Invalid clamp_mode '{}': expected clip or preserve_hue",
+                                param
+                            ))
+                        }
+                    };
+                }
+                "grade" => {
+                    config.grade = self.parse_grade(param)?;
                 }
                 "maxverts" => {
                     config.maxverts = param.parse::<u32>().map_err(|e| e.to_string())?;
@@ -246,6 +1123,18 @@ impl ParsedConfigState {
         }
         Ok(())
     }
+
+    /// Rejects `keyword` in scenes older than format `version`
+    fn require_version(&self, keyword: &str, version: u32) -> Result<(), String> {
+        if self.format_version < version {
+            return Err(format!(
+                "{} requires 'version {}' (the scene is version {})",
+                keyword, version, self.format_version
+            ));
+        }
+        Ok(())
+    }
+
     fn parse_size(&self, value: &str) -> Result<(u32, u32), String> {
         let dims: Vec<&str> = value.split(' ').collect();
         if dims.len() != 2 {
@@ -261,25 +1150,43 @@ impl ParsedConfigState {
         Ok((width, height))
     }
 
-    fn parse_point_light(&self, value: &str) -> Result<Light, String> {
+    /// `pixel_offset ox oy`, each within `[0, 1]`
+    fn parse_pixel_offset(value: &str) -> Result<(f32, f32), String> {
         let params: Vec<&str> = value.split(' ').collect();
-        if params.len() != 6 {
-            return Err("Invalid point light format".to_string());
+        let [ox, oy] = params.as_slice() else {
+            return Err("Invalid pixel_offset format: expected ox oy".to_string());
+        };
+        let offset = (Self::parse_f32(ox)?, Self::parse_f32(oy)?);
+        if !(0.0..=1.0).contains(&offset.0) || !(0.0..=1.0).contains(&offset.1) {
+            return Err(format!("pixel_offset must be between 0 and 1, got {} {}", ox, oy));
         }
+        Ok(offset)
+    }
+
+    /// `point x y z r g b`, optionally followed by `cutoff distance`
+    fn parse_point_light(&self, value: &str) -> Result<Light, String> {
+        let params: Vec<&str> = value.split(' ').collect();
+        let cutoff = match params.len() {
+            6 => None,
+            8 if params[6] == "cutoff" => {
+                let cutoff = Self::parse_f32(params[7])?;
+                if cutoff <= 0.0 {
+                    return Err("Point light cutoff must be greater than zero".to_string());
+                }
+                Some(cutoff)
+            }
+            _ => return Err("Invalid point light format".to_string()),
+        };
         let position = Vector3::new(
-            params[0].parse::<f32>().map_err(|e| e.to_string())?,
-            params[1].parse::<f32>().map_err(|e| e.to_string())?,
-            params[2].parse::<f32>().map_err(|e| e.to_string())?,
-        );
-        let color = Vector3::new(
-            params[3].parse::<f32>().map_err(|e| e.to_string())?,
-            params[4].parse::<f32>().map_err(|e| e.to_string())?,
-            params[5].parse::<f32>().map_err(|e| e.to_string())?,
+            Self::parse_f32(params[0])?,
+            Self::parse_f32(params[1])?,
+            Self::parse_f32(params[2])?,
         );
+        let color = self.parse_color(&params[3..6].join(" "))?;
 
-        ParsedConfigState::check_rgb_values(color.x, color.y, color.z)?;
+        self.check_emittance("point", color)?;
 
-        Ok(Light::Point { position, color })
+        Ok(Light::Point { position, color, cutoff })
     }
 
     fn parse_directional_light(&self, value: &str) -> Result<Light, String> {
@@ -288,17 +1195,13 @@ impl ParsedConfigState {
             return Err("Invalid directional light format".to_string());
         }
         let direction = Vector3::new(
-            params[0].parse::<f32>().map_err(|e| e.to_string())?,
-            params[1].parse::<f32>().map_err(|e| e.to_string())?,
-            params[2].parse::<f32>().map_err(|e| e.to_string())?,
-        );
-        let color = Vector3::new(
-            params[3].parse::<f32>().map_err(|e| e.to_string())?,
-            params[4].parse::<f32>().map_err(|e| e.to_string())?,
-            params[5].parse::<f32>().map_err(|e| e.to_string())?,
+            Self::parse_f32(params[0])?,
+            Self::parse_f32(params[1])?,
+            Self::parse_f32(params[2])?,
         );
+        let color = self.parse_color(&params[3..6].join(" "))?;
 
-        ParsedConfigState::check_rgb_values(color.x, color.y, color.z)?;
+        self.check_emittance("directional", color)?;
 
         Ok(Light::Directional {
             direction: direction.normalize(),
@@ -306,29 +1209,41 @@ impl ParsedConfigState {
         })
     }
 
+    fn parse_fov_axis(value: &str) -> Result<FovAxis, String> {
+        match value {
+            "vertical" => Ok(FovAxis::Vertical),
+            "horizontal" => Ok(FovAxis::Horizontal),
+            "diagonal" => Ok(FovAxis::Diagonal),
+            _ => Err(format!(
+                "Invalid fov_axis '{}': expected vertical, horizontal or diagonal",
+                value
+            )),
+        }
+    }
+
     fn parse_camera(&self, value: &str) -> Result<Camera, String> {
         let params: Vec<&str> = value.split(' ').collect();
         if params.len() != 10 {
             return Err("Invalid camera format".to_string());
         }
         let position = Vector3::new(
-            params[0].parse::<f32>().map_err(|e| e.to_string())?,
-            params[1].parse::<f32>().map_err(|e| e.to_string())?,
-            params[2].parse::<f32>().map_err(|e| e.to_string())?,
+            Self::parse_f32(params[0])?,
+            Self::parse_f32(params[1])?,
+            Self::parse_f32(params[2])?,
         );
         let look_at = Vector3::new(
-            params[3].parse::<f32>().map_err(|e| e.to_string())?,
-            params[4].parse::<f32>().map_err(|e| e.to_string())?,
-            params[5].parse::<f32>().map_err(|e| e.to_string())?,
+            Self::parse_f32(params[3])?,
+            Self::parse_f32(params[4])?,
+            Self::parse_f32(params[5])?,
         );
         let up = Vector3::new(
-            params[6].parse::<f32>().map_err(|e| e.to_string())?,
-            params[7].parse::<f32>().map_err(|e| e.to_string())?,
-            params[8].parse::<f32>().map_err(|e| e.to_string())?,
+            Self::parse_f32(params[6])?,
+            Self::parse_f32(params[7])?,
+            Self::parse_f32(params[8])?,
         );
-        let fov = params[9].parse::<f32>().map_err(|e| e.to_string())?;
+        let fov = Self::parse_f32(params[9])?;
 
-        if fov < 1.0 || fov > 179.0 {
+        if !(1.0..=179.0).contains(&fov) {
             return Err("Field of view (fov) must be between 1 and 179 degrees".to_string());
         }
 
@@ -337,6 +1252,8 @@ impl ParsedConfigState {
             look_at,
             up,
             fov,
+            fov_axis: FovAxis::default(),
+            projection: Projection::default(),
         })
     }
 
@@ -345,13 +1262,147 @@ impl ParsedConfigState {
         if comps.len() != 3 {
             return Err("Invalid ambient light format".to_string());
         }
-        let r = comps[0].parse::<f32>().map_err(|e| e.to_string())?;
-        let g = comps[1].parse::<f32>().map_err(|e| e.to_string())?;
-        let b = comps[2].parse::<f32>().map_err(|e| e.to_string())?;
+        let color = self.parse_color(value)?;
+        self.check_emittance("ambient", color)?;
+        Ok(color)
+    }
+
+    /// `skydome r g b samples` or `skydome FILE samples`: an emissive dome around the
+    /// scene, uniform or from an environment image, seen by escaping rays and lighting
+    /// diffuse surfaces with `samples` rays per hit (shorthand for `envmap` plus `ibl`)
+    fn parse_skydome(&mut self, value: &str) -> Result<(EnvironmentMap, u32), String> {
+        let params: Vec<&str> = value.split(' ').collect();
+        let samples = match params.last().map(|samples| samples.parse::<u32>()) {
+            Some(Ok(samples)) if samples > 0 => samples,
+            _ => return Err("skydome sample count must be a positive integer".to_string()),
+        };
+        let dome = match params.len() {
+            4 => {
+                let color = self.parse_color(&params[0..3].join(" "))?;
+                if color.iter().any(|c| *c < 0.0) {
+                    return Err("skydome color components must be non-negative".to_string());
+                }
+                EnvironmentMap::new(1, 1, vec![color])?
+            }
+            2 => {
+                let path = match &self.scene_path {
+                    Some(scene_path) => resolve_relative_to(scene_path, params[0]),
+                    None => params[0].to_string(),
+                };
+                let dome = EnvironmentMap::load(&path)?;
+                self.sources.push(path);
+                dome
+            }
+            _ => return Err("Invalid skydome format: expected r g b samples or FILE samples".to_string()),
+        };
+        Ok((dome, samples))
+    }
+
+    /// `ambient_hemi skyr skyg skyb groundr groundg groundb`
+    fn parse_ambient_hemi(&self, value: &str) -> Result<AmbientLight, String> {
+        let params: Vec<&str> = value.split(' ').collect();
+        if params.len() != 6 {
+            return Err("Invalid ambient_hemi format".to_string());
+        }
+        let sky = self.parse_ambient(&params[0..3].join(" "))?;
+        let ground = self.parse_ambient(&params[3..6].join(" "))?;
+        Ok(AmbientLight { sky, ground })
+    }
+
+    /// `anisotropy strength` with strength in `[0, 1)`; 0 keeps the highlight isotropic
+    fn parse_anisotropy(value: &str) -> Result<f32, String> {
+        let strength = Self::parse_f32(value)?;
+        if !(0.0..1.0).contains(&strength) {
+            return Err("Anisotropy must be in [0, 1)".to_string());
+        }
+        Ok(strength)
+    }
+
+    /// `roughness r` with r in `[0, 1]`; 0 keeps reflections mirror-sharp
+    fn parse_roughness(value: &str) -> Result<f32, String> {
+        let roughness = Self::parse_f32(value)?;
+        if !(0.0..=1.0).contains(&roughness) {
+            return Err("Roughness must be between 0.0 and 1.0".to_string());
+        }
+        Ok(roughness)
+    }
+
+    fn parse_transparency(value: &str) -> Result<f32, String> {
+        let transparency = Self::parse_f32(value)?;
+        if !(0.0..=1.0).contains(&transparency) {
+            return Err("Transparency must be between 0.0 and 1.0".to_string());
+        }
+        Ok(transparency)
+    }
+
+    fn parse_ior(value: &str) -> Result<f32, String> {
+        let ior = Self::parse_f32(value)?;
+        if !(ior > 0.0 && ior.is_finite()) {
+            return Err("ior must be greater than zero".to_string());
+        }
+        Ok(ior)
+    }
+
+    /// `bands count r1 g1 b1 r2 g2 b2`
+    fn parse_bands(&self, value: &str) -> Result<Pattern, String> {
+        let params: Vec<&str> = value.split(' ').collect();
+        if params.len() != 7 {
+            return Err("Invalid bands format".to_string());
+        }
+        let count = params[0]
+            .parse::<u16>()
+            .ok()
+            .and_then(NonZeroU16::new)
+            .ok_or("Band count must be between 1 and 65535")?;
+        let (color1, color2) = self.parse_pattern_colors(&params[1..])?;
+        Ok(Pattern::Bands {
+            count,
+            color1,
+            color2,
+        })
+    }
+
+    /// `checker r1 g1 b1 r2 g2 b2`
+    fn parse_checker(&self, value: &str) -> Result<Pattern, String> {
+        let params: Vec<&str> = value.split(' ').collect();
+        if params.len() != 6 {
+            return Err("Invalid checker format".to_string());
+        }
+        let (color1, color2) = self.parse_pattern_colors(&params)?;
+        Ok(Pattern::Checker { color1, color2 })
+    }
 
-        ParsedConfigState::check_rgb_values(r, g, b)?;
+    /// Two `r g b` colors, each within the diffuse range
+    fn parse_pattern_colors(&self, params: &[&str]) -> Result<(Vector3<f32>, Vector3<f32>), String> {
+        let color1 = self.parse_color(&params[0..3].join(" "))?;
+        let color2 = self.parse_color(&params[3..6].join(" "))?;
+        ParsedConfigState::check_reflectance("pattern", color1)?;
+        ParsedConfigState::check_reflectance("pattern", color2)?;
+        Ok((color1, color2))
+    }
+
+    /// `grade lr lg lb gammar gammag gammab gainr gaing gainb`
+    fn parse_grade(&self, value: &str) -> Result<ColorGrade, String> {
+        let params: Vec<&str> = value.split(' ').collect();
+        if params.len() != 9 {
+            return Err("Invalid grade format".to_string());
+        }
+        let lift = self.parse_simple_vec3(&params[0..3].join(" "))?;
+        let gamma = self.parse_simple_vec3(&params[3..6].join(" "))?;
+        let gain = self.parse_simple_vec3(&params[6..9].join(" "))?;
+        if gamma.iter().any(|g| *g <= 0.0) {
+            return Err("Grade gamma must be greater than zero".to_string());
+        }
+        Ok(ColorGrade { lift, gamma, gain })
+    }
 
-        Ok(Vector3::new(r, g, b))
+    /// Parses a finite floating point value. Accepts signs, leading/trailing dots
+    /// (`.5`, `1.`) and scientific notation (`1e3`); rejects `inf` and `NaN`.
+    fn parse_f32(value: &str) -> Result<f32, String> {
+        match value.parse::<f32>() {
+            Ok(number) if number.is_finite() => Ok(number),
+            _ => Err(format!("Invalid number: '{}'", value)),
+        }
     }
 
     fn parse_simple_vec3(&self, value: &str) -> Result<Vector3<f32>, String> {
@@ -359,12 +1410,17 @@ impl ParsedConfigState {
         if comps.len() != 3 {
             return Err("Invalid Vector3 format".to_string());
         }
-        let x: f32 = comps[0].parse::<f32>().map_err(|e| e.to_string())?;
-        let y = comps[1].parse::<f32>().map_err(|e| e.to_string())?;
-        let z = comps[2].parse::<f32>().map_err(|e| e.to_string())?;
+        let x: f32 = Self::parse_f32(comps[0])?;
+        let y = Self::parse_f32(comps[1])?;
+        let z = Self::parse_f32(comps[2])?;
         Ok(Vector3::new(x, y, z))
     }
 
+    /// Three color components, scaled from 0-255 to 0-1 under `colorspace byte`
+    fn parse_color(&self, value: &str) -> Result<Vector3<f32>, String> {
+        Ok(self.parse_simple_vec3(value)? / self.color_divisor)
+    }
+
     fn parse_output(&self, value: &str) -> Result<String, String> {
         let output_file = value.trim();
         if output_file.is_empty() {
@@ -373,9 +1429,44 @@ impl ParsedConfigState {
         Ok(output_file.to_string())
     }
 
-    fn check_rgb_values(r: f32, g: f32, b: f32) -> Result<(), String> {
-        if !(0.0..=1.0).contains(&r) || !(0.0..=1.0).contains(&g) || !(0.0..=1.0).contains(&b) {
-            return Err("RGB values must be between 0.0 and 1.0".to_string());
+    fn check_diffuse_ambient_sum(diffuse: Vector3<f32>, ambient: Vector3<f32>) -> Result<(), String> {
+        if (diffuse.x + ambient.x) > 1.0
+            || (diffuse.y + ambient.y) > 1.0
+            || (diffuse.z + ambient.z) > 1.0
+        {
+            return Err(
+                "Sum of diffuse color and ambient light components must not exceed 1.0".to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Checks a reflectance given by `keyword`: the fraction of light a surface sends
+    /// back, within `[0, 1]` whatever the `hdr` mode.
+    fn check_reflectance(keyword: &str, color: Vector3<f32>) -> Result<(), String> {
+        if color.iter().any(|c| !(0.0..=1.0).contains(c)) {
+            return Err(format!(
+                "Reflectance '{}' must be between 0.0 and 1.0, got {} {} {}",
+                keyword, color.x, color.y, color.z
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks an emittance given by `keyword`: the light a source gives off, which may
+    /// exceed 1 under `hdr on`.
+    fn check_emittance(&self, keyword: &str, color: Vector3<f32>) -> Result<(), String> {
+        if color.iter().any(|c| *c < 0.0) {
+            return Err(format!(
+                "Emittance '{}' must not be negative, got {} {} {}",
+                keyword, color.x, color.y, color.z
+            ));
+        }
+        if !self.hdr && color.iter().any(|c| *c > 1.0) {
+            return Err(format!(
+                "Emittance '{}' must be between 0.0 and 1.0 unless 'hdr on' is set, got {} {} {}",
+                keyword, color.x, color.y, color.z
+            ));
         }
         Ok(())
     }
@@ -387,20 +1478,42 @@ impl ParsedConfigState {
             return Err("Invalid sphere format".to_string());
         }
         let center = Vector3::new(
-            params[0].parse::<f32>().map_err(|e| e.to_string())?,
-            params[1].parse::<f32>().map_err(|e| e.to_string())?,
-            params[2].parse::<f32>().map_err(|e| e.to_string())?,
+            Self::parse_f32(params[0])?,
+            Self::parse_f32(params[1])?,
+            Self::parse_f32(params[2])?,
         );
-        let radius = params[3].parse::<f32>().map_err(|e| e.to_string())?;
-        if radius <= 0.0 {
-            return Err("Sphere radius must be greater than zero".to_string());
+        // A negative radius makes an inward-facing sphere, e.g. a background dome
+        let radius = Self::parse_f32(params[3])?;
+        if radius == 0.0 || !radius.is_finite() {
+            return Err("Sphere radius must be non-zero".to_string());
         }
         Ok(Shape::Sphere {
             center,
+            radius: radius.abs(),
+            material: self.material,
+            node_index: 0,
+            inward: radius < 0.0,
+            transmission: self.transmission,
+        })
+    }
+
+    /// `capsule ax ay az bx by bz radius`
+    fn parse_capsule(&self, value: &str) -> Result<Shape, String> {
+        let params: Vec<&str> = value.split(' ').collect();
+        if params.len() != 7 {
+            return Err("Invalid capsule format".to_string());
+        }
+        let a = self.parse_simple_vec3(&params[0..3].join(" "))?;
+        let b = self.parse_simple_vec3(&params[3..6].join(" "))?;
+        let radius = Self::parse_f32(params[6])?;
+        if radius <= 0.0 {
+            return Err("Capsule radius must be greater than zero".to_string());
+        }
+        Ok(Shape::Capsule {
+            a,
+            b,
             radius,
-            diffuse_color: self.diffuse_color,
-            specular_color: self.specular_color,
-            shininess: self.shininess,
+            material: self.material,
             node_index: 0,
         })
     }
@@ -410,54 +1523,419 @@ impl ParsedConfigState {
         if params.len() != 3 {
             return Err("Invalid triangle format".to_string());
         }
-        let v0_index = params[0].parse::<usize>().map_err(|e| e.to_string())?;
-        let v1_index = params[1].parse::<usize>().map_err(|e| e.to_string())?;
-        let v2_index = params[2].parse::<usize>().map_err(|e| e.to_string())?;
+        let indices = self.parse_vertex_indices(&params)?;
+        self.triangle(indices[0], indices[1], indices[2])
+    }
 
-        if v0_index >= self.vertices.len()
-            || v1_index >= self.vertices.len()
-            || v2_index >= self.vertices.len()
-        {
-            return Err("Triangle vertex index out of bounds".to_string());
+    /// `tristrip i0 i1 i2 i3 ...`: every index after the first two closes a triangle with
+    /// the previous two. Every other triangle swaps its first two vertices so that all of
+    /// them keep the winding of the first one.
+    fn parse_triangle_strip(&self, value: &str) -> Result<Vec<Shape>, String> {
+        let params: Vec<&str> = value.split(' ').collect();
+        if params.len() < 3 {
+            return Err("Invalid triangle strip format".to_string());
         }
+        let indices = self.parse_vertex_indices(&params)?;
+        indices
+            .windows(3)
+            .enumerate()
+            .map(|(k, w)| {
+                if k % 2 == 0 {
+                    self.triangle(w[0], w[1], w[2])
+                } else {
+                    self.triangle(w[1], w[0], w[2])
+                }
+            })
+            .collect()
+    }
 
+    /// `trifan i0 i1 i2 ...`: every triangle shares `i0` and an edge with the previous one
+    fn parse_triangle_fan(&self, value: &str) -> Result<Vec<Shape>, String> {
+        let params: Vec<&str> = value.split(' ').collect();
+        if params.len() < 3 {
+            return Err("Invalid triangle fan format".to_string());
+        }
+        let indices = self.parse_vertex_indices(&params)?;
+        indices[1..]
+            .windows(2)
+            .map(|w| self.triangle(indices[0], w[0], w[1]))
+            .collect()
+    }
+
+    fn parse_vertex_indices(&self, params: &[&str]) -> Result<Vec<usize>, String> {
+        params
+            .iter()
+            .map(|param| {
+                let index = param.parse::<usize>().map_err(|e| e.to_string())?;
+                if index >= self.vertices.len() {
+                    return Err("Triangle vertex index out of bounds".to_string());
+                }
+                Ok(index)
+            })
+            .collect()
+    }
+
+    /// Whether the triangle on these vertex indices has (numerically) zero area: two
+    /// vertices coincide or all three are collinear. Such triangles have no defined
+    /// normal. The test is relative to the edge lengths, so tiny triangles still pass.
+    fn is_degenerate(&self, v0: usize, v1: usize, v2: usize) -> bool {
+        let edge1 = self.vertices[v1] - self.vertices[v0];
+        let edge2 = self.vertices[v2] - self.vertices[v0];
+        edge1.cross(&edge2).norm() <= 1e-6 * edge1.norm() * edge2.norm()
+    }
+
+    /// Triangle on these vertex indices with the current material, rejecting
+    /// degenerate ones
+    fn triangle(&self, v0: usize, v1: usize, v2: usize) -> Result<Shape, String> {
+        if self.is_degenerate(v0, v1, v2) {
+            return Err(format!(
+                "Triangle {} {} {} has zero area (its vertices are identical or collinear)",
+                v0, v1, v2
+            ));
+        }
         Ok(Shape::Triangle {
-            v0: self.vertices[v0_index],
-            v1: self.vertices[v1_index],
-            v2: self.vertices[v2_index],
-            diffuse_color: self.diffuse_color,
-            specular_color: self.specular_color,
-            shininess: self.shininess,
+            v0: self.vertices[v0],
+            v1: self.vertices[v1],
+            v2: self.vertices[v2],
+            material: self.material,
+            node_index: 0,
+            twosided: self.twosided,
+        })
+    }
+
+    /// Adds triangles to the mesh being defined, or to the scene outside of a mesh
+    fn add_triangles(&mut self, triangles: Vec<Shape>, config: &mut Config) -> Result<(), String> {
+        match &mut self.current_mesh {
+            // Mesh triangles are local; `scene_up` applies to their instances instead
+            Some((_, mesh)) => mesh.extend(triangles),
+            None => {
+                for triangle in triangles {
+                    self.push_object(triangle, config)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a scene-level object, placed by the current `push_transform` group and then
+    /// reoriented by the current `scene_up`
+    fn push_object(&self, mut shape: Shape, config: &mut Config) -> Result<(), String> {
+        if let Some(transform) = self.transform_stack.last() {
+            shape.transform(transform)?;
+        }
+        shape.rotate(&self.scene_rotation);
+        config.scene_objects.push(shape);
+        Ok(())
+    }
+
+    /// `push_transform [translate x y z] [rotate rx ry rz] [scale sx sy sz]`: opens a
+    /// group whose objects are placed by this transform, then by the enclosing groups'
+    fn push_transform(&mut self, clauses: &[&str]) -> Result<(), String> {
+        self.check_not_in_mesh("push_transform")?;
+        let local = self.parse_transform_clauses(clauses)?;
+        let transform = match self.transform_stack.last() {
+            Some(parent) => parent.then(&local),
+            None => local,
+        };
+        self.transform_stack.push(transform);
+        Ok(())
+    }
+
+    fn pop_transform(&mut self) -> Result<(), String> {
+        self.check_not_in_mesh("pop_transform")?;
+        self.transform_stack
+            .pop()
+            .map(|_| ())
+            .ok_or_else(|| "pop_transform without matching push_transform".to_string())
+    }
+
+    /// `grid_repeat nx ny nz spacing`: grid dimensions (each at least 1) and the distance
+    /// between neighbouring copies
+    fn parse_grid_repeat(value: &str) -> Result<([u32; 3], f32), String> {
+        let params: Vec<&str> = value.split(' ').collect();
+        if params.len() != 4 {
+            return Err("Invalid grid_repeat format: expected nx ny nz spacing".to_string());
+        }
+        let mut counts = [0; 3];
+        for (count, param) in counts.iter_mut().zip(&params[0..3]) {
+            *count = param
+                .parse::<u32>()
+                .ok()
+                .filter(|count| *count > 0)
+                .ok_or_else(|| format!("Invalid grid_repeat count '{}': expected a positive integer", param))?;
+        }
+        let spacing = Self::parse_f32(params[3])?;
+        Ok((counts, spacing))
+    }
+
+    /// Fills an `nx * ny * nz` grid with copies of the last object, which stays at the
+    /// grid's first cell, and share its name. Steps follow the scene axes, so they are
+    /// placed by the current `push_transform` group and reoriented by `scene_up` like
+    /// the object itself.
+    fn grid_repeat(&self, counts: [u32; 3], spacing: f32, config: &mut Config) -> Result<(), String> {
+        let original = config
+            .scene_objects
+            .last()
+            .cloned()
+            .ok_or_else(|| "grid_repeat needs a previously defined object".to_string())?;
+        let name = config.object_names.get(&(config.scene_objects.len() - 1)).cloned();
+        let copies = counts.iter().map(|&count| count as usize).product::<usize>();
+        config.scene_objects.reserve(copies - 1);
+        for z in 0..counts[2] {
+            for y in 0..counts[1] {
+                for x in 0..counts[0] {
+                    if (x, y, z) == (0, 0, 0) {
+                        continue;
+                    }
+                    let step = Vector3::new(x as f32, y as f32, z as f32) * spacing;
+                    let step = self.transform_stack.last().map_or(step, |group| group.vector_to_world(step));
+                    let mut copy = original.clone();
+                    copy.translate(&(self.scene_rotation * step));
+                    if let Some(name) = &name {
+                        config.object_names.insert(config.scene_objects.len(), name.clone());
+                    }
+                    config.scene_objects.push(copy);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// `scene_up x y z`: the up axis of the geometry that follows, which is rotated so
+    /// that this axis becomes +Y (`scene_up 0 1 0` restores the default)
+    fn parse_scene_up(value: &str) -> Result<Rotation3<f32>, String> {
+        let params: Vec<&str> = value.split(' ').collect();
+        if params.len() != 3 {
+            return Err("Invalid scene_up format".to_string());
+        }
+        let up = Vector3::new(
+            Self::parse_f32(params[0])?,
+            Self::parse_f32(params[1])?,
+            Self::parse_f32(params[2])?,
+        )
+        .try_normalize(1e-6)
+        .ok_or("scene_up must not be a zero vector")?;
+        // Only undefined for an up axis of -Y, where any half turn about X will do
+        Ok(Rotation3::rotation_between(&up, &Vector3::y())
+            .unwrap_or_else(|| Rotation3::from_axis_angle(&Vector3::x_axis(), std::f32::consts::PI)))
+    }
+
+    fn begin_mesh(&mut self, value: &str) -> Result<(), String> {
+        let name = value.trim();
+        if self.current_mesh.is_some() {
+            return Err("Meshes cannot be nested".to_string());
+        }
+        if name.is_empty() || name.contains(' ') {
+            return Err("Invalid mesh name".to_string());
+        }
+        if self.meshes.contains_key(name) {
+            return Err(format!("Mesh '{}' is already defined", name));
+        }
+        self.current_mesh = Some((name.to_string(), Vec::new()));
+        Ok(())
+    }
+
+    fn end_mesh(&mut self) -> Result<(), String> {
+        let (name, triangles) = self
+            .current_mesh
+            .take()
+            .ok_or_else(|| "endmesh without matching mesh".to_string())?;
+        let mesh = Mesh::new(name.clone(), triangles)?;
+        self.meshes.insert(name, Arc::new(mesh));
+        Ok(())
+    }
+
+    fn check_not_in_mesh(&self, keyword: &str) -> Result<(), String> {
+        match &self.current_mesh {
+            Some((name, _)) => Err(format!(
+                "'{}' is not allowed inside mesh '{}' (only tri)",
+                keyword, name
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// `material NAME [diffuse r g b] [specular r g b] [shininess s] [ambient r g b]`,
+    /// unspecified properties keeping their defaults
+    fn parse_material_definition(&self, value: &str) -> Result<(String, Material), String> {
+        let params: Vec<&str> = value.split(' ').collect();
+        let name = params[0].to_string();
+        let mut material = Material::default();
+
+        let mut rest = &params[1..];
+        while !rest.is_empty() {
+            let arity = match rest[0] {
+                "shininess" | "anisotropy" | "roughness" => 1,
+                _ => 3,
+            };
+            if rest.len() < arity + 1 {
+                return Err(format!("Invalid material format for '{}'", name));
+            }
+            let values = rest[1..=arity].join(" ");
+            match rest[0] {
+                "diffuse" => {
+                    let diffuse = self.parse_color(&values)?;
+                    ParsedConfigState::check_reflectance("diffuse", diffuse)?;
+                    material.diffuse_color = diffuse;
+                }
+                "specular" => {
+                    material.specular_color = self.parse_color(&values)?;
+                    if material.specular_color.iter().any(|c| *c < 0.0) {
+                        return Err("Specular color components must be non-negative".to_string());
+                    }
+                }
+                "shininess" => {
+                    material.shininess = Self::parse_f32(&values)?;
+                    if material.shininess < 0.0 {
+                        return Err("Shininess must be non-negative".to_string());
+                    }
+                }
+                "ambient" => {
+                    material.ambient = Some(self.parse_ambient(&values)?);
+                }
+                "anisotropy" => {
+                    material.anisotropy = Self::parse_anisotropy(&values)?;
+                }
+                "roughness" => {
+                    material.roughness = Self::parse_roughness(&values)?;
+                }
+                other => return Err(format!("Unknown material property: {}", other)),
+            }
+            rest = &rest[arity + 1..];
+        }
+        if let Some(ambient) = material.ambient {
+            ParsedConfigState::check_diffuse_ambient_sum(material.diffuse_color, ambient)?;
+        }
+        Ok((name, material))
+    }
+
+    /// Loads the `material` definitions of a library file. The path is looked up next to
+    /// the scene file first, then relative to the working directory.
+    fn load_material_library(&mut self, path: &str) -> Result<(), String> {
+        let resolved = self
+            .scene_path
+            .as_deref()
+            .map(|scene_path| resolve_relative_to(scene_path, path))
+            .filter(|candidate| Path::new(candidate).exists())
+            .unwrap_or_else(|| path.to_string());
+        let file = File::open(&resolved)
+            .map_err(|e| format!("Failed to open material library '{}': {}", resolved, e))?;
+        self.sources.push(resolved.clone());
+
+        for line in io::BufReader::new(file).lines() {
+            let line = line.map_err(|e| e.to_string())?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(COMMENT_CHAR) {
+                continue;
+            }
+            match line.split_once(' ') {
+                Some(("material", definition)) => {
+                    let (name, material) = self.parse_material_definition(definition.trim())?;
+                    self.materials.insert(name, material);
+                }
+                _ => {
+                    return Err(format!(
+                        "Material library '{}' may only contain material definitions, found: {}",
+                        resolved, line
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// `instance NAME [translate x y z] [rotate rx ry rz] [scale sx sy sz]`
+    fn parse_instance(&self, value: &str) -> Result<Shape, String> {
+        let params: Vec<&str> = value.split(' ').collect();
+        let mesh = self
+            .meshes
+            .get(params[0])
+            .ok_or_else(|| format!("Unknown mesh: {}", params[0]))?;
+
+        Ok(Shape::Instance {
+            mesh: Arc::clone(mesh),
+            transform: Box::new(self.parse_transform_clauses(&params[1..])?),
             node_index: 0,
         })
     }
 
+    /// `[translate x y z] [rotate rx ry rz] [scale sx sy sz]` as given to `instance` and
+    /// `push_transform`, applied as scale, then rotation, then translation
+    fn parse_transform_clauses(&self, clauses: &[&str]) -> Result<Transform, String> {
+        let mut translation = Vector3::zeros();
+        let mut rotation = Vector3::zeros();
+        let mut scale = Vector3::repeat(1.0);
+        let mut rest = clauses;
+        while !rest.is_empty() {
+            if rest.len() < 4 {
+                return Err("Invalid transform format".to_string());
+            }
+            let v = self.parse_simple_vec3(&rest[1..4].join(" "))?;
+            match rest[0] {
+                "translate" => translation = v,
+                "rotate" => rotation = v,
+                "scale" => scale = v,
+                other => return Err(format!("Unknown transform: {}", other)),
+            }
+            rest = &rest[4..];
+        }
+        Transform::from_trs(translation, rotation, scale)
+    }
+
     fn parse_plane(&self, value: &str) -> Result<Shape, String> {
         let params: Vec<&str> = value.split(' ').collect();
         if params.len() != 6 {
             return Err("Invalid plane format".to_string());
         }
-        let point = Vector3::new(
-            params[0].parse::<f32>().map_err(|e| e.to_string())?,
-            params[1].parse::<f32>().map_err(|e| e.to_string())?,
-            params[2].parse::<f32>().map_err(|e| e.to_string())?,
-        );
-        let normal = Vector3::new(
-            params[3].parse::<f32>().map_err(|e| e.to_string())?,
-            params[4].parse::<f32>().map_err(|e| e.to_string())?,
-            params[5].parse::<f32>().map_err(|e| e.to_string())?,
-        )
-        .normalize();
+        let (point, normal) = Self::parse_point_normal(&params)?;
 
         Ok(Shape::Plane {
             point,
             normal,
-            diffuse_color: self.diffuse_color,
-            specular_color: self.specular_color,
-            shininess: self.shininess,
+            material: self.material,
+            node_index: 0,
+            aabb_half_size: PLANE_AABB_SIZE,
+            uv_scale: self.uv_scale,
+        })
+    }
+
+    /// `plane_bounded px py pz nx ny nz halfsize`
+    fn parse_plane_bounded(&self, value: &str) -> Result<Shape, String> {
+        let params: Vec<&str> = value.split(' ').collect();
+        if params.len() != 7 {
+            return Err("Invalid plane_bounded format".to_string());
+        }
+        let (point, normal) = Self::parse_point_normal(&params[0..6])?;
+        let half_size = Self::parse_f32(params[6])?;
+        if half_size <= 0.0 {
+            return Err("plane_bounded half size must be greater than zero".to_string());
+        }
+
+        Ok(Shape::BoundedPlane {
+            point,
+            normal,
+            half_size,
+            material: self.material,
             node_index: 0,
+            uv_scale: self.uv_scale,
         })
     }
+
+    /// A point followed by a normal, which is normalized
+    fn parse_point_normal(params: &[&str]) -> Result<(Vector3<f32>, Vector3<f32>), String> {
+        let point = Vector3::new(
+            Self::parse_f32(params[0])?,
+            Self::parse_f32(params[1])?,
+            Self::parse_f32(params[2])?,
+        );
+        let normal = Vector3::new(
+            Self::parse_f32(params[3])?,
+            Self::parse_f32(params[4])?,
+            Self::parse_f32(params[5])?,
+        )
+        .normalize();
+        Ok((point, normal))
+    }
 }
 // test
 
@@ -472,6 +1950,62 @@ mod tests {
         assert_eq!(height, 1080);
     }
 
+    #[test]
+    fn test_missing_size_warns_and_uses_defaults() {
+        let mut parsed_config = ParsedConfigState::new();
+        let config = parsed_config.load_config_str("sphere 0 0 0 1\n").unwrap();
+        assert!(!parsed_config.size_was_set());
+        assert_eq!((config.width, config.height), (800, 600));
+        assert_eq!(parsed_config.warnings(), ["No size given, using the default 800x600"]);
+
+        let config = parsed_config.load_config_str("size 64 48\n").unwrap();
+        assert!(parsed_config.size_was_set());
+        assert_eq!((config.width, config.height), (64, 48));
+        assert!(parsed_config.warnings().is_empty());
+    }
+
+    #[test]
+    fn test_specular_without_shininess_warns() {
+        let warnings = |scene: &str| {
+            let mut state = ParsedConfigState::new();
+            state.load_config_str(&format!("size 8 8\n{scene}")).unwrap();
+            state.warnings().to_vec()
+        };
+
+        let flat = warnings("specular 0.5 0.5 0.5\nsphere 0 0 0 1\nsphere 3 0 0 1\n");
+        assert_eq!(flat.len(), 1);
+        assert!(flat[0].starts_with("2 object(s) have a specular color but shininess 0"));
+        assert!(flat[0].contains("shininess such as 32"));
+        let in_mesh = warnings(
+            "maxverts 3\nvertex 0 0 0\nvertex 1 0 0\nvertex 0 1 0\nspecular 1 1 1\n\
+             mesh shard\ntri 0 1 2\nendmesh\ninstance shard translate 0 0 0\n",
+        );
+        assert_eq!(in_mesh.len(), 1);
+
+        assert!(warnings("specular 0.5 0.5 0.5\nshininess 32\nsphere 0 0 0 1\n").is_empty());
+        assert!(warnings("shininess 0\nsphere 0 0 0 1\n").is_empty());
+    }
+
+    #[test]
+    fn test_orbit_moves_the_camera_around_a_center() {
+        let mut config = ParsedConfigState::new()
+            .load_config_str("camera 0 0 5 0 0 0 0 1 0 45\n")
+            .unwrap();
+        config.orbit(Vector3::zeros(), 90.0);
+        assert!((config.camera.position() - Vector3::new(5.0, 0.0, 0.0)).norm() < 1e-5);
+        assert!(config.camera.look_at().norm() < 1e-5);
+        assert_eq!(config.camera.up(), Vector3::y());
+
+        // The look-at point swings with the camera when it is not the center
+        config.set_camera_look_at(Vector3::new(0.0, 0.0, 1.0));
+        config.orbit(Vector3::zeros(), 90.0);
+        assert!((config.camera.position() - Vector3::new(0.0, 0.0, -5.0)).norm() < 1e-5);
+        assert!((config.camera.look_at() - Vector3::new(1.0, 0.0, 0.0)).norm() < 1e-5);
+
+        config.set_camera_position(Vector3::new(1.0, 2.0, 3.0));
+        assert_eq!(config.camera.position(), Vector3::new(1.0, 2.0, 3.0));
+    }
+
     #[test]
     fn test_parse_camera() {
         let parsed_config = ParsedConfigState::new();
@@ -491,6 +2025,884 @@ mod tests {
         assert_eq!(ambient, Vector3::new(0.2, 0.3, 0.4));
     }
 
+    #[test]
+    fn test_instances_share_one_mesh() {
+        let mut scene = String::from(
+            "maxverts 3
+vertex 0 0 0
+vertex 1 0 0
+vertex 0 1 0
+mesh tri1
+tri 0 1 2
+endmesh
+",
+        );
+        for i in 0..100 {
+            scene.push_str(&format!("instance tri1 translate {} 0 0 rotate 0 0 {}\n", i * 2, i));
+        }
+        let config = ParsedConfigState::new().load_config_str(&scene).unwrap();
+
+        let objects = config.get_scene_objects();
+        assert_eq!(objects.len(), 100);
+        let Shape::Instance { mesh: first, .. } = &objects[0] else {
+            panic!("Expected an instance");
+        };
+        for object in objects {
+            let Shape::Instance { mesh, .. } = object else {
+                panic!("Expected an instance");
+            };
+            assert!(Arc::ptr_eq(mesh, first));
+        }
+        // One copy of the triangle data, one transform per instance
+        assert_eq!(first.triangles().len(), 1);
+        assert_eq!(Arc::strong_count(first), 100);
+        assert!(std::mem::size_of::<Shape>() <= 128);
+    }
+
+    #[test]
+    fn test_load_errors_by_kind() {
+        assert!(matches!(
+            ParsedConfigState::new().load_config_file("test_file/does_not_exist.test"),
+            Err(RayTracerError::Io { .. })
+        ));
+
+        let err = ParsedConfigState::new()
+            .load_config_str("size 10 10\n# comment\n\nsphere 0 0 zero 1\n")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err,
+            RayTracerError::Parse {
+                line: 4,
+                msg: "Invalid number: 'zero'".to_string()
+            }
+        );
+        assert_eq!(err.to_string(), "line 4: Invalid number: 'zero'");
+
+        assert!(matches!(
+            ParsedConfigState::new().load_config_str("mesh open\n"),
+            Err(RayTracerError::InvalidScene(_))
+        ));
+    }
+
+    #[test]
+    fn test_reflectance_and_emittance_ranges() {
+        let error = |scene: &str| match ParsedConfigState::new().load_config_str(scene) {
+            Err(RayTracerError::Parse { line, msg }) => (line, msg),
+            Err(other) => panic!("unexpected error {}", other),
+            Ok(_) => panic!("scene should not load"),
+        };
+
+        // Reflectance never exceeds 1, even with HDR lights
+        let (line, msg) = error("hdr on\nsphere 0 0 0 1\ndiffuse 1.2 0.5 0.5\n");
+        assert_eq!(line, 3);
+        assert_eq!(msg, "Reflectance 'diffuse' must be between 0.0 and 1.0, got 1.2 0.5 0.5");
+        assert!(error("checker 1 2 0 0 0 0\n").1.starts_with("Reflectance 'pattern'"));
+
+        // Emittance may exceed 1 only in HDR mode
+        let (line, msg) = error("size 4 4\npoint 0 5 0 4 4 4\n");
+        assert_eq!(line, 2);
+        assert_eq!(msg, "Emittance 'point' must be between 0.0 and 1.0 unless 'hdr on' is set, got 4 4 4");
+        let config = ParsedConfigState::new()
+            .load_config_str("hdr on\npoint 0 5 0 4 4 4\ndirectional 0 1 0 2 2 2\n")
+            .unwrap();
+        assert_eq!(config.get_lights()[0].color(), Vector3::repeat(4.0));
+        assert_eq!(config.get_lights()[1].color(), Vector3::repeat(2.0));
+        assert!(error("hdr on\npoint 0 5 0 -1 0 0\n").1.starts_with("Emittance 'point' must not be negative"));
+        assert!(error("hdr on\nhdr off\ndirectional 0 1 0 2 2 2\n").1.starts_with("Emittance 'directional'"));
+    }
+
+    #[test]
+    fn test_instance_unknown_mesh() {
+        let result = ParsedConfigState::new().load_config_str("instance missing\n");
+        assert_eq!(
+            result.err(),
+            Some(RayTracerError::Parse {
+                line: 1,
+                msg: "Unknown mesh: missing".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_material_ambient_is_per_shape() {
+        let config = ParsedConfigState::new()
+            .load_config_str(
+                "ambient 0.1 0.1 0.1
+diffuse 0.5 0.5 0.5
+material_ambient 0.4 0.4 0.4
+sphere 0 0 0 1
+material_ambient none
+sphere 3 0 0 1
+",
+            )
+            .unwrap();
+        let ambients: Vec<_> = config
+            .get_scene_objects()
+            .iter()
+            .map(|shape| match shape {
+                Shape::Sphere { material, .. } => material.ambient,
+                _ => panic!("Expected spheres"),
+            })
+            .collect();
+        assert_eq!(ambients, vec![Some(Vector3::new(0.4, 0.4, 0.4)), None]);
+    }
+
+    #[test]
+    fn test_parse_grade() {
+        let parsed_config = ParsedConfigState::new();
+        let grade = parsed_config
+            .parse_grade("0.1 0.1 0.1 1 1.2 1 2 2 2")
+            .unwrap();
+        assert_eq!(grade.lift, Vector3::repeat(0.1));
+        assert_eq!(grade.gamma, Vector3::new(1.0, 1.2, 1.0));
+        assert_eq!(grade.gain, Vector3::repeat(2.0));
+        assert!(parsed_config.parse_grade("0 0 0 0 1 1 1 1 1").is_err());
+    }
+
+    #[test]
+    fn test_parse_capsule() {
+        let config = ParsedConfigState::new()
+            .load_config_str("diffuse 0.5 0 0\ncapsule 0 0 0 0 2 0 0.5\n")
+            .unwrap();
+        match &config.get_scene_objects()[0] {
+            Shape::Capsule {
+                a,
+                b,
+                radius,
+                material,
+                ..
+            } => {
+                assert_eq!(*a, Vector3::zeros());
+                assert_eq!(*b, Vector3::new(0.0, 2.0, 0.0));
+                assert_eq!(*radius, 0.5);
+                assert_eq!(material.diffuse_color, Vector3::new(0.5, 0.0, 0.0));
+            }
+            _ => panic!("Expected a capsule"),
+        }
+        assert!(ParsedConfigState::new().load_config_str("capsule 0 0 0 0 2 0\n").is_err());
+        assert!(ParsedConfigState::new().load_config_str("capsule 0 0 0 0 2 0 0\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_plane_bounded() {
+        let config = ParsedConfigState::new()
+            .load_config_str("plane_bounded 0 -1 0 0 2 0 5\nsphere 0 0 0 1\n")
+            .unwrap();
+        match &config.get_scene_objects()[0] {
+            Shape::BoundedPlane {
+                point,
+                normal,
+                half_size,
+                ..
+            } => {
+                assert_eq!(*point, Vector3::new(0.0, -1.0, 0.0));
+                assert_eq!(*normal, Vector3::y());
+                assert_eq!(*half_size, 5.0);
+            }
+            _ => panic!("Expected a bounded plane"),
+        }
+        // Unlike infinite planes, the tile counts towards the scene bounds
+        assert_eq!(
+            config.bounding_box(),
+            Some((Vector3::new(-5.0, -1.0, -5.0), Vector3::new(5.0, 1.0, 5.0)))
+        );
+        assert!(ParsedConfigState::new().load_config_str("plane_bounded 0 0 0 0 1 0\n").is_err());
+        assert!(ParsedConfigState::new().load_config_str("plane_bounded 0 0 0 0 1 0 -1\n").is_err());
+    }
+
+    #[test]
+    fn test_names_tag_the_next_objects() {
+        let mut parser = ParsedConfigState::new();
+        let mut config = parser
+            .load_config_str(
+                "name ball\ndiffuse 1 0 0\nsphere 0 0 0 1\nsphere 3 0 0 1\n\
+                 maxverts 4\nvertex 0 0 0\nvertex 1 0 0\nvertex 0 1 0\nvertex 1 1 0\n\
+                 name strip\ntristrip 0 1 2 3\nname row\nsphere 0 5 0 1\ngrid_repeat 3 1 1 2\n\
+                 name dangling\n",
+            )
+            .unwrap();
+        let names: Vec<Option<&str>> = (0..config.get_scene_objects().len())
+            .map(|index| config.object_name(index))
+            .collect();
+        // A name skips non-object lines and covers every object of the line it lands on
+        assert_eq!(
+            names,
+            [Some("ball"), None, Some("strip"), Some("strip"), Some("row"), Some("row"), Some("row")]
+        );
+        assert!(parser.warnings().iter().any(|warning| warning.contains("'dangling'")));
+
+        // Selecting objects keeps the names of the survivors aligned
+        assert_eq!(config.select_objects(&[], &["strip".to_string()]), 2);
+        assert_eq!(config.object_name(0), Some("ball"));
+        assert_eq!(config.object_name(1), None);
+        assert_eq!(config.object_name(2), Some("row"));
+
+        assert!(ParsedConfigState::new().load_config_str("name two words\n").is_err());
+    }
+
+    #[test]
+    fn test_transform_stack_places_grouped_objects() {
+        let mut scene = String::from("maxverts 8\n");
+        for corner in 0..8 {
+            scene.push_str(&format!("vertex {} {} {}\n", corner & 1, (corner >> 1) & 1, corner >> 2));
+        }
+        scene.push_str("mesh box\n");
+        for face in ["0 2 3 1", "4 5 7 6", "0 1 5 4", "2 6 7 3", "0 4 6 2", "1 3 7 5"] {
+            let v: Vec<&str> = face.split(' ').collect();
+            scene.push_str(&format!("tri {} {} {}\ntri {} {} {}\n", v[0], v[1], v[2], v[0], v[2], v[3]));
+        }
+        scene.push_str(
+            "endmesh\npush_transform translate 5 0 0\ninstance box\n\
+             push_transform scale 2 2 2\nsphere 0 1 0 1\npop_transform\n\
+             tri 0 1 2\npop_transform\ninstance box\n",
+        );
+        let config = ParsedConfigState::new().load_config_str(&scene).unwrap();
+        let objects = config.get_scene_objects();
+        let close = |a: Vector3<f32>, b: Vector3<f32>| (a - b).norm() < 1e-5;
+        let box_origin = |index: usize| match &objects[index] {
+            Shape::Instance { transform, .. } => transform.point_to_world(Vector3::zeros()),
+            other => panic!("Expected an instance, got {:?}", other),
+        };
+
+        // The box inside the group is moved with it, the one after the pop is not
+        assert!(close(box_origin(0), Vector3::new(5.0, 0.0, 0.0)));
+        assert!(close(box_origin(3), Vector3::zeros()));
+        // Nested groups compose: scaled first, then translated by the outer group
+        match &objects[1] {
+            Shape::Sphere { center, radius, .. } => {
+                assert!(close(*center, Vector3::new(5.0, 2.0, 0.0)), "{:?}", center);
+                assert!((radius - 2.0).abs() < 1e-5);
+            }
+            other => panic!("Expected a sphere, got {:?}", other),
+        }
+        // The inner pop restores the outer group only
+        match &objects[2] {
+            Shape::Triangle { v0, v1, .. } => {
+                assert!(close(*v0, Vector3::new(5.0, 0.0, 0.0)));
+                assert!(close(*v1, Vector3::new(6.0, 0.0, 0.0)));
+            }
+            other => panic!("Expected a triangle, got {:?}", other),
+        }
+
+        assert!(ParsedConfigState::new().load_config_str("pop_transform\n").is_err());
+        assert!(ParsedConfigState::new().load_config_str("push_transform translate 1 0 0\n").is_err());
+        assert!(ParsedConfigState::new()
+            .load_config_str("push_transform scale 1 2 1\nsphere 0 0 0 1\npop_transform\n")
+            .is_err());
+    }
+
+    #[test]
+    fn test_scene_up_reorients_following_geometry() {
+        let config = ParsedConfigState::new()
+            .load_config_str(
+                "sphere 1 2 3 1\nscene_up 0 0 1\nsphere 1 2 3 1\nplane 0 0 -1 0 0 1\n\
+                 maxverts 3\nvertex 0 0 0\nvertex 1 0 0\nvertex 0 1 0\n\
+                 mesh unit\ntri 0 1 2\nendmesh\ninstance unit translate 0 0 5\n\
+                 scene_up 0 1 0\nsphere 1 2 3 1\nscene_up 0 -1 0\nsphere 1 2 3 1\n",
+            )
+            .unwrap();
+        let objects = config.get_scene_objects();
+        let center = |index: usize| match &objects[index] {
+            Shape::Sphere { center, .. } => *center,
+            other => panic!("Expected a sphere, got {:?}", other),
+        };
+        let close = |a: Vector3<f32>, b: Vector3<f32>| (a - b).norm() < 1e-5;
+
+        // Before `scene_up` and after resetting it, positions are kept as written
+        assert_eq!(center(0), Vector3::new(1.0, 2.0, 3.0));
+        assert_eq!(center(4), Vector3::new(1.0, 2.0, 3.0));
+        // Z-up (x, y, z) becomes Y-up (x, z, -y)
+        assert!(close(center(1), Vector3::new(1.0, 3.0, -2.0)), "{:?}", center(1));
+        match &objects[2] {
+            Shape::Plane { point, normal, .. } => {
+                assert!(close(*point, Vector3::new(0.0, -1.0, 0.0)));
+                assert!(close(*normal, Vector3::y()));
+            }
+            other => panic!("Expected a plane, got {:?}", other),
+        }
+        match &objects[3] {
+            Shape::Instance { transform, .. } => {
+                assert!(close(transform.point_to_world(Vector3::zeros()), Vector3::new(0.0, 5.0, 0.0)));
+            }
+            other => panic!("Expected an instance, got {:?}", other),
+        }
+        // Upside down: y flips, x stays
+        let flipped = center(5);
+        assert!((flipped.x - 1.0).abs() < 1e-5 && (flipped.y + 2.0).abs() < 1e-5, "{:?}", flipped);
+
+        assert!(ParsedConfigState::new().load_config_str("scene_up 0 0 0\n").is_err());
+    }
+
+    #[test]
+    fn test_grid_repeat_copies_last_object() {
+        let config = ParsedConfigState::new()
+            .load_config_str("sphere 1 0 0 0.5\ngrid_repeat 2 2 1 3\n")
+            .unwrap();
+        let centers: Vec<Vector3<f32>> = config
+            .get_scene_objects()
+            .iter()
+            .map(|object| match object {
+                Shape::Sphere { center, .. } => *center,
+                other => panic!("Expected a sphere, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            centers,
+            vec![
+                Vector3::new(1.0, 0.0, 0.0),
+                Vector3::new(4.0, 0.0, 0.0),
+                Vector3::new(1.0, 3.0, 0.0),
+                Vector3::new(4.0, 3.0, 0.0),
+            ]
+        );
+
+        for invalid in ["grid_repeat 2 2 1 3\n", "sphere 0 0 0 1\ngrid_repeat 0 2 1 3\n"] {
+            assert!(ParsedConfigState::new().load_config_str(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_parse_aa_pattern() {
+        let default = ParsedConfigState::new().load_config_str("size 1 1\n").unwrap();
+        assert_eq!(default.aa_pattern, AaPattern::Jitter);
+        for (name, pattern) in [
+            ("grid", AaPattern::Grid),
+            ("rgss", AaPattern::RotatedGrid),
+            ("poisson", AaPattern::Poisson),
+        ] {
+            let config = ParsedConfigState::new()
+                .load_config_str(&format!("aa_pattern {}\n", name))
+                .unwrap();
+            assert_eq!(config.aa_pattern, pattern);
+        }
+        assert!(ParsedConfigState::new().load_config_str("aa_pattern halton\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_aa_combine() {
+        let default = ParsedConfigState::new().load_config_str("size 1 1\n").unwrap();
+        assert_eq!(default.aa_combine, AaCombine::Mean);
+        let config = ParsedConfigState::new().load_config_str("aa_combine median\n").unwrap();
+        assert_eq!(config.aa_combine, AaCombine::Median);
+        let config = ParsedConfigState::new().load_config_str("aa_combine clamped\n").unwrap();
+        assert_eq!(config.aa_combine, AaCombine::Clamped);
+        assert!(ParsedConfigState::new().load_config_str("aa_combine max\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_clamp_mode() {
+        let default = ParsedConfigState::new().load_config_str("size 1 1\n").unwrap();
+        assert_eq!(default.clamp_mode, ClampMode::Clip);
+        let config = ParsedConfigState::new().load_config_str("clamp_mode preserve_hue\n").unwrap();
+        assert_eq!(config.clamp_mode, ClampMode::PreserveHue);
+        assert!(ParsedConfigState::new().load_config_str("clamp_mode scale\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_ambient_hemi() {
+        let config = ParsedConfigState::new()
+            .load_config_str("ambient_hemi 0.4 0.6 0.8 0.2 0.1 0\n")
+            .unwrap();
+        let hemi = config.ambient;
+        assert_eq!(hemi.sky, Vector3::new(0.4, 0.6, 0.8));
+        assert_eq!(hemi.ground, Vector3::new(0.2, 0.1, 0.0));
+        assert!(ParsedConfigState::new().load_config_str("ambient_hemi 1 1 1\n").is_err());
+        assert!(ParsedConfigState::new().load_config_str("ambient_hemi 2 0 0 0 0 0\n").is_err());
+    }
+
+    #[test]
+    fn test_named_materials_apply_to_following_shapes() {
+        let config = ParsedConfigState::new()
+            .load_config_str(
+                "material red diffuse 0.8 0 0 specular 0 0 0
+material mirror diffuse 0 0 0 specular 0.9 0.9 0.9 shininess 100
+usematerial red
+sphere -2 0 0 1
+usematerial mirror
+sphere 2 0 0 1
+",
+            )
+            .unwrap();
+        let materials: Vec<Material> = config
+            .get_scene_objects()
+            .iter()
+            .map(|shape| match shape {
+                Shape::Sphere { material, .. } => *material,
+                _ => panic!("Expected a sphere"),
+            })
+            .collect();
+
+        assert_eq!(materials[0].diffuse_color, Vector3::new(0.8, 0.0, 0.0));
+        assert_eq!(materials[0].specular_color, Vector3::zeros());
+        assert_eq!(materials[1].diffuse_color, Vector3::zeros());
+        assert_eq!(materials[1].specular_color, Vector3::repeat(0.9));
+        assert_eq!(materials[1].shininess, 100.0);
+    }
+
+    #[test]
+    fn test_anisotropy_keyword() {
+        let config = ParsedConfigState::new()
+            .load_config_str(
+                "anisotropy 0.5
+sphere 0 0 0 1
+material brushed anisotropy 0.8
+usematerial brushed
+sphere 2 0 0 1
+",
+            )
+            .unwrap();
+        let anisotropies: Vec<f32> = config
+            .get_scene_objects()
+            .iter()
+            .map(|shape| match shape {
+                Shape::Sphere { material, .. } => material.anisotropy,
+                _ => panic!("Expected a sphere"),
+            })
+            .collect();
+        assert_eq!(anisotropies, [0.5, 0.8]);
+
+        for value in ["1", "-0.1", "x"] {
+            assert!(ParsedConfigState::new().load_config_str(&format!("anisotropy {value}\n")).is_err());
+        }
+    }
+
+    #[test]
+    fn test_colorspace_byte_scales_colors() {
+        let config = ParsedConfigState::new()
+            .load_config_str(
+                "colorspace byte
+diffuse 255 128 0
+point 0 5 0 255 255 255
+sphere 0 0 0 1
+colorspace float
+diffuse 0.5 0.5 0.5
+sphere 2 0 0 1
+",
+            )
+            .unwrap();
+        let diffuse: Vec<Vector3<f32>> = config
+            .get_scene_objects()
+            .iter()
+            .map(|shape| match shape {
+                Shape::Sphere { material, .. } => material.diffuse_color,
+                _ => panic!("Expected a sphere"),
+            })
+            .collect();
+        assert_eq!(diffuse[0].x, 1.0);
+        assert!((diffuse[0].y - 0.502).abs() < 1e-3);
+        assert_eq!(diffuse[0].z, 0.0);
+        assert_eq!(diffuse[1], Vector3::repeat(0.5));
+        assert_eq!(config.get_lights()[0].color(), Vector3::repeat(1.0));
+
+        // Byte values are still range checked after scaling
+        assert!(ParsedConfigState::new().load_config_str("colorspace byte\ndiffuse 256 0 0\n").is_err());
+        assert!(ParsedConfigState::new().load_config_str("diffuse 255 128 0\n").is_err());
+        assert!(ParsedConfigState::new().load_config_str("colorspace srgb\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_roughness() {
+        let config = ParsedConfigState::new()
+            .load_config_str("roughness 0.25\nsphere 0 0 0 1\n")
+            .unwrap();
+        match &config.get_scene_objects()[0] {
+            Shape::Sphere { material, .. } => assert_eq!(material.roughness, 0.25),
+            _ => panic!("Expected a sphere"),
+        }
+        assert!(ParsedConfigState::new().load_config_str("roughness 1.5\n").is_err());
+        assert!(ParsedConfigState::new().load_config_str("material m roughness -1\n").is_err());
+    }
+
+    #[test]
+    fn test_scene_cache_reuses_parsed_config() {
+        let dir = std::env::temp_dir().join(format!("raytracer_scene_cache_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("palette.mtl"), "material red diffuse 0.8 0 0\n").unwrap();
+        let scene = dir.join("scene.test");
+        std::fs::write(&scene, "mtllib palette.mtl\nusematerial red\nsphere 0 0 0 1\nplane 0 -1 0 0 1 0\n").unwrap();
+        let scene = scene.to_str().unwrap();
+        let load = || {
+            let mut state = ParsedConfigState::new();
+            state.set_cache_dir(Some(dir.join("cache")));
+            let config = state.load_config_file(scene).unwrap();
+            (config, state)
+        };
+        let diffuse = |config: &Config| match &config.get_scene_objects()[0] {
+            Shape::Sphere { material, .. } => material.diffuse_color,
+            other => panic!("Expected a sphere, got {:?}", other),
+        };
+
+        let (parsed, state) = load();
+        assert!(!state.loaded_from_cache());
+        let (cached, state) = load();
+        assert!(state.loaded_from_cache());
+        assert_eq!(bincode::serialize(&cached).unwrap(), bincode::serialize(&parsed).unwrap());
+        assert_eq!(state.warnings(), ["No size given, using the default 800x600"]);
+        assert_eq!(cached.output_file, parsed.output_file);
+
+        // Editing the material library invalidates the entry
+        std::fs::write(dir.join("palette.mtl"), "material red diffuse 0.25 0 0\n").unwrap();
+        let (reparsed, state) = load();
+        assert!(!state.loaded_from_cache());
+        assert_eq!(diffuse(&reparsed), Vector3::new(0.25, 0.0, 0.0));
+        assert!(load().1.loaded_from_cache());
+
+        // Scenes with mesh instances are parsed every time
+        std::fs::write(
+            scene,
+            "maxverts 3\nvertex 0 0 0\nvertex 1 0 0\nvertex 0 1 0\nmesh m\ntri 0 1 2\nendmesh\ninstance m\n",
+        )
+        .unwrap();
+        let (_, state) = load();
+        assert!(!state.loaded_from_cache());
+        assert!(!state.warnings().iter().any(|warning| warning.contains("Scene cache")));
+        assert!(!load().1.loaded_from_cache());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_mtllib_named_material() {
+        let dir = std::env::temp_dir().join(format!("raytracer_mtllib_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("palette.mtl"),
+            "# shared palette\nmaterial red diffuse 0.8 0 0 specular 0.1 0.1 0.1 shininess 20\n",
+        )
+        .unwrap();
+        let scene = dir.join("scene.test");
+        std::fs::write(&scene, "mtllib palette.mtl\nusematerial red\nsphere 0 0 0 1\n").unwrap();
+
+        let config = ParsedConfigState::new().load_config_file(scene.to_str().unwrap()).unwrap();
+        match &config.get_scene_objects()[0] {
+            Shape::Sphere { material, .. } => {
+                assert_eq!(material.diffuse_color, Vector3::new(0.8, 0.0, 0.0));
+                assert_eq!(material.specular_color, Vector3::repeat(0.1));
+                assert_eq!(material.shininess, 20.0);
+            }
+            _ => panic!("Expected a sphere"),
+        }
+
+        std::fs::write(dir.join("geometry.mtl"), "sphere 0 0 0 1\n").unwrap();
+        std::fs::write(&scene, "mtllib geometry.mtl\n").unwrap();
+        assert!(ParsedConfigState::new().load_config_file(scene.to_str().unwrap()).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            ParsedConfigState::new().load_config_str("usematerial nope\n").err(),
+            Some(RayTracerError::Parse {
+                line: 1,
+                msg: "Unknown material: nope".to_string()
+            })
+        );
+    }
+
+    fn triangle_vertices(shape: &Shape) -> [Vector3<f32>; 3] {
+        match shape {
+            Shape::Triangle { v0, v1, v2, .. } => [*v0, *v1, *v2],
+            _ => panic!("Expected a triangle"),
+        }
+    }
+
+    const FIVE_VERTICES: &str = "maxverts 5
+vertex 0 0 0
+vertex 1 0 0
+vertex 0 1 0
+vertex 1 1 0
+vertex 0 2 0
+";
+
+    #[test]
+    fn test_tristrip_alternates_winding() {
+        let config = ParsedConfigState::new()
+            .load_config_str(&format!("{FIVE_VERTICES}tristrip 0 1 2 3 4\n"))
+            .unwrap();
+        let config_tris = ParsedConfigState::new()
+            .load_config_str(&format!("{FIVE_VERTICES}tri 0 1 2\ntri 2 1 3\ntri 2 3 4\n"))
+            .unwrap();
+
+        let strip = config.get_scene_objects();
+        assert_eq!(strip.len(), 3);
+        for (a, b) in strip.iter().zip(config_tris.get_scene_objects()) {
+            assert_eq!(triangle_vertices(a), triangle_vertices(b));
+        }
+        // Consistent winding: all faces point the same way
+        for shape in strip {
+            let [v0, v1, v2] = triangle_vertices(shape);
+            assert!((v1 - v0).cross(&(v2 - v0)).z > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_trifan_shares_first_vertex() {
+        let config = ParsedConfigState::new()
+            .load_config_str(&format!("{FIVE_VERTICES}trifan 3 1 0 2 4\n"))
+            .unwrap();
+        let fan = config.get_scene_objects();
+        assert_eq!(fan.len(), 3);
+
+        let vertices = [
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(1.0, 0.0, 0.0),
+            Vector3::new(0.0, 1.0, 0.0),
+            Vector3::new(1.0, 1.0, 0.0),
+            Vector3::new(0.0, 2.0, 0.0),
+        ];
+        let expected = [[3, 1, 0], [3, 0, 2], [3, 2, 4]];
+        for (shape, [i0, i1, i2]) in fan.iter().zip(expected) {
+            assert_eq!(triangle_vertices(shape), [vertices[i0], vertices[i1], vertices[i2]]);
+        }
+    }
+
+    #[test]
+    fn test_strip_and_fan_validate_indices() {
+        for line in ["tristrip 0 1", "tristrip 0 1 2 5", "trifan 0 1 x"] {
+            let result = ParsedConfigState::new().load_config_str(&format!("{FIVE_VERTICES}{line}\n"));
+            assert!(matches!(result, Err(RayTracerError::Parse { line: 7, .. })), "{line}");
+        }
+    }
+
+    #[test]
+    fn test_zero_area_triangles_are_rejected() {
+        // Vertices 0, 2 and 4 all lie on the x = 0 line
+        for line in ["tri 0 2 4", "tri 1 1 3", "tristrip 0 1 2 2", "trifan 0 2 4 1"] {
+            match ParsedConfigState::new().load_config_str(&format!("{FIVE_VERTICES}{line}\n")) {
+                Err(RayTracerError::Parse { line: 7, msg }) => assert!(msg.contains("zero area"), "{msg}"),
+                Err(other) => panic!("{line}: unexpected {:?}", other),
+                Ok(_) => panic!("{line} should be rejected"),
+            }
+        }
+
+        // Tiny but well-formed triangles are kept
+        let tiny = ParsedConfigState::new()
+            .load_config_str("maxverts 3\nvertex 0 0 0\nvertex 1e-4 0 0\nvertex 0 1e-4 0\ntri 0 1 2\n")
+            .unwrap();
+        assert_eq!(tiny.get_scene_objects().len(), 1);
+    }
+
+    #[test]
+    fn test_bounding_box_skips_planes() {
+        let config = ParsedConfigState::new()
+            .load_config_str(
+                "plane 0 -1 0 0 1 0\nsphere 1 2 3 1\nmaxverts 3\nvertex 0 0 0\nvertex 4 0 0\nvertex 0 0 -2\ntri 0 1 2\n",
+            )
+            .unwrap();
+        assert_eq!(
+            config.bounding_box(),
+            Some((Vector3::new(0.0, 0.0, -2.0), Vector3::new(4.0, 3.0, 4.0)))
+        );
+        let planes_only = ParsedConfigState::new().load_config_str("plane 0 0 0 0 1 0\n").unwrap();
+        assert_eq!(planes_only.bounding_box(), None);
+    }
+
+    #[test]
+    fn test_merge_lighting_rig_with_geometry() {
+        let mut rig = ParsedConfigState::new()
+            .load_config_str(
+                "camera 0 2 8 0 0 0 0 1 0 50\nambient 0.1 0.1 0.1\npoint 0 5 0 1 1 1\ndirectional 0 -1 -1 0.5 0.5 0.5\n",
+            )
+            .unwrap();
+        let geometry = ParsedConfigState::new()
+            .load_config_str("camera 9 9 9 0 0 0 0 1 0 30\nambient 0.3 0.3 0.3\nsphere 0 1 0 1\nplane 0 0 0 0 1 0\n")
+            .unwrap();
+
+        rig.merge(geometry);
+
+        assert_eq!(rig.get_scene_objects().len(), 2);
+        assert_eq!(rig.get_lights().len(), 2);
+        assert_eq!(rig.camera.position, Vector3::new(0.0, 2.0, 8.0));
+        assert_eq!(rig.camera.fov, 50.0);
+        assert_eq!(rig.ambient, AmbientLight::uniform(Vector3::repeat(0.1)));
+    }
+
+    #[test]
+    fn test_clip_distances() {
+        let config = ParsedConfigState::new().load_config_str("size 10 10\n").unwrap();
+        assert_eq!((config.clip_near, config.clip_far), (0.0, f32::INFINITY));
+
+        let config = ParsedConfigState::new()
+            .load_config_str("clip_far 50\nclip_near 0.5\n")
+            .unwrap();
+        assert_eq!((config.clip_near, config.clip_far), (0.5, 50.0));
+
+        assert!(ParsedConfigState::new().load_config_str("clip_near -1\n").is_err());
+        assert!(ParsedConfigState::new()
+            .load_config_str("clip_near 10\nclip_far 5\n")
+            .is_err());
+    }
+
+    #[test]
+    fn test_ibl_requires_envmap() {
+        assert_eq!(
+            ParsedConfigState::new().load_config_str("ibl 16\n").err(),
+            Some(RayTracerError::InvalidScene("ibl requires an envmap".to_string()))
+        );
+        assert!(ParsedConfigState::new().load_config_str("ibl 0\n").is_err());
+        assert!(ParsedConfigState::new()
+            .load_config_str("envmap does_not_exist.hdr\n")
+            .is_err());
+    }
+
+    #[test]
+    fn test_fov_axis() {
+        let config = ParsedConfigState::new()
+            .load_config_str("fov_axis diagonal\ncamera 0 0 0 0 0 -1 0 1 0 90\n")
+            .unwrap();
+        assert_eq!(config.camera.fov_axis, FovAxis::Diagonal);
+        let (half_width, half_height) = config.camera.view_half_extents(4.0 / 3.0);
+        assert!((half_width - 0.8).abs() < 1e-6);
+        assert!((half_height - 0.6).abs() < 1e-6);
+
+        let config = ParsedConfigState::new().load_config_str("size 10 10\n").unwrap();
+        assert_eq!(config.camera.fov_axis, FovAxis::Vertical);
+        assert!(ParsedConfigState::new().load_config_str("fov_axis sideways\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_f32_forms() {
+        for (text, expected) in [("+1.0", 1.0), ("-0.5", -0.5), ("1e2", 100.0), (".25", 0.25), ("1.", 1.0)] {
+            assert_eq!(ParsedConfigState::parse_f32(text), Ok(expected), "{text}");
+        }
+        for text in ["", "abc", "inf", "NaN", "1..0"] {
+            assert_eq!(
+                ParsedConfigState::parse_f32(text),
+                Err(format!("Invalid number: '{}'", text))
+            );
+        }
+    }
+
+    #[test]
+    fn test_numeric_forms_in_sphere_camera_ambient() {
+        let parsed_config = ParsedConfigState::new();
+
+        let Shape::Sphere { center, radius, .. } =
+            parsed_config.parse_sphere("+1.0 -0.5 1e2 .25").unwrap()
+        else {
+            panic!("Expected a sphere");
+        };
+        assert_eq!(center, Vector3::new(1.0, -0.5, 100.0));
+        assert_eq!(radius, 0.25);
+
+        let camera = parsed_config
+            .parse_camera("+1.0 -0.5 1e2 .25 1. 0 0 +1 0 6e1")
+            .unwrap();
+        assert_eq!(camera.position, Vector3::new(1.0, -0.5, 100.0));
+        assert_eq!(camera.look_at, Vector3::new(0.25, 1.0, 0.0));
+        assert_eq!(camera.fov, 60.0);
+
+        let ambient = parsed_config.parse_ambient("+0.5 .25 1.").unwrap();
+        assert_eq!(ambient, Vector3::new(0.5, 0.25, 1.0));
+        let ambient = parsed_config.parse_ambient("1e-1 -0.0 0").unwrap();
+        assert_eq!(ambient, Vector3::new(0.1, 0.0, 0.0));
+    }
+
+    #[test]
+    fn test_sphere_bands_alternate_by_latitude() {
+        let config = ParsedConfigState::new()
+            .load_config_str("bands 3 1 0 0 0 0 1\nsphere 0 0 0 1\n")
+            .unwrap();
+        let sphere = &config.get_scene_objects()[0];
+        let hit_color = |origin: Vector3<f32>, direction: Vector3<f32>| {
+            sphere
+                .intersect(&crate::raytracer::config::Ray::new(origin, direction))
+                .expect("Ray should hit the sphere")
+                .material
+                .diffuse_color
+        };
+
+        let north = hit_color(Vector3::new(0.0, 5.0, 0.0), -Vector3::y());
+        let equator = hit_color(Vector3::new(0.0, 0.0, 5.0), -Vector3::z());
+        let south = hit_color(Vector3::new(0.0, -5.0, 0.0), Vector3::y());
+
+        assert_eq!(north, Vector3::new(1.0, 0.0, 0.0));
+        assert_eq!(equator, Vector3::new(0.0, 0.0, 1.0));
+        assert_eq!(south, Vector3::new(1.0, 0.0, 0.0));
+        assert!(ParsedConfigState::new().load_config_str("bands 0 1 0 0 0 0 1\n").is_err());
+        assert!(ParsedConfigState::new().load_config_str("bands 70000 1 0 0 0 0 1\n").is_err());
+    }
+
+    #[test]
+    fn test_plane_checker_cells_follow_uv_scale() {
+        let config = ParsedConfigState::new()
+            .load_config_str("uv_scale 0.5\nchecker 1 1 1 0 0 0\nplane 0 0 0 0 1 0\n")
+            .unwrap();
+        let plane = &config.get_scene_objects()[0];
+        let color_below = |x: f32, z: f32| {
+            plane
+                .intersect(&crate::raytracer::config::Ray::new(Vector3::new(x, 5.0, z), -Vector3::y()))
+                .expect("Ray should hit the plane")
+                .material
+                .diffuse_color
+        };
+
+        // Cells are 1 / uv_scale = 2 world units wide along both in-plane axes
+        let origin_cell = color_below(0.5, 0.5);
+        assert_eq!(origin_cell, Vector3::repeat(1.0));
+        assert_eq!(color_below(1.5, 1.5), origin_cell);
+        assert_eq!(color_below(0.5, 2.5), Vector3::zeros());
+        assert_eq!(color_below(2.5, 0.5), Vector3::zeros());
+        assert_eq!(color_below(2.5, 2.5), origin_cell);
+        assert_eq!(color_below(-0.5, 0.5), Vector3::zeros());
+
+        assert!(ParsedConfigState::new().load_config_str("uv_scale 0\n").is_err());
+        assert!(ParsedConfigState::new().load_config_str("checker 1 1 1\n").is_err());
+    }
+
+    #[test]
+    fn test_version_directive_gates_newer_keywords() {
+        let scene = "diffuse 1 0 0\nreset_material\nsphere 0 0 0 1\n";
+
+        let mut parsed = ParsedConfigState::new();
+        let config = parsed.load_config_str(&format!("# v2 scene\nversion 2\n{}", scene)).unwrap();
+        assert_eq!(parsed.format_version(), 2);
+        match &config.get_scene_objects()[0] {
+            Shape::Sphere { material, .. } => assert_eq!(material.diffuse_color, DEFAULT_DIFFUSE_COLOR),
+            other => panic!("Expected a sphere, got {:?}", other),
+        }
+
+        // Without a version line the scene is version 1, which predates reset_material
+        let mut parsed = ParsedConfigState::new();
+        let err = match parsed.load_config_str(scene) {
+            Err(e) => e.to_string(),
+            Ok(_) => panic!("reset_material should need version 2"),
+        };
+        assert!(err.contains("requires 'version 2'"), "{}", err);
+        assert_eq!(parsed.format_version(), 1);
+
+        // Version 1 ignores stray words, version 2 reports them
+        assert!(ParsedConfigState::new().load_config_str("stray\n").is_ok());
+        assert!(ParsedConfigState::new().load_config_str("version 2\nstray\n").is_err());
+
+        assert!(ParsedConfigState::new().load_config_str("version 3\n").is_err());
+        assert!(ParsedConfigState::new().load_config_str("size 4 4\nversion 2\n").is_err());
+    }
+
+    #[test]
+    fn test_output_resolved_relative_to_scene_file() {
+        let dir = std::env::temp_dir().join("raytracer_output_relative").join("subdir");
+        std::fs::create_dir_all(&dir).unwrap();
+        let scene = dir.join("scene.test");
+        std::fs::write(&scene, "output out.png\n").unwrap();
+
+        let config = ParsedConfigState::new()
+            .load_config_file(scene.to_str().unwrap())
+            .unwrap();
+
+        assert_eq!(Path::new(&config.output_file), dir.join("out.png"));
+    }
+
+    #[test]
+    fn test_output_resolution_keeps_absolute_and_bare_paths() {
+        let absolute = std::env::temp_dir().join("abs.png");
+        let absolute = absolute.to_str().unwrap();
+        assert_eq!(resolve_relative_to("subdir/scene.test", absolute), absolute);
+        assert_eq!(resolve_relative_to("scene.test", "out.png"), "out.png");
+        assert_eq!(
+            Path::new(&resolve_relative_to("subdir/scene.test", "out.png")),
+            Path::new("subdir/out.png")
+        );
+    }
+
     #[test]
     fn test_parse_output() {
         let parsed_config = ParsedConfigState::new();
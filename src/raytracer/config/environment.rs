@@ -0,0 +1,49 @@
+use nalgebra::Vector3;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+
+/// Equirectangular (latitude/longitude) environment surrounding the scene.
+///
+/// The top row of the image looks towards +Y, the bottom row towards -Y, and the
+/// horizontal center looks towards -Z. Texels are linear radiance, so HDR images
+/// (`.hdr`, `.exr`) can hold values above 1.
+#[derive(Serialize, Deserialize)]
+pub struct EnvironmentMap {
+    width: u32,
+    height: u32,
+    texels: Vec<Vector3<f32>>,
+}
+
+impl EnvironmentMap {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let img = image::open(path)
+            .map_err(|e| format!("Failed to load environment map '{}': {}", path, e))?
+            .into_rgb32f();
+        let (width, height) = img.dimensions();
+        let texels = img
+            .pixels()
+            .map(|p| Vector3::new(p[0], p[1], p[2]))
+            .collect();
+        EnvironmentMap::new(width, height, texels)
+    }
+
+    pub fn new(width: u32, height: u32, texels: Vec<Vector3<f32>>) -> Result<Self, String> {
+        if width == 0 || height == 0 || texels.len() != (width * height) as usize {
+            return Err("Environment map size does not match its texel count".to_string());
+        }
+        Ok(EnvironmentMap {
+            width,
+            height,
+            texels,
+        })
+    }
+
+    /// Radiance arriving from unit `direction`, nearest texel.
+    pub fn radiance(&self, direction: &Vector3<f32>) -> Vector3<f32> {
+        let u = 0.5 + direction.x.atan2(-direction.z) / (2.0 * PI);
+        let v = direction.y.clamp(-1.0, 1.0).acos() / PI;
+        let x = ((u * self.width as f32) as u32).min(self.width - 1);
+        let y = ((v * self.height as f32) as u32).min(self.height - 1);
+        self.texels[(y * self.width + x) as usize]
+    }
+}
@@ -0,0 +1,125 @@
+use nalgebra::Vector3;
+use serde::{Deserialize, Serialize};
+
+/// Lift/gamma/gain color grading applied per channel to the final linear color.
+///
+/// `out = gain * (x + lift * (1 - x)) ^ (1 / gamma)`: lift raises the shadows while
+/// leaving white at 1, gamma bends the midtones and gain scales the highlights.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ColorGrade {
+    pub lift: Vector3<f32>,
+    pub gamma: Vector3<f32>,
+    pub gain: Vector3<f32>,
+}
+
+impl ColorGrade {
+    pub fn identity() -> Self {
+        ColorGrade {
+            lift: Vector3::zeros(),
+            gamma: Vector3::repeat(1.0),
+            gain: Vector3::repeat(1.0),
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == ColorGrade::identity()
+    }
+
+    pub fn apply(&self, color: Vector3<f32>) -> Vector3<f32> {
+        if self.is_identity() {
+            return color;
+        }
+        Vector3::from_fn(|i, _| {
+            let lifted = (color[i] + self.lift[i] * (1.0 - color[i])).max(0.0);
+            self.gain[i] * lifted.powf(1.0 / self.gamma[i])
+        })
+    }
+}
+
+impl Default for ColorGrade {
+    fn default() -> Self {
+        ColorGrade::identity()
+    }
+}
+
+/// How over-bright colors are brought into `[0, 1]` before quantization.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum ClampMode {
+    /// Clamp each channel independently, e.g. (2, 1, 0) becomes (1, 1, 0)
+    #[default]
+    Clip,
+    /// Divide all channels by the largest one when it exceeds 1, keeping the hue:
+    /// (2, 1, 0) becomes (1, 0.5, 0)
+    PreserveHue,
+}
+
+impl ClampMode {
+    /// Channels may still fall outside `[0, 1]` (negative values, or any value in
+    /// `Clip` mode); the final per-channel clamp happens when the pixel is packed.
+    pub fn apply(&self, color: Vector3<f32>) -> Vector3<f32> {
+        match self {
+            ClampMode::Clip => color,
+            ClampMode::PreserveHue => {
+                let max = color.max();
+                if max > 1.0 {
+                    color / max
+                } else {
+                    color
+                }
+            }
+        }
+    }
+}
+
+/// Rec. 709 relative luminance of a linear RGB color.
+pub(crate) fn luminance(color: &Vector3<f32>) -> f32 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity_grade_is_noop() {
+        let color = Vector3::new(0.25, 1.5, -0.1);
+        assert_eq!(ColorGrade::identity().apply(color), color);
+    }
+
+    #[test]
+    fn test_lift_raises_darks_without_clipping_brights() {
+        let grade = ColorGrade {
+            lift: Vector3::repeat(0.2),
+            ..ColorGrade::identity()
+        };
+
+        let dark = grade.apply(Vector3::repeat(0.1));
+        let bright = grade.apply(Vector3::repeat(1.0));
+
+        assert!((dark.x - 0.28).abs() < 1e-6);
+        assert_eq!(bright, Vector3::repeat(1.0));
+    }
+
+    #[test]
+    fn test_preserve_hue_scales_by_max_channel() {
+        let orange = Vector3::new(2.0, 1.0, 0.0);
+        assert_eq!(ClampMode::PreserveHue.apply(orange), Vector3::new(1.0, 0.5, 0.0));
+        assert_eq!(ClampMode::Clip.apply(orange), orange);
+
+        let in_range = Vector3::new(0.9, 0.2, 0.1);
+        assert_eq!(ClampMode::PreserveHue.apply(in_range), in_range);
+    }
+
+    #[test]
+    fn test_gamma_and_gain() {
+        let grade = ColorGrade {
+            lift: Vector3::zeros(),
+            gamma: Vector3::new(2.0, 1.0, 1.0),
+            gain: Vector3::new(1.0, 1.0, 0.5),
+        };
+        let graded = grade.apply(Vector3::new(0.25, 0.25, 0.8));
+        assert!((graded.x - 0.5).abs() < 1e-6);
+        assert!((graded.y - 0.25).abs() < 1e-6);
+        assert!((graded.z - 0.4).abs() < 1e-6);
+    }
+}
@@ -1,7 +1,14 @@
 use nalgebra::Vector3;
+use serde::{Deserialize, Serialize};
 
+#[derive(Serialize, Deserialize)]
 pub enum Light {
-    Point { position: Vector3<f32>, color: Vector3<f32> },
+    /// `cutoff` is the distance beyond which the light reaches nothing, `None` for no limit
+    Point {
+        position: Vector3<f32>,
+        color: Vector3<f32>,
+        cutoff: Option<f32>,
+    },
     Directional { direction: Vector3<f32>, color: Vector3<f32> },
 }
 
@@ -11,4 +18,77 @@ impl Light {
             Light::Point { color, .. } | Light::Directional { color, .. } => *color,
         }
     }
+
+    /// Unit vector from `point` towards the light.
+    pub fn direction_from(&self, point: Vector3<f32>) -> Vector3<f32> {
+        match self {
+            Light::Point { position, .. } => (*position - point).normalize(),
+            Light::Directional { direction, .. } => *direction,
+        }
+    }
+
+    /// Whether the light can contribute at `point`: false beyond a point light's cutoff.
+    pub fn reaches(&self, point: Vector3<f32>) -> bool {
+        match self {
+            Light::Point {
+                position,
+                cutoff: Some(cutoff),
+                ..
+            } => (*position - point).norm() <= *cutoff,
+            _ => true,
+        }
+    }
+}
+
+/// Ambient fill light: surfaces facing +Y receive `sky`, surfaces facing -Y receive
+/// `ground`, with a linear blend on the normal's vertical component in between. The
+/// scene's uniform `ambient` is the degenerate case where both colors are equal.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AmbientLight {
+    pub sky: Vector3<f32>,
+    pub ground: Vector3<f32>,
+}
+
+impl AmbientLight {
+    /// The same `color` whatever the surface orientation
+    pub fn uniform(color: Vector3<f32>) -> Self {
+        AmbientLight { sky: color, ground: color }
+    }
+
+    pub fn is_uniform(&self) -> bool {
+        self.sky == self.ground
+    }
+
+    /// Per-channel maximum over every orientation
+    pub fn brightest(&self) -> Vector3<f32> {
+        self.sky.sup(&self.ground)
+    }
+
+    pub fn color_at(&self, normal: &Vector3<f32>) -> Vector3<f32> {
+        let t = (normal.y.clamp(-1.0, 1.0) + 1.0) * 0.5;
+        self.ground + (self.sky - self.ground) * t
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ambient_light_follows_normal() {
+        let ambient = AmbientLight {
+            sky: Vector3::new(0.4, 0.6, 0.8),
+            ground: Vector3::new(0.2, 0.1, 0.0),
+        };
+        assert_eq!(ambient.color_at(&Vector3::y()), ambient.sky);
+        assert_eq!(ambient.color_at(&-Vector3::y()), ambient.ground);
+        assert!((ambient.color_at(&Vector3::x()) - Vector3::new(0.3, 0.35, 0.4)).norm() < 1e-6);
+        assert_eq!(ambient.brightest(), ambient.sky);
+
+        let uniform = AmbientLight::uniform(Vector3::repeat(0.2));
+        assert!(uniform.is_uniform() && !ambient.is_uniform());
+        for normal in [Vector3::y(), -Vector3::y(), Vector3::z()] {
+            assert_eq!(uniform.color_at(&normal), Vector3::repeat(0.2));
+        }
+    }
 }
@@ -0,0 +1,202 @@
+use nalgebra::Vector3;
+use serde::{Deserialize, Serialize};
+
+/// Color lookup table read from a `.cube` file (the Adobe/Resolve format), applied to
+/// display colors after grading and `clamp_mode` to match a given look.
+///
+/// A 1D table is a tone curve applied to each channel independently; a 3D table maps
+/// whole colors, interpolated trilinearly between its lattice points. Inputs are
+/// clamped to the table's domain (`[0, 1]` unless the file gives `DOMAIN_MIN`/`DOMAIN_MAX`).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Lut {
+    kind: LutKind,
+    /// Entries per axis
+    size: usize,
+    domain_min: Vector3<f32>,
+    domain_max: Vector3<f32>,
+    /// `size` entries for a 1D table; `size^3` for a 3D table, red varying fastest
+    table: Vec<Vector3<f32>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum LutKind {
+    Curve,
+    Cube,
+}
+
+impl LutKind {
+    /// Largest size the `.cube` format allows for this kind of table
+    fn max_size(self) -> usize {
+        match self {
+            LutKind::Curve => 65536,
+            LutKind::Cube => 256,
+        }
+    }
+}
+
+impl Lut {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| format!("Failed to load LUT '{}': {}", path, e))?;
+        Lut::parse(&content).map_err(|e| format!("Invalid LUT '{}': {}", path, e))
+    }
+
+    /// Parses the text of a `.cube` file
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let mut kind = None;
+        let mut domain_min = Vector3::zeros();
+        let mut domain_max = Vector3::repeat(1.0);
+        let mut table = Vec::new();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let keyword = words.next().unwrap_or("");
+            let rest: Vec<&str> = words.collect();
+            match keyword {
+                "TITLE" => {}
+                "LUT_1D_SIZE" | "LUT_3D_SIZE" => {
+                    if kind.is_some() {
+                        return Err("more than one LUT size line".to_string());
+                    }
+                    let size = match rest.as_slice() {
+                        [size] => size.parse::<usize>().map_err(|e| e.to_string())?,
+                        _ => return Err(format!("invalid {} line", keyword)),
+                    };
+                    if size < 2 {
+                        return Err("LUT size must be at least 2".to_string());
+                    }
+                    let lut_kind = if keyword == "LUT_1D_SIZE" { LutKind::Curve } else { LutKind::Cube };
+                    let max_size = lut_kind.max_size();
+                    if size > max_size {
+                        return Err(format!("{} must be at most {}", keyword, max_size));
+                    }
+                    kind = Some((lut_kind, size));
+                }
+                "DOMAIN_MIN" => domain_min = parse_triplet(&rest)?,
+                "DOMAIN_MAX" => domain_max = parse_triplet(&rest)?,
+                _ => {
+                    let mut values = vec![keyword];
+                    values.extend(rest);
+                    table.push(parse_triplet(&values)?);
+                }
+            }
+        }
+
+        let Some((kind, size)) = kind else {
+            return Err("missing LUT_1D_SIZE or LUT_3D_SIZE".to_string());
+        };
+        let expected = match kind {
+            LutKind::Curve => Some(size),
+            LutKind::Cube => size.checked_pow(3),
+        }
+        .ok_or_else(|| format!("LUT size {} is too large", size))?;
+        if table.len() != expected {
+            return Err(format!("expected {} entries, found {}", expected, table.len()));
+        }
+        if (0..3).any(|i| domain_max[i] <= domain_min[i]) {
+            return Err("DOMAIN_MAX must be greater than DOMAIN_MIN".to_string());
+        }
+        Ok(Lut {
+            kind,
+            size,
+            domain_min,
+            domain_max,
+            table,
+        })
+    }
+
+    pub fn apply(&self, color: Vector3<f32>) -> Vector3<f32> {
+        // Position of the color in table coordinates, 0..=size-1 on each axis
+        let last = (self.size - 1) as f32;
+        let position = Vector3::from_fn(|i, _| {
+            let t = (color[i] - self.domain_min[i]) / (self.domain_max[i] - self.domain_min[i]);
+            t.clamp(0.0, 1.0) * last
+        });
+        match self.kind {
+            LutKind::Curve => Vector3::from_fn(|i, _| {
+                let (low, high, t) = split(position[i], self.size);
+                self.table[low][i] + (self.table[high][i] - self.table[low][i]) * t
+            }),
+            LutKind::Cube => {
+                let (r0, r1, tr) = split(position.x, self.size);
+                let (g0, g1, tg) = split(position.y, self.size);
+                let (b0, b1, tb) = split(position.z, self.size);
+                let at = |r: usize, g: usize, b: usize| self.table[r + self.size * (g + self.size * b)];
+                let lerp = |a: Vector3<f32>, b: Vector3<f32>, t: f32| a + (b - a) * t;
+                let g0_plane = lerp(lerp(at(r0, g0, b0), at(r1, g0, b0), tr), lerp(at(r0, g1, b0), at(r1, g1, b0), tr), tg);
+                let g1_plane = lerp(lerp(at(r0, g0, b1), at(r1, g0, b1), tr), lerp(at(r0, g1, b1), at(r1, g1, b1), tr), tg);
+                lerp(g0_plane, g1_plane, tb)
+            }
+        }
+    }
+}
+
+/// Lattice points around table coordinate `position` and the blend between them
+fn split(position: f32, size: usize) -> (usize, usize, f32) {
+    let low = (position.floor() as usize).min(size - 2);
+    (low, low + 1, position - low as f32)
+}
+
+fn parse_triplet(values: &[&str]) -> Result<Vector3<f32>, String> {
+    match values {
+        [r, g, b] => {
+            let parse = |value: &str| value.parse::<f32>().map_err(|_| format!("invalid LUT value '{}'", value));
+            Ok(Vector3::new(parse(r)?, parse(g)?, parse(b)?))
+        }
+        _ => Err(format!("expected 3 values, found '{}'", values.join(" "))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `.cube` text of a 3D table of the given size mapping every color through `f`
+    fn cube_text(size: usize, f: impl Fn(Vector3<f32>) -> Vector3<f32>) -> String {
+        let mut text = format!("TITLE \"test\"\nLUT_3D_SIZE {}\n", size);
+        for b in 0..size {
+            for g in 0..size {
+                for r in 0..size {
+                    let color = Vector3::new(r as f32, g as f32, b as f32) / (size - 1) as f32;
+                    let mapped = f(color);
+                    text.push_str(&format!("{} {} {}\n", mapped.x, mapped.y, mapped.z));
+                }
+            }
+        }
+        text
+    }
+
+    #[test]
+    fn test_identity_and_inverting_cubes() {
+        let identity = Lut::parse(&cube_text(5, |color| color)).unwrap();
+        let invert = Lut::parse(&cube_text(2, |color| Vector3::repeat(1.0) - color)).unwrap();
+
+        for color in [Vector3::new(0.1, 0.5, 0.9), Vector3::new(0.33, 0.0, 1.0)] {
+            assert!((identity.apply(color) - color).norm() < 1e-6);
+            assert!((invert.apply(color) - (Vector3::repeat(1.0) - color)).norm() < 1e-6);
+        }
+        // Outside the domain colors are clamped first
+        assert_eq!(invert.apply(Vector3::new(2.0, -1.0, 0.0)), Vector3::new(0.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn test_curve_maps_channels_independently() {
+        let lut = Lut::parse("# squares\nLUT_1D_SIZE 3\nDOMAIN_MAX 2 2 2\n0 0 0\n0.25 0.5 1\n1 1 1\n").unwrap();
+        let mapped = lut.apply(Vector3::new(0.5, 1.0, 1.5));
+        assert!((mapped - Vector3::new(0.125, 0.5, 1.0)).norm() < 1e-6);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Lut::parse("0 0 0\n1 1 1\n").is_err());
+        assert!(Lut::parse("LUT_3D_SIZE 2\n0 0 0\n").is_err());
+        assert!(Lut::parse("LUT_1D_SIZE 2\n0 0\n1 1 1\n").is_err());
+        assert!(Lut::parse("LUT_1D_SIZE 2\nDOMAIN_MIN 1 1 1\nDOMAIN_MAX 0 1 1\n0 0 0\n1 1 1\n").is_err());
+        // Sizes whose table could not be allocated, or whose cube overflows usize
+        assert!(Lut::parse("LUT_3D_SIZE 257\n").unwrap_err().contains("at most 256"));
+        assert!(Lut::parse("LUT_3D_SIZE 4000000000000\n").is_err());
+        assert!(Lut::parse("LUT_1D_SIZE 65537\n").is_err());
+    }
+}
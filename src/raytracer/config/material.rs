@@ -0,0 +1,127 @@
+use nalgebra::{Vector2, Vector3};
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU16;
+
+/// Surface properties shared by every shape type.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Material {
+    pub diffuse_color: Vector3<f32>,
+    pub specular_color: Vector3<f32>,
+    pub shininess: f32,
+    /// Stretches the specular highlight along the surface tangent, from 0 (isotropic)
+    /// towards 1 (a thin streak), as on brushed metal
+    pub anisotropy: f32,
+    /// Blurs reflections from 0 (perfect mirror) to 1 (reflected rays spread over a
+    /// 90 degree cone)
+    pub roughness: f32,
+    /// Ambient term used instead of the scene-wide `ambient` when set
+    pub ambient: Option<Vector3<f32>>,
+    /// Procedural pattern replacing the diffuse color
+    pub pattern: Option<Pattern>,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Material {
+            diffuse_color: Vector3::zeros(),
+            specular_color: Vector3::zeros(),
+            shininess: 0.0,
+            anisotropy: 0.0,
+            roughness: 0.0,
+            ambient: None,
+            pattern: None,
+        }
+    }
+}
+
+/// How much light passes through a transparent sphere and how it bends on the way.
+/// Only spheres carry one, as a refracted ray needs a closed volume to leave through.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Transmission {
+    /// Fraction of the surface color replaced by light refracted through it, from 0
+    /// (opaque) to 1 (clear). Transparent spheres still cast full shadows.
+    pub transparency: f32,
+    /// Index of refraction bending rays entering the sphere (1 leaves them straight)
+    pub ior: f32,
+}
+
+impl Transmission {
+    pub const OPAQUE: Transmission = Transmission {
+        transparency: 0.0,
+        ior: 1.0,
+    };
+}
+
+/// Two-color procedural patterns replacing a material's diffuse color.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Pattern {
+    /// Latitude banding for spheres: `count` horizontal stripes from the south pole
+    /// (-Y) to the north pole (+Y), alternating between `color1` and `color2`.
+    /// `count` is non-zero by construction and capped at 65535, which keeps
+    /// `Option<Pattern>` (and so every shape) small.
+    Bands {
+        count: NonZeroU16,
+        color1: Vector3<f32>,
+        color2: Vector3<f32>,
+    },
+    /// Checkerboard over a plane's automatic planar UVs (planes only): unit cells in
+    /// UV space, `color1` on the cell containing the UV origin.
+    Checker {
+        color1: Vector3<f32>,
+        color2: Vector3<f32>,
+    },
+}
+
+impl Pattern {
+    /// Stripe color for a point on the sphere with unit outward `normal`, or `None`
+    /// for patterns that are not defined on spheres.
+    pub fn color_at_normal(&self, normal: &Vector3<f32>) -> Option<Vector3<f32>> {
+        let Pattern::Bands { count, color1, color2 } = *self else {
+            return None;
+        };
+        let latitude = normal.y.clamp(-1.0, 1.0).asin();
+        let t = (latitude + std::f32::consts::FRAC_PI_2) / std::f32::consts::PI;
+        let count = u32::from(count.get());
+        let band = ((t * count as f32) as u32).min(count - 1);
+        Some(if band.is_multiple_of(2) { color1 } else { color2 })
+    }
+
+    /// Cell color at planar coordinates `uv`, or `None` for patterns that are not
+    /// defined on planes.
+    ///
+    /// `footprint` is the width in UV units of the area seen by the ray (see
+    /// `Ray::spread`): the checker is box-filtered over that square, so cells smaller
+    /// than a pixel fade to the average of both colors instead of aliasing. This is
+    /// what a mip pyramid would do for an image texture, computed exactly. A footprint
+    /// of 0 point-samples.
+    pub fn color_at_uv(&self, uv: &Vector2<f32>, footprint: f32) -> Option<Vector3<f32>> {
+        let Pattern::Checker { color1, color2 } = *self else {
+            return None;
+        };
+        if footprint < MIN_FILTER_FOOTPRINT {
+            let cell = uv.x.floor() as i64 + uv.y.floor() as i64;
+            return Some(if cell.rem_euclid(2) == 0 { color1 } else { color2 });
+        }
+        // A cell is odd when exactly one of its coordinates is
+        let odd_u = odd_cell_coverage(uv.x, footprint);
+        let odd_v = odd_cell_coverage(uv.y, footprint);
+        let odd = odd_u * (1.0 - odd_v) + odd_v * (1.0 - odd_u);
+        Some(color1 * (1.0 - odd) + color2 * odd)
+    }
+}
+
+/// Footprints narrower than this (in cells) are point-sampled: the filtered result
+/// would differ by less than the 8-bit output precision.
+const MIN_FILTER_FOOTPRINT: f32 = 1e-3;
+
+/// Fraction of `[x - width / 2, x + width / 2]` covered by odd unit cells
+/// (`floor(x)` odd).
+fn odd_cell_coverage(x: f32, width: f32) -> f32 {
+    // Length of the odd cells within [0, x]: one per period of 2, plus the part of
+    // the current period past its even half
+    let integral = |x: f32| {
+        let periods = (x / 2.0).floor();
+        periods + (x - 2.0 * periods - 1.0).max(0.0)
+    };
+    ((integral(x + width / 2.0) - integral(x - width / 2.0)) / width).clamp(0.0, 1.0)
+}
@@ -1,6 +1,16 @@
+mod binary_scene;
 mod camera;
 mod config_builder;
+pub mod environment;
+pub mod grade;
 pub mod light;
+pub mod lut;
+pub mod material;
+pub mod sampling;
+mod scene_cache;
 pub mod shape;
-pub use config_builder::{Config, ParsedConfigState};
+pub mod transform;
+pub use binary_scene::{load_binary, save_binary, BINARY_EXTENSION};
+pub use camera::{Camera, FovAxis, Projection};
+pub use config_builder::{Config, ParsedConfigState, DEFAULT_PIXEL_OFFSET, MAX_DEPTH};
 pub use shape::Ray;
@@ -0,0 +1,227 @@
+use crate::raytracer::config::grade::luminance;
+use crate::raytracer::rng::Rng;
+use nalgebra::Vector3;
+use serde::{Deserialize, Serialize};
+
+/// Candidates drawn for each `Poisson` sample, the one farthest from the samples
+/// already placed being kept
+const POISSON_CANDIDATES: u32 = 16;
+
+/// Brightest a sample may be under `AaCombine::Clamped`, as a multiple of the
+/// median or mean sample luminance, whichever is higher
+const CLAMPED_LUMINANCE_RATIO: f32 = 2.0;
+
+/// How the sub-pixel positions of anti-aliasing samples are laid out, set by
+/// `aa_pattern`. Positions are in pixels from the pixel's top-left corner.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AaPattern {
+    /// Independent uniform positions (the historical behaviour)
+    #[default]
+    Jitter,
+    /// Cell centers of a regular grid as close to square as the count allows
+    Grid,
+    /// Rotated-grid supersampling: the grid's cells shifted so that no two samples
+    /// share a row or column, which smooths near-horizontal and near-vertical edges
+    /// a regular grid leaves stepped
+    RotatedGrid,
+    /// Random positions kept apart from each other (best-candidate sampling)
+    Poisson,
+}
+
+impl AaPattern {
+    /// `samples` positions within a pixel. `rng` is only drawn from by the random
+    /// patterns, so the fixed ones give the same positions in every pixel.
+    pub fn positions(&self, samples: u32, rng: &mut Rng) -> Vec<(f32, f32)> {
+        let count = samples as usize;
+        let cols = (samples as f32).sqrt().ceil().max(1.0) as usize;
+        let rows = count.div_ceil(cols);
+        match self {
+            AaPattern::Jitter => (0..count).map(|_| (rng.next_f32(), rng.next_f32())).collect(),
+            AaPattern::Grid => (0..count)
+                .map(|index| {
+                    let (row, col) = (index / cols, index % cols);
+                    // Spread a short last row across the whole pixel width
+                    let in_row = if row + 1 == rows { count - row * cols } else { cols };
+                    ((col as f32 + 0.5) / in_row as f32, (row as f32 + 0.5) / rows as f32)
+                })
+                .collect(),
+            AaPattern::RotatedGrid => (0..count)
+                .map(|index| {
+                    let (row, col) = (index / cols, index % cols);
+                    let x = (col as f32 + (row as f32 + 0.5) / rows as f32) / cols as f32;
+                    let y = (row as f32 + ((cols - 1 - col) as f32 + 0.5) / cols as f32) / rows as f32;
+                    (x, y)
+                })
+                .collect(),
+            AaPattern::Poisson => {
+                let mut positions: Vec<(f32, f32)> = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let mut best = (0.0, 0.0);
+                    let mut best_distance = -1.0;
+                    for _ in 0..POISSON_CANDIDATES {
+                        let candidate = (rng.next_f32(), rng.next_f32());
+                        let distance = positions
+                            .iter()
+                            .map(|&placed| wrapped_distance_squared(candidate, placed))
+                            .fold(f32::INFINITY, f32::min);
+                        if distance > best_distance {
+                            best = candidate;
+                            best_distance = distance;
+                        }
+                    }
+                    positions.push(best);
+                }
+                positions
+            }
+        }
+    }
+}
+
+/// How the samples of an anti-aliased pixel are combined into its color, set by
+/// `aa_combine`. The robust modes keep a rare very bright sample (a firefly) from
+/// dominating the pixel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AaCombine {
+    /// Plain average of the samples
+    #[default]
+    Mean,
+    /// Per-channel median, ignoring outliers altogether
+    Median,
+    /// Average after scaling down samples brighter than `CLAMPED_LUMINANCE_RATIO`
+    /// times the median luminance, keeping their hue. The mean luminance floors the
+    /// limit, so an edge over a mostly black pixel is not clamped to black.
+    Clamped,
+}
+
+impl AaCombine {
+    /// Color of a pixel whose samples are `colors`, which must not be empty
+    pub fn combine(&self, colors: &[Vector3<f32>]) -> Vector3<f32> {
+        match self {
+            AaCombine::Mean => colors.iter().sum::<Vector3<f32>>() / colors.len() as f32,
+            AaCombine::Median => Vector3::from_fn(|channel, _| median(colors.iter().map(|color| color[channel]))),
+            AaCombine::Clamped => {
+                let mean = colors.iter().map(luminance).sum::<f32>() / colors.len() as f32;
+                let limit = CLAMPED_LUMINANCE_RATIO * median(colors.iter().map(luminance)).max(mean);
+                colors
+                    .iter()
+                    .map(|color| {
+                        let brightness = luminance(color);
+                        if brightness > limit {
+                            color * (limit / brightness)
+                        } else {
+                            *color
+                        }
+                    })
+                    .sum::<Vector3<f32>>()
+                    / colors.len() as f32
+            }
+        }
+    }
+}
+
+/// Middle value, or the average of the two middle values of an even count
+fn median(values: impl Iterator<Item = f32>) -> f32 {
+    let mut values: Vec<f32> = values.collect();
+    values.sort_by(f32::total_cmp);
+    let middle = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[middle - 1] + values[middle]) / 2.0
+    } else {
+        values[middle]
+    }
+}
+
+/// Squared distance between two positions in a pixel tiled with copies of itself,
+/// so samples near opposite edges of neighbouring pixels are kept apart too
+fn wrapped_distance_squared(a: (f32, f32), b: (f32, f32)) -> f32 {
+    let wrap = |d: f32| d.abs().min(1.0 - d.abs());
+    wrap(a.0 - b.0).powi(2) + wrap(a.1 - b.1).powi(2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATTERNS: [AaPattern; 4] = [AaPattern::Jitter, AaPattern::Grid, AaPattern::RotatedGrid, AaPattern::Poisson];
+
+    #[test]
+    fn test_patterns_give_the_requested_sample_count() {
+        for pattern in PATTERNS {
+            for samples in [1, 2, 4, 5, 9, 16] {
+                let positions = pattern.positions(samples, &mut Rng::new(7));
+                assert_eq!(positions.len(), samples as usize, "{:?}", pattern);
+                for (x, y) in positions {
+                    assert!((0.0..1.0).contains(&x) && (0.0..1.0).contains(&y), "{:?}: {} {}", pattern, x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_rotated_grid_shares_no_row_or_column() {
+        let grid = AaPattern::Grid.positions(4, &mut Rng::new(0));
+        assert_eq!(grid, [(0.25, 0.25), (0.75, 0.25), (0.25, 0.75), (0.75, 0.75)]);
+
+        let rotated = AaPattern::RotatedGrid.positions(4, &mut Rng::new(0));
+        assert_eq!(rotated, [(0.125, 0.375), (0.625, 0.125), (0.375, 0.875), (0.875, 0.625)]);
+        for (index, a) in rotated.iter().enumerate() {
+            for b in &rotated[index + 1..] {
+                assert!(a.0 != b.0 && a.1 != b.1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_robust_combines_suppress_a_firefly() {
+        let mut colors = vec![Vector3::repeat(0.1); 7];
+        colors.push(Vector3::new(1.0, 0.9, 0.8));
+
+        let mean = AaCombine::Mean.combine(&colors);
+        assert!((mean - Vector3::new(0.2125, 0.2, 0.1875)).norm() < 1e-6);
+        assert_eq!(AaCombine::Median.combine(&colors), Vector3::repeat(0.1));
+        // The firefly is scaled down to twice the mean brightness, keeping its hue
+        let clamped = AaCombine::Clamped.combine(&colors);
+        assert!(clamped.x > 0.1 && clamped.x < 0.15 && clamped.x > clamped.z, "{}", clamped);
+
+        // Without outliers the robust combines stay close to the mean
+        let edge = [Vector3::repeat(0.2), Vector3::repeat(0.2), Vector3::repeat(0.3), Vector3::repeat(0.3)];
+        for combine in [AaCombine::Mean, AaCombine::Median, AaCombine::Clamped] {
+            assert!((combine.combine(&edge) - Vector3::repeat(0.25)).norm() < 1e-6, "{:?}", combine);
+        }
+    }
+
+    #[test]
+    fn test_clamped_keeps_an_edge_over_black() {
+        // A white edge covering three of eight samples: the median is black
+        let mut colors = vec![Vector3::zeros(); 5];
+        colors.extend([Vector3::repeat(1.0); 3]);
+
+        let mean = AaCombine::Mean.combine(&colors);
+        let clamped = AaCombine::Clamped.combine(&colors);
+        assert_eq!(AaCombine::Median.combine(&colors), Vector3::zeros());
+        assert!(clamped.x >= 0.5 * mean.x && clamped.x <= mean.x, "{} {}", clamped, mean);
+        assert_eq!(clamped.x, clamped.z);
+
+        // Lit samples at most twice the mean are left as they are
+        colors.truncate(2);
+        colors.extend([Vector3::repeat(1.0); 2]);
+        assert_eq!(AaCombine::Clamped.combine(&colors), AaCombine::Mean.combine(&colors));
+    }
+
+    #[test]
+    fn test_poisson_keeps_samples_apart() {
+        let min_distance = |positions: &[(f32, f32)]| {
+            let mut min = f32::INFINITY;
+            for (index, &a) in positions.iter().enumerate() {
+                for &b in &positions[index + 1..] {
+                    min = min.min(wrapped_distance_squared(a, b).sqrt());
+                }
+            }
+            min
+        };
+        let poisson = AaPattern::Poisson.positions(16, &mut Rng::new(3));
+        let jitter = AaPattern::Jitter.positions(16, &mut Rng::new(3));
+        assert!(min_distance(&poisson) > min_distance(&jitter));
+        assert_eq!(poisson, AaPattern::Poisson.positions(16, &mut Rng::new(3)));
+    }
+}
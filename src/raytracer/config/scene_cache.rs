@@ -0,0 +1,123 @@
+//! On-disk cache of parsed scenes, enabled with `ParsedConfigState::set_cache_dir`.
+//!
+//! A cache file holds a `CacheKey` followed by the parsed `Config`, its objects (which
+//! `Config` leaves out of its own serialization) and the parser state that callers can
+//! observe, serialized with bincode. The entry is only used while
+//! every source file (the scene, its material libraries, environment map and LUT) and the
+//! running executable still have the size and modification time recorded in the key,
+//! so editing the scene or rebuilding the parser invalidates it.
+
+use crate::raytracer::config::shape::Shape;
+use crate::raytracer::config::Config;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::UNIX_EPOCH;
+
+/// Bumped whenever the layout of cache files changes
+const CACHE_VERSION: u32 = 21;
+
+/// Distinguishes the temporary files of concurrent writers in one process
+static NEXT_PARTIAL: AtomicUsize = AtomicUsize::new(0);
+
+/// Size and modification time of a file when its cache entry was written
+#[derive(Serialize, Deserialize, PartialEq)]
+struct FileStamp {
+    len: u64,
+    modified_nanos: u128,
+}
+
+impl FileStamp {
+    fn of(path: &Path) -> Option<FileStamp> {
+        let metadata = fs::metadata(path).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(FileStamp {
+            len: metadata.len(),
+            modified_nanos: modified.as_nanos(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+struct CacheKey {
+    version: u32,
+    executable: Option<FileStamp>,
+    sources: Vec<(String, Option<FileStamp>)>,
+}
+
+impl CacheKey {
+    fn current(sources: &[String]) -> CacheKey {
+        CacheKey {
+            version: CACHE_VERSION,
+            executable: std::env::current_exe().ok().and_then(|exe| FileStamp::of(&exe)),
+            sources: sources
+                .iter()
+                .map(|source| (source.clone(), FileStamp::of(Path::new(source))))
+                .collect(),
+        }
+    }
+
+    fn is_current(&self) -> bool {
+        let sources: Vec<String> = self.sources.iter().map(|(source, _)| source.clone()).collect();
+        self.sources.iter().all(|(_, stamp)| stamp.is_some()) && *self == CacheKey::current(&sources)
+    }
+}
+
+/// Parsed scene as stored in the cache: the config without its objects, the objects,
+/// whether it had a `size` line, its load warnings and its format version
+pub(crate) type CachedScene = (Config, Vec<Shape>, bool, Vec<String>, u32);
+
+/// `CachedScene` borrowed from the parser, as written by `store`
+pub(crate) type CacheEntry<'a> = (&'a Config, &'a [Shape], bool, &'a [String], u32);
+
+/// Cache file for `scene` in `dir`, named after the scene file and a hash of its path
+pub(crate) fn cache_path(dir: &Path, scene: &str) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    fs::canonicalize(scene)
+        .unwrap_or_else(|_| PathBuf::from(scene))
+        .hash(&mut hasher);
+    let stem = Path::new(scene)
+        .file_stem()
+        .map_or_else(|| "scene".into(), |stem| stem.to_string_lossy());
+    dir.join(format!("{}-{:016x}.cache", stem, hasher.finish()))
+}
+
+/// Reads the entry at `path`, `None` if it is missing, unreadable or out of date
+pub(crate) fn load(path: &Path) -> Option<CachedScene> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let key: CacheKey = bincode::deserialize_from(&mut reader).ok()?;
+    if !key.is_current() {
+        return None;
+    }
+    bincode::deserialize_from(&mut reader).ok()
+}
+
+/// Writes the entry for a scene parsed from `sources`. The file is written aside and
+/// renamed into place, so concurrent loads never see a partial entry.
+pub(crate) fn store(path: &Path, sources: &[String], entry: CacheEntry) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let partial = path.with_extension(format!(
+        "partial-{}-{}",
+        std::process::id(),
+        NEXT_PARTIAL.fetch_add(1, Ordering::Relaxed)
+    ));
+    let result = write_entry(&partial, sources, entry)
+        .and_then(|()| fs::rename(&partial, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    result
+}
+
+fn write_entry(path: &Path, sources: &[String], entry: CacheEntry) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    bincode::serialize_into(&mut writer, &CacheKey::current(sources)).map_err(io::Error::other)?;
+    bincode::serialize_into(&mut writer, &entry).map_err(io::Error::other)?;
+    writer.flush()
+}
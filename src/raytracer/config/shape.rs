@@ -1,48 +1,177 @@
+use crate::raytracer::config::material::{Material, Transmission};
+use crate::raytracer::config::transform::Transform;
 use bvh::aabb::{Aabb, Bounded};
-use bvh::bounding_hierarchy::BHShape;
-use nalgebra::{Point3, Vector3};
+use bvh::bounding_hierarchy::{BHShape, BoundingHierarchy};
+use bvh::bvh::Bvh;
+use nalgebra::{Point3, Rotation3, Vector2, Vector3};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Shape {
+    /// Sphere of positive `radius`. An `inward` sphere is seen from inside: its normals
+    /// point toward the center and rays hit the far side, so a large one enclosing the
+    /// scene acts as a background dome. It still blocks light coming from outside it.
+    /// Spheres are the only shapes that can be transparent.
     Sphere {
         center: Vector3<f32>,
         radius: f32,
-        diffuse_color: Vector3<f32>,
-        specular_color: Vector3<f32>,
-        shininess: f32,
+        material: Material,
         node_index: usize,
+        inward: bool,
+        transmission: Transmission,
     },
+    /// A `twosided` triangle is shaded the same from both sides: hits on its back get
+    /// the normal flipped toward the ray, as for thin leaves or flags.
     Triangle {
         v0: Vector3<f32>,
         v1: Vector3<f32>,
         v2: Vector3<f32>,
-        diffuse_color: Vector3<f32>,
-        specular_color: Vector3<f32>,
-        shininess: f32,
+        material: Material,
         node_index: usize,
+        twosided: bool,
     },
     Plane {
         point: Vector3<f32>,
         normal: Vector3<f32>,
-        diffuse_color: Vector3<f32>,
-        specular_color: Vector3<f32>,
-        shininess: f32,
+        material: Material,
         node_index: usize,
+        /// Half-size of the cube around `point` used as the plane's AABB; see `fit_plane_aabb`
+        aabb_half_size: f32,
+        /// Density of the automatic planar UVs: one UV unit spans `1 / uv_scale` world units
+        uv_scale: f32,
     },
+    /// Square patch of the plane through `point` with unit `normal`, extending
+    /// `half_size` along both planar UV axes (see `planar_uv`): a finite floor tile
+    /// with a tight AABB, unlike `Plane`.
+    BoundedPlane {
+        point: Vector3<f32>,
+        normal: Vector3<f32>,
+        half_size: f32,
+        material: Material,
+        node_index: usize,
+        uv_scale: f32,
+    },
+    /// Cylinder of `radius` around the segment `a`-`b`, closed by hemispheres:
+    /// every point within `radius` of the segment.
+    Capsule {
+        a: Vector3<f32>,
+        b: Vector3<f32>,
+        radius: f32,
+        material: Material,
+        node_index: usize,
+    },
+    /// A placed copy of a shared mesh. Only the transform is stored per instance;
+    /// the triangles live once in the `Mesh`. Not serializable: each instance would
+    /// carry its own copy of the mesh, so scenes using them are not cached.
+    #[serde(skip)]
+    Instance {
+        mesh: Arc<Mesh>,
+        transform: Box<Transform>,
+        node_index: usize,
+    },
+}
+
+/// Named triangle mesh defined once and referenced by any number of `Shape::Instance`s.
+/// Triangles are stored in mesh-local coordinates with their own BVH.
+pub struct Mesh {
+    pub name: String,
+    triangles: Vec<Shape>,
+    bvh: Bvh<f32, 3>,
+    bounds: Aabb<f32, 3>,
+}
+
+impl Mesh {
+    pub fn new(name: String, mut triangles: Vec<Shape>) -> Result<Self, String> {
+        if triangles.is_empty() {
+            return Err(format!("Mesh '{}' has no triangles", name));
+        }
+        let bvh = Bvh::build_par(&mut triangles);
+        let bounds = triangles
+            .iter()
+            .skip(1)
+            .fold(triangles[0].aabb(), |acc, triangle| union_aabb(&acc, &triangle.aabb()));
+        Ok(Mesh {
+            name,
+            triangles,
+            bvh,
+            bounds,
+        })
+    }
+
+    pub fn triangles(&self) -> &[Shape] {
+        &self.triangles
+    }
+
+    /// Nearest hit of a ray given in mesh-local coordinates.
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        self.bvh
+            .traverse(&ray.to_bvh_ray(), &self.triangles)
+            .iter()
+            .filter_map(|triangle| triangle.intersect(ray).map(|hit| (self.triangle_index(triangle), hit)))
+            .min_by(|(a_index, a), (b_index, b)| {
+                a.distance
+                    .partial_cmp(&b.distance)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+                    .then(a_index.cmp(b_index))
+            })
+            .map(|(_, hit)| hit)
+    }
+
+    /// Position of `triangle`, borrowed from this mesh, in definition order. Breaks ties
+    /// between coincident triangles independently of BVH traversal order.
+    fn triangle_index(&self, triangle: &Shape) -> usize {
+        (triangle as *const Shape as usize - self.triangles.as_ptr() as usize) / std::mem::size_of::<Shape>()
+    }
+}
+
+impl fmt::Debug for Mesh {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mesh")
+            .field("name", &self.name)
+            .field("triangles", &self.triangles.len())
+            .finish()
+    }
 }
 
 pub struct Ray {
     pub origin: Vector3<f32>,
     pub direction: Vector3<f32>,
+    /// Ray differential: angle in radians between this ray and its neighbour one pixel
+    /// over, so the footprint at distance `t` is about `t * spread` wide. 0 for rays
+    /// that are point-sampled (everything but primary rays).
+    pub spread: f32,
+}
+
+impl Ray {
+    /// Point-sampled ray, with no footprint
+    pub fn new(origin: Vector3<f32>, direction: Vector3<f32>) -> Self {
+        Ray {
+            origin,
+            direction,
+            spread: 0.0,
+        }
+    }
+
+    /// The same ray in the `bvh` crate's representation, for BVH traversal.
+    pub fn to_bvh_ray(&self) -> bvh::ray::Ray<f32, 3> {
+        bvh::ray::Ray::new(Point3::from(self.origin), self.direction)
+    }
 }
 
 pub struct Intersection {
     pub distance: f32,
+    /// Largest curvature of the surface at `point`: 1 / radius on round shapes, 0 on
+    /// flat ones. Used to estimate how fast the normal turns across a pixel.
+    pub curvature: f32,
     pub normal: Vector3<f32>,
+    /// Unit surface direction perpendicular to `normal`, orienting anisotropic highlights
+    pub tangent: Vector3<f32>,
     pub point: Vector3<f32>,
-    pub diffuse_color: Vector3<f32>,
-    pub specular_color: Vector3<f32>,
-    pub shininess: f32,
+    pub material: Material,
+    /// Light passing through the surface, opaque except on transparent spheres
+    pub transmission: Transmission,
     pub is_back_face: bool,
 }
 
@@ -51,18 +180,47 @@ impl Shape {
         match self {
             Shape::Sphere { .. } => intersect_sphere(ray, self),
             Shape::Plane { .. } => intersect_plane(ray, self),
+            Shape::BoundedPlane { .. } => intersect_plane_bounded(ray, self),
             Shape::Triangle { .. } => intersect_triangle(ray, self),
+            Shape::Capsule { .. } => intersect_capsule(ray, self),
+            Shape::Instance {
+                mesh, transform, ..
+            } => intersect_instance(ray, mesh, transform),
         }
     }
 }
 
+fn intersect_instance(ray: &Ray, mesh: &Mesh, transform: &Transform) -> Option<Intersection> {
+    let local_ray = Ray {
+        origin: transform.point_to_local(ray.origin),
+        direction: transform.vector_to_local(ray.direction),
+        spread: ray.spread,
+    };
+    let hit = mesh.intersect(&local_ray)?;
+
+    // The local direction is not renormalized, so the hit distance is valid in world space
+    let point = ray.origin + ray.direction * hit.distance;
+    let normal = transform.normal_to_world(hit.normal);
+    // A non-uniform scale can shear the tangent off the surface, so project it back
+    let tangent = transform.vector_to_world(hit.tangent);
+    let tangent = (tangent - normal * normal.dot(&tangent)).normalize();
+
+    Some(Intersection {
+        point,
+        normal,
+        tangent,
+        is_back_face: normal.dot(&ray.direction) > 0.0,
+        ..hit
+    })
+}
+
 fn intersect_sphere(ray: &Ray, sphere: &Shape) -> Option<Intersection> {
     let Shape::Sphere {
         center,
         radius,
-        diffuse_color,
-        specular_color,
-        shininess,
+        material,
+        inward,
+        transmission,
         ..
     } = sphere
     else {
@@ -77,38 +235,173 @@ fn intersect_sphere(ray: &Ray, sphere: &Shape) -> Option<Intersection> {
     if discriminant < 0.0 {
         None
     } else {
-        let t = -half_b - discriminant.sqrt();
+        let (near, far) = (-half_b - discriminant.sqrt(), -half_b + discriminant.sqrt());
+        // An inward sphere is only visible from inside, where the far root is ahead.
+        // Rays refracted into a transparent sphere leave it through the far side too.
+        let exiting = !*inward && near < 0.0 && transmission.transparency > 0.0;
+        let t = if *inward || exiting { far } else { near };
         if t < 0.0 {
             return None;
         }
         let point = ray.origin + ray.direction * t;
-        let normal = (point - *center).normalize();
+        let outward = (point - *center).normalize();
+        let normal = if *inward { -outward } else { outward };
+
+        let mut material = *material;
+        if let Some(color) = material.pattern.and_then(|pattern| pattern.color_at_normal(&outward)) {
+            material.diffuse_color = color;
+        }
+
+        // Along the lines of latitude, falling back to an arbitrary direction at the poles
+        let around_y = Vector3::new(-normal.z, 0.0, normal.x);
+        let tangent = if around_y.norm_squared() > 1e-12 {
+            around_y.normalize()
+        } else {
+            any_tangent(&normal)
+        };
 
         Some(Intersection {
             distance: t,
+            // The concave inside is treated as flat by `specular_aa`
+            curvature: if *inward { 0.0 } else { 1.0 / radius },
             normal,
+            tangent,
             point,
-            diffuse_color: *diffuse_color,
-            specular_color: *specular_color,
-            shininess: *shininess,
-            is_back_face: false,
+            material,
+            transmission: *transmission,
+            is_back_face: exiting,
         })
     }
 }
 
+/// Distance along `ray` at which it enters the sphere, if ahead of the origin.
+fn sphere_entry(ray: &Ray, center: &Vector3<f32>, radius: f32) -> Option<f32> {
+    let oc = ray.origin - *center;
+    let half_b = oc.dot(&ray.direction);
+    let c = oc.dot(&oc) - radius * radius;
+    let discriminant = half_b * half_b - c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let t = -half_b - discriminant.sqrt();
+    (t >= 0.0).then_some(t)
+}
+
+fn intersect_capsule(ray: &Ray, capsule: &Shape) -> Option<Intersection> {
+    let Shape::Capsule {
+        a,
+        b,
+        radius,
+        material,
+        ..
+    } = capsule
+    else {
+        return None;
+    };
+
+    // The capsule is the union of its side and both end spheres, so the first entry
+    // is the nearest of their entries. The side only counts between the end caps.
+    let axis = *b - *a;
+    let axis_len2 = axis.dot(&axis);
+    let oa = ray.origin - *a;
+    let d_axis = ray.direction.dot(&axis);
+    let oa_axis = oa.dot(&axis);
+    let qa = axis_len2 - d_axis * d_axis;
+    let side = if qa > 1e-8 {
+        let qb = axis_len2 * ray.direction.dot(&oa) - oa_axis * d_axis;
+        let qc = axis_len2 * oa.dot(&oa) - oa_axis * oa_axis - radius * radius * axis_len2;
+        let discriminant = qb * qb - qa * qc;
+        if discriminant >= 0.0 {
+            let t = (-qb - discriminant.sqrt()) / qa;
+            let along = oa_axis + t * d_axis;
+            (t >= 0.0 && along > 0.0 && along < axis_len2).then_some(t)
+        } else {
+            None
+        }
+    } else {
+        // Ray parallel to the axis: it can only enter through a cap
+        None
+    };
+
+    let t = [side, sphere_entry(ray, a, *radius), sphere_entry(ray, b, *radius)]
+        .into_iter()
+        .flatten()
+        .min_by(|x, y| x.partial_cmp(y).unwrap_or(std::cmp::Ordering::Equal))?;
+
+    let point = ray.origin + ray.direction * t;
+    // Radial direction from the nearest point of the segment
+    let s = if axis_len2 > 0.0 {
+        ((point - *a).dot(&axis) / axis_len2).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let normal = (point - (*a + axis * s)).normalize();
+    // Along the axis where possible, like the grain of a brushed rod
+    let along_axis = axis - normal * normal.dot(&axis);
+    let tangent = if along_axis.norm_squared() > 1e-12 {
+        along_axis.normalize()
+    } else {
+        any_tangent(&normal)
+    };
+
+    Some(Intersection {
+        distance: t,
+        curvature: 1.0 / radius,
+        normal,
+        tangent,
+        point,
+        material: *material,
+        transmission: Transmission::OPAQUE,
+        is_back_face: false,
+    })
+}
+
 fn intersect_plane(ray: &Ray, plane: &Shape) -> Option<Intersection> {
     let Shape::Plane {
         point,
         normal,
-        diffuse_color,
-        specular_color,
-        shininess,
+        material,
+        uv_scale,
+        ..
+    } = plane
+    else {
+        return None;
+    };
+
+    hit_plane(ray, point, normal, material, *uv_scale)
+}
+
+fn intersect_plane_bounded(ray: &Ray, plane: &Shape) -> Option<Intersection> {
+    let Shape::BoundedPlane {
+        point,
+        normal,
+        half_size,
+        material,
+        uv_scale,
         ..
     } = plane
     else {
         return None;
     };
 
+    let hit = hit_plane(ray, point, normal, material, *uv_scale)?;
+    // Unscaled planar UVs are the hit's offsets from `point` along the patch axes
+    let local = planar_uv(&hit.point, point, normal, 1.0);
+    if local.x.abs() > *half_size || local.y.abs() > *half_size {
+        return None;
+    }
+    Some(hit)
+}
+
+/// Intersection with the infinite plane through `point`, with `material`'s pattern
+/// evaluated at the hit's planar UVs.
+fn hit_plane(
+    ray: &Ray,
+    point: &Vector3<f32>,
+    normal: &Vector3<f32>,
+    material: &Material,
+    uv_scale: f32,
+) -> Option<Intersection> {
     let denom = normal.dot(&ray.direction);
     if denom.abs() < 1e-6 {
         return None;
@@ -121,14 +414,25 @@ fn intersect_plane(ray: &Ray, plane: &Shape) -> Option<Intersection> {
 
     let intersection_point = ray.origin + ray.direction * t;
 
+    let mut material = *material;
+    if let Some(pattern) = material.pattern {
+        let uv = planar_uv(&intersection_point, point, normal, uv_scale);
+        // The footprint stretches by 1 / cos where the plane recedes from the ray
+        let footprint = ray.spread * t / denom.abs() * uv_scale;
+        if let Some(color) = pattern.color_at_uv(&uv, footprint) {
+            material.diffuse_color = color;
+        }
+    }
+
     Some(Intersection {
         distance: t,
+        curvature: 0.0,
         normal: *normal,
+        tangent: any_tangent(normal),
         point: intersection_point,
-        diffuse_color: *diffuse_color,
-        specular_color: *specular_color,
-        shininess: *shininess,
-        is_back_face: false, 
+        material,
+        transmission: Transmission::OPAQUE,
+        is_back_face: denom > 0.0,
     })
 }
 
@@ -137,9 +441,8 @@ fn intersect_triangle(ray: &Ray, triangle: &Shape) -> Option<Intersection> {
         v0,
         v1,
         v2,
-        diffuse_color,
-        specular_color,
-        shininess,
+        material,
+        twosided,
         ..
     } = triangle
     else {
@@ -159,7 +462,7 @@ fn intersect_triangle(ray: &Ray, triangle: &Shape) -> Option<Intersection> {
     let s = ray.origin - *v0;
     let u = f * s.dot(&h);
 
-    if u < 0.0 || u > 1.0 {
+    if !(0.0..=1.0).contains(&u) {
         return None;
     }
 
@@ -176,21 +479,43 @@ fn intersect_triangle(ray: &Ray, triangle: &Shape) -> Option<Intersection> {
     }
 
     let intersection_point = ray.origin + ray.direction * t;
-    let normal = edge1.cross(&edge2).normalize();
-    
-    let is_back_face = normal.dot(&ray.direction) > 0.0;
+    let mut normal = edge1.cross(&edge2).normalize();
+
+    let mut is_back_face = normal.dot(&ray.direction) > 0.0;
+    if *twosided && is_back_face {
+        normal = -normal;
+        is_back_face = false;
+    }
 
+    // Triangles carry no UVs, so the tangent follows the first edge
     Some(Intersection {
         distance: t,
+        curvature: 0.0,
         normal,
+        tangent: edge1.normalize(),
         point: intersection_point,
-        diffuse_color: *diffuse_color,
-        specular_color: *specular_color,
-        shininess: *shininess,
+        material: *material,
+        transmission: Transmission::OPAQUE,
         is_back_face,
     })
 }
 
+/// A unit vector perpendicular to unit `normal`, for surfaces without a natural tangent.
+pub fn any_tangent(normal: &Vector3<f32>) -> Vector3<f32> {
+    let helper = if normal.x.abs() > 0.9 { Vector3::y() } else { Vector3::x() };
+    helper.cross(normal).normalize()
+}
+
+/// Automatic UVs on the plane through `origin` with unit `normal`: `point` projected on
+/// `any_tangent(normal)` and `normal x tangent`, relative to `origin` and multiplied by
+/// `uv_scale`.
+pub fn planar_uv(point: &Vector3<f32>, origin: &Vector3<f32>, normal: &Vector3<f32>, uv_scale: f32) -> Vector2<f32> {
+    let u_axis = any_tangent(normal);
+    let v_axis = normal.cross(&u_axis);
+    let local = point - origin;
+    Vector2::new(local.dot(&u_axis), local.dot(&v_axis)) * uv_scale
+}
+
 // ==================== BVH Trait Implementations ====================
 // The following trait implementations enable the BVH (Bounding Volume Hierarchy)
 // acceleration structure. Each shape must provide:
@@ -198,7 +523,146 @@ fn intersect_triangle(ray: &Ray, triangle: &Shape) -> Option<Intersection> {
 // 2. A way to store/retrieve its position in the BVH tree via the BHShape trait
 
 /// Size used for plane AABBs. Planes are infinite, so we use a very large but finite box.
-const PLANE_AABB_SIZE: f32 = 1e10;
+pub const PLANE_AABB_SIZE: f32 = 1e10;
+
+/// Extra room around the scene bounds given to fitted plane AABBs, as a fraction of the
+/// bounds' diagonal.
+const PLANE_AABB_MARGIN: f32 = 0.1;
+
+impl Shape {
+    /// Shrinks a plane's AABB from `PLANE_AABB_SIZE` to a cube around its point that
+    /// covers the scene bounds `min`-`max` with some margin. A 1e10 box makes every BVH
+    /// node containing the plane enormous; a scene-sized one keeps the tree tight.
+    ///
+    /// Rays may still hit the plane outside that box (a floor stretching to the horizon),
+    /// so callers must test planes on their own rather than relying on BVH culling.
+    /// Other shapes are left untouched.
+    pub fn fit_plane_aabb(&mut self, min: Vector3<f32>, max: Vector3<f32>) {
+        if let Shape::Plane {
+            point,
+            aabb_half_size,
+            ..
+        } = self
+        {
+            let margin = (max - min).norm() * PLANE_AABB_MARGIN + 1e-3;
+            let reach = (min - *point).abs().sup(&(max - *point).abs()).max();
+            *aabb_half_size = reach + margin;
+        }
+    }
+
+    /// Scene keyword that creates this kind of shape, e.g. `"sphere"`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Shape::Sphere { .. } => "sphere",
+            Shape::Triangle { .. } => "tri",
+            Shape::Plane { .. } => "plane",
+            Shape::BoundedPlane { .. } => "plane_bounded",
+            Shape::Capsule { .. } => "capsule",
+            Shape::Instance { .. } => "instance",
+        }
+    }
+
+    /// Material of the shape, `None` for an instance, whose triangles each carry their own
+    pub fn material(&self) -> Option<&Material> {
+        match self {
+            Shape::Sphere { material, .. }
+            | Shape::Triangle { material, .. }
+            | Shape::Plane { material, .. }
+            | Shape::BoundedPlane { material, .. }
+            | Shape::Capsule { material, .. } => Some(material),
+            Shape::Instance { .. } => None,
+        }
+    }
+
+    /// Moves the shape by `offset`; an instance is moved as a whole
+    pub fn translate(&mut self, offset: &Vector3<f32>) {
+        match self {
+            Shape::Sphere { center, .. } => *center += offset,
+            Shape::Triangle { v0, v1, v2, .. } => {
+                for vertex in [v0, v1, v2] {
+                    *vertex += offset;
+                }
+            }
+            Shape::Plane { point, .. } | Shape::BoundedPlane { point, .. } => *point += offset,
+            Shape::Capsule { a, b, .. } => {
+                *a += offset;
+                *b += offset;
+            }
+            Shape::Instance { transform, .. } => {
+                **transform = Transform::from_translation(offset).then(transform);
+            }
+        }
+    }
+
+    /// Rotates the shape about the world origin: positions and normals of primitives,
+    /// or the whole placement of an instance (its mesh is shared and left untouched).
+    pub fn rotate(&mut self, rotation: &Rotation3<f32>) {
+        match self {
+            Shape::Sphere { center, .. } => *center = rotation * *center,
+            Shape::Triangle { v0, v1, v2, .. } => {
+                for vertex in [v0, v1, v2] {
+                    *vertex = rotation * *vertex;
+                }
+            }
+            Shape::Plane { point, normal, .. } | Shape::BoundedPlane { point, normal, .. } => {
+                *point = rotation * *point;
+                *normal = rotation * *normal;
+            }
+            Shape::Capsule { a, b, .. } => {
+                *a = rotation * *a;
+                *b = rotation * *b;
+            }
+            Shape::Instance { transform, .. } => {
+                **transform = Transform::from_rotation(rotation).then(transform);
+            }
+        }
+    }
+
+    /// Places the shape with `transform`: vertices, positions and normals of primitives,
+    /// or the whole placement of an instance. Spheres, capsules and bounded planes keep
+    /// their round or square outline, so they only take transforms scaling all axes alike.
+    pub fn transform(&mut self, transform: &Transform) -> Result<(), String> {
+        let kind = self.kind();
+        let uniform_scale = || {
+            transform
+                .uniform_scale()
+                .ok_or_else(|| format!("A {} cannot be scaled unevenly", kind))
+        };
+        match self {
+            Shape::Sphere { center, radius, .. } => {
+                *radius *= uniform_scale()?;
+                *center = transform.point_to_world(*center);
+            }
+            Shape::Triangle { v0, v1, v2, .. } => {
+                for vertex in [&mut *v0, &mut *v1, &mut *v2] {
+                    *vertex = transform.point_to_world(*vertex);
+                }
+                // Keep the front face on the same side of the surface
+                if transform.flips_orientation() {
+                    std::mem::swap(v1, v2);
+                }
+            }
+            Shape::Plane { point, normal, .. } => {
+                *point = transform.point_to_world(*point);
+                *normal = transform.normal_to_world(*normal);
+            }
+            Shape::BoundedPlane { point, normal, half_size, .. } => {
+                *half_size *= uniform_scale()?;
+                *point = transform.point_to_world(*point);
+                *normal = transform.normal_to_world(*normal);
+            }
+            Shape::Capsule { a, b, radius, .. } => {
+                *radius *= uniform_scale()?;
+                *a = transform.point_to_world(*a);
+                *b = transform.point_to_world(*b);
+            }
+            Shape::Instance { transform: placement, .. } => {
+                **placement = transform.then(placement);
+            }
+        }
+        Ok(())
+    }
+}
 
 /// Implement Bounded trait to provide AABBs (Axis-Aligned Bounding Boxes) for each shape.
 /// The BVH uses these AABBs to quickly determine which objects a ray might intersect.
@@ -232,18 +696,73 @@ impl Bounded<f32, 3> for Shape {
                 
                 Aabb::with_bounds(min, max)
             }
-            Shape::Plane { .. } => {
-                // Planes are infinite, so we create a very large AABB.
+            Shape::Capsule { a, b, radius, .. } => {
+                // Capsule AABB: the segment's box grown by the radius on every side
+                let half_size = Vector3::repeat(*radius);
+                let min = Point3::from(a.inf(b) - half_size);
+                let max = Point3::from(a.sup(b) + half_size);
+                Aabb::with_bounds(min, max)
+            }
+            Shape::Plane {
+                point,
+                aabb_half_size,
+                ..
+            } => {
+                // Planes are infinite, so we create a very large AABB unless it was
+                // fitted to the scene.
                 // Note: Infinite primitives like planes don't benefit much from BVH,
                 // but we need to provide an AABB for the trait implementation.
-                let min = Point3::new(-PLANE_AABB_SIZE, -PLANE_AABB_SIZE, -PLANE_AABB_SIZE);
-                let max = Point3::new(PLANE_AABB_SIZE, PLANE_AABB_SIZE, PLANE_AABB_SIZE);
+                if *aabb_half_size >= PLANE_AABB_SIZE {
+                    let min = Point3::new(-PLANE_AABB_SIZE, -PLANE_AABB_SIZE, -PLANE_AABB_SIZE);
+                    let max = Point3::new(PLANE_AABB_SIZE, PLANE_AABB_SIZE, PLANE_AABB_SIZE);
+                    return Aabb::with_bounds(min, max);
+                }
+                let half_size = Vector3::repeat(*aabb_half_size);
+                Aabb::with_bounds(Point3::from(point - half_size), Point3::from(point + half_size))
+            }
+            Shape::BoundedPlane {
+                point,
+                normal,
+                half_size,
+                ..
+            } => {
+                // Bounded plane AABB: the square's corners span `half_size` along both
+                // patch axes, which is `half_size * (|u| + |v|)` on each world axis
+                let u_axis = any_tangent(normal);
+                let v_axis = normal.cross(&u_axis);
+                let extent = (u_axis.abs() + v_axis.abs()) * *half_size;
+                Aabb::with_bounds(Point3::from(point - extent), Point3::from(point + extent))
+            }
+            Shape::Instance {
+                mesh, transform, ..
+            } => {
+                // Transform all eight corners of the local bounds and take their extent
+                let (lo, hi) = (mesh.bounds.min, mesh.bounds.max);
+                let corners = (0..8).map(|i| {
+                    let corner = Vector3::new(
+                        if i & 1 == 0 { lo.x } else { hi.x },
+                        if i & 2 == 0 { lo.y } else { hi.y },
+                        if i & 4 == 0 { lo.z } else { hi.z },
+                    );
+                    Point3::from(transform.point_to_world(corner))
+                });
+                let mut min = Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY);
+                let mut max = Point3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY);
+                for corner in corners {
+                    min = min.inf(&corner);
+                    max = max.sup(&corner);
+                }
                 Aabb::with_bounds(min, max)
             }
         }
     }
 }
 
+/// Smallest AABB containing both `a` and `b`.
+fn union_aabb(a: &Aabb<f32, 3>, b: &Aabb<f32, 3>) -> Aabb<f32, 3> {
+    Aabb::with_bounds(a.min.inf(&b.min), a.max.sup(&b.max))
+}
+
 /// Implement BHShape trait to allow shapes to store their position in the BVH tree.
 /// The BVH library needs to track which tree node each shape belongs to.
 impl BHShape<f32, 3> for Shape {
@@ -252,6 +771,9 @@ impl BHShape<f32, 3> for Shape {
             Shape::Sphere { node_index, .. } => *node_index = index,
             Shape::Triangle { node_index, .. } => *node_index = index,
             Shape::Plane { node_index, .. } => *node_index = index,
+            Shape::BoundedPlane { node_index, .. } => *node_index = index,
+            Shape::Capsule { node_index, .. } => *node_index = index,
+            Shape::Instance { node_index, .. } => *node_index = index,
         }
     }
 
@@ -260,6 +782,235 @@ impl BHShape<f32, 3> for Shape {
             Shape::Sphere { node_index, .. } => *node_index,
             Shape::Triangle { node_index, .. } => *node_index,
             Shape::Plane { node_index, .. } => *node_index,
+            Shape::BoundedPlane { node_index, .. } => *node_index,
+            Shape::Capsule { node_index, .. } => *node_index,
+            Shape::Instance { node_index, .. } => *node_index,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Index of each variant, so the harness below notices variants without a probe.
+    fn variant_index(shape: &Shape) -> usize {
+        match shape {
+            Shape::Sphere { .. } => 0,
+            Shape::Triangle { .. } => 1,
+            Shape::Plane { .. } => 2,
+            Shape::Instance { .. } => 3,
+            Shape::Capsule { .. } => 4,
+            Shape::BoundedPlane { .. } => 5,
+        }
+    }
+    const VARIANT_COUNT: usize = 6;
+
+    fn triangle(v0: Vector3<f32>, v1: Vector3<f32>, v2: Vector3<f32>) -> Shape {
+        Shape::Triangle {
+            v0,
+            v1,
+            v2,
+            material: Material::default(),
+            node_index: 0,
+            twosided: false,
+        }
+    }
+
+    /// Every shape paired with a ray origin on its front side and a point of the shape
+    /// to aim at. Triangles are counter-clockwise when seen from the origin.
+    fn front_face_probes() -> Vec<(Shape, Vector3<f32>, Vector3<f32>)> {
+        let local_triangle = triangle(
+            Vector3::new(-1.0, -1.0, 0.0),
+            Vector3::new(1.0, -1.0, 0.0),
+            Vector3::new(0.0, 1.0, 0.0),
+        );
+        let mesh = Arc::new(Mesh::new("probe".to_string(), vec![local_triangle]).unwrap());
+        let transform = Transform::from_trs(
+            Vector3::new(1.0, 2.0, -5.0),
+            Vector3::new(0.0, 30.0, 0.0),
+            Vector3::new(2.0, 1.0, 3.0),
+        )
+        .unwrap();
+
+        vec![
+            (
+                Shape::Sphere {
+                    center: Vector3::new(1.0, 2.0, 3.0),
+                    radius: 1.5,
+                    material: Material::default(),
+                    node_index: 0,
+                    inward: false,
+                    transmission: Transmission::OPAQUE,
+                },
+                Vector3::new(-4.0, 2.0, 3.0),
+                Vector3::new(-0.5, 2.0, 3.0),
+            ),
+            (
+                triangle(
+                    Vector3::new(0.0, 0.0, -2.0),
+                    Vector3::new(2.0, 0.0, -2.0),
+                    Vector3::new(0.0, 2.0, -2.0),
+                ),
+                Vector3::new(0.5, 0.5, 3.0),
+                Vector3::new(0.5, 0.5, -2.0),
+            ),
+            (
+                Shape::Plane {
+                    point: Vector3::new(0.0, -1.0, 0.0),
+                    normal: Vector3::y(),
+                    material: Material::default(),
+                    node_index: 0,
+                    aabb_half_size: PLANE_AABB_SIZE,
+                    uv_scale: 1.0,
+                },
+                Vector3::new(0.0, 4.0, 0.0),
+                Vector3::new(2.0, -1.0, -3.0),
+            ),
+            (
+                Shape::Instance {
+                    mesh,
+                    transform: Box::new(transform),
+                    node_index: 0,
+                },
+                Vector3::new(1.0, 2.0, 0.0),
+                transform.point_to_world(Vector3::zeros()),
+            ),
+            (
+                capsule(),
+                Vector3::new(0.0, 1.0, 5.0),
+                Vector3::new(0.0, 1.0, 0.5),
+            ),
+            (
+                floor_tile(),
+                Vector3::new(1.0, 3.0, 2.0),
+                Vector3::new(-1.5, 0.0, 1.0),
+            ),
+        ]
+    }
+
+    /// 4x4 tile on the y = 0 plane, centered on the origin
+    fn floor_tile() -> Shape {
+        Shape::BoundedPlane {
+            point: Vector3::zeros(),
+            normal: Vector3::y(),
+            half_size: 2.0,
+            material: Material::default(),
+            node_index: 0,
+            uv_scale: 1.0,
+        }
+    }
+
+    #[test]
+    fn test_bounded_plane_hits_only_within_patch() {
+        let down_from = |x: f32, z: f32| Ray::new(Vector3::new(x, 5.0, z), -Vector3::y());
+        let tile = floor_tile();
+
+        for (x, z) in [(0.0, 0.0), (1.9, -1.9), (-1.9, 1.0)] {
+            let hit = tile.intersect(&down_from(x, z)).expect("inside the patch");
+            assert!((hit.point - Vector3::new(x, 0.0, z)).norm() < 1e-5);
+        }
+        for (x, z) in [(2.1, 0.0), (0.0, -2.1), (2.5, 2.5)] {
+            assert!(tile.intersect(&down_from(x, z)).is_none(), "({x}, {z}) is past the edge");
+        }
+
+        let aabb = tile.aabb();
+        assert_eq!(aabb.min.coords, Vector3::new(-2.0, 0.0, -2.0));
+        assert_eq!(aabb.max.coords, Vector3::new(2.0, 0.0, 2.0));
+    }
+
+    fn capsule() -> Shape {
+        Shape::Capsule {
+            a: Vector3::zeros(),
+            b: Vector3::new(0.0, 2.0, 0.0),
+            radius: 0.5,
+            material: Material::default(),
+            node_index: 0,
+        }
+    }
+
+    #[test]
+    fn test_capsule_grazing_ray_hits_rounded_end() {
+        // Passes above the top of the segment, so only the hemisphere around b is hit
+        let ray = Ray::new(Vector3::new(0.3, 2.3, 5.0), -Vector3::z());
+        let hit = capsule().intersect(&ray).unwrap();
+        let b = Vector3::new(0.0, 2.0, 0.0);
+
+        assert!((hit.distance - (5.0 - 0.07f32.sqrt())).abs() < 1e-4);
+        assert!(((hit.point - b).norm() - 0.5).abs() < 1e-4);
+        assert!((hit.normal - (hit.point - b) / 0.5).norm() < 1e-4);
+
+        // Beyond the radius around the end it misses entirely
+        let miss = Ray::new(Vector3::new(0.0, 2.6, 5.0), -Vector3::z());
+        assert!(capsule().intersect(&miss).is_none());
+    }
+
+    #[test]
+    fn test_capsule_side_and_axial_hits() {
+        // Side hit: the normal is perpendicular to the axis
+        let side = capsule()
+            .intersect(&Ray::new(Vector3::new(5.0, 0.5, 0.0), -Vector3::x()))
+            .unwrap();
+        assert!((side.point - Vector3::new(0.5, 0.5, 0.0)).norm() < 1e-5);
+        assert!((side.normal - Vector3::x()).norm() < 1e-5);
+
+        // A ray straight down the axis enters through the top cap
+        let axial = capsule()
+            .intersect(&Ray::new(Vector3::new(0.0, 10.0, 0.0), -Vector3::y()))
+            .unwrap();
+        assert!((axial.distance - 7.5).abs() < 1e-5);
+        assert!((axial.normal - Vector3::y()).norm() < 1e-5);
+
+        let aabb = capsule().aabb();
+        assert_eq!(aabb.min.coords, Vector3::new(-0.5, -0.5, -0.5));
+        assert_eq!(aabb.max.coords, Vector3::new(0.5, 2.5, 0.5));
+    }
+
+    #[test]
+    fn test_front_face_normals_point_back_at_the_ray() {
+        let probes = front_face_probes();
+        let mut covered = [false; VARIANT_COUNT];
+
+        for (shape, origin, target) in &probes {
+            covered[variant_index(shape)] = true;
+            let ray = Ray::new(*origin, (target - origin).normalize());
+            let hit = shape
+                .intersect(&ray)
+                .unwrap_or_else(|| panic!("probe missed {:?}", shape));
+
+            assert!((hit.point - target).norm() < 1e-4, "{:?}: hit {:?}", shape, hit.point);
+            assert!((hit.normal.norm() - 1.0).abs() < 1e-4, "{:?}: normal not unit", shape);
+            assert!(hit.normal.dot(&ray.direction) < 0.0, "{:?}: normal faces away", shape);
+            assert!(!hit.is_back_face, "{:?}: front face flagged as back face", shape);
+        }
+
+        assert!(covered.iter().all(|c| *c), "every shape variant needs a probe");
+    }
+
+    #[test]
+    fn test_bvh_ray_keeps_origin_and_direction() {
+        let ray = Ray::new(Vector3::new(1.5, -2.0, 3.25), Vector3::new(0.0, 0.6, -0.8));
+        let bvh_ray = ray.to_bvh_ray();
+        assert_eq!(bvh_ray.origin.coords, ray.origin);
+        assert_eq!(bvh_ray.direction, ray.direction);
+    }
+
+    #[test]
+    fn test_flat_shapes_report_back_faces() {
+        for (shape, origin, target) in front_face_probes() {
+            if matches!(shape, Shape::Sphere { .. } | Shape::Capsule { .. }) {
+                continue;
+            }
+            // Mirror the origin through the surface to look at it from behind
+            let front = shape
+                .intersect(&Ray::new(origin, (target - origin).normalize()))
+                .unwrap();
+            let behind = target - front.normal * (target - origin).norm();
+            let hit = shape
+                .intersect(&Ray::new(behind, (target - behind).normalize()))
+                .unwrap_or_else(|| panic!("back probe missed {:?}", shape));
+
+            assert!(hit.is_back_face, "{:?}: back face not flagged", shape);
         }
     }
 }
@@ -0,0 +1,116 @@
+use nalgebra::{Matrix4, Point3, Rotation3, Vector3};
+use serde::{Deserialize, Serialize};
+
+/// Affine object-to-world transform, stored together with its inverse so rays can be
+/// moved into object space without inverting the matrix for every intersection test.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Transform {
+    matrix: Matrix4<f32>,
+    inverse: Matrix4<f32>,
+}
+
+impl Transform {
+    pub fn identity() -> Self {
+        Transform {
+            matrix: Matrix4::identity(),
+            inverse: Matrix4::identity(),
+        }
+    }
+
+    /// Builds a transform applying `scale`, then `rotation` (Euler angles in degrees around
+    /// X, then Y, then Z), then `translation`.
+    pub fn from_trs(
+        translation: Vector3<f32>,
+        rotation: Vector3<f32>,
+        scale: Vector3<f32>,
+    ) -> Result<Self, String> {
+        let rotation = Rotation3::from_euler_angles(
+            rotation.x.to_radians(),
+            rotation.y.to_radians(),
+            rotation.z.to_radians(),
+        );
+        let matrix = Matrix4::new_translation(&translation)
+            * rotation.to_homogeneous()
+            * Matrix4::new_nonuniform_scaling(&scale);
+        Self::from_matrix(matrix)
+    }
+
+    pub fn from_rotation(rotation: &Rotation3<f32>) -> Self {
+        Transform {
+            matrix: rotation.to_homogeneous(),
+            inverse: rotation.inverse().to_homogeneous(),
+        }
+    }
+
+    pub fn from_translation(translation: &Vector3<f32>) -> Self {
+        Transform {
+            matrix: Matrix4::new_translation(translation),
+            inverse: Matrix4::new_translation(&-translation),
+        }
+    }
+
+    pub fn from_matrix(matrix: Matrix4<f32>) -> Result<Self, String> {
+        let inverse = matrix
+            .try_inverse()
+            .ok_or_else(|| "Transform is not invertible (zero scale?)".to_string())?;
+        Ok(Transform { matrix, inverse })
+    }
+
+    pub fn matrix(&self) -> &Matrix4<f32> {
+        &self.matrix
+    }
+
+    /// Common factor of a transform scaling all axes alike (possibly mirrored), `None`
+    /// for a non-uniform scale or shear.
+    pub fn uniform_scale(&self) -> Option<f32> {
+        let linear = self.matrix.fixed_view::<3, 3>(0, 0);
+        let gram = linear.transpose() * linear;
+        let squared = gram[(0, 0)];
+        let tolerance = squared * 1e-4;
+        (0..3)
+            .all(|i| (0..3).all(|j| (gram[(i, j)] - if i == j { squared } else { 0.0 }).abs() <= tolerance))
+            .then(|| squared.sqrt())
+    }
+
+    /// Whether the transform mirrors space, reversing the winding of triangles
+    pub fn flips_orientation(&self) -> bool {
+        self.matrix.fixed_view::<3, 3>(0, 0).determinant() < 0.0
+    }
+
+    /// Returns the transform equivalent to applying `other` first, then `self`.
+    pub fn then(&self, other: &Transform) -> Transform {
+        Transform {
+            matrix: self.matrix * other.matrix,
+            inverse: other.inverse * self.inverse,
+        }
+    }
+
+    pub fn point_to_world(&self, point: Vector3<f32>) -> Vector3<f32> {
+        self.matrix.transform_point(&Point3::from(point)).coords
+    }
+
+    pub fn point_to_local(&self, point: Vector3<f32>) -> Vector3<f32> {
+        self.inverse.transform_point(&Point3::from(point)).coords
+    }
+
+    pub fn vector_to_world(&self, vector: Vector3<f32>) -> Vector3<f32> {
+        self.matrix.transform_vector(&vector)
+    }
+
+    /// Direction vectors are not renormalized, so a ray parameter `t` means the same
+    /// point in both spaces.
+    pub fn vector_to_local(&self, vector: Vector3<f32>) -> Vector3<f32> {
+        self.inverse.transform_vector(&vector)
+    }
+
+    /// Normals transform by the inverse transpose to stay perpendicular to scaled surfaces.
+    pub fn normal_to_world(&self, normal: Vector3<f32>) -> Vector3<f32> {
+        self.inverse.transpose().transform_vector(&normal).normalize()
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform::identity()
+    }
+}
@@ -0,0 +1,109 @@
+//! Edge-avoiding à-trous denoiser for stochastic renders (light sampling, glossy
+//! reflections, image-based lighting).
+//!
+//! Each pass blurs the framebuffer with a 5x5 B3-spline kernel whose taps are spread
+//! `2^pass` pixels apart, so a few passes cover a wide footprint cheaply. Every tap is
+//! weighted down when its albedo or normal differs from the center pixel's, which keeps
+//! object silhouettes, creases and texture edges sharp, and when its color differs by
+//! much more than the filter `strength`, which keeps real shading detail.
+
+use nalgebra::Vector3;
+use rayon::prelude::*;
+
+/// Filter passes; the last one reaches 2 * 2^(PASSES - 1) = 16 pixels from the center
+const PASSES: u32 = 4;
+
+/// B3-spline kernel weights for tap offsets 0, 1 and 2
+const KERNEL: [f32; 3] = [3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Albedo difference over which a tap is mostly rejected
+const ALBEDO_SIGMA: f32 = 0.05;
+
+/// Exponent sharpening the normal weight `max(n.n', 0)`
+const NORMAL_POWER: i32 = 64;
+
+/// Guide buffers for one pixel: its unlit surface color and unit normal, zero where the
+/// primary ray escapes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Guide {
+    pub albedo: Vector3<f32>,
+    pub normal: Vector3<f32>,
+}
+
+/// Denoises a row-major `width`x`height` linear framebuffer guided by one `Guide` per
+/// pixel.
+///
+/// `strength` is the color difference the filter smooths over in the first pass; it is
+/// halved in each later pass, so noise is flattened while larger shading changes are
+/// kept. Higher values also spread fireflies into their surroundings.
+pub fn denoise(color: &[Vector3<f32>], guides: &[Guide], width: usize, height: usize, strength: f32) -> Vec<Vector3<f32>> {
+    let mut current = color.to_vec();
+    for pass in 0..PASSES {
+        let step = 1 << pass;
+        let sigma = strength / (1 << pass) as f32;
+        let mut next = vec![Vector3::zeros(); current.len()];
+        next.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+            for (x, out) in row.iter_mut().enumerate() {
+                *out = filter_pixel(&current, guides, width, height, (x, y), step, sigma);
+            }
+        });
+        current = next;
+    }
+    current
+}
+
+/// Weighted average of the 5x5 taps `step` pixels apart around `center`
+fn filter_pixel(
+    color: &[Vector3<f32>],
+    guides: &[Guide],
+    width: usize,
+    height: usize,
+    center: (usize, usize),
+    step: usize,
+    sigma: f32,
+) -> Vector3<f32> {
+    let index = center.1 * width + center.0;
+    let (center_color, center_guide) = (color[index], guides[index]);
+    let mut sum = Vector3::zeros();
+    let mut total_weight = 0.0;
+    for dy in -2i32..=2 {
+        let Some(y) = offset(center.1, dy, step, height) else {
+            continue;
+        };
+        for dx in -2i32..=2 {
+            let Some(x) = offset(center.0, dx, step, width) else {
+                continue;
+            };
+            let tap = y * width + x;
+            let weight = KERNEL[dx.unsigned_abs() as usize]
+                * KERNEL[dy.unsigned_abs() as usize]
+                * color_weight(&center_color, &color[tap], sigma)
+                * guide_weight(&center_guide, &guides[tap]);
+            sum += color[tap] * weight;
+            total_weight += weight;
+        }
+    }
+    // The center tap always has a positive weight
+    sum / total_weight
+}
+
+/// `position + delta * step` if it lies within `0..size`
+fn offset(position: usize, delta: i32, step: usize, size: usize) -> Option<usize> {
+    let moved = position as i64 + delta as i64 * step as i64;
+    (0..size as i64).contains(&moved).then_some(moved as usize)
+}
+
+fn color_weight(center: &Vector3<f32>, tap: &Vector3<f32>, sigma: f32) -> f32 {
+    (-(center - tap).norm_squared() / (sigma * sigma)).exp()
+}
+
+fn guide_weight(center: &Guide, tap: &Guide) -> f32 {
+    let albedo = (-(center.albedo - tap.albedo).norm_squared() / (ALBEDO_SIGMA * ALBEDO_SIGMA)).exp();
+    // Background pixels (zero normal) only blend with each other
+    let normal = if center.normal == tap.normal {
+        1.0
+    } else {
+        center.normal.dot(&tap.normal).max(0.0).powi(NORMAL_POWER)
+    };
+    albedo * normal
+}
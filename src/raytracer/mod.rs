@@ -1,4 +1,9 @@
-mod config;
+pub mod config;
+mod denoise;
+#[allow(clippy::module_inception)]
 mod raytracer;
-pub use config::ParsedConfigState;
-pub use raytracer::RayTracer;
+mod rng;
+mod stats;
+pub use config::{Camera, Config, FovAxis, ParsedConfigState, Projection};
+pub use raytracer::{BvhLeaf, Frame, PixelRegion, RayHit, RayTracer, SampleAovs};
+pub use stats::RenderStats;
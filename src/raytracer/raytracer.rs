@@ -1,11 +1,466 @@
+use crate::error::RayTracerError;
 use crate::imgcomparator::Image;
-use crate::raytracer::config::light::Light::{Directional, Point};
-use crate::raytracer::config::Config;
+use crate::raytracer::config::light::Light::{self, Directional, Point};
+use crate::raytracer::config::environment::EnvironmentMap;
+use crate::raytracer::config::grade::luminance;
+use crate::raytracer::config::shape::{any_tangent, Intersection, Shape};
+use crate::raytracer::config::{Camera, Config, Projection, DEFAULT_PIXEL_OFFSET, MAX_DEPTH};
 use crate::raytracer::config::Ray;
+use crate::raytracer::denoise::{denoise, Guide};
+use crate::raytracer::rng::Rng;
+use crate::raytracer::stats::{
+    add_primary_rays, add_reflection_ray, add_refraction_ray, add_shadow_ray, count_rays, count_secondary_rays,
+    RayCount, RenderStats,
+};
 use rayon::prelude::*;
-use bvh::bvh::Bvh;
-use bvh::bounding_hierarchy::BoundingHierarchy;
-use nalgebra::{Point3, Vector3};
+use bvh::aabb::{Aabb, Bounded};
+use bvh::bvh::{Bvh, BvhNode};
+use bvh::bounding_hierarchy::{BHShape, BoundingHierarchy};
+use nalgebra::Vector3;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Value written by `render_depth_map` for the farthest hit and for background pixels.
+pub const DEPTH_MAX: u32 = u16::MAX as u32;
+
+/// Value written by `render_object_ids` for pixels that see no object.
+pub const NO_OBJECT: u32 = u32::MAX;
+
+/// Value written by `render_normal_aov` for pixels that see no object. Opaque black
+/// would decode to (-1, -1, -1), which is not a unit normal, so it never collides.
+pub const NORMAL_AOV_BACKGROUND: u32 = 0xFF00_0000;
+
+/// Stream identifier mixed into the per-hit seed used by light importance sampling.
+const LIGHT_SAMPLING_SEED: u64 = 0x4C49_4748_5453;
+
+/// Stream identifier mixed into the per-hit seed used by environment lighting.
+const IBL_SAMPLING_SEED: u64 = 0x4942_4C53;
+
+/// Stream identifier mixed into the per-hit seed used by glossy reflections.
+const GLOSSY_SAMPLING_SEED: u64 = 0x474C_4F53;
+
+/// Reflected rays averaged at a rough surface seen directly. Deeper bounces cast a
+/// single jittered ray so the ray count does not grow exponentially with `maxdepth`.
+const GLOSSY_SAMPLES: u32 = 16;
+
+/// Stream identifier mixed into the per-pixel seed used to jitter progressive passes.
+const PROGRESSIVE_SAMPLING_SEED: u64 = 0x5052_4F47;
+
+/// Stream identifier mixed into the per-pixel seed used to jitter edge samples.
+const EDGE_AA_SAMPLING_SEED: u64 = 0x4544_4745;
+
+/// Neighbouring hits on the same object whose normals are further apart than this
+/// (cosine of the angle) are treated as a crease and supersampled by `edge_aa`.
+const EDGE_NORMAL_COS: f32 = 0.9;
+
+/// Neighbouring hits on the same object whose distances differ by more than this
+/// fraction of the nearer one are treated as a self-occlusion edge by `edge_aa`.
+const EDGE_DEPTH_RATIO: f32 = 0.1;
+
+/// Narrowest highlight, in pixels, that `stable_shininess` lets through.
+const HIGHLIGHT_MIN_PIXELS: f32 = 2.0;
+
+/// Distance past a transparent surface at which refracted rays start, far enough that
+/// they do not hit the surface they cross again.
+const REFRACTION_OFFSET: f32 = 1e-4;
+
+/// State carried along a ray path: the secondary bounces taken so far, whose total
+/// `maxdepth` bounds and each kind `max_reflect`/`max_refract`, and the pixel
+/// footprint of the view the path started from.
+#[derive(Clone, Copy, Debug)]
+struct Bounces {
+    reflections: u32,
+    refractions: u32,
+    footprint: PixelFootprint,
+}
+
+impl Bounces {
+    /// Path of a primary ray of a view with pixels of `footprint`
+    fn primary(footprint: PixelFootprint) -> Self {
+        Bounces {
+            reflections: 0,
+            refractions: 0,
+            footprint,
+        }
+    }
+
+    fn total(self) -> u32 {
+        self.reflections + self.refractions
+    }
+
+    fn reflected(self) -> Self {
+        Bounces {
+            reflections: self.reflections + 1,
+            ..self
+        }
+    }
+
+    fn refracted(self) -> Self {
+        Bounces {
+            refractions: self.refractions + 1,
+            ..self
+        }
+    }
+}
+
+/// First-pass result of a render for one pixel: the linear color of its center sample,
+/// and what its primary ray hit, for `edge_aa` to compare with the neighbouring pixels
+/// and `denoise` to guide its filter.
+#[derive(Clone, Default)]
+struct EdgeProbe {
+    color: Vector3<f32>,
+    object: u32,
+    distance: f32,
+    normal: Vector3<f32>,
+    guide: Guide,
+}
+
+impl EdgeProbe {
+    /// Whether a silhouette, crease or depth jump separates the two pixels
+    fn differs_from(&self, other: &EdgeProbe) -> bool {
+        if self.object != other.object {
+            return true;
+        }
+        self.object != NO_OBJECT
+            && (self.normal.dot(&other.normal) < EDGE_NORMAL_COS
+                || (self.distance - other.distance).abs() > EDGE_DEPTH_RATIO * self.distance.min(other.distance))
+    }
+}
+
+/// Colors of a pixel supersampled by `edge_aa`.
+#[derive(Clone, Copy)]
+struct EdgeSamples {
+    /// `aa_combine` of the samples' display colors, each clamped to `[0, 1]`
+    display: Vector3<f32>,
+    /// `aa_combine` of the samples' linear colors
+    linear: Vector3<f32>,
+    /// Luminance variance of the display colors
+    variance: f32,
+}
+
+/// Display image of a render with the linear colors and sampling AOVs behind it, as
+/// from `RayTracer::render_scene_frame`.
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub image: Image,
+    /// Ungraded linear color of each pixel, row-major (see `RayTracer::render_linear`)
+    pub linear: Vec<Vector3<f32>>,
+    pub aovs: SampleAovs,
+}
+
+/// Nearest hit of a ray, as found by `RayTracer::raycast` and `trace_single_ray`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RayHit {
+    /// Index of the hit object in the scene's object list, as in `render_object_ids`
+    pub object_index: usize,
+    /// Scene keyword of the hit object, e.g. `"sphere"` (see `Shape::kind`)
+    pub object_kind: &'static str,
+    /// Distance from the ray origin, in units of the (normalized) ray direction
+    pub distance: f32,
+    pub point: Vector3<f32>,
+    /// Shading normal at `point`, facing the ray origin
+    pub normal: Vector3<f32>,
+    pub is_back_face: bool,
+}
+
+/// One object's leaf in the BVH, as reported by `RayTracer::bvh_leaves`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BvhLeaf {
+    /// BVH node holding the object (`Shape`'s `node_index`)
+    pub node_index: usize,
+    /// Index of the object in the scene's object list
+    pub object_index: usize,
+    /// Scene keyword of the object, e.g. `"sphere"`
+    pub object_kind: &'static str,
+    /// Corners of the AABB the BVH was built from
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+/// Per-pixel sampling buffers of an adaptive render, as from
+/// `RayTracer::render_sample_aovs`. Row-major, one entry per pixel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SampleAovs {
+    /// Primary rays averaged into the pixel
+    pub counts: Vec<u32>,
+    /// Variance of the luminance of the pixel's supersamples, 0 where it has one sample
+    pub variance: Vec<f32>,
+}
+
+/// Rectangle of pixels `x..x + width` by `y..y + height`, as used by
+/// `RayTracer::render_region`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PixelRegion {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl PixelRegion {
+    /// Every pixel of a `width`x`height` image
+    pub fn full(width: u32, height: u32) -> Self {
+        PixelRegion { x: 0, y: 0, width, height }
+    }
+}
+
+/// Quantizes a linear color to an opaque packed 0xAARRGGBB pixel, clamping each
+/// channel to [0, 1] and rounding to the nearest 8-bit value.
+fn pack_color(color: &Vector3<f32>) -> u32 {
+    // One clamp/scale/round over the whole vector lets the compiler vectorize it
+    let channels = (color.map(|c| c.clamp(0.0, 1.0)) * 255.0).map(|c| c.round() as u32);
+    (255 << 24) | (channels.x << 16) | (channels.y << 8) | channels.z
+}
+
+/// Ashikhmin-Shirley style highlight: the Blinn-Phong lobe with the exponent lowered to
+/// `shininess * (1 - anisotropy)` along the tangent, so the highlight stretches in that
+/// direction. It reduces to `n_dot_h ^ shininess` when `anisotropy` is 0.
+fn anisotropic_specular(
+    intersection: &Intersection,
+    half_vector: &Vector3<f32>,
+    n_dot_h: f32,
+    shininess: f32,
+) -> f32 {
+    let material = &intersection.material;
+    let sin2 = 1.0 - n_dot_h * n_dot_h;
+    if sin2 <= 1e-8 {
+        return n_dot_h;
+    }
+    let bitangent = intersection.normal.cross(&intersection.tangent);
+    let h_t = half_vector.dot(&intersection.tangent);
+    let h_b = half_vector.dot(&bitangent);
+    let along = shininess * (1.0 - material.anisotropy);
+    let exponent = (along * h_t * h_t + shininess * h_b * h_b) / sin2;
+    n_dot_h.powf(exponent)
+}
+
+/// Uniform direction within the cone of half-angle `half_angle` around unit `axis`.
+fn sample_cone(axis: &Vector3<f32>, half_angle: f32, rng: &mut Rng) -> Vector3<f32> {
+    let tangent = any_tangent(axis);
+    let bitangent = axis.cross(&tangent);
+
+    let cos_theta = 1.0 - rng.next_f32() * (1.0 - half_angle.cos());
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * std::f32::consts::PI * rng.next_f32();
+    (tangent * (sin_theta * phi.cos()) + bitangent * (sin_theta * phi.sin()) + axis * cos_theta).normalize()
+}
+
+/// Direction on the hemisphere around unit `normal` with probability proportional to
+/// the cosine of its angle to the normal.
+fn cosine_sample_hemisphere(normal: &Vector3<f32>, rng: &mut Rng) -> Vector3<f32> {
+    let tangent = any_tangent(normal);
+    let bitangent = normal.cross(&tangent);
+
+    let phi = 2.0 * std::f32::consts::PI * rng.next_f32();
+    let r2 = rng.next_f32();
+    let r = r2.sqrt();
+    (tangent * (r * phi.cos()) + bitangent * (r * phi.sin()) + normal * (1.0 - r2).sqrt()).normalize()
+}
+
+/// Relative slack past `max_t` within which `traverse_limited` still visits a box, so
+/// rounding in the slab test never prunes an object hit just short of `max_t`.
+const TRAVERSAL_SLACK: f32 = 1e-4;
+
+/// Distance along `ray` at which it enters `aabb` (0 if it starts inside), `None` if it
+/// misses the box. `ray.direction` must be normalized for the result to be a distance.
+fn aabb_entry_distance(ray: &bvh::ray::Ray<f32, 3>, aabb: &Aabb<f32, 3>) -> Option<f32> {
+    let mut t_min = 0.0f32;
+    let mut t_max = f32::INFINITY;
+    for axis in 0..3 {
+        let inverse = 1.0 / ray.direction[axis];
+        let mut t0 = (aabb.min[axis] - ray.origin[axis]) * inverse;
+        let mut t1 = (aabb.max[axis] - ray.origin[axis]) * inverse;
+        if t0.is_nan() || t1.is_nan() {
+            // Parallel to the slab and starting on its boundary plane
+            if ray.origin[axis] < aabb.min[axis] || ray.origin[axis] > aabb.max[axis] {
+                return None;
+            }
+            continue;
+        }
+        if t0 > t1 {
+            std::mem::swap(&mut t0, &mut t1);
+        }
+        t_min = t_min.max(t0);
+        t_max = t_max.min(t1);
+    }
+    (t_max >= t_min).then_some(t_min)
+}
+
+/// Shapes whose bounding box `ray` enters before `max_t`, like `Bvh::traverse` but
+/// skipping every subtree whose box starts farther along the ray than `max_t`.
+fn traverse_limited<'a, S: Bounded<f32, 3>>(
+    bvh: &Bvh<f32, 3>,
+    ray: &bvh::ray::Ray<f32, 3>,
+    max_t: f32,
+    shapes: &'a [S],
+) -> Vec<&'a S> {
+    let limit = max_t + TRAVERSAL_SLACK * max_t.max(1.0);
+    let reaches = |aabb: &Aabb<f32, 3>| aabb_entry_distance(ray, aabb).is_some_and(|t| t <= limit);
+    let mut found = Vec::new();
+    let mut stack = match bvh.nodes.first() {
+        // A single-object tree has no parent box to test
+        Some(BvhNode::Leaf { shape_index, .. }) => {
+            if reaches(&shapes[*shape_index].aabb()) {
+                found.push(&shapes[*shape_index]);
+            }
+            return found;
+        }
+        Some(_) => vec![0],
+        None => return found,
+    };
+    while let Some(index) = stack.pop() {
+        match &bvh.nodes[index] {
+            BvhNode::Leaf { shape_index, .. } => found.push(&shapes[*shape_index]),
+            BvhNode::Node {
+                child_l_index,
+                child_l_aabb,
+                child_r_index,
+                child_r_aabb,
+                ..
+            } => {
+                if reaches(child_l_aabb) {
+                    stack.push(*child_l_index);
+                }
+                if reaches(child_r_aabb) {
+                    stack.push(*child_r_index);
+                }
+            }
+        }
+    }
+    found
+}
+
+/// How far apart the primary rays of neighbouring pixels are, which sizes the filters
+/// keeping detail narrower than a pixel from aliasing.
+#[derive(Clone, Copy, Debug)]
+struct PixelFootprint {
+    /// Angle in radians between neighbouring primary rays at the image center, 0 for
+    /// an orthographic view
+    spread: f32,
+    /// Width in world units of the pixels of an orthographic view, 0 for a perspective
+    /// one
+    span: f32,
+}
+
+/// Camera basis and image-plane extents used to generate primary rays.
+struct ViewPlane {
+    origin: Vector3<f32>,
+    camera_vector: Vector3<f32>,
+    normal_to_plane: Vector3<f32>,
+    v: Vector3<f32>,
+    pixel_width: f32,
+    pixel_height: f32,
+    img_width_by_2: f32,
+    img_height_by_2: f32,
+    footprint: PixelFootprint,
+    /// Primary rays carry their footprint for texture filtering (`texture_filter`)
+    filter_textures: bool,
+    /// Primary rays are parallel to `camera_vector`, starting across the image plane
+    orthographic: bool,
+    /// Sample position of `primary_ray` within its pixel (see `Config::pixel_offset`)
+    pixel_offset: (f32, f32),
+}
+
+impl ViewPlane {
+    fn new(camera: &Camera, width: u32, height: u32) -> Self {
+        let camera_vector = camera.direction().normalize();
+        let normal_to_plane = camera_vector.cross(&camera.up).normalize();
+        let v = normal_to_plane.cross(&camera_vector).normalize();
+
+        let (pixel_width, pixel_height) = camera.view_half_extents(width as f32 / height as f32);
+        let orthographic = matches!(camera.projection, Projection::Orthographic { .. });
+
+        ViewPlane {
+            origin: camera.position,
+            camera_vector,
+            normal_to_plane,
+            v,
+            pixel_width,
+            pixel_height,
+            img_width_by_2: width as f32 / 2.0,
+            img_height_by_2: height as f32 / 2.0,
+            footprint: Self::footprint(pixel_height, height, orthographic),
+            filter_textures: true,
+            orthographic,
+            pixel_offset: DEFAULT_PIXEL_OFFSET,
+        }
+    }
+
+    /// Image-plane position, in pixels from the top-left corner, where `offset` from the
+    /// camera (a point relative to the origin, or for a perspective view a direction
+    /// towards infinity) is seen; `None` when it is not in front of the camera.
+    fn project(&self, offset: &Vector3<f32>) -> Option<(f32, f32)> {
+        let depth = offset.dot(&self.camera_vector);
+        if depth <= 0.0 {
+            return None;
+        }
+        let scale = if self.orthographic { 1.0 } else { depth };
+        let a = offset.dot(&self.normal_to_plane) / scale;
+        let b = offset.dot(&self.v) / scale;
+        Some((
+            self.img_width_by_2 + a / self.pixel_width * self.img_width_by_2,
+            self.img_height_by_2 - b / self.pixel_height * self.img_height_by_2,
+        ))
+    }
+
+    /// Footprint of pixels `height` of which span the `pixel_height` half extent
+    fn footprint(pixel_height: f32, height: u32, orthographic: bool) -> PixelFootprint {
+        let pixel_size = 2.0 * pixel_height / height.max(1) as f32;
+        // Parallel rays do not diverge, so their footprint does not grow with distance
+        if orthographic {
+            PixelFootprint { spread: 0.0, span: pixel_size }
+        } else {
+            PixelFootprint { spread: pixel_size, span: 0.0 }
+        }
+    }
+
+    /// Same view with point-sampled primary rays
+    fn without_footprint(mut self) -> Self {
+        self.filter_textures = false;
+        self
+    }
+
+    /// Path state of the view's primary rays
+    fn primary_path(&self) -> Bounces {
+        Bounces::primary(self.footprint)
+    }
+
+    /// Same view sampling each pixel at `offset` from its top-left corner
+    fn with_pixel_offset(mut self, offset: (f32, f32)) -> Self {
+        self.pixel_offset = offset;
+        self
+    }
+
+    /// Primary ray through pixel (`x`, `y`), at its center unless the view has another
+    /// `pixel_offset`.
+    fn primary_ray(&self, x: usize, y: usize) -> Ray {
+        self.ray_through(x as f32 + self.pixel_offset.0, y as f32 + self.pixel_offset.1)
+    }
+
+    /// Primary ray through the image-plane point (`x`, `y`), in pixels from the top-left
+    /// corner of the image.
+    fn ray_through(&self, x: f32, y: f32) -> Ray {
+        let a = (self.pixel_width * (x - self.img_width_by_2)) / self.img_width_by_2;
+        let b = (self.pixel_height * (self.img_height_by_2 - y)) / self.img_height_by_2;
+
+        if self.orthographic {
+            return Ray::new(self.origin + self.normal_to_plane * a + self.v * b, self.camera_vector);
+        }
+
+        let through = self.normal_to_plane * a + self.v * b + self.camera_vector;
+
+        // Off-center pixels subtend a smaller angle, by the cosine to the view axis
+        Ray {
+            origin: self.origin,
+            direction: through.normalize(),
+            spread: if self.filter_textures { self.footprint.spread / through.norm() } else { 0.0 },
+        }
+    }
+}
 
 /// RayTracer with BVH (Bounding Volume Hierarchy) acceleration structure.
 /// 
@@ -18,6 +473,18 @@ pub struct RayTracer {
     /// BVH acceleration structure for fast ray-object intersection queries.
     /// Built once during initialization using Surface Area Heuristic (SAH) for optimal partitioning.
     bvh: Bvh<f32, 3>,
+    /// Indices of the planes in the scene's object list. Their AABBs are fitted to the
+    /// other objects, so they are tested for every ray instead of through BVH culling.
+    planes: Vec<usize>,
+    /// Test shadow rays against every object instead of the BVH's candidates, as a
+    /// reference for BVH shadow bugs. Only enabled by tests.
+    brute_force_shadows: bool,
+}
+
+thread_local! {
+    /// Cancel flag of the `with_timeout` call this thread is rendering for, installed on
+    /// each worker of the thread pool that call renders on
+    static RENDER_CANCELLED: RefCell<Option<Arc<AtomicBool>>> = const { RefCell::new(None) };
 }
 
 impl RayTracer {
@@ -27,288 +494,3280 @@ impl RayTracer {
     /// with large scenes. The construction uses SAH (Surface Area Heuristic) to determine
     /// optimal split planes, resulting in efficient traversal during rendering.
     pub fn new(mut config: Config) -> Self {
-        // Build BVH from scene objects using parallel construction
-        let mut objects = config.get_scene_objects().clone();
-        let bvh = Bvh::build_par(&mut objects);
-        
-        // Update the config with the modified objects (they now have BVH indices)
-        *config.get_scene_objects_mut() = objects;
-        
-        RayTracer { config, bvh }
+        let (bvh, planes) = Self::build_bvh(&mut config);
+
+        RayTracer {
+            config,
+            bvh,
+            planes,
+            brute_force_shadows: false,
+        }
+    }
+
+    /// Replaces the scene camera used by `render` and the other scene-view renders,
+    /// e.g. with a copy moved by `Camera::orbit` for each frame of a flythrough. The
+    /// BVH is kept.
+    pub fn set_camera(&mut self, camera: Camera) {
+        self.config.camera = camera;
+    }
+
+    /// Builds the BVH over `config`'s objects, returning it with the indices of the
+    /// scene's planes.
+    fn build_bvh(config: &mut Config) -> (Bvh<f32, 3>, Vec<usize>) {
+        let bounds = config.bounding_box();
+        let objects = config.get_scene_objects_mut();
+        // Size plane AABBs to the finite objects so they do not bloat the tree
+        if let Some((min, max)) = bounds {
+            for object in objects.iter_mut() {
+                object.fit_plane_aabb(min, max);
+            }
+        }
+        let planes = objects
+            .iter()
+            .enumerate()
+            .filter(|(_, object)| matches!(object, Shape::Plane { .. }))
+            .map(|(index, _)| index)
+            .collect();
+        // Parallel construction; stores each object's node index in the object
+        let bvh = Bvh::build_par(objects);
+        (bvh, planes)
+    }
+
+    #[cfg(test)]
+    fn with_brute_force_shadows(mut self) -> Self {
+        self.brute_force_shadows = true;
+        self
+    }
+
+    pub fn render(&self) -> Result<Image, RayTracerError> {
+        self.render_with_stats().map(|(image, _)| image)
+    }
+
+    /// Runs `render` (e.g. `RayTracer::render_with_stats`) under a watchdog that
+    /// cancels it once `timeout` has passed, so a pathological scene cannot hang the
+    /// caller. A cancelled render cuts every ray path short at its next bounce and
+    /// skips its remaining pixels, and its result is discarded.
+    ///
+    /// `render` runs on a thread pool of its own, as large as the current one, whose
+    /// workers see this call's cancel flag; renders running at the same time on the
+    /// same `RayTracer` are not affected.
+    ///
+    /// # Returns
+    /// * `Err(RayTracerError::Timeout)` - `render` was still running after `timeout`
+    pub fn with_timeout<T: Send>(
+        &self,
+        timeout: Duration,
+        render: impl FnOnce(&Self) -> Result<T, RayTracerError> + Send,
+    ) -> Result<T, RayTracerError> {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&cancelled);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(rayon::current_num_threads())
+            .start_handler(move |_| {
+                RENDER_CANCELLED.with(|cancelled| *cancelled.borrow_mut() = Some(Arc::clone(&flag)))
+            })
+            .build()
+            .map_err(|e| RayTracerError::Render(format!("Cannot start the render threads: {}", e)))?;
+        let (finished, watchdog) = mpsc::channel::<()>();
+        let result = thread::scope(|scope| {
+            let cancelled = &cancelled;
+            scope.spawn(move || {
+                if watchdog.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout) {
+                    cancelled.store(true, Ordering::Relaxed);
+                }
+            });
+            let result = pool.install(|| render(self));
+            // Wakes the watchdog so the scope does not wait for the full timeout
+            drop(finished);
+            result
+        });
+        if cancelled.load(Ordering::Relaxed) {
+            return Err(RayTracerError::Timeout(timeout));
+        }
+        result
+    }
+
+    /// Whether the watchdog of the `with_timeout` call this thread renders for has
+    /// given up on it. Always false outside `with_timeout`.
+    fn is_cancelled(&self) -> bool {
+        RENDER_CANCELLED.with(|cancelled| {
+            cancelled.borrow().as_ref().is_some_and(|cancelled| cancelled.load(Ordering::Relaxed))
+        })
+    }
+
+    /// Renders the scene and reports how many rays of each kind were cast.
+    pub fn render_with_stats(&self) -> Result<(Image, RenderStats), RayTracerError> {
+        self.render_scene_frame().map(|(frame, stats)| (frame.image, stats))
+    }
+
+    /// Renders the scene once, returning everything the other scene renders derive from
+    /// it: the display image, its linear framebuffer and sampling AOVs, and the rays
+    /// cast. See `render_linear` and `render_sample_aovs` for the buffers.
+    pub fn render_scene_frame(&self) -> Result<(Frame, RenderStats), RayTracerError> {
+        self.check_renderable()?;
+        let start_time = Instant::now();
+        let (frame, rays) = self.render_frame(&self.config.camera, self.config.width, self.config.height);
+        Ok((frame, rays.stats(start_time.elapsed(), self.config.seed.unwrap_or(0))))
+    }
+
+    /// Linear-workflow render: the display image as from `render_with_stats` together
+    /// with the linear framebuffer behind it, one scene-referred color per pixel in
+    /// row-major order, before grading, `clamp_mode`, `lut` and 8-bit quantization.
+    /// Running each linear value through those stages gives the display pixel, so the
+    /// buffer can be saved as a sidecar (`save_linear`) for color-managed pipelines.
+    ///
+    /// Pixels supersampled by `edge_aa` hold the `aa_combine` of their linear samples,
+    /// while their display color combines the samples after clamping each one, so the
+    /// two can differ there. With `denoise` the buffer holds the filtered colors.
+    pub fn render_linear(&self) -> Result<(Image, Vec<Vector3<f32>>, RenderStats), RayTracerError> {
+        self.render_scene_frame().map(|(frame, stats)| (frame.image, frame.linear, stats))
+    }
+
+    /// Rejects configs that cannot be rendered, such as those built in code without
+    /// the parser's checks.
+    fn check_renderable(&self) -> Result<(), RayTracerError> {
+        Self::check_size(self.config.width, self.config.height)?;
+        self.check_depth()
+    }
+
+    fn check_size(width: u32, height: u32) -> Result<(), RayTracerError> {
+        if width == 0 || height == 0 {
+            return Err(RayTracerError::Render(format!(
+                "Cannot render an empty {}x{} image",
+                width, height
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_depth(&self) -> Result<(), RayTracerError> {
+        if self.config.maxdepth > MAX_DEPTH {
+            return Err(RayTracerError::Render(format!(
+                "maxdepth {} exceeds the limit of {}",
+                self.config.maxdepth, MAX_DEPTH
+            )));
+        }
+        Ok(())
+    }
+
+    /// Renders the scene from `camera` at `width`x`height`, ignoring the scene's own
+    /// camera and size. The BVH is shared, so this is cheap to call repeatedly
+    /// (e.g. for interactive previews or camera animations).
+    ///
+    /// With `edge_aa`, pixels on silhouettes and creases are supersampled, and with
+    /// `denoise` the result is then filtered; see `render_frame`.
+    pub fn render_to_image_with_camera(&self, camera: &Camera, width: u32, height: u32) -> Image {
+        self.render_frame(camera, width, height).0.image
+    }
+
+    /// Renders the scene from `camera` at `width`x`height`. A first pass traces every
+    /// pixel center, recording the object, distance and normal it hits. With `edge_aa`,
+    /// pixels differing from a 4-neighbour (a silhouette, crease or depth jump) are then
+    /// re-rendered from extra samples (see `supersample_edges`), while flat interiors keep
+    /// their single sample. With `denoise`, the linear colors are finally smoothed by the
+    /// edge-aware filter of `denoise`, guided by the albedo and normal of each pixel
+    /// center, before grading and quantization. Returns the frame with the rays cast.
+    fn render_frame(&self, camera: &Camera, width: u32, height: u32) -> (Frame, RayCount) {
+        let (probes, probe_rays) = self.trace_view(camera, width, height, |ray, path| {
+            let hit = self.closest_hit(ray);
+            let color = self.shade_hit(hit.as_ref().map(|(_, intersection)| intersection), ray.direction, path, None);
+            match &hit {
+                Some((shape, intersection)) => EdgeProbe {
+                    color,
+                    object: self.object_index(shape) as u32,
+                    distance: intersection.distance,
+                    normal: intersection.normal,
+                    guide: Guide {
+                        albedo: intersection.material.diffuse_color,
+                        normal: intersection.normal,
+                    },
+                },
+                None => EdgeProbe {
+                    color,
+                    object: NO_OBJECT,
+                    distance: f32::INFINITY,
+                    ..EdgeProbe::default()
+                },
+            }
+        });
+
+        let samples = self.config.edge_aa_samples;
+        let (edges, edge_rays) = if samples > 0 {
+            self.supersample_edges(camera, width, height, &probes, samples)
+        } else {
+            (vec![None; probes.len()], RayCount::default())
+        };
+        let mut linear: Vec<Vector3<f32>> = probes
+            .iter()
+            .zip(&edges)
+            .map(|(probe, edge)| edge.map_or(probe.color, |edge| edge.linear))
+            .collect();
+        let aovs = SampleAovs {
+            counts: edges.iter().map(|edge| edge.map_or(1, |_| 1 + samples)).collect(),
+            variance: edges.iter().map(|edge| edge.map_or(0.0, |edge| edge.variance)).collect(),
+        };
+        if let Some(strength) = self.config.denoise {
+            let guides: Vec<Guide> = probes.iter().map(|probe| probe.guide).collect();
+            linear = denoise(&linear, &guides, width as usize, height as usize, strength);
+        }
+        let data = linear
+            .iter()
+            .zip(&edges)
+            .map(|(color, edge)| match edge {
+                // Denoised pixels are displayed from their filtered linear color
+                Some(edge) if self.config.denoise.is_none() => pack_color(&edge.display),
+                _ => pack_color(&self.display_color(self.config.grade.apply(*color))),
+            })
+            .collect();
+        let frame = Frame {
+            image: Image::new(width, height, data),
+            linear,
+            aovs,
+        };
+        (frame, probe_rays + edge_rays)
+    }
+
+    /// Second pass of edge-directed anti-aliasing: every pixel differing from a
+    /// 4-neighbour in `probes` is traced again with `samples` rays laid out by
+    /// `aa_pattern` and combined by `aa_combine`. `None` for the other pixels. Returns
+    /// them with the rays cast.
+    fn supersample_edges(
+        &self,
+        camera: &Camera,
+        width: u32,
+        height: u32,
+        probes: &[EdgeProbe],
+        samples: u32,
+    ) -> (Vec<Option<EdgeSamples>>, RayCount) {
+        let view = self.view_plane(camera, width, height);
+        let (width, height) = (width as usize, height as usize);
+        let mut edges = vec![None; width * height];
+        let rays = edges
+            .par_chunks_mut(width)
+            .enumerate()
+            .map(|(y, row)| {
+                count_rays(|| {
+                    for (x, edge) in row.iter_mut().enumerate() {
+                        if self.is_cancelled() {
+                            return;
+                        }
+                        let index = y * width + x;
+                        let probe = &probes[index];
+                        let neighbours = [
+                            (x > 0).then(|| index - 1),
+                            (x + 1 < width).then(|| index + 1),
+                            (y > 0).then(|| index - width),
+                            (y + 1 < height).then(|| index + width),
+                        ];
+                        let on_edge = neighbours
+                            .iter()
+                            .flatten()
+                            .any(|&neighbour| probe.differs_from(&probes[neighbour]));
+                        if !on_edge {
+                            continue;
+                        }
+
+                        let mut rng = Rng::new(self.stream_seed(EDGE_AA_SAMPLING_SEED) ^ (index as u64).rotate_left(32));
+                        let linear: Vec<Vector3<f32>> = self
+                            .config
+                            .aa_pattern
+                            .positions(samples, &mut rng)
+                            .into_iter()
+                            .map(|(ox, oy)| {
+                                let ray = view.ray_through(x as f32 + ox, y as f32 + oy);
+                                self.find_color_recursive(&ray, view.primary_path(), None)
+                            })
+                            .collect();
+                        let display: Vec<Vector3<f32>> = linear
+                            .iter()
+                            .map(|color| self.display_color(self.config.grade.apply(*color)).map(|c| c.clamp(0.0, 1.0)))
+                            .collect();
+                        let mean = display.iter().map(luminance).sum::<f32>() / samples as f32;
+                        *edge = Some(EdgeSamples {
+                            display: self.config.aa_combine.combine(&display),
+                            linear: self.config.aa_combine.combine(&linear),
+                            variance: display.iter().map(|c| (luminance(c) - mean).powi(2)).sum::<f32>() / samples as f32,
+                        });
+                        add_primary_rays(samples as u64);
+                    }
+                })
+                .1
+            })
+            .reduce(RayCount::default, |a, b| a + b);
+        (edges, rays)
+    }
+
+    /// Renders the scene as `render` does, along with the sampling AOVs of every pixel.
+    /// Without `edge_aa` each pixel holds its single center sample, so counts are all 1
+    /// and variances 0.
+    pub fn render_sample_aovs(&self) -> Result<(Image, SampleAovs), RayTracerError> {
+        self.render_scene_frame().map(|(frame, _)| (frame.image, frame.aovs))
+    }
+
+    /// Renders the scene once, returning both the display image (as from `render`) and
+    /// the raw float framebuffer behind it: one graded linear color per pixel in
+    /// row-major order, before `clamp_mode`, `lut` and 8-bit quantization, so over-bright
+    /// values survive for external tone mapping or HDR formats. Fails like `render` for
+    /// a scene that cannot be rendered.
+    pub fn render_hdr(&self) -> Result<(Image, Vec<Vector3<f32>>), RayTracerError> {
+        self.check_renderable()?;
+        let (frame, _) = self.render_frame(&self.config.camera, self.config.width, self.config.height);
+        let framebuffer = frame.linear.iter().map(|color| self.config.grade.apply(*color)).collect();
+        Ok((frame.image, framebuffer))
+    }
+
+    /// Renders the scene `passes` times, accumulating the samples, and calls `on_update`
+    /// with the running average after every pass. The first pass traces pixel centers
+    /// and matches `render`; later passes jitter each primary ray within its pixel, so
+    /// edges converge towards their anti-aliased look while the caller shows progress.
+    /// Returns the final average, or `RayTracerError::Render` for a scene that cannot be
+    /// rendered (see `render`).
+    pub fn render_progressive(&self, passes: u32, mut on_update: impl FnMut(&Image)) -> Result<Image, RayTracerError> {
+        self.check_renderable()?;
+        let (width, height) = (self.config.width, self.config.height);
+        let view = self.view_plane(&self.config.camera, width, height);
+        let mut accumulated = vec![Vector3::zeros(); width as usize * height as usize];
+        let mut image = Image::new(width, height, vec![pack_color(&Vector3::zeros()); accumulated.len()]);
+
+        for pass in 0..passes {
+            accumulated
+                .par_chunks_mut(width as usize)
+                .enumerate()
+                .for_each(|(y, row)| {
+                    for (x, sum) in row.iter_mut().enumerate() {
+                        let (dx, dy) = if pass == 0 {
+                            self.config.pixel_offset
+                        } else {
+                            let pixel = (y * width as usize + x) as u64;
+                            let mut rng = Rng::new(self.stream_seed(PROGRESSIVE_SAMPLING_SEED) ^ pixel.rotate_left(32) ^ pass as u64);
+                            (rng.next_f32(), rng.next_f32())
+                        };
+                        let ray = view.ray_through(x as f32 + dx, y as f32 + dy);
+                        *sum += self.final_color(&ray, view.primary_path()).map(|c| c.clamp(0.0, 1.0));
+                    }
+                    add_primary_rays(row.len() as u64);
+                });
+
+            let samples = (pass + 1) as f32;
+            image.data = accumulated.iter().map(|sum| pack_color(&(sum / samples))).collect();
+            on_update(&image);
+        }
+        Ok(image)
+    }
+
+    /// Re-renders the pixels of `region` into `image`, a previous render of this scene,
+    /// leaving the others untouched. Pixels are traced through their centers as by
+    /// `render`; `edge_aa` is not applied.
+    ///
+    /// # Returns
+    /// * `Err(RayTracerError::DimensionMismatch)` - `image` is not the scene's size
+    /// * `Err(RayTracerError::Render)` - `region` extends past the image
+    pub fn render_region(&self, image: &mut Image, region: PixelRegion) -> Result<(), RayTracerError> {
+        let (width, height) = (self.config.width, self.config.height);
+        if (image.width, image.height) != (width, height) {
+            return Err(RayTracerError::DimensionMismatch {
+                expected: (width, height),
+                actual: (image.width, image.height),
+            });
+        }
+        let fits = |start: u32, extent: u32, limit: u32| start.checked_add(extent).is_some_and(|end| end <= limit);
+        if !fits(region.x, region.width, width) || !fits(region.y, region.height, height) {
+            return Err(RayTracerError::Render(format!(
+                "Region {}x{} at ({}, {}) extends past the {}x{} image",
+                region.width, region.height, region.x, region.y, width, height
+            )));
+        }
+        let view = self.view_plane(&self.config.camera, width, height);
+        image
+            .data
+            .par_chunks_mut(width as usize)
+            .enumerate()
+            .skip(region.y as usize)
+            .take(region.height as usize)
+            .for_each(|(y, row)| {
+                let columns = region.x as usize..(region.x + region.width) as usize;
+                for (pixel, x) in row[columns.clone()].iter_mut().zip(columns) {
+                    if self.is_cancelled() {
+                        return;
+                    }
+                    *pixel = self.find_color(&view.primary_ray(x, y), view.primary_path());
+                }
+                add_primary_rays(region.width as u64);
+            });
+        Ok(())
+    }
+
+    /// Pixels whose color can change when an object's AABB moves from `old` to `new`
+    /// (`(min, max)` corners), or `None` if the change is not visible.
+    ///
+    /// The region covers the screen projection of both boxes and of the shadows they
+    /// cast: each box extruded away from every light, out to infinity. Reflections and
+    /// image-based lighting can carry a change anywhere, so scenes with `maxdepth` above
+    /// 1 or `ibl` get the whole image, as do boxes reaching behind the camera.
+    pub fn dirty_region(
+        &self,
+        old: (Vector3<f32>, Vector3<f32>),
+        new: (Vector3<f32>, Vector3<f32>),
+    ) -> Option<PixelRegion> {
+        let (width, height) = (self.config.width, self.config.height);
+        let full = Some(PixelRegion::full(width, height));
+        if self.config.maxdepth > 1 || self.config.ibl_samples > 0 {
+            return full;
+        }
+        // Shadow extrusions have no vanishing point under parallel projection
+        if matches!(self.config.camera.projection, Projection::Orthographic { .. }) {
+            return full;
+        }
+        let view = ViewPlane::new(&self.config.camera, width, height);
+        let mut offsets = Vec::new();
+        for (min, max) in [old, new] {
+            for corner in 0..8 {
+                let point = Vector3::new(
+                    if corner & 1 == 0 { min.x } else { max.x },
+                    if corner & 2 == 0 { min.y } else { max.y },
+                    if corner & 4 == 0 { min.z } else { max.z },
+                );
+                offsets.push(point - view.origin);
+                // The shadow volume's far end is seen at the vanishing point of each
+                // extrusion direction
+                for light in self.config.get_lights() {
+                    offsets.push(-light.direction_from(point));
+                }
+            }
+        }
+
+        let (mut left, mut top) = (f32::INFINITY, f32::INFINITY);
+        let (mut right, mut bottom) = (f32::NEG_INFINITY, f32::NEG_INFINITY);
+        for offset in &offsets {
+            let Some((x, y)) = view.project(offset) else {
+                return full;
+            };
+            (left, right) = (left.min(x), right.max(x));
+            (top, bottom) = (top.min(y), bottom.max(y));
+        }
+        // One pixel of margin for rays grazing the projected outline
+        let left = (left.floor() - 1.0).max(0.0);
+        let top = (top.floor() - 1.0).max(0.0);
+        let right = (right.ceil() + 1.0).min(width as f32);
+        let bottom = (bottom.ceil() + 1.0).min(height as f32);
+        if left >= right || top >= bottom {
+            return None;
+        }
+        Some(PixelRegion {
+            x: left as u32,
+            y: top as u32,
+            width: (right - left) as u32,
+            height: (bottom - top) as u32,
+        })
+    }
+
+    /// Replaces object `index` with `shape` and brings `image`, a previous render of
+    /// this scene, up to date by re-rendering only the `dirty_region` of the change.
+    /// Planes are unbounded, so changing one re-renders everything, as do `edge_aa`
+    /// and `denoise` (see `render_region`). Returns the re-rendered region.
+    ///
+    /// # Returns
+    /// * `Err(RayTracerError::Render)` - `index` is not an object of the scene
+    /// * `Err(RayTracerError::DimensionMismatch)` - `image` is not the scene's size
+    pub fn update_object(
+        &mut self,
+        index: usize,
+        shape: Shape,
+        image: &mut Image,
+    ) -> Result<Option<PixelRegion>, RayTracerError> {
+        let (width, height) = (self.config.width, self.config.height);
+        if (image.width, image.height) != (width, height) {
+            return Err(RayTracerError::DimensionMismatch {
+                expected: (width, height),
+                actual: (image.width, image.height),
+            });
+        }
+        let Some(old) = self.config.get_scene_objects().get(index) else {
+            return Err(RayTracerError::Render(format!("No object with index {}", index)));
+        };
+        let bounds = |shape: &Shape| {
+            let aabb = shape.aabb();
+            (aabb.min.coords, aabb.max.coords)
+        };
+        let unbounded = matches!(old, Shape::Plane { .. }) || matches!(shape, Shape::Plane { .. });
+        let old_bounds = bounds(old);
+        let new_bounds = bounds(&shape);
+
+        self.config.get_scene_objects_mut()[index] = shape;
+        let (bvh, planes) = Self::build_bvh(&mut self.config);
+        self.bvh = bvh;
+        self.planes = planes;
+
+        if self.config.edge_aa_samples > 0 || self.config.denoise.is_some() {
+            self.check_renderable()?;
+            *image = self.render_to_image_with_camera(&self.config.camera, width, height);
+            return Ok(Some(PixelRegion::full(width, height)));
+        }
+        let region = if unbounded {
+            Some(PixelRegion::full(width, height))
+        } else {
+            self.dirty_region(old_bounds, new_bounds)
+        };
+        if let Some(region) = region {
+            self.render_region(image, region)?;
+        }
+        Ok(region)
+    }
+
+    /// Renders the scene's camera view once per `(width, height)` entry, e.g. a thumbnail
+    /// and the full-size image. All sizes share this tracer's BVH; only the image-plane
+    /// sampling changes, so the aspect ratio may differ from the scene's `size`.
+    ///
+    /// # Returns
+    /// * `Err(RayTracerError::Render)` - An entry is empty or the scene's `maxdepth` is
+    ///   over the limit; nothing is rendered
+    pub fn render_multi_res(&self, resolutions: &[(u32, u32)]) -> Result<Vec<Image>, RayTracerError> {
+        self.check_depth()?;
+        for &(width, height) in resolutions {
+            Self::check_size(width, height)?;
+        }
+        Ok(resolutions
+            .iter()
+            .map(|&(width, height)| self.render_to_image_with_camera(&self.config.camera, width, height))
+            .collect())
+    }
+
+    /// Renders the primary-ray hit distance of every pixel as a 16-bit depth map.
+    ///
+    /// Each pixel holds a value in `0..=65535` (stored in the low 16 bits of the packed pixel):
+    /// the nearest hit in the frame maps to 0 and the farthest hit to `DEPTH_MAX`.
+    /// Pixels whose primary ray misses every object are set to `DEPTH_MAX` (the far value).
+    /// Use `save_depth_image` to write the result as a 16-bit grayscale PNG. Fails like
+    /// `render` for a scene that cannot be rendered.
+    pub fn render_depth_map(&self) -> Result<Image, RayTracerError> {
+        self.check_renderable()?;
+        let distances = self.trace_pixels(|ray, _| {
+            self.closest_intersection(ray)
+                .map_or(f32::INFINITY, |intersection| intersection.distance)
+        });
+
+        let (near, far) = distances
+            .iter()
+            .filter(|d| d.is_finite())
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(near, far), &d| {
+                (near.min(d), far.max(d))
+            });
+        let range = far - near;
+
+        let depth_data = distances
+            .iter()
+            .map(|&d| {
+                if !d.is_finite() {
+                    DEPTH_MAX
+                } else if range > 0.0 {
+                    ((d - near) / range * DEPTH_MAX as f32).round() as u32
+                } else {
+                    0
+                }
+            })
+            .collect();
+
+        Ok(Image::new(self.config.width, self.config.height, depth_data))
+    }
+
+    /// Work map for profiling: the number of rays traced for each pixel, its primary ray
+    /// plus every shadow, reflection and refraction ray spawned from it. Row-major, one entry per
+    /// pixel. Counting rays rather than timing keeps the map identical across runs and
+    /// thread counts. Every pixel gets one centered sample; `edge_aa` is not applied.
+    /// Fails like `render` for a scene that cannot be rendered.
+    pub fn render_work_map(&self) -> Result<Vec<u32>, RayTracerError> {
+        self.check_renderable()?;
+        Ok(self.trace_pixels(|ray, path| {
+            let (_, secondary) = count_secondary_rays(|| self.find_color(ray, path));
+            1 + secondary as u32
+        }))
+    }
+
+    /// `render_work_map` as a heatmap image: black for the cheapest pixels through red
+    /// and yellow to white for the most expensive one in the frame.
+    pub fn render_heatmap(&self) -> Result<Image, RayTracerError> {
+        let work = self.render_work_map()?;
+        let (least, most) = work
+            .iter()
+            .fold((u32::MAX, 0), |(least, most), &rays| (least.min(rays), most.max(rays)));
+        let range = most.saturating_sub(least).max(1) as f32;
+        let data = work
+            .iter()
+            .map(|&rays| {
+                let heat = 3.0 * (rays - least) as f32 / range;
+                pack_color(&Vector3::new(heat, heat - 1.0, heat - 2.0).map(|c| c.clamp(0.0, 1.0)))
+            })
+            .collect();
+        Ok(Image::new(self.config.width, self.config.height, data))
+    }
+
+    /// Index into the scene's object list of the object seen through each pixel, or
+    /// `NO_OBJECT` where the primary ray escapes. Row-major, one entry per pixel. Fails
+    /// like `render` for a scene that cannot be rendered.
+    pub fn render_object_ids(&self) -> Result<Vec<u32>, RayTracerError> {
+        self.check_renderable()?;
+        Ok(self.trace_pixels(|ray, _| {
+            self.closest_hit(ray)
+                .map_or(NO_OBJECT, |(shape, _)| self.object_index(shape) as u32)
+        }))
+    }
+
+    /// Segmentation pass, e.g. for machine-learning datasets: each pixel's RGB holds
+    /// `render_object_ids` plus one, so every object gets its own exact value that
+    /// survives saving as a lossless image, and background pixels are opaque black.
+    /// Decode with `(pixel & 0xFF_FFFF) - 1`. Scenes with more objects than 24 bits can
+    /// count are rejected.
+    pub fn render_segmentation(&self) -> Result<Image, RayTracerError> {
+        let objects = self.config.get_scene_objects().len();
+        if objects >= 0xFF_FFFF {
+            return Err(RayTracerError::Render(format!(
+                "Cannot encode the ids of {} objects in a segmentation image",
+                objects
+            )));
+        }
+        let data = self
+            .render_object_ids()?
+            .iter()
+            .map(|&id| if id == NO_OBJECT { 0xFF00_0000 } else { 0xFF00_0000 | (id + 1) })
+            .collect();
+        Ok(Image::new(self.config.width, self.config.height, data))
+    }
+
+    /// Unlit albedo AOV: the diffuse color of the surface seen through each pixel, with
+    /// no lighting, shadows, reflections or grading. Pixels whose primary ray escapes
+    /// show the background, as in `render`. Fails like `render` for a scene that cannot
+    /// be rendered.
+    pub fn render_albedo(&self) -> Result<Image, RayTracerError> {
+        self.check_renderable()?;
+        let data = self.trace_pixels(|ray, _| {
+            let albedo = match self.closest_intersection(ray) {
+                Some(intersection) => intersection.material.diffuse_color,
+                None => self.background(&ray.direction),
+            };
+            pack_color(&albedo)
+        });
+        Ok(Image::new(self.config.width, self.config.height, data))
+    }
+
+    /// Normal AOV: the world-space unit normal of the surface seen through each pixel,
+    /// encoded per channel as `0.5 * (n + 1)` (x in red, y in green, z in blue).
+    /// Background pixels are `NORMAL_AOV_BACKGROUND`. Fails like `render` for a scene
+    /// that cannot be rendered.
+    pub fn render_normal_aov(&self) -> Result<Image, RayTracerError> {
+        self.check_renderable()?;
+        let data = self.trace_pixels(|ray, _| match self.closest_intersection(ray) {
+            Some(intersection) => pack_color(&((intersection.normal + Vector3::repeat(1.0)) * 0.5)),
+            None => NORMAL_AOV_BACKGROUND,
+        });
+        Ok(Image::new(self.config.width, self.config.height, data))
+    }
+
+    /// One image per light, in scene order, holding only that light's contribution
+    /// (direct and through reflections). The primary hit of each pixel is found once and
+    /// shaded for every light. Passes are linear and ungraded; adding them to an
+    /// ambient-only render gives the beauty render when no `light_samples` or `ibl` is set.
+    /// Fails like `render` for a scene that cannot be rendered.
+    pub fn render_light_passes(&self) -> Result<Vec<(usize, Image)>, RayTracerError> {
+        self.check_renderable()?;
+        let light_count = self.config.get_lights().len();
+        let per_pixel = self.trace_pixels(|ray, path| match self.closest_intersection(ray) {
+            Some(intersection) => (0..light_count)
+                .map(|index| pack_color(&self.shade(&intersection, ray.direction, path, Some(index))))
+                .collect(),
+            None => vec![pack_color(&Vector3::zeros()); light_count],
+        });
+
+        Ok((0..light_count)
+            .map(|index| {
+                let data = per_pixel.iter().map(|pixel: &Vec<u32>| pixel[index]).collect();
+                (index, Image::new(self.config.width, self.config.height, data))
+            })
+            .collect())
+    }
+
+    /// Casts one primary ray through the center of every pixel of the scene's own view.
+    fn trace_pixels<T, F>(&self, per_pixel: F) -> Vec<T>
+    where
+        T: Clone + Default + Send,
+        F: Fn(&Ray, Bounces) -> T + Sync,
+    {
+        self.trace_view(
+            &self.config.camera,
+            self.config.width,
+            self.config.height,
+            per_pixel,
+        )
+        .0
+    }
+
+    /// Casts one primary ray through the center of every pixel and collects `per_pixel`
+    /// results, given each ray with the state of its path, in row-major order, along
+    /// with the rays cast. Rows are processed in parallel.
+    fn trace_view<T, F>(&self, camera: &Camera, width: u32, height: u32, per_pixel: F) -> (Vec<T>, RayCount)
+    where
+        T: Clone + Default + Send,
+        F: Fn(&Ray, Bounces) -> T + Sync,
+    {
+        let view = self.view_plane(camera, width, height);
+        let width = width as usize;
+        let height = height as usize;
+
+        let mut data = vec![T::default(); width * height];
+
+        let rays = data
+            .par_chunks_mut(width)
+            .enumerate()
+            .map(|(y, row)| {
+                count_rays(|| {
+                    for (x, value) in row.iter_mut().enumerate() {
+                        if self.is_cancelled() {
+                            return;
+                        }
+                        *value = per_pixel(&view.primary_ray(x, y), view.primary_path());
+                    }
+                    add_primary_rays(row.len() as u64);
+                })
+                .1
+            })
+            .reduce(RayCount::default, |a, b| a + b);
+
+        (data, rays)
+    }
+
+    /// Primary rays of `camera` at `width`x`height` through the scene's `pixel_offset`,
+    /// carrying their pixel footprint unless `texture_filter` is off.
+    fn view_plane(&self, camera: &Camera, width: u32, height: u32) -> ViewPlane {
+        let view = ViewPlane::new(camera, width, height).with_pixel_offset(self.config.pixel_offset);
+        if self.config.texture_filter {
+            view
+        } else {
+            view.without_footprint()
+        }
+    }
+
+    /// Seed of the random stream `stream` under the scene's `seed`.
+    fn stream_seed(&self, stream: u64) -> u64 {
+        stream ^ self.config.seed.unwrap_or(0)
+    }
+
+    /// Traces one ray through the scene for debugging a pixel: returns the packed color
+    /// `render` would give a primary ray from `origin` along `direction` (normalized
+    /// here), and the nearest hit it shaded, `None` if the ray escapes.
+    pub fn trace_single_ray(&self, origin: Vector3<f32>, direction: Vector3<f32>) -> (u32, Option<RayHit>) {
+        let view = self.view_plane(&self.config.camera, self.config.width, self.config.height);
+        let ray = Ray {
+            origin,
+            direction: direction.normalize(),
+            spread: if view.filter_textures { view.footprint.spread } else { 0.0 },
+        };
+        (self.find_color(&ray, view.primary_path()), self.raycast(origin, direction))
+    }
+
+    /// Nearest object hit by the ray from `origin` along `direction` (normalized here),
+    /// found through the BVH as for rendering: hits outside the scene's clipping range
+    /// are ignored. `None` if the ray escapes. For picking and tools built on the scene.
+    pub fn raycast(&self, origin: Vector3<f32>, direction: Vector3<f32>) -> Option<RayHit> {
+        let ray = Ray::new(origin, direction.normalize());
+        self.closest_hit(&ray).map(|(shape, intersection)| RayHit {
+            object_index: self.object_index(shape),
+            object_kind: shape.kind(),
+            distance: intersection.distance,
+            point: intersection.point,
+            normal: intersection.normal,
+            is_back_face: intersection.is_back_face,
+        })
+    }
+
+    /// BVH leaf and AABB of every object, ordered by node index, to inspect how the
+    /// tree partitioned the scene. Plane AABBs are the fitted ones (see `fit_plane_aabb`).
+    pub fn bvh_leaves(&self) -> Vec<BvhLeaf> {
+        let mut leaves: Vec<BvhLeaf> = self
+            .config
+            .get_scene_objects()
+            .iter()
+            .enumerate()
+            .map(|(object_index, shape)| {
+                let aabb = shape.aabb();
+                BvhLeaf {
+                    node_index: shape.bh_node_index(),
+                    object_index,
+                    object_kind: shape.kind(),
+                    min: aabb.min.coords,
+                    max: aabb.max.coords,
+                }
+            })
+            .collect();
+        leaves.sort_by_key(|leaf| (leaf.node_index, leaf.object_index));
+        leaves
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn get_output_path(&self) -> &str {
+        &self.config.output_file
+    }
+
+    fn find_color(&self, ray: &Ray, path: Bounces) -> u32 {
+        pack_color(&self.final_color(ray, path))
+    }
+
+    /// Graded, range-mapped color seen along a primary ray, before quantization.
+    fn final_color(&self, ray: &Ray, path: Bounces) -> Vector3<f32> {
+        self.display_color(self.config.grade.apply(self.find_color_recursive(ray, path, None)))
+    }
+
+    /// Output stage after grading: `clamp_mode`, then the scene's `lut` if any.
+    fn display_color(&self, graded: Vector3<f32>) -> Vector3<f32> {
+        let color = self.config.clamp_mode.apply(graded);
+        match &self.config.lut {
+            Some(lut) => lut.apply(color),
+            None => color,
+        }
+    }
+
+    /// Finds the nearest intersection of `ray` with the scene.
+    fn closest_intersection(&self, ray: &Ray) -> Option<Intersection> {
+        self.closest_hit(ray).map(|(_, intersection)| intersection)
+    }
+
+    /// Position of `shape` in the scene's object list. `shape` must be borrowed from
+    /// that list, as returned by `closest_hit`.
+    fn object_index(&self, shape: &Shape) -> usize {
+        let base = self.config.get_scene_objects().as_ptr() as usize;
+        (shape as *const Shape as usize - base) / std::mem::size_of::<Shape>()
+    }
+
+    /// Nearest intersection of `ray` with the scene, with the object that was hit.
+    fn closest_hit(&self, ray: &Ray) -> Option<(&Shape, Intersection)> {
+        // Use BVH to get candidate objects that the ray might intersect.
+        // This is the key optimization: instead of testing all objects, the BVH
+        // quickly identifies only the objects whose bounding boxes intersect the ray.
+        let candidates = self.candidates(ray);
+
+        // Find closest intersection among candidates returned by BVH, ignoring hits
+        // outside the clipping range. Coincident surfaces are resolved in favour of the
+        // object defined first, so the result does not depend on traversal order.
+        let (clip_near, clip_far) = (self.config.clip_near, self.config.clip_far);
+        candidates
+            .into_iter()
+            .filter_map(|object| object.intersect(ray).map(|hit| (object, hit)))
+            .filter(|(_, hit)| hit.distance >= clip_near && hit.distance <= clip_far)
+            .min_by(|(a_object, a), (b_object, b)| {
+                a.distance
+                    .partial_cmp(&b.distance)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+                    .then_with(|| self.object_index(a_object).cmp(&self.object_index(b_object)))
+            })
+    }
+
+    /// Objects `ray` may hit: the BVH's candidates plus every plane, since a plane's
+    /// fitted AABB does not cover hits far outside the scene.
+    fn candidates(&self, ray: &Ray) -> Vec<&Shape> {
+        let objects = self.config.get_scene_objects();
+        let mut candidates: Vec<&Shape> = self
+            .bvh
+            .traverse(&ray.to_bvh_ray(), objects)
+            .into_iter()
+            .filter(|object| !matches!(object, Shape::Plane { .. }))
+            .collect();
+        candidates.extend(self.planes.iter().map(|&index| &objects[index]));
+        candidates
+    }
+
+    /// Objects `ray` may hit before `max_t`: `candidates` with the BVH traversal cut off
+    /// at that distance, so occluders beyond a point light are never tested.
+    fn candidates_within(&self, ray: &Ray, max_t: f32) -> Vec<&Shape> {
+        let objects = self.config.get_scene_objects();
+        let mut candidates: Vec<&Shape> = traverse_limited(&self.bvh, &ray.to_bvh_ray(), max_t, objects)
+            .into_iter()
+            .filter(|object| !matches!(object, Shape::Plane { .. }))
+            .collect();
+        candidates.extend(self.planes.iter().map(|&index| &objects[index]));
+        candidates
+    }
+
+    /// Shininess used for the highlight at `intersection`, capped so the highlight spans
+    /// at least `HIGHLIGHT_MIN_PIXELS` pixels.
+    ///
+    /// A Blinn-Phong lobe `n_dot_h ^ s` falls off over a half-vector angle of about
+    /// `1 / sqrt(s)`. Across one pixel of `footprint` the normal turns by `spread *
+    /// distance * curvature` (plus about half the view angle for the half vector itself),
+    /// or by `span * curvature` under parallel orthographic rays, so with
+    /// one sample per pixel a narrower lobe is caught by some frames and missed by
+    /// others as the camera moves. Moderate shininess is far below the cap and left
+    /// unchanged; `specular_aa off` disables the cap.
+    fn stable_shininess(&self, intersection: &Intersection, footprint: PixelFootprint) -> f32 {
+        let shininess = intersection.material.shininess;
+        if !self.config.specular_aa {
+            return shininess;
+        }
+        let sweep = footprint.spread * (intersection.distance * intersection.curvature + 0.5)
+            + footprint.span * intersection.curvature;
+        let limit = 1.0 / (HIGHLIGHT_MIN_PIXELS * sweep).powi(2);
+        shininess.min(limit.max(1.0))
+    }
+
+    /// Direct lighting from one light at `intersection`, zero if the light is occluded
+    /// or the point lies beyond the light's cutoff.
+    fn light_contribution(
+        &self,
+        light: &Light,
+        intersection: &Intersection,
+        direction: Vector3<f32>,
+        footprint: PixelFootprint,
+    ) -> Vector3<f32> {
+        // Out of reach: no shadow ray needed
+        if !light.reaches(intersection.point) {
+            return Vector3::zeros();
+        }
+        let light_dir = light.direction_from(intersection.point);
+        let n_dot_l = intersection.normal.dot(&light_dir).max(0.0);
+        // A light behind the surface adds nothing (except the unclamped specular used by
+        // shininess 1), so skip the shadow traversal entirely.
+        if n_dot_l <= 0.0 && intersection.material.shininess != 1.0 {
+            return Vector3::zeros();
+        }
+
+        // shadow ray
+        let shadow_ray = Ray::new(intersection.point + intersection.normal * 1e-6, light_dir);
+        add_shadow_ray();
+
+        // Use BVH for shadow ray testing. This is particularly beneficial for complex
+        // scenes with many objects, as shadow rays are cast for every intersection point
+        // and every light source. BVH drastically reduces the number of intersection tests,
+        // and for a point light the traversal stops at the light.
+        let shadow_candidates = if self.brute_force_shadows {
+            self.config.get_scene_objects().iter().collect()
+        } else {
+            match light {
+                Point { position, .. } => {
+                    self.candidates_within(&shadow_ray, (*position - intersection.point).norm())
+                }
+                Directional { .. } => self.candidates(&shadow_ray),
+            }
+        };
+
+        let in_shadow = shadow_candidates
+            .iter()
+            .filter_map(|object| object.intersect(&shadow_ray))
+            .any(|shadow_intersection| {
+                // Hits within the bias are the shaded surface itself (or rounding noise
+                // around it), never a real occluder between the point and the light
+                if shadow_intersection.distance <= self.config.shadow_bias {
+                    return false;
+                }
+                if intersection.is_back_face && shadow_intersection.is_back_face {
+                    return false;
+                }
+                match light {
+                    Point { position, .. } => {
+                        shadow_intersection.distance < (*position - intersection.point).norm()
+                    }
+                    // Infinitely far: any hit past the bias is between the point and the light
+                    Directional { .. } => true,
+                }
+            });
+        if in_shadow {
+            return Vector3::zeros();
+        }
+
+        let light_color = light.color();
+        let diffuse = intersection.material.diffuse_color * n_dot_l;
+        let view_dir = -direction;
+        let half_vector = (light_dir + view_dir).normalize();
+        let n_dot_h = intersection.normal.dot(&half_vector).max(0.0);
+
+        let specular_factor = if intersection.material.shininess == 1.0 {
+            n_dot_h
+        } else if intersection.material.shininess == 0.0 {
+            if n_dot_l > 0.0 { n_dot_h } else { 0.0 }
+        } else if n_dot_l <= 0.0 {
+            0.0
+        } else if intersection.material.anisotropy > 0.0 {
+            anisotropic_specular(intersection, &half_vector, n_dot_h, self.stable_shininess(intersection, footprint))
+        } else {
+            n_dot_h.powf(self.stable_shininess(intersection, footprint))
+        };
+
+        let specular = intersection.material.specular_color * specular_factor;
+        (diffuse + specular).component_mul(&light_color)
+    }
+
+    /// Estimates the summed contribution of all lights by shading only `samples` of them.
+    ///
+    /// Lights are picked (with replacement) with probability proportional to their unshadowed
+    /// diffuse estimate `luminance(color) * max(n.l, 0)`, and each pick is divided by its
+    /// probability, so the estimate converges to the all-lights result. Lights facing away
+    /// from the surface or cut off before reaching it are never picked.
+    fn sample_lights(
+        &self,
+        intersection: &Intersection,
+        direction: Vector3<f32>,
+        samples: usize,
+        footprint: PixelFootprint,
+    ) -> Vector3<f32> {
+        let lights = self.config.get_lights();
+        let weights: Vec<f32> = lights
+            .iter()
+            .map(|light| {
+                if !light.reaches(intersection.point) {
+                    return 0.0;
+                }
+                let n_dot_l = intersection.normal.dot(&light.direction_from(intersection.point));
+                luminance(&light.color()) * n_dot_l.max(0.0)
+            })
+            .collect();
+        let total_weight: f32 = weights.iter().sum();
+        if total_weight <= 0.0 {
+            return Vector3::zeros();
+        }
+
+        let mut rng = Rng::from_point(intersection.point, self.stream_seed(LIGHT_SAMPLING_SEED));
+        let mut estimate = Vector3::zeros();
+        for _ in 0..samples {
+            let mut target = rng.next_f32() * total_weight;
+            // Fall back to the last light with a nonzero weight if rounding overshoots
+            let mut chosen = weights.iter().rposition(|w| *w > 0.0).unwrap_or(0);
+            for (i, weight) in weights.iter().enumerate() {
+                if *weight > 0.0 && target < *weight {
+                    chosen = i;
+                    break;
+                }
+                target -= weight;
+            }
+            let probability = weights[chosen] / total_weight;
+            estimate += self.light_contribution(&lights[chosen], intersection, direction, footprint)
+                / (probability * samples as f32);
+        }
+        estimate
+    }
+
+    /// Diffuse light received from the environment map, estimated with cosine-weighted
+    /// samples. The cosine and the 1/pi of the Lambert BRDF cancel against the sampling
+    /// density, leaving the mean unoccluded radiance times the diffuse color.
+    fn environment_lighting(&self, environment: &EnvironmentMap, intersection: &Intersection) -> Vector3<f32> {
+        let samples = self.config.ibl_samples;
+        let origin = intersection.point + intersection.normal * 1e-6;
+        let mut rng = Rng::from_point(intersection.point, self.stream_seed(IBL_SAMPLING_SEED));
+        let mut radiance = Vector3::zeros();
+        for _ in 0..samples {
+            let direction = cosine_sample_hemisphere(&intersection.normal, &mut rng);
+            add_shadow_ray();
+            if self.closest_intersection(&Ray::new(origin, direction)).is_none() {
+                radiance += environment.radiance(&direction);
+            }
+        }
+        intersection.material.diffuse_color.component_mul(&radiance) / samples as f32
+    }
+
+    /// Mean color reflected by a rough surface: reflected rays jittered within a cone
+    /// around the mirror direction whose half-angle grows with the material's roughness.
+    /// Jittered rays dipping below the surface fall back to the mirror direction.
+    fn glossy_reflection(
+        &self,
+        intersection: &Intersection,
+        origin: Vector3<f32>,
+        mirror_dir: Vector3<f32>,
+        facing_normal: Vector3<f32>,
+        bounces: Bounces,
+        only_light: Option<usize>,
+    ) -> Vector3<f32> {
+        let half_angle = intersection.material.roughness * std::f32::consts::FRAC_PI_4;
+        let samples = if bounces.total() == 0 { GLOSSY_SAMPLES } else { 1 };
+        let mut rng = Rng::from_point(intersection.point, self.stream_seed(GLOSSY_SAMPLING_SEED));
+        let mut color = Vector3::zeros();
+        for _ in 0..samples {
+            let mut direction = sample_cone(&mirror_dir, half_angle, &mut rng);
+            if direction.dot(&facing_normal) <= 0.0 {
+                direction = mirror_dir;
+            }
+            add_reflection_ray();
+            color += self.reflected_color(&Ray::new(origin, direction), bounces.reflected(), only_light);
+        }
+        color / samples as f32
+    }
+
+    /// Color seen along a reflected ray, faded with the distance to what it hits by the
+    /// scene's `reflection_falloff`. Rays that escape keep the background at full
+    /// strength, so sky reflections do not go black.
+    fn reflected_color(&self, ray: &Ray, bounces: Bounces, only_light: Option<usize>) -> Vector3<f32> {
+        let Some(falloff) = self.config.reflection_falloff else {
+            return self.find_color_recursive(ray, bounces, only_light);
+        };
+        if bounces.total() > self.config.maxdepth.min(MAX_DEPTH) {
+            return Vector3::zeros();
+        }
+        let hit = self.closest_intersection(ray);
+        let fade = hit.as_ref().map_or(1.0, |hit| (-hit.distance / falloff).exp());
+        self.shade_hit(hit.as_ref(), ray.direction, bounces, only_light) * fade
+    }
+
+    /// Color transmitted through a transparent surface: the ray bends by Snell's law
+    /// with the material's `ior` (entering the object when it hits the front face,
+    /// leaving it otherwise), or is mirrored on total internal reflection.
+    fn refracted_color(
+        &self,
+        intersection: &Intersection,
+        direction: Vector3<f32>,
+        bounces: Bounces,
+        only_light: Option<usize>,
+    ) -> Vector3<f32> {
+        let ior = intersection.transmission.ior;
+        let (normal, eta) = if direction.dot(&intersection.normal) < 0.0 {
+            (intersection.normal, 1.0 / ior)
+        } else {
+            (-intersection.normal, ior)
+        };
+        let cos_in = -direction.dot(&normal);
+        let sin2_out = eta * eta * (1.0 - cos_in * cos_in);
+        let refract_dir = if sin2_out > 1.0 {
+            direction + 2.0 * cos_in * normal
+        } else {
+            eta * direction + (eta * cos_in - (1.0 - sin2_out).sqrt()) * normal
+        };
+        // Start just past the surface, on the side the ray continues into
+        let side = if refract_dir.dot(&normal) < 0.0 { -normal } else { normal };
+        let origin = intersection.point + side * REFRACTION_OFFSET;
+        add_refraction_ray();
+        self.find_color_recursive(&Ray::new(origin, refract_dir.normalize()), bounces.refracted(), only_light)
+    }
+
+    /// Color seen along a ray. With `only_light` set, only that light's direct and
+    /// reflected contribution is returned: no ambient, environment or background.
+    fn find_color_recursive(&self, ray: &Ray, bounces: Bounces, only_light: Option<usize>) -> Vector3<f32> {
+        // Also bounds configs built in code, which skip the parser's maxdepth check
+        if bounces.total() > self.config.maxdepth.min(MAX_DEPTH) {
+            return Vector3::zeros();
+        }
+        // A single pixel can branch into more paths than a timeout allows for
+        if self.is_cancelled() {
+            return Vector3::zeros();
+        }
+
+        self.shade_hit(self.closest_intersection(ray).as_ref(), ray.direction, bounces, only_light)
+    }
+
+    /// Color carried back along `direction` from `hit`, or from the background when the
+    /// ray escaped.
+    fn shade_hit(
+        &self,
+        hit: Option<&Intersection>,
+        direction: Vector3<f32>,
+        bounces: Bounces,
+        only_light: Option<usize>,
+    ) -> Vector3<f32> {
+        match hit {
+            Some(intersection) => self.shade(intersection, direction, bounces, only_light),
+            None if only_light.is_some() => Vector3::zeros(),
+            None => self.background(&direction),
+        }
+    }
+
+    fn shade(
+        &self,
+        intersection: &Intersection,
+        direction: Vector3<f32>,
+        bounces: Bounces,
+        only_light: Option<usize>,
+    ) -> Vector3<f32> {
+        let maxdepth = self.config.maxdepth.min(MAX_DEPTH);
+        let lights = self.config.get_lights();
+        let mut final_color = match only_light {
+            Some(index) => self.light_contribution(&lights[index], intersection, direction, bounces.footprint),
+            None => {
+                // Accumulate light contributions from all light sources
+                let mut light_accumulator = Vector3::zeros();
+                let light_samples = self.config.light_samples as usize;
+
+                if light_samples == 0 || light_samples >= lights.len() {
+                    for light in lights {
+                        light_accumulator += self.light_contribution(light, intersection, direction, bounces.footprint);
+                    }
+                } else {
+                    light_accumulator = self.sample_lights(intersection, direction, light_samples, bounces.footprint);
+                }
+                if let Some(environment) = &self.config.environment {
+                    if self.config.ibl_samples > 0 {
+                        light_accumulator += self.environment_lighting(environment, intersection);
+                    }
+                }
+
+                let ambient = intersection
+                    .material
+                    .ambient
+                    .unwrap_or_else(|| self.config.ambient.color_at(&intersection.normal));
+                light_accumulator + ambient
+            }
+        };
+        
+        let can_bounce = bounces.total() + 1 < maxdepth;
+
+        // Past the refraction budget a transparent surface is shaded as opaque
+        let transparency = intersection.transmission.transparency;
+        if transparency > 0.0 && can_bounce && bounces.refractions < self.config.max_refract {
+            let refracted = self.refracted_color(intersection, direction, bounces, only_light);
+            final_color = final_color * (1.0 - transparency) + refracted * transparency;
+        }
+
+        let is_reflective = intersection.material.specular_color.x > 0.0 
+            || intersection.material.specular_color.y > 0.0 
+            || intersection.material.specular_color.z > 0.0;
+        
+        if is_reflective && can_bounce && bounces.reflections < self.config.max_reflect {
+            let reflect_dir = direction - 2.0 * direction.dot(&intersection.normal) * intersection.normal;
+            
+            // Offset towards the side the ray came from: a plane's normal may point
+            // away from the viewer, and offsetting along it would start the reflected
+            // ray behind the mirror
+            let facing_normal = if direction.dot(&intersection.normal) > 0.0 {
+                -intersection.normal
+            } else {
+                intersection.normal
+            };
+            let reflect_origin = intersection.point + facing_normal * 1e-6;
+            
+            let reflected_color = if intersection.material.roughness > 0.0 {
+                self.glossy_reflection(intersection, reflect_origin, reflect_dir, facing_normal, bounces, only_light)
+            } else {
+                add_reflection_ray();
+                self.reflected_color(&Ray::new(reflect_origin, reflect_dir), bounces.reflected(), only_light)
+            };
+            
+            let reflection_contribution = intersection.material.specular_color.component_mul(&reflected_color);
+            final_color += reflection_contribution;
+        }
+        
+        final_color
+    }
+
+    /// Color seen by a ray that escapes the scene
+    fn background(&self, direction: &Vector3<f32>) -> Vector3<f32> {
+        match &self.config.environment {
+            Some(environment) => environment.radiance(direction),
+            None => Vector3::zeros(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imgcomparator::file_to_image;
+    use crate::imgcomparator::save_image;
+    use crate::imgcomparator::Image;
+    use crate::raytracer::ParsedConfigState;
+    use std::path::Path;
+
+    const SAVE_DIFF_IMAGES: bool = true;
+
+    #[test]
+    fn test_raytracer_tp31() {
+        test_file("test_file/jalon3/tp31");
+    }
+    #[test]
+    fn test_raytracer_tp32() {
+        test_file("test_file/jalon3/tp32");
+    }
+
+    #[test]
+    fn test_raytracer_tp33() {
+        test_file("test_file/jalon3/tp33");
+    }
+
+    #[test]
+    fn test_raytracer_tp34() {
+        test_file("test_file/jalon3/tp34");
+    }
+
+    #[test]
+    fn test_raytracer_tp35() {
+        test_file("test_file/jalon3/tp35");
+    }
+
+    #[test]
+    fn test_raytracer_tp51diffuse() {
+        test_file("test_file/jalon5/tp51-diffuse");
+    }
+
+    #[test]
+    fn test_raytracer_tp51specular() {
+        test_file("test_file/jalon5/tp51-specular");
+    }
+
+    #[test]
+    fn test_raytracer_tp52() {
+        test_file("test_file/jalon5/tp52");
+    }
+
+    #[test]
+    fn test_raytracer_tp53() {
+        test_file("test_file/jalon5/tp53");
+    }
+
+    #[test]
+    fn test_raytracer_tp54() {
+        test_file("test_file/jalon5/tp54");
+    }
+
+    #[test]
+    fn test_raytracer_tp55() {
+        test_file("test_file/jalon5/tp55");
+    }
+
+    #[test]
+    fn test_raytracer_tp61directional() {
+        test_file("test_file/jalon6/tp61-dir");
+    }
+
+    #[test]
+    fn test_raytracer_tp61() {
+        test_file("test_file/jalon6/tp61");
+    }
+
+    #[test]
+    fn test_raytracer_tp62_1() {
+        test_file("test_file/jalon6/tp62-1");
+    }
+
+    #[test]
+    fn test_raytracer_tp62_2() {
+        test_file("test_file/jalon6/tp62-2");
+    }
+
+    #[test]
+    fn test_raytracer_tp62_3() {
+        test_file("test_file/jalon6/tp62-3");
+    }
+
+    #[test]
+    fn test_raytracer_tp62_4() {
+        test_file("test_file/jalon6/tp62-4");
+    }
+
+    #[test]
+    fn test_raytracer_tp62_5() {
+        test_file("test_file/jalon6/tp62-5");
+    }
+
+    #[test]
+    fn test_raytracer_tp63() {
+        test_file("test_file/jalon6/tp63");
+    }
+
+    #[test]
+    fn test_raytracer_tp64() {
+        test_file("test_file/jalon6/tp64");
+    }
+
+    #[test]
+    fn test_depth_map_orders_spheres_by_distance() {
+        let scene = "size 40 20
+camera 0 0 10 0 0 0 0 1 0 45
+sphere -2 0 3 1
+sphere 2 0 -3 1
+";
+        let config = ParsedConfigState::new()
+            .load_config_str(scene)
+            .expect("Failed to parse scene");
+        let ray_tracer = RayTracer::new(config);
+        let depth = ray_tracer.render_depth_map().unwrap();
+
+        let near_sphere = depth.data[10 * 40 + 12];
+        let far_sphere = depth.data[10 * 40 + 23];
+        let background = depth.data[0];
+
+        assert!(near_sphere < far_sphere);
+        assert!(far_sphere <= DEPTH_MAX);
+        assert_eq!(background, DEPTH_MAX);
+    }
+
+    #[test]
+    fn test_render_mesh_instances() {
+        let mut scene = String::from(
+            "size 50 50
+camera 0 0 30 0 0 0 0 1 0 45
+directional 0 0 1 1 1 1
+diffuse 1 0 0
+maxverts 4
+vertex -0.5 -0.5 0
+vertex 0.5 -0.5 0
+vertex 0.5 0.5 0
+vertex -0.5 0.5 0
+mesh square
+tri 0 1 2
+tri 0 2 3
+endmesh
+",
+        );
+        // 10x10 grid of unit squares, spaced 2 apart and centered on the origin
+        for i in 0..10 {
+            for j in 0..10 {
+                let x = i as f32 * 2.0 - 9.0;
+                let y = j as f32 * 2.0 - 9.0;
+                scene.push_str(&format!("instance square translate {x} {y} 0\n"));
+            }
+        }
+        let config = ParsedConfigState::new().load_config_str(&scene).unwrap();
+        assert_eq!(config.get_scene_objects().len(), 100);
+
+        let image = RayTracer::new(config).render().unwrap();
+
+        // Pixel (27, 23) lands on the square at (1, 1); the center pixel falls in the gap
+        // between squares and stays black
+        assert_eq!(image.data[23 * 50 + 27] & 0xFFFFFF, 0xFF0000);
+        assert_eq!(image.data[25 * 50 + 25] & 0xFFFFFF, 0x000000);
+    }
+
+    #[test]
+    fn test_material_ambient_brightens_shadowed_object() {
+        // Both spheres are lit from behind, so only the ambient term reaches the camera
+        let scene = "size 40 20
+camera 0 0 10 0 0 0 0 1 0 45
+ambient 0.1 0.1 0.1
+directional 0 0 -1 1 1 1
+diffuse 0.5 0.5 0.5
+sphere -2 0 0 1
+material_ambient 0.4 0.4 0.4
+sphere 2 0 0 1
+";
+        let config = ParsedConfigState::new().load_config_str(scene).unwrap();
+        let image = RayTracer::new(config).render().unwrap();
+
+        let plain = image.data[10 * 40 + 15] & 0xFFFFFF;
+        let self_lit = image.data[10 * 40 + 24] & 0xFFFFFF;
+        assert_eq!(plain, 0x1A1A1A);
+        assert_eq!(self_lit, 0x666666);
+    }
+
+    #[test]
+    fn test_hemisphere_ambient_follows_normal() {
+        // No lights: the floor (normal +Y) shows the sky color, the ceiling (normal -Y)
+        // the ground color
+        let scene = "size 20 20
+camera 0 0 0 0 0 -1 0 1 0 60
+ambient_hemi 0.4 0.6 0.8 0.2 0.1 0
+plane 0 -1 0 0 1 0
+plane 0 1 0 0 -1 0
+";
+        let config = ParsedConfigState::new().load_config_str(scene).unwrap();
+        let image = RayTracer::new(config).render().unwrap();
+
+        assert_eq!(image.data[19 * 20 + 10] & 0xFFFFFF, 0x6699CC);
+        assert_eq!(image.data[10] & 0xFFFFFF, 0x331A00);
+    }
+
+    #[test]
+    fn test_environment_lights_sphere_without_lights() {
+        let scene = "size 40 40
+camera 0 0 5 0 0 0 0 1 0 45
+diffuse 0.8 0.8 0.8
+sphere 0 0 0 1
+";
+        // Bright sky over a dim ground
+        let (width, height) = (16, 8);
+        let texels = (0..width * height)
+            .map(|i| if i / width < height / 2 { Vector3::repeat(1.0) } else { Vector3::repeat(0.1) })
+            .collect::<Vec<_>>();
+
+        let mut config = ParsedConfigState::new().load_config_str(scene).unwrap();
+        config.environment = Some(EnvironmentMap::new(width, height, texels.clone()).unwrap());
+        let unlit = RayTracer::new(config).render().unwrap();
+
+        let mut config = ParsedConfigState::new().load_config_str(scene).unwrap();
+        config.environment = Some(EnvironmentMap::new(width, height, texels).unwrap());
+        config.ibl_samples = 64;
+        let lit = RayTracer::new(config).render().unwrap();
+
+        let brightness = |image: &Image, x: usize, y: usize| {
+            let (r, g, b) = crate::imgcomparator::extract_rgb(image.data[y * 40 + x]);
+            r + g + b
+        };
+        // The background shows the environment, the sphere stays black without ibl
+        assert_eq!(brightness(&unlit, 0, 0), 3 * 255);
+        assert_eq!(brightness(&unlit, 20, 12), 0);
+
+        let top = brightness(&lit, 20, 12);
+        let bottom = brightness(&lit, 20, 28);
+        assert!(bottom > 0, "sphere should be lit by the environment alone");
+        assert!(top > 2 * bottom, "sky-facing side ({top}) should outshine ground-facing side ({bottom})");
+    }
+
+    #[test]
+    fn test_skydome_lights_diffuse_surface_without_lights() {
+        let scene = "size 20 20
+camera 0 0 5 0 0 0 0 1 0 45
+diffuse 0.8 0.6 0.4
+sphere 0 0 0 1
+";
+        let dark = RayTracer::new(ParsedConfigState::new().load_config_str(scene).unwrap()).render().unwrap();
+        assert_eq!(dark.data[10 * 20 + 10] & 0xFFFFFF, 0);
+
+        let config = ParsedConfigState::new()
+            .load_config_str(&format!("{scene}skydome 1 1 1 8\n"))
+            .unwrap();
+        let lit = RayTracer::new(config).render().unwrap();
+        // Nothing occludes a lone sphere, so every sample sees the unit dome: the
+        // surface shows its diffuse color, and escaping rays show the dome itself
+        assert_eq!(lit.data[10 * 20 + 10] & 0xFFFFFF, 0xCC9966);
+        assert_eq!(lit.data[0] & 0xFFFFFF, 0xFFFFFF);
+
+        for invalid in ["skydome 1 1 1 0\n", "skydome 1 1 1\n", "skydome -1 1 1 4\n"] {
+            assert!(ParsedConfigState::new().load_config_str(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_clip_far_hides_distant_object() {
+        // The red sphere is 9 units away, the green one 29 units away behind it
+        let scene = |clip: &str| {
+            format!(
+                "size 40 20
+camera 0 0 10 0 0 0 0 1 0 45
+{clip}
+ambient 0.2 0.2 0.2
+diffuse 0.8 0 0
+sphere -2 0 0 1
+diffuse 0 0.8 0
+sphere 8 0 -20 2
+"
+            )
+        };
+        let render = |clip: &str| {
+            RayTracer::new(ParsedConfigState::new().load_config_str(&scene(clip)).unwrap())
+                .render()
+                .unwrap()
+        };
+        let unclipped = render("");
+        let clipped = render("clip_far 20");
+
+        assert_eq!(unclipped.data[10 * 40 + 27] & 0xFFFFFF, 0x333333);
+        assert_eq!(clipped.data[10 * 40 + 27] & 0xFFFFFF, 0);
+        assert_eq!(clipped.data[10 * 40 + 15], unclipped.data[10 * 40 + 15]);
+        assert_ne!(clipped.data[10 * 40 + 15] & 0xFFFFFF, 0);
+    }
+
+    #[test]
+    fn test_mirror_floor_reflects_sphere() {
+        // Seen from above the floor, the sphere sits above the image center and its
+        // mirror image below it. The mirror shows the unlit underside, hence the
+        // sphere's own ambient term.
+        let scene = |maxdepth: u32, floor_normal: &str| {
+            format!(
+                "size 40 40
+camera 0 1 6 0 0 0 0 1 0 45
+maxdepth {maxdepth}
+point 2 4 4 1 1 1
+diffuse 0.5 0 0
+material_ambient 0.5 0 0
+sphere 0 1 0 0.5
+material_ambient none
+diffuse 0 0 0
+specular 1 1 1
+shininess 500
+plane 0 0 0 {floor_normal}
+"
+            )
+        };
+        let reflected_rows = |maxdepth: u32, floor_normal: &str| {
+            let config = ParsedConfigState::new().load_config_str(&scene(maxdepth, floor_normal)).unwrap();
+            let image = RayTracer::new(config).render().unwrap();
+            (20..40)
+                .filter(|y| {
+                    let (r, g, _) = crate::imgcomparator::extract_rgb(image.data[y * 40 + 20]);
+                    r > 100 && g < 60
+                })
+                .count()
+        };
+
+        assert_eq!(reflected_rows(1, "0 1 0"), 0);
+        assert!(reflected_rows(2, "0 1 0") > 0);
+        // The plane's orientation must not matter
+        assert_eq!(reflected_rows(2, "0 -1 0"), reflected_rows(2, "0 1 0"));
+    }
+
+    #[test]
+    fn test_segmentation_gives_each_object_its_own_value() {
+        let scene = "size 40 20
+camera 0 0 10 0 0 0 0 1 0 45
+ambient 0.2 0.2 0.2
+diffuse 0.5 0.5 0.5
+sphere -2 0 0 1
+sphere 2 0 0 1
+";
+        let ray_tracer = RayTracer::new(ParsedConfigState::new().load_config_str(scene).unwrap());
+        let segmentation = ray_tracer.render_segmentation().unwrap();
+        assert_eq!((segmentation.width, segmentation.height), (40, 20));
+
+        let mut values: Vec<u32> = segmentation.data.iter().map(|pixel| pixel & 0xFF_FFFF).collect();
+        values.sort_unstable();
+        values.dedup();
+        assert_eq!(values, [0, 1, 2]);
+
+        let ids = ray_tracer.render_object_ids().unwrap();
+        for (pixel, id) in segmentation.data.iter().zip(&ids) {
+            let decoded = (pixel & 0xFF_FFFF).wrapping_sub(1);
+            assert_eq!(if *id == NO_OBJECT { u32::MAX } else { *id }, decoded);
+        }
+    }
+
+    #[test]
+    fn test_outline_marks_object_boundaries() {
+        let scene = "size 40 20
+camera 0 0 10 0 0 0 0 1 0 45
+ambient 0.2 0.2 0.2
+sphere -2 0 0 1
+sphere 2 0 0 1
+";
+        let config = ParsedConfigState::new().load_config_str(scene).unwrap();
+        let ray_tracer = RayTracer::new(config);
+        let mut image = ray_tracer.render().unwrap();
+        let ids = ray_tracer.render_object_ids().unwrap();
+
+        let row = &ids[10 * 40..11 * 40];
+        assert_eq!(row[0], NO_OBJECT);
+        assert_eq!(row[15], 0);
+        assert_eq!(row[24], 1);
+
+        image.overlay_outline(&ids, 0xFF00FF).unwrap();
+        // Last background pixel before the first sphere
+        let edge = row.iter().position(|id| *id == 0).unwrap() - 1;
+        assert_eq!(image.data[10 * 40 + edge] & 0xFFFFFF, 0xFF00FF);
+        assert_eq!(image.data[10 * 40 + 15] & 0xFFFFFF, 0x333333);
+        assert_eq!(image.data[10 * 40] & 0xFFFFFF, 0);
+    }
+
+    #[test]
+    fn test_render_multi_res_is_consistent_across_sizes() {
+        let scene = "size 200 150
+camera 0 0 6 0 0 0 0 1 0 45
+ambient 0.1 0.1 0.1
+point 3 4 6 1 1 1
+diffuse 0.8 0.4 0.2
+sphere 0 0 0 1.5
+diffuse 0.3 0.6 0.3
+plane 0 -1.5 0 0 1 0
+";
+        // One tracer, hence one BVH build, serves both sizes
+        let ray_tracer = RayTracer::new(ParsedConfigState::new().load_config_str(scene).unwrap());
+        let images = ray_tracer.render_multi_res(&[(100, 75), (200, 150)]).unwrap();
+        let (small, large) = (&images[0], &images[1]);
+        assert_eq!((small.width, small.height), (100, 75));
+        assert_eq!(*large, ray_tracer.render().unwrap());
+
+        // Box-filter the large render down to the small size
+        let downscaled = Image::new(
+            100,
+            75,
+            (0..75)
+                .flat_map(|y| (0..100).map(move |x| (x, y)))
+                .map(|(x, y)| {
+                    let mut sum = [0u32; 3];
+                    for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                        let (r, g, b) = crate::imgcomparator::extract_rgb(large.data[(2 * y + dy) * 200 + 2 * x + dx]);
+                        sum[0] += r;
+                        sum[1] += g;
+                        sum[2] += b;
+                    }
+                    0xFF00_0000 | ((sum[0] / 4) << 16) | ((sum[1] / 4) << 8) | (sum[2] / 4)
+                })
+                .collect(),
+        );
+        let psnr = Image::psnr(small, &downscaled).unwrap();
+        assert!(psnr > 25.0, "PSNR {psnr:.1} dB");
+    }
+
+    #[test]
+    fn test_render_rejects_empty_image() {
+        let mut config = ParsedConfigState::new().load_config_str("size 4 4\n").unwrap();
+        config.width = 0;
+        let ray_tracer = RayTracer::new(config);
+        assert!(matches!(ray_tracer.render(), Err(RayTracerError::Render(_))));
+        assert!(matches!(ray_tracer.render_progressive(2, |_| {}), Err(RayTracerError::Render(_))));
+        assert!(matches!(ray_tracer.render_hdr(), Err(RayTracerError::Render(_))));
+        assert!(matches!(ray_tracer.render_depth_map(), Err(RayTracerError::Render(_))));
+        assert!(matches!(ray_tracer.render_object_ids(), Err(RayTracerError::Render(_))));
+        assert!(matches!(ray_tracer.render_multi_res(&[(4, 4), (0, 4)]), Err(RayTracerError::Render(_))));
+        assert!(matches!(ray_tracer.render_albedo(), Err(RayTracerError::Render(_))));
+        assert!(matches!(ray_tracer.render_normal_aov(), Err(RayTracerError::Render(_))));
+        assert!(matches!(ray_tracer.render_light_passes(), Err(RayTracerError::Render(_))));
+        assert!(matches!(ray_tracer.render_heatmap(), Err(RayTracerError::Render(_))));
+
+        let mut config = ParsedConfigState::new().load_config_str("size 4 4\n").unwrap();
+        config.maxdepth = MAX_DEPTH + 1;
+        let ray_tracer = RayTracer::new(config);
+        assert!(matches!(ray_tracer.render_hdr(), Err(RayTracerError::Render(_))));
+        assert!(matches!(ray_tracer.render_multi_res(&[(4, 4)]), Err(RayTracerError::Render(_))));
+    }
+
+    #[test]
+    fn test_pack_color_matches_per_channel_rounding() {
+        // The original per-channel packing, kept verbatim as the reference
+        #[allow(clippy::manual_clamp)]
+        fn reference(color: &Vector3<f32>) -> u32 {
+            let r = (color.x.max(0.0).min(1.0) * 255.0).round() as u32;
+            let g = (color.y.max(0.0).min(1.0) * 255.0).round() as u32;
+            let b = (color.z.max(0.0).min(1.0) * 255.0).round() as u32;
+            (255 << 24) | (r << 16) | (g << 8) | b
+        }
+
+        let mut rng = Rng::new(7);
+        for i in 0..=2000 {
+            // Exact quantization steps and their rounding midpoints, then random values
+            // including out-of-range ones
+            let step = i as f32 / 2000.0 * 1.2 - 0.1;
+            let midpoint = (i % 256) as f32 / 255.0 + 0.5 / 255.0;
+            let random = Vector3::new(rng.next_f32(), rng.next_f32(), rng.next_f32()) * 1.4
+                - Vector3::repeat(0.2);
+            for color in [Vector3::new(step, midpoint, 1.0 - step), random] {
+                assert_eq!(pack_color(&color), reference(&color), "{color:?}");
+            }
+        }
+        for special in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY, -0.0] {
+            let color = Vector3::repeat(special);
+            assert_eq!(pack_color(&color), reference(&color));
+        }
+    }
+
+    #[test]
+    fn test_light_importance_sampling_converges_with_fewer_shadow_rays() {
+        let mut scene = String::from(
+            "size 64 48
+camera 0 6 6 0 0 0 0 1 0 45
+diffuse 0.8 0.8 0.8
+plane 0 0 0 0 1 0
+sphere 0 1 0 1
+",
+        );
+        // 50 dim point lights on a 10x5 grid above the floor
+        for i in 0..10 {
+            for j in 0..5 {
+                let x = i as f32 - 4.5;
+                let z = j as f32 - 2.0;
+                scene.push_str(&format!("point {x} 4 {z} 0.02 0.02 0.02\n"));
+            }
+        }
+
+        let all = RayTracer::new(ParsedConfigState::new().load_config_str(&scene).unwrap());
+        let (all_image, all_stats) = all.render_with_stats().unwrap();
+
+        scene.push_str("light_samples 4\n");
+        let sampled = RayTracer::new(ParsedConfigState::new().load_config_str(&scene).unwrap());
+        let (sampled_image, sampled_stats) = sampled.render_with_stats().unwrap();
+        let (all_shadow_rays, sampled_shadow_rays) = (all_stats.shadow_rays, sampled_stats.shadow_rays);
+
+        // Unshadowed areas are nearly exact; the remaining noise is in the sphere's shadow
+        let psnr = Image::psnr(&all_image, &sampled_image).unwrap();
+        assert!(psnr > 20.0, "PSNR too low: {psnr}");
+        assert!(sampled_shadow_rays * 10 < all_shadow_rays);
+    }
+
+    #[test]
+    fn test_single_sphere_matches_analytic_blinn_phong() {
+        // Odd image size so the center pixel's primary ray hits the sphere at (0, 0, 1)
+        let scene = "size 33 33
+camera 0 0 5 0 0 0 0 1 0 30
+ambient 0.1 0.1 0.1
+directional 1 1 1 1 0.9 0.8
+diffuse 0.6 0.3 0.2
+specular 0.3 0.3 0.3
+shininess 20
+sphere 0 0 0 1
+";
+        let config = ParsedConfigState::new().load_config_str(scene).unwrap();
+        let image = RayTracer::new(config).render().unwrap();
+        let (r, g, b) = crate::imgcomparator::extract_rgb(image.data[16 * 33 + 16]);
+
+        // Hand-computed Blinn-Phong at the front pole: n = v = (0, 0, 1), l = (1, 1, 1) / sqrt(3)
+        let n_dot_l = 1.0 / 3.0f64.sqrt();
+        let half = {
+            let h = [n_dot_l, n_dot_l, n_dot_l + 1.0];
+            let len = (h[0] * h[0] + h[1] * h[1] + h[2] * h[2]).sqrt();
+            [h[0] / len, h[1] / len, h[2] / len]
+        };
+        let n_dot_h = half[2];
+        let specular = 0.3 * n_dot_h.powf(20.0);
+        let expected = |diffuse: f64, light: f64| {
+            let c = 0.1 + (diffuse * n_dot_l + specular) * light;
+            (c.clamp(0.0, 1.0) * 255.0).round() as i32
+        };
+
+        assert!((r as i32 - expected(0.6, 1.0)).abs() <= 1, "red {r} vs {}", expected(0.6, 1.0));
+        assert!((g as i32 - expected(0.3, 0.9)).abs() <= 1, "green {g} vs {}", expected(0.3, 0.9));
+        assert!((b as i32 - expected(0.2, 0.8)).abs() <= 1, "blue {b} vs {}", expected(0.2, 0.8));
+    }
+
+    #[test]
+    fn test_raycast_finds_the_nearest_object() {
+        let scene = "sphere 0 0 -10 1\nsphere 4 0 -5 2\nsphere 0 0 -20 3\n";
+        let ray_tracer = RayTracer::new(ParsedConfigState::new().load_config_str(scene).unwrap());
+
+        let hit = ray_tracer.raycast(Vector3::zeros(), Vector3::new(0.0, 0.0, -3.0)).unwrap();
+        assert_eq!(hit.object_index, 0);
+        assert_eq!(hit.object_kind, "sphere");
+        assert!((hit.distance - 9.0).abs() < 1e-4);
+        assert!((hit.point - Vector3::new(0.0, 0.0, -9.0)).norm() < 1e-4);
+        assert!((hit.normal - Vector3::z()).norm() < 1e-4);
+
+        let side = ray_tracer.raycast(Vector3::new(4.0, 0.0, 0.0), -Vector3::z()).unwrap();
+        assert_eq!(side.object_index, 1);
+        assert!((side.distance - 3.0).abs() < 1e-4);
+
+        assert_eq!(ray_tracer.raycast(Vector3::zeros(), Vector3::y()), None);
+    }
+
+    #[test]
+    fn test_trace_single_ray_reports_nearest_hit() {
+        let scene = "size 8 8
+camera 0 0 5 0 0 0 0 1 0 30
+ambient 0.2 0.2 0.2
+diffuse 0.4 0.4 0.4
+directional 0 0 1 1 1 1
+sphere 3 0 0 0.5
+sphere 0 0 0 1
+";
+        let config = ParsedConfigState::new().load_config_str(scene).unwrap();
+        let ray_tracer = RayTracer::new(config);
+
+        let (color, hit) = ray_tracer.trace_single_ray(Vector3::new(0.0, 0.0, 5.0), Vector3::new(0.0, 0.0, -2.0));
+        let hit = hit.expect("The ray points at the sphere");
+        assert_eq!(hit.object_index, 1);
+        assert_eq!(hit.object_kind, "sphere");
+        assert!((hit.distance - 4.0).abs() < 1e-4, "{}", hit.distance);
+        assert!((hit.normal - Vector3::z()).norm() < 1e-4);
+        // Ambient plus a head-on light: 0.2 + 0.4 = 0.6 on every channel
+        let level = (0.6f32 * 255.0).round() as u32;
+        assert_eq!(color & 0xFFFFFF, (level << 16) | (level << 8) | level);
+
+        let (background, miss) = ray_tracer.trace_single_ray(Vector3::new(0.0, 0.0, 5.0), Vector3::new(0.0, 1.0, 0.0));
+        assert!(miss.is_none());
+        assert_eq!(background & 0xFFFFFF, 0);
+    }
+
+    #[test]
+    fn test_merged_scenes_render_like_one_scene() {
+        let setup = "size 32 24\ncamera 0 2 6 0 0 0 0 1 0 45\nambient 0.1 0.1 0.1\npoint 2 4 3 0.8 0.8 0.8\n";
+        let sphere = "diffuse 0.8 0.2 0.2\nsphere 0 1 0 1\n";
+        let plane = "diffuse 0.2 0.6 0.2\nplane 0 0 0 0 1 0\n";
+        let load = |scene: &str| ParsedConfigState::new().load_config_str(scene).unwrap();
+
+        // The plane's layer has its own camera and light setup, which the merge drops
+        let mut merged = load(&format!("{setup}{sphere}"));
+        merged.merge(load(&format!("camera 5 5 5 0 0 0 0 1 0 90\nambient 0.3 0.3 0.3\n{plane}")));
+        let image = RayTracer::new(merged).render().unwrap();
+
+        let expected = RayTracer::new(load(&format!("{setup}{sphere}{plane}"))).render().unwrap();
+        let (diff, _) = Image::compare(&image, &expected).unwrap();
+        assert_eq!(diff, 0);
+        // The sphere casts its shadow on the plane it was merged with
+        let sphere_only = RayTracer::new(load(&format!("{setup}{sphere}"))).render().unwrap();
+        assert_ne!(image.data, sphere_only.data);
     }
 
-pub fn render(&self) -> Result<Image, String> {
-    let width = self.config.width as usize;
-    let height = self.config.height as usize;
-    
-    let mut image_data = vec![0u32; width * height];
+    #[test]
+    fn test_set_camera_renders_like_a_scene_with_that_camera() {
+        let scene = |camera: &str| {
+            format!(
+                "size 24 16\n{camera}\nambient 0.2 0.2 0.2\npoint 2 3 4 1 1 1\nspecular_aa on\n\
+                 diffuse 0.5 0.5 0.5\nspecular 0.5 0.5 0.5\nshininess 400\nsphere 0 0 0 1\nplane 0 -1 0 0 1 0\n"
+            )
+        };
+        let load = |camera: &str| ParsedConfigState::new().load_config_str(&scene(camera)).unwrap();
 
-    let camera_vector = self.config.camera.direction().normalize();
-    let normal_to_plane = camera_vector.cross(&self.config.camera.up).normalize();
-    let v = normal_to_plane.cross(&camera_vector).normalize();
-    
-    let fovrad = self.config.camera.fov * std::f32::consts::PI / 180.0;
-    let pixel_height = (fovrad / 2.0).tan();
-    let pixel_width = pixel_height * (self.config.width as f32 / self.config.height as f32);
+        let mut ray_tracer = RayTracer::new(load("camera 0 1 6 0 0 0 0 1 0 60"));
+        let mut camera = load("camera 0 1 6 0 0 0 0 1 0 20").camera;
+        camera.set_position(Vector3::new(6.0, 1.0, 0.0));
+        ray_tracer.set_camera(camera);
 
-    let img_width_by_2 = self.config.width as f32 / 2.0;
-    let img_height_by_2 = self.config.height as f32 / 2.0;
+        let expected = RayTracer::new(load("camera 6 1 0 0 0 0 0 1 0 20")).render().unwrap();
+        let image = ray_tracer.render().unwrap();
+        let (diff, _) = Image::compare(&image, &expected).unwrap();
+        assert_eq!(diff, 0);
+    }
 
-    image_data.par_chunks_mut(width)
-        .enumerate()
-        .for_each(|(y, row)| {
-            
-            let b = (pixel_height * (img_height_by_2 - (y as f32 + 0.5))) / img_height_by_2;
-
-            for (x, pixel) in row.iter_mut().enumerate() {
-                let a = (pixel_width * ((x as f32 + 0.5) - img_width_by_2)) / img_width_by_2;
-                
-                let d = (normal_to_plane * a + v * b + camera_vector).normalize();
-                
-                let color = self.find_color(self.config.camera.position, d);
-                
-                *pixel = color;
+    #[test]
+    fn test_timeout_abandons_slow_renders() {
+        // Mirrors bouncing up to maxdepth and 16 samples per silhouette pixel take
+        // far longer than the timeout
+        let mut slow = String::from("size 1000 1000\ncamera 0 0 8 0 0 0 0 1 0 60\nmaxdepth 8\nedge_aa 16\n");
+        slow.push_str("ambient 0.1 0.1 0.1\npoint 0 5 5 1 1 1\ndiffuse 0.3 0.3 0.3\nspecular 0.6 0.6 0.6\nshininess 20\n");
+        for index in 0..25 {
+            slow.push_str(&format!("sphere {} {} 0 0.45\n", index % 5 - 2, index / 5 - 2));
+        }
+        let ray_tracer = RayTracer::new(ParsedConfigState::new().load_config_str(&slow).unwrap());
+        let timeout = Duration::from_millis(10);
+        assert_eq!(
+            ray_tracer.with_timeout(timeout, RayTracer::render_with_stats).unwrap_err(),
+            RayTracerError::Timeout(timeout)
+        );
+
+        let fast = "size 8 8\ncamera 0 0 5 0 0 0 0 1 0 30\nambient 0.5 0.5 0.5\ndiffuse 0.5 0.5 0.5\nsphere 0 0 0 1\n";
+        let ray_tracer = RayTracer::new(ParsedConfigState::new().load_config_str(fast).unwrap());
+        let image = ray_tracer.with_timeout(Duration::from_secs(60), RayTracer::render).unwrap();
+        assert_eq!(image.data, ray_tracer.render().unwrap().data);
+    }
+
+    #[test]
+    fn test_timeout_cuts_a_branching_pixel_short() {
+        // Each hit on the glass splits into a reflected and a refracted ray, and the
+        // mirrors on either side send both back, so the one pixel branches into more
+        // paths than could ever be traced
+        let scene = "size 1 1\ncamera 0 0 2.5 0 0 0 0 1 0 10\nmaxdepth 60\nspecular 0.9 0.9 0.9\n\
+            plane 0 0 -3 0 0 1\nplane 0 0 3 0 0 -1\ntransparency 0.5\nior 1.5\nsphere 0 0 0 1\n";
+        let ray_tracer = RayTracer::new(ParsedConfigState::new().load_config_str(scene).unwrap());
+        let timeout = Duration::from_millis(10);
+        let start = Instant::now();
+        assert_eq!(
+            ray_tracer.with_timeout(timeout, RayTracer::render).unwrap_err(),
+            RayTracerError::Timeout(timeout)
+        );
+        assert!(start.elapsed() < Duration::from_secs(10), "The cancelled pixel kept tracing");
+    }
+
+    #[test]
+    fn test_timeout_does_not_cancel_other_renders() {
+        let scene = "size 64 64\ncamera 0 0 5 0 0 0 0 1 0 30\nmaxdepth 8\nambient 0.2 0.2 0.2\n\
+            point 0 5 5 1 1 1\ndiffuse 0.5 0.5 0.5\nspecular 0.5 0.5 0.5\nsphere 0 0 0 1\n";
+        let ray_tracer = RayTracer::new(ParsedConfigState::new().load_config_str(scene).unwrap());
+        let expected = ray_tracer.render().unwrap();
+        thread::scope(|scope| {
+            scope.spawn(|| ray_tracer.with_timeout(Duration::ZERO, RayTracer::render));
+            for _ in 0..4 {
+                assert_eq!(ray_tracer.render().unwrap().data, expected.data);
             }
         });
+    }
 
-    Ok(Image::new(
-        self.config.width,
-        self.config.height,
-        image_data,
-    ))
-}
+    #[test]
+    fn test_median_aa_combine_picks_a_sample_color() {
+        // An unlit sphere is flat, so every sample is either its color or the background
+        let render = |combine: &str| {
+            let scene = format!(
+                "size 24 24\ncamera 0 0 5 0 0 0 0 1 0 30\nambient 0.5 0.5 0.5\ndiffuse 0.5 0.5 0.5\n\
+                 edge_aa 9\naa_pattern grid\naa_combine {combine}\nsphere 0 0 0 1\n"
+            );
+            let image = RayTracer::new(ParsedConfigState::new().load_config_str(&scene).unwrap()).render().unwrap();
+            let mut colors: Vec<u32> = image.data.clone();
+            colors.sort_unstable();
+            colors.dedup();
+            colors
+        };
+        assert!(render("mean").len() > 2);
+        assert_eq!(render("median").len(), 2);
+    }
 
-    pub fn get_output_path(&self) -> &str {
-        &self.config.output_file
+    #[test]
+    fn test_edge_aa_supersamples_only_silhouettes() {
+        let scene = "size 32 32
+camera 0 0 5 0 0 0 0 1 0 30
+ambient 0.2 0.2 0.2
+directional 0 0 1 1 1 1
+edge_aa 8
+sphere 0 0 0 1
+";
+        let config = ParsedConfigState::new().load_config_str(scene).unwrap();
+        let ray_tracer = RayTracer::new(config);
+        let camera = &ray_tracer.config().camera;
+        let (Frame { image, aovs, .. }, _) = ray_tracer.render_frame(camera, 32, 32);
+        let counts = aovs.counts;
+
+        // The sphere's interior and the empty corners keep their single center sample
+        assert_eq!(counts[16 * 32 + 16], 1);
+        assert_eq!(counts[0], 1);
+        // The row through the center crosses the outline, where the 8 extra rays are spent
+        assert!(counts[16 * 32..17 * 32].contains(&9));
+        let edge_pixels = counts.iter().filter(|&&count| count > 1).count();
+        assert!(edge_pixels > 0 && edge_pixels < 32 * 32 / 4, "{edge_pixels} edge pixels");
+
+        let (rendered, stats) = ray_tracer.render_with_stats().unwrap();
+        assert_eq!(rendered.data, image.data);
+        assert_eq!(stats.primary_rays, counts.iter().map(|&count| count as u64).sum::<u64>());
+
+        assert!(ParsedConfigState::new().load_config_str("edge_aa 0\n").is_err());
     }
 
-    fn find_color(&self, origin: Vector3<f32>, direction: Vector3<f32>) -> u32 {
-        let color_vec = self.find_color_recursive(origin, direction, 0);
-        let r = (color_vec.x.max(0.0).min(1.0) * 255.0).round() as u32;
-        let g = (color_vec.y.max(0.0).min(1.0) * 255.0).round() as u32;
-        let b = (color_vec.z.max(0.0).min(1.0) * 255.0).round() as u32;
-        (255 << 24) | (r << 16) | (g << 8) | b
+    #[test]
+    fn test_sample_aovs_follow_edges() {
+        let scene = "size 32 32
+camera 0 0 5 0 0 0 0 1 0 30
+ambient 0.2 0.2 0.2
+directional 0 0 1 1 1 1
+edge_aa 8
+sphere 0 0 0 1
+";
+        let ray_tracer = RayTracer::new(ParsedConfigState::new().load_config_str(scene).unwrap());
+        let (image, aovs) = ray_tracer.render_sample_aovs().unwrap();
+        assert_eq!(image.data, ray_tracer.render().unwrap().data);
+
+        // Flat interior and background keep one sample and no variance
+        for index in [16 * 32 + 16, 0, 31] {
+            assert_eq!(aovs.counts[index], 1);
+            assert_eq!(aovs.variance[index], 0.0);
+        }
+        let (edge, flat): (Vec<usize>, Vec<usize>) = (0..32 * 32).partition(|&index| aovs.counts[index] > 1);
+        assert!(!edge.is_empty() && edge.len() < flat.len());
+        assert!(flat.iter().all(|&index| aovs.variance[index] == 0.0));
+        // Straddling the outline, the jittered samples mix sphere and background
+        let edge_variance = edge.iter().map(|&index| aovs.variance[index]).fold(0.0, f32::max);
+        assert!(edge_variance > 0.01, "{edge_variance}");
+
+        let plain = RayTracer::new(ParsedConfigState::new().load_config_str(&scene.replace("edge_aa 8\n", "")).unwrap());
+        let (_, aovs) = plain.render_sample_aovs().unwrap();
+        assert!(aovs.counts.iter().all(|&count| count == 1));
+        assert!(aovs.variance.iter().all(|&variance| variance == 0.0));
     }
 
-    /// Helper function to create a BVH ray from Vector3 origin and direction.
-    fn create_bvh_ray(origin: Vector3<f32>, direction: Vector3<f32>) -> bvh::ray::Ray<f32, 3> {
-        let origin_point = Point3::from(origin);
-        bvh::ray::Ray::new(origin_point, direction)
+    #[test]
+    fn test_coincident_surfaces_pick_first_defined() {
+        // The plane is always tested after the BVH's candidates, the two triangles share
+        // one AABB: in both cases the object defined first must win the tie
+        let render = |first: &str, second: &str| {
+            let scene = format!(
+                "size 9 9\ncamera 0 5 0 0 0 0 0 0 -1 30\ndirectional 0 1 0 1 1 1\n\
+                 maxverts 3\nvertex -9 0 -9\nvertex 9 0 -9\nvertex 0 0 9\n\
+                 diffuse 1 0 0\n{first}\ndiffuse 0 1 0\n{second}\n"
+            );
+            let config = ParsedConfigState::new().load_config_str(&scene).unwrap();
+            RayTracer::new(config).render().unwrap().data[4 * 9 + 4] & 0xFFFFFF
+        };
+
+        for (first, second) in [
+            ("plane 0 0 0 0 1 0", "plane_bounded 0 0 0 0 1 0 5"),
+            ("plane_bounded 0 0 0 0 1 0 5", "plane 0 0 0 0 1 0"),
+            ("tri 0 2 1", "tri 0 2 1"),
+            ("tri 0 2 1", "plane 0 0 0 0 1 0"),
+        ] {
+            assert_eq!(render(first, second), 0xFF0000, "{first} then {second}");
+            assert_eq!(render(second, first), 0xFF0000, "{second} then {first}");
+        }
     }
 
-    fn find_color_recursive(&self, origin: Vector3<f32>, direction: Vector3<f32>, depth: u32) -> Vector3<f32> {
-        if depth > self.config.maxdepth {
-            return Vector3::zeros();
+    #[test]
+    fn test_directional_shadows_need_an_occluder_past_the_bias() {
+        // The ground at the origin is lit along (1, 1, 0): a sphere on that line casts a
+        // shadow, the same sphere mirrored onto the far side of the light direction does not
+        let render = |extra: &str| {
+            let scene = format!(
+                "size 9 9\ncamera 0 10 0 0 0 0 0 0 -1 30\ndirectional 1 1 0 1 1 1\n\
+                 diffuse 1 1 1\nplane 0 0 0 0 1 0\n{extra}\n"
+            );
+            let config = ParsedConfigState::new().load_config_str(&scene).unwrap();
+            RayTracer::new(config).render().unwrap().data[4 * 9 + 4] & 0xFFFFFF
+        };
+
+        let lit = render("");
+        assert_ne!(lit, 0);
+        assert_eq!(render("sphere -3 -3 0 1"), lit);
+        assert_eq!(render("sphere 3 3 0 1"), 0);
+        // The sphere is about 3.2 units away along the shadow ray
+        assert_eq!(render("shadow_bias 2\nsphere 3 3 0 1"), 0);
+        assert_eq!(render("shadow_bias 5\nsphere 3 3 0 1"), lit);
+    }
+
+    #[test]
+    fn test_receding_checker_is_filtered_by_pixel_footprint() {
+        // Towards the horizon many checker cells fall within one pixel: point sampling
+        // picks one at random, the filtered lookup averages them
+        let render = |filter: &str| {
+            let scene = format!(
+                "size 64 48\ncamera 0 1 0 0 1 -10 0 1 0 40\ndirectional 0 1 0 1 1 1\n\
+                 texture_filter {filter}\nchecker 1 1 1 0 0 0\nplane 0 0 0 0 1 0\n"
+            );
+            let config = ParsedConfigState::new().load_config_str(&scene).unwrap();
+            RayTracer::new(config).render().unwrap()
+        };
+        // Summed difference between horizontal neighbours in the rows just below the horizon
+        let high_frequency = |image: &Image| {
+            (25..33)
+                .flat_map(|y| (0..63).map(move |x| (x, y)))
+                .map(|(x, y)| {
+                    let left = (image.data[y * 64 + x] & 0xFF) as i32;
+                    let right = (image.data[y * 64 + x + 1] & 0xFF) as i32;
+                    (left - right).unsigned_abs()
+                })
+                .sum::<u32>()
+        };
+
+        let point_sampled = render("off");
+        let filtered = render("on");
+        assert!(high_frequency(&filtered) * 4 < high_frequency(&point_sampled));
+        // Far away the cells blend to mid-gray instead of flickering black and white
+        assert!((24..32).all(|y| (0..64).all(|x| (filtered.data[y * 64 + x] & 0xFF).abs_diff(128) <= 40)));
+        // Near the camera cells span many pixels: only the pixels on cell edges change
+        let changed = (0..64).filter(|&x| filtered.data[47 * 64 + x] != point_sampled.data[47 * 64 + x]).count();
+        assert!(changed < 16, "{changed}");
+    }
+
+    #[test]
+    fn test_binary_scene_renders_like_its_text_source() {
+        let scene = "size 24 18\ncamera 0 1 6 0 0 0 0 1 0 45\nambient 0.1 0.1 0.1\npoint 2 4 3 1 1 1\n\
+                     output shot.png\nmaxverts 4\nvertex -1 0 0\nvertex 1 0 0\nvertex 1 2 0\nvertex -1 2 0\n\
+                     diffuse 0.8 0.2 0.2\ntri 0 1 2\ntri 0 2 3\ndiffuse 0.2 0.8 0.2\nspecular 0.5 0.5 0.5\n\
+                     shininess 20\nsphere 0 0.5 1 0.5\nchecker 1 1 1 0 0 0\nplane 0 0 0 0 1 0\n";
+        let text = ParsedConfigState::new().load_config_str(scene).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("raytracer_binary_scene_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("scene.rtscene");
+        let path = path.to_str().unwrap();
+        crate::raytracer::config::save_binary(&text, path).unwrap();
+        let mut state = ParsedConfigState::new();
+        let binary = state.load_config_file(path).unwrap();
+
+        assert!(state.size_was_set() && state.warnings().is_empty());
+        assert_eq!(binary.output_file, dir.join("shot.png").to_string_lossy());
+        assert_eq!(binary.get_scene_objects().len(), 4);
+        let expected = RayTracer::new(text).render().unwrap();
+        assert_eq!(RayTracer::new(binary).render().unwrap(), expected);
+
+        let instanced = "size 24 18\ncamera 0 1 6 0 0 0 0 1 0 45\npoint 2 4 3 1 1 1\nmaxverts 3\n\
+                         vertex -1 0 0\nvertex 1 0 0\nvertex 0 2 0\nmesh shard\ntri 0 1 2\nendmesh\n\
+                         instance shard translate -1 0 0\ninstance shard translate 1 0 0 rotate 0 0 30\n";
+        let text = ParsedConfigState::new().load_config_str(instanced).unwrap();
+        crate::raytracer::config::save_binary(&text, path).unwrap();
+        let binary = ParsedConfigState::new().load_config_file(path).unwrap();
+        let [Shape::Instance { mesh: first, .. }, Shape::Instance { mesh: second, .. }] =
+            binary.get_scene_objects().as_slice()
+        else {
+            panic!("Expected two instances");
+        };
+        assert!(Arc::ptr_eq(first, second));
+        let expected = RayTracer::new(text).render().unwrap();
+        assert_eq!(RayTracer::new(binary).render().unwrap(), expected);
+
+        std::fs::write(path, "size 24 18\n").unwrap();
+        assert!(matches!(
+            ParsedConfigState::new().load_config_file(path),
+            Err(RayTracerError::InvalidScene(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_binary_scenes_render_the_golden_images() {
+        let dir = std::env::temp_dir().join(format!("raytracer_binary_golden_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for path in ["test_file/jalon5/tp52", "test_file/jalon6/tp63"] {
+            let binary_path = dir.join("scene.rtscene");
+            let binary_path = binary_path.to_str().unwrap();
+            ParsedConfigState::new()
+                .convert_to_binary(&format!("{path}.test"), binary_path)
+                .unwrap();
+            let config = ParsedConfigState::new().load_config_file(binary_path).unwrap();
+            let expected = file_to_image(&format!("{path}.png")).unwrap();
+            let (diff, _) = Image::compare(&RayTracer::new(config).render().unwrap(), &expected).unwrap();
+            assert_eq!(diff, 0, "{path}");
         }
-        
-        let ray: Ray = Ray { origin, direction };
-        
-        // Use BVH to get candidate objects that the ray might intersect.
-        // This is the key optimization: instead of testing all objects, the BVH
-        // quickly identifies only the objects whose bounding boxes intersect the ray.
-        let bvh_ray = Self::create_bvh_ray(origin, direction);
-        let candidates = self.bvh.traverse(&bvh_ray, self.config.get_scene_objects());
-        
-        // Find closest intersection among candidates returned by BVH
-        let closest_intersection = candidates
-            .iter()
-            .filter_map(|object| object.intersect(&ray))
-            .min_by(|a, b| {
-                a.distance
-                    .partial_cmp(&b.distance)
-                    .unwrap_or(std::cmp::Ordering::Equal)
-            });
-            
-        if let Some(intersection) = closest_intersection {
-            // Accumulate light contributions from all light sources
-            let mut light_accumulator = Vector3::zeros();
-            
-            for light in self.config.get_lights() {
-                // shadow ray
-                let light_dir = match light {
-                    Point { position, .. } => (*position - intersection.point).normalize(),
-                    Directional { direction, .. } => *direction,
-                };
-                let shadow_ray = Ray {
-                    origin: intersection.point + intersection.normal * 1e-6,
-                    direction: light_dir,
-                };
-                
-                // Use BVH for shadow ray testing. This is particularly beneficial for complex
-                // scenes with many objects, as shadow rays are cast for every intersection point
-                // and every light source. BVH drastically reduces the number of intersection tests.
-                let shadow_bvh_ray = Self::create_bvh_ray(shadow_ray.origin, shadow_ray.direction);
-                let shadow_candidates = self.bvh.traverse(&shadow_bvh_ray, self.config.get_scene_objects());
-                
-                let in_shadow = shadow_candidates
-                    .iter()
-                    .filter_map(|object| object.intersect(&shadow_ray))
-                    .any(|shadow_intersection| {
-                        if shadow_intersection.distance < 1e-6 {
-                            return false;
-                        }
-                        if intersection.is_back_face && shadow_intersection.is_back_face {
-                            return false;
-                        }
-                        match light {
-                            Point { position, .. } => {
-                                shadow_intersection.distance < (*position - intersection.point).norm()
-                            }
-                            Directional { .. } => true,
-                        }
-                    });
-                if !in_shadow {
-                    let light_color = light.color();
-                    let n_dot_l = intersection.normal.dot(&light_dir).max(0.0);
-                    let diffuse = intersection.diffuse_color * n_dot_l;
-                    let view_dir = -direction;
-                    let half_vector = (light_dir + view_dir).normalize();
-                    let n_dot_h = intersection.normal.dot(&half_vector).max(0.0);
-                    
-                    let specular_factor = if intersection.shininess == 1.0 {
-                        n_dot_h
-                    } else if intersection.shininess == 0.0 {
-                        if n_dot_l > 0.0 { n_dot_h } else { 0.0 }
-                    } else {
-                        if n_dot_l > 0.0 { n_dot_h.powf(intersection.shininess) } else { 0.0 }
-                    };
-                    
-                    let specular = intersection.specular_color * specular_factor;
-                    light_accumulator += (diffuse + specular).component_mul(&light_color);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_update_object_rerenders_only_around_the_change() {
+        let scene = |x: f32| {
+            format!(
+                "size 48 36\ncamera 0 1 6 0 0 0 0 1 0 45\nambient 0.1 0.1 0.1\npoint 1 5 6 1 1 1\n\
+                 diffuse 0.5 0.5 0.5\nplane 0 -1 0 0 1 0\nsphere 1.5 0 -1 0.5\n\
+                 diffuse 0.9 0.1 0.1\nsphere {x} -0.5 0 0.3\n"
+            )
+        };
+        let load = |x: f32| ParsedConfigState::new().load_config_str(&scene(x)).unwrap();
+        let mut ray_tracer = RayTracer::new(load(-1.5));
+        let before = ray_tracer.render().unwrap();
+        let moved = load(-1.1).get_scene_objects()[2].clone();
+
+        let mut image = Image::new(before.width, before.height, before.data.clone());
+        let region = ray_tracer.update_object(2, moved, &mut image).unwrap().unwrap();
+        let expected = RayTracer::new(load(-1.1)).render().unwrap();
+
+        assert_eq!(image, expected);
+        assert!(region.width * region.height < 48 * 36 / 2, "{:?}", region);
+        for (index, (old, new)) in before.data.iter().zip(&expected.data).enumerate() {
+            let (x, y) = (index as u32 % 48, index as u32 / 48);
+            let inside = (region.x..region.x + region.width).contains(&x)
+                && (region.y..region.y + region.height).contains(&y);
+            assert!(old == new || inside, "pixel ({x}, {y}) changed outside {:?}", region);
+        }
+        assert!(ray_tracer.update_object(3, Shape::Sphere {
+            center: Vector3::zeros(),
+            radius: 1.0,
+            material: Default::default(),
+            node_index: 0,
+            inward: false,
+            transmission: crate::raytracer::config::material::Transmission::OPAQUE,
+        }, &mut image).is_err());
+    }
+
+    #[test]
+    fn test_render_region_rejects_regions_past_the_image() {
+        let config = ParsedConfigState::new()
+            .load_config_str("size 8 6
+camera 0 0 5 0 0 0 0 1 0 45
+ambient 0.1 0.1 0.1
+sphere 0 0 0 1
+")
+            .unwrap();
+        let ray_tracer = RayTracer::new(config);
+        let mut image = Image::new(8, 6, vec![0; 48]);
+        let region = |x, y, width, height| PixelRegion { x, y, width, height };
+
+        assert!(ray_tracer.render_region(&mut image, region(2, 1, 6, 5)).is_ok());
+        assert!(ray_tracer.render_region(&mut image, region(3, 0, 6, 1)).is_err());
+        assert!(ray_tracer.render_region(&mut image, region(u32::MAX, 0, 2, 1)).is_err());
+        assert!(ray_tracer.render_region(&mut image, region(0, 1, 1, u32::MAX)).is_err());
+    }
+
+    #[test]
+    fn test_lut_is_applied_to_display_colors() {
+        let dir = std::env::temp_dir().join(format!("raytracer_lut_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let corners = |f: fn(f32) -> f32| {
+            let mut cube = String::from("LUT_3D_SIZE 2\n");
+            for b in [0.0, 1.0] {
+                for g in [0.0, 1.0] {
+                    for r in [0.0, 1.0] {
+                        cube.push_str(&format!("{} {} {}\n", f(r), f(g), f(b)));
+                    }
                 }
             }
-            
-            let mut final_color = light_accumulator + self.config.ambient;
-            
-            let is_reflective = intersection.specular_color.x > 0.0 
-                || intersection.specular_color.y > 0.0 
-                || intersection.specular_color.z > 0.0;
-            
-            if is_reflective && depth + 1 < self.config.maxdepth {
-                let reflect_dir = direction - 2.0 * direction.dot(&intersection.normal) * intersection.normal;
-                
-                let reflect_origin = intersection.point + intersection.normal * 1e-6;
-                
-                let reflected_color = self.find_color_recursive(reflect_origin, reflect_dir, depth + 1);
-                
-                let reflection_contribution = intersection.specular_color.component_mul(&reflected_color);
-                final_color += reflection_contribution;
+            cube
+        };
+        std::fs::write(dir.join("identity.cube"), corners(|c| c)).unwrap();
+        std::fs::write(dir.join("invert.cube"), corners(|c| 1.0 - c)).unwrap();
+        let render = |lut: &str| {
+            let scene = dir.join("scene.test");
+            std::fs::write(
+                &scene,
+                format!(
+                    "size 16 12\ncamera 0 0 5 0 0 0 0 1 0 45\nambient 0.1 0.2 0.3\n{lut}\n\
+                     point 2 3 4 1 1 1\ndiffuse 0.7 0.4 0.2\nsphere 0 0 0 1\n"
+                ),
+            )
+            .unwrap();
+            let config = ParsedConfigState::new().load_config_file(scene.to_str().unwrap()).unwrap();
+            RayTracer::new(config).render().unwrap()
+        };
+
+        let plain = render("");
+        assert_eq!(render("lut identity.cube"), plain);
+        let inverted = render("lut invert.cube");
+        for (&pixel, &inverse) in plain.data.iter().zip(&inverted.data) {
+            let (r, g, b) = crate::imgcomparator::extract_rgb(pixel);
+            let (ir, ig, ib) = crate::imgcomparator::extract_rgb(inverse);
+            // Rounding may land either side of a half-way value
+            for (channel, inverse) in [(r, ir), (g, ig), (b, ib)] {
+                assert!((255 - channel).abs_diff(inverse) <= 1, "{:06X} inverted to {:06X}", pixel, inverse);
             }
-            
-            final_color
-        } else {
-            Vector3::zeros()
         }
+        std::fs::remove_dir_all(&dir).unwrap();
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::imgcomparator::file_to_image;
-    use crate::imgcomparator::save_image;
-    use crate::imgcomparator::Image;
-    use crate::raytracer::ParsedConfigState;
+    #[test]
+    fn test_sharp_highlight_is_stable_as_camera_moves() {
+        // Shininess 1000 on a unit sphere gives a highlight narrower than a pixel: as the
+        // camera slides by a fraction of a pixel, the brightest pixel lands on the peak of
+        // the lobe in some frames and on its tail in others
+        let peak = |dx: f32, specular_aa: &str| {
+            let scene = format!(
+                "size 32 32\ncamera {dx} 0 5 {dx} 0 0 0 1 0 30\npoint {dx} 0 5 1 1 1\n\
+                 specular_aa {specular_aa}\ndiffuse 0 0 0\nspecular 1 1 1\nshininess 1000\n\
+                 sphere 0 0 0 1\n"
+            );
+            let config = ParsedConfigState::new().load_config_str(&scene).unwrap();
+            let image = RayTracer::new(config).render().unwrap();
+            image.data.iter().map(|pixel| (pixel & 0xFF) as f32).fold(0.0, f32::max)
+        };
+        let spread = |specular_aa: &str| {
+            let frames: Vec<f32> = (0..8).map(|k| peak(k as f32 * 0.01, specular_aa)).collect();
+            let max = frames.iter().cloned().fold(f32::MIN, f32::max);
+            let min = frames.iter().cloned().fold(f32::MAX, f32::min);
+            max / min
+        };
 
-    const SAVE_DIFF_IMAGES: bool = true;
+        assert!(spread("off") > 2.0, "the unclamped highlight is expected to flicker");
+        assert!(spread("on") < 1.1);
+    }
 
     #[test]
-    fn test_raytracer_tp31() {
-        test_file("test_file/jalon3/tp31");
+    fn test_highlight_cap_follows_the_rendered_size() {
+        let scene = |size: u32| {
+            format!(
+                "size {size} {size}\ncamera 0 0 5 0 0 0 0 1 0 30\npoint 0 0 5 1 1 1\n\
+                 diffuse 0 0 0\nspecular 1 1 1\nshininess 1000\nsphere 0 0 0 1\n"
+            )
+        };
+        let load = |size: u32| RayTracer::new(ParsedConfigState::new().load_config_str(&scene(size)).unwrap());
+
+        // Smaller pixels let a narrower highlight through, whichever size the scene names
+        let small = load(32);
+        let large = small.render_to_image_with_camera(&small.config().camera, 128, 128);
+        assert_eq!(large, load(128).render().unwrap());
     }
+
     #[test]
-    fn test_raytracer_tp32() {
-        test_file("test_file/jalon3/tp32");
+    fn test_bvh_leaves_give_each_object_its_own_node() {
+        let scene = "sphere -3 0 0 1\nsphere 3 0 0 1\ncapsule 0 -2 0 0 2 0 0.5\nplane 0 -5 0 0 1 0\n";
+        let config = ParsedConfigState::new().load_config_str(scene).unwrap();
+        let leaves = RayTracer::new(config).bvh_leaves();
+
+        assert_eq!(leaves.len(), 4);
+        assert!(leaves.windows(2).all(|pair| pair[0].node_index < pair[1].node_index));
+        let mut objects: Vec<usize> = leaves.iter().map(|leaf| leaf.object_index).collect();
+        objects.sort();
+        assert_eq!(objects, vec![0, 1, 2, 3]);
+
+        let sphere = leaves.iter().find(|leaf| leaf.object_index == 1).unwrap();
+        assert_eq!(sphere.object_kind, "sphere");
+        assert_eq!((sphere.min, sphere.max), (Vector3::new(2.0, -1.0, -1.0), Vector3::new(4.0, 1.0, 1.0)));
     }
 
     #[test]
-    fn test_raytracer_tp33() {
-        test_file("test_file/jalon3/tp33");
+    fn test_pixel_offset_shifts_the_image_by_half_a_pixel() {
+        // 16 pixels over 4 units: a pixel is 0.25 units wide, so half-pixel shifts are exact
+        let render = |camera_x: f32, camera_y: f32, extra: &str| {
+            let scene = format!(
+                "size 16 16\ncamera {camera_x} {camera_y} 5 {camera_x} {camera_y} 0 0 1 0 45\northo 4\n{extra}\
+                 directional 0 0 1 1 1 1\ndiffuse 0.8 0.4 0.2\nsphere 0.3 -0.2 0 1.3\n"
+            );
+            RayTracer::new(ParsedConfigState::new().load_config_str(&scene).unwrap()).render().unwrap()
+        };
+
+        let centers = render(0.0, 0.0, "");
+        let corners = render(0.0, 0.0, "pixel_offset 0 0\n");
+        assert_ne!(corners.data, centers.data);
+        // Sampling corners matches sampling centers with the view moved half a pixel up
+        // and to the left
+        assert_eq!(corners.data, render(-0.125, 0.125, "").data);
+        assert_eq!(render(0.0, 0.0, "pixel_offset 0.5 0.5\n").data, centers.data);
+
+        assert!(ParsedConfigState::new().load_config_str("pixel_offset 0.5\n").is_err());
+        assert!(ParsedConfigState::new().load_config_str("pixel_offset 0.5 1.5\n").is_err());
     }
 
     #[test]
-    fn test_raytracer_tp34() {
-        test_file("test_file/jalon3/tp34");
+    fn test_orthographic_primary_rays_are_parallel_and_report_depth() {
+        let scene = |projection: &str| {
+            format!("size 16 12\ncamera 0 0 5 0 0 0 0 1 0 45\n{projection}plane 0 0 -1 0 0 1\nsphere 0 0 0 1\n")
+        };
+        let ortho = RayTracer::new(ParsedConfigState::new().load_config_str(&scene("ortho 4\n")).unwrap());
+        let perspective = RayTracer::new(ParsedConfigState::new().load_config_str(&scene("")).unwrap());
+
+        let view = ortho.view_plane(&ortho.config.camera, 16, 12);
+        let pixel = 4.0 / 12.0;
+        for y in 0..12 {
+            for x in 0..16 {
+                let ray = view.primary_ray(x, y);
+                assert_eq!(ray.direction, -Vector3::z());
+                let expected_origin = Vector3::new((x as f32 + 0.5 - 8.0) * pixel, (6.0 - y as f32 - 0.5) * pixel, 5.0);
+                assert!((ray.origin - expected_origin).norm() < 1e-5, "pixel ({x}, {y}) origin {:?}", ray.origin);
+
+                // The distance is the depth below the image plane: the sphere's front
+                // inside the unit disk, the backdrop plane 6 units away elsewhere
+                let across = ray.origin.xy().norm_squared();
+                let depth = if across < 1.0 { 5.0 - (1.0 - across).sqrt() } else { 6.0 };
+                let distance = ortho.closest_intersection(&ray).unwrap().distance;
+                assert!((distance - depth).abs() < 1e-4, "pixel ({x}, {y}) at {distance}, expected {depth}");
+            }
+        }
+
+        // Perspective rays fan out, reaching the backdrop further away at the corners
+        let view = perspective.view_plane(&perspective.config.camera, 16, 12);
+        let corner = view.primary_ray(0, 0);
+        assert!(corner.direction.z > -0.95);
+        assert!(perspective.closest_intersection(&corner).unwrap().distance > 6.5);
+
+        // A flat backdrop is at one depth across the orthographic depth map
+        let depths = ortho.render_depth_map().unwrap();
+        assert_eq!(depths.data[0], DEPTH_MAX);
+        assert!(depths.data.iter().all(|&depth| depth == DEPTH_MAX || depth < DEPTH_MAX / 2));
+
+        assert!(ParsedConfigState::new().load_config_str("ortho 0\n").is_err());
     }
 
     #[test]
-    fn test_raytracer_tp35() {
-        test_file("test_file/jalon3/tp35");
+    fn test_camera_inside_inward_sphere_sees_its_interior() {
+        let scene = |radius: &str| {
+            format!(
+                "size 20 15
+camera 0 0 0 0 0 -1 0 1 0 60
+ambient 0.1 0.1 0.1
+point 0 0 0 1 1 1
+diffuse 0.8 0.2 0.2
+sphere 0 0 0 {radius}
+"
+            )
+        };
+        let dome = RayTracer::new(ParsedConfigState::new().load_config_str(&scene("-50")).unwrap());
+        let solid = RayTracer::new(ParsedConfigState::new().load_config_str(&scene("50")).unwrap());
+
+        assert!(dome.render_object_ids().unwrap().iter().all(|&id| id == 0));
+        assert!(solid.render_object_ids().unwrap().iter().all(|&id| id == NO_OBJECT));
+
+        let (_, hit) = dome.trace_single_ray(Vector3::zeros(), Vector3::new(0.0, 0.0, -1.0));
+        let hit = hit.expect("Ray from the center should hit the dome");
+        assert!((hit.distance - 50.0).abs() < 1e-3);
+        assert!((hit.normal - Vector3::z()).norm() < 1e-5, "normal {:?}", hit.normal);
+        assert!(!hit.is_back_face);
+
+        // The light at the center faces every point of the interior, lighting it head-on
+        let image = dome.render().unwrap();
+        let (r, g, b) = crate::imgcomparator::extract_rgb(image.data[(7 * 20 + 10) as usize]);
+        assert!(r > 200 && g < 80 && b < 80, "center pixel ({r}, {g}, {b})");
+
+        assert!(ParsedConfigState::new().load_config_str("sphere 0 0 0 0\n").is_err());
     }
 
     #[test]
-    fn test_raytracer_tp51diffuse() {
-        test_file("test_file/jalon5/tp51-diffuse");
+    fn test_work_map_is_higher_on_reflective_objects() {
+        let scene = "size 40 30
+camera 0 0 10 0 0 0 0 1 0 45
+maxdepth 3
+point 0 5 5 1 1 1
+diffuse 0.5 0.5 0.5
+specular 0 0 0
+sphere -1.2 0 0 1
+specular 0.5 0.5 0.5
+sphere 1.2 0 0 1
+";
+        let ray_tracer = RayTracer::new(ParsedConfigState::new().load_config_str(scene).unwrap());
+        let work = ray_tracer.render_work_map().unwrap();
+        let ids = ray_tracer.render_object_ids().unwrap();
+        let work_on = |object: u32| -> Vec<u32> {
+            ids.iter().zip(&work).filter(|&(&id, _)| id == object).map(|(_, &rays)| rays).collect()
+        };
+
+        let (background, matte, mirror) = (work_on(NO_OBJECT), work_on(0), work_on(1));
+        assert!(!matte.is_empty() && !mirror.is_empty());
+        // An escaping primary ray is all the work a background pixel costs
+        assert!(background.iter().all(|&rays| rays == 1));
+        assert!(mirror.iter().all(|&rays| rays > 1));
+        let mean = |rays: &[u32]| rays.iter().sum::<u32>() as f32 / rays.len() as f32;
+        assert!(mean(&mirror) > mean(&matte));
+
+        // Counting rays keeps the map deterministic under parallel rendering
+        assert_eq!(ray_tracer.render_work_map().unwrap(), work);
+        let heatmap = ray_tracer.render_heatmap().unwrap();
+        let background_pixel = ids.iter().position(|&id| id == NO_OBJECT).unwrap();
+        assert_eq!(heatmap.data[background_pixel], pack_color(&Vector3::zeros()));
     }
 
     #[test]
-    fn test_raytracer_tp51specular() {
-        test_file("test_file/jalon5/tp51-specular");
+    fn test_transparent_mirror_ray_count_is_bounded_per_kind() {
+        let render = |limits: &str| {
+            let scene = format!(
+                "size 20 15
+camera 0 0 6 0 0 0 0 1 0 45
+maxdepth 12
+{limits}point 0 4 6 1 1 1
+diffuse 0.2 0.2 0.2
+specular 0.5 0.5 0.5
+transparency 0.7
+ior 1.5
+sphere 0 0 0 2
+"
+            );
+            let ray_tracer = RayTracer::new(ParsedConfigState::new().load_config_str(&scene).unwrap());
+            ray_tracer.render_with_stats().unwrap().1
+        };
+
+        // Both kinds branch at every bounce inside the sphere, so only maxdepth bounds
+        // the tree of secondary rays
+        let unbounded = render("");
+        let bounded = render("max_reflect 2\nmax_refract 2\n");
+        assert!(bounded.reflection_rays > 0 && bounded.refraction_rays > 0);
+        // A pixel reaches each (reflections, refractions) pair along C(r + f, r) paths:
+        // 18 secondary rays at most with 2 of each
+        let secondary = |stats: &RenderStats| stats.reflection_rays + stats.refraction_rays;
+        assert!(secondary(&bounded) <= 18 * 20 * 15, "{:?}", bounded);
+        assert!(secondary(&unbounded) > 10 * secondary(&bounded), "{:?}", unbounded);
+
+        // The limits apply independently
+        let no_reflections = render("max_reflect 0\n");
+        assert_eq!(no_reflections.reflection_rays, 0);
+        assert!(no_reflections.refraction_rays > 0);
+        let no_refractions = render("max_refract 0\n");
+        assert_eq!(no_refractions.refraction_rays, 0);
+        assert!(no_refractions.reflection_rays > 0);
     }
 
     #[test]
-    fn test_raytracer_tp52() {
-        test_file("test_file/jalon5/tp52");
+    fn test_clear_glass_with_unit_ior_is_invisible() {
+        let render = |sphere: &str| {
+            let scene = format!(
+                "size 16 12
+camera 0 0 6 0 0 0 0 1 0 45
+maxdepth 4
+material backdrop ambient 0.5 0.1 0.1
+usematerial backdrop
+plane 0 0 -3 0 0 1
+material black
+usematerial black
+{sphere}"
+            );
+            RayTracer::new(ParsedConfigState::new().load_config_str(&scene).unwrap()).render().unwrap()
+        };
+
+        let backdrop = render("");
+        let glass = render("transparency 1\nior 1\nsphere 0 0 0 1.5\n");
+        let opaque = render("sphere 0 0 0 1.5\n");
+        let center = 6 * 16 + 8;
+        assert_ne!(opaque.data[center], backdrop.data[center]);
+        // Entering and leaving the sphere without bending, the ray reaches the plane
+        assert_eq!(glass.data, backdrop.data);
+
+        assert!(ParsedConfigState::new().load_config_str("transparency 1.5\n").is_err());
+        assert!(ParsedConfigState::new().load_config_str("ior 0\n").is_err());
     }
 
     #[test]
-    fn test_raytracer_tp53() {
-        test_file("test_file/jalon5/tp53");
+    fn test_twosided_triangle_shades_both_sides_alike() {
+        // A triangle facing +Z, viewed from `side` with the light beside the viewer
+        let view = |twosided: &str, side: f32| {
+            let scene = format!(
+                "version 2
+point 0 0 {} 1 1 1
+diffuse 0.8 0.6 0.4
+twosided {twosided}
+maxverts 3
+vertex -1 -1 0
+vertex 1 -1 0
+vertex 0 1 0
+tri 0 1 2
+",
+                3.0 * side
+            );
+            let ray_tracer = RayTracer::new(ParsedConfigState::new().load_config_str(&scene).unwrap());
+            let (color, hit) = ray_tracer.trace_single_ray(Vector3::new(0.1, 0.0, 3.0 * side), -Vector3::z() * side);
+            (color, hit.unwrap())
+        };
+
+        let (front, front_hit) = view("on", 1.0);
+        let (back, back_hit) = view("on", -1.0);
+        assert_ne!(front, 0);
+        assert_eq!(front, back);
+        assert_eq!(front_hit.normal, Vector3::z());
+        assert_eq!(back_hit.normal, -Vector3::z());
+        assert!(!back_hit.is_back_face);
+
+        // One-sided by default: the back keeps the front's normal and is left unlit
+        let (one_sided_front, _) = view("off", 1.0);
+        let (one_sided_back, back_hit) = view("off", -1.0);
+        assert_eq!(one_sided_front, front);
+        assert_ne!(one_sided_back, front);
+        assert!(back_hit.is_back_face);
+
+        // Not part of the material: resetting the material keeps the flag
+        let (reset_back, _) = view("on\nreset_material\ndiffuse 0.8 0.6 0.4", -1.0);
+        assert_eq!(reset_back, back);
     }
 
     #[test]
-    fn test_raytracer_tp54() {
-        test_file("test_file/jalon5/tp54");
+    fn test_reflection_falloff_fades_distant_reflections() {
+        // A mirror reflects a self-lit sphere behind the viewer, placed near the mirror
+        // or far from it
+        let reflected = |sphere_z: f32, falloff: &str| {
+            let scene = format!(
+                "maxdepth 2
+{falloff}specular 0.8 0.8 0.8
+plane 0 0 0 0 0 1
+specular 0 0 0
+material_ambient 0.6 0.6 0.6
+sphere 0 0 {sphere_z} 0.5
+"
+            );
+            let ray_tracer = RayTracer::new(ParsedConfigState::new().load_config_str(&scene).unwrap());
+            let (color, _) = ray_tracer.trace_single_ray(Vector3::new(0.0, 0.0, 0.5), -Vector3::z());
+            color & 0xFF
+        };
+
+        // Without falloff the reflection does not depend on the distance
+        assert!(reflected(3.0, "") > 0);
+        assert_eq!(reflected(3.0, ""), reflected(30.0, ""));
+
+        let near = reflected(3.0, "reflection_falloff 10\n");
+        let far = reflected(30.0, "reflection_falloff 10\n");
+        assert!(far < near, "far {} should be dimmer than near {}", far, near);
+        assert!(near < reflected(3.0, ""));
+
+        assert!(ParsedConfigState::new().load_config_str("reflection_falloff 0\n").is_err());
     }
 
     #[test]
-    fn test_raytracer_tp55() {
-        test_file("test_file/jalon5/tp55");
+    fn test_denoise_smooths_flat_regions_and_keeps_edges() {
+        // Two tiles meeting at x = 0 (between columns 31 and 32), lit through one randomly
+        // picked light per pixel; values are red channels
+        let render = |denoise: &str| {
+            let scene = format!(
+                "size 64 32
+camera 0 0 10 0 0 0 0 1 0 30
+ortho 4
+{denoise}ambient 0.05 0.05 0.05
+light_samples 1
+point -3 2 4 0.4 0.1 0.1
+point 3 -2 4 0.1 0.4 0.1
+point 0 3 3 0.1 0.1 0.4
+point -2 -3 5 0.3 0.3 0.1
+diffuse 0.3 0.3 0.3
+plane_bounded -2 0 0 0 0 1 2
+diffuse 0.9 0.9 0.9
+plane_bounded 2 0 0 0 0 1 2
+"
+            );
+            let image = RayTracer::new(ParsedConfigState::new().load_config_str(&scene).unwrap())
+                .render()
+                .unwrap();
+            // Picking lights by brightness keeps luminance steady; the noise is in hue
+            image
+                .data
+                .iter()
+                .map(|&pixel| crate::imgcomparator::extract_rgb(pixel).0 as f32)
+                .collect::<Vec<f32>>()
+        };
+        let flat_variance = |red: &[f32]| {
+            let values: Vec<f32> = (4..28).flat_map(|y| (4..28).map(move |x| red[y * 64 + x])).collect();
+            let mean = values.iter().sum::<f32>() / values.len() as f32;
+            values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / values.len() as f32
+        };
+        let edge_step = |red: &[f32]| (4..28).map(|y| red[y * 64 + 32] - red[y * 64 + 31]).sum::<f32>() / 24.0;
+
+        let noisy = render("");
+        let denoised = render("denoise 1\n");
+        assert!(flat_variance(&denoised) < flat_variance(&noisy) / 4.0);
+        assert!(edge_step(&noisy) > 20.0);
+        assert!(edge_step(&denoised) > 0.8 * edge_step(&noisy));
+
+        assert!(ParsedConfigState::new().load_config_str("denoise 0\n").is_err());
     }
 
     #[test]
-    fn test_raytracer_tp61directional() {
-        test_file("test_file/jalon6/tp61-dir");
+    fn test_denoise_filters_the_edge_aa_result() {
+        let scene = |extra: &str, x: f32| {
+            format!(
+                "size 32 32\ncamera 0 0 5 0 0 0 0 1 0 30\nambient 0.2 0.2 0.2\ndirectional 0 0 1 1 1 1\n\
+                 denoise 1\n{extra}sphere {x} 0 0 1\n"
+            )
+        };
+        let load = |extra: &str, x: f32| RayTracer::new(ParsedConfigState::new().load_config_str(&scene(extra, x)).unwrap());
+
+        let ray_tracer = load("edge_aa 8\n", 0.0);
+        let (image, aovs) = ray_tracer.render_sample_aovs().unwrap();
+        assert_eq!(image, ray_tracer.render().unwrap());
+        assert!(aovs.counts.contains(&9));
+        // Without edge_aa the silhouette pixels keep their single aliased sample
+        assert_ne!(image, load("", 0.0).render().unwrap());
+
+        // Denoised pixels depend on their neighbours, so moving an object re-renders all
+        let mut ray_tracer = load("", 0.0);
+        let mut image = ray_tracer.render().unwrap();
+        let moved = load("", 0.2).config().get_scene_objects()[0].clone();
+        let region = ray_tracer.update_object(0, moved, &mut image).unwrap();
+        assert_eq!(region, Some(PixelRegion::full(32, 32)));
+        assert_eq!(image, load("", 0.2).render().unwrap());
     }
 
+    /// Regression guard for the exact-match tests: every stochastic feature combined must
+    /// render byte-identically run after run, whatever the thread count. Pixels are
+    /// written by index and each pixel's samples are summed in a fixed order, so rayon's
+    /// scheduling must never show in the output.
     #[test]
-    fn test_raytracer_tp61() {
-        test_file("test_file/jalon6/tp61");
+    fn test_parallel_renders_are_reproducible() {
+        let scene = "version 2
+size 48 36
+camera 0 1 6 0 0 0 0 1 0 45
+seed 7
+maxdepth 4
+edge_aa 4
+light_samples 1
+point 3 4 3 0.6 0.3 0.3
+point -3 3 2 0.3 0.6 0.3
+directional 0 1 1 0.2 0.2 0.5
+diffuse 0.5 0.5 0.5
+specular 0.4 0.4 0.4
+shininess 32
+roughness 0.3
+sphere -1 0 0 1
+transparency 0.6
+ior 1.4
+sphere 1.2 0 0.5 0.8
+reset_material
+diffuse 0.6 0.6 0.6
+plane 0 -1 0 0 1 0
+";
+        let ray_tracer = RayTracer::new(ParsedConfigState::new().load_config_str(scene).unwrap());
+        let render = || {
+            let (image, stats) = ray_tracer.render_with_stats().unwrap();
+            (image.data, stats.total_rays())
+        };
+
+        let reference = render();
+        for _ in 0..8 {
+            assert!(render() == reference, "A repeated render differs");
+        }
+        for threads in [1, 3] {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            assert!(pool.install(render) == reference, "The render differs on {} threads", threads);
+        }
     }
 
     #[test]
-    fn test_raytracer_tp62_1() {
-        test_file("test_file/jalon6/tp62-1");
+    fn test_point_light_cutoff_stops_its_contribution() {
+        // Spheres seen from +Z, lit from a point about 5.7 and 9.8 away from their front
+        let shade = |cutoff: &str| {
+            let scene = format!(
+                "point 0 0 5 1 1 1{cutoff}\ndiffuse 0.8 0.8 0.8\nsphere 0 -4 0 1\nsphere 0 -9 0 1\n"
+            );
+            let ray_tracer = RayTracer::new(ParsedConfigState::new().load_config_str(&scene).unwrap());
+            let ((near, far), rays) = count_rays(|| {
+                (
+                    ray_tracer.trace_single_ray(Vector3::new(0.0, -4.0, 5.0), -Vector3::z()).0,
+                    ray_tracer.trace_single_ray(Vector3::new(0.0, -9.0, 5.0), -Vector3::z()).0,
+                )
+            });
+            (near & 0xFF, far & 0xFF, rays.shadow)
+        };
+
+        let (near, far, shadow_rays) = shade("");
+        assert!(near > 0 && far > 0);
+        assert_eq!(shadow_rays, 2);
+
+        // The far sphere is out of reach: unlit, and without a shadow ray
+        let (near_cut, far_cut, shadow_rays) = shade(" cutoff 7");
+        assert_eq!(near_cut, near);
+        assert_eq!(far_cut, 0);
+        assert_eq!(shadow_rays, 1);
+
+        assert!(ParsedConfigState::new().load_config_str("point 0 0 0 1 1 1 cutoff 0\n").is_err());
+        assert!(ParsedConfigState::new().load_config_str("point 0 0 0 1 1 1 radius 5\n").is_err());
     }
 
     #[test]
-    fn test_raytracer_tp62_2() {
-        test_file("test_file/jalon6/tp62-2");
+    fn test_render_stats_count_rays() {
+        let scene = |specular: &str| {
+            format!(
+                "size 40 30
+camera 0 0 10 0 0 0 0 1 0 45
+maxdepth 3
+point 0 5 5 1 1 1
+diffuse 0.5 0.5 0.5
+specular {specular}
+sphere -1.2 0 0 1
+sphere 1.2 0 0 1
+"
+            )
+        };
+        let matte = RayTracer::new(ParsedConfigState::new().load_config_str(&scene("0 0 0")).unwrap());
+        let mirror = RayTracer::new(ParsedConfigState::new().load_config_str(&scene("0.5 0.5 0.5")).unwrap());
+
+        let (_, matte_stats) = matte.render_with_stats().unwrap();
+        let (_, mirror_stats) = mirror.render_with_stats().unwrap();
+
+        assert_eq!(matte_stats.primary_rays, 40 * 30);
+        assert_eq!(mirror_stats.primary_rays, 40 * 30);
+        assert_eq!(matte_stats.reflection_rays, 0);
+        assert!(mirror_stats.reflection_rays > 0);
+        assert!(mirror_stats.shadow_rays > matte_stats.shadow_rays);
+        assert_eq!(
+            mirror_stats.total_rays(),
+            mirror_stats.primary_rays + mirror_stats.shadow_rays + mirror_stats.reflection_rays
+        );
+
+        // Counters restart with every render
+        let (_, again) = mirror.render_with_stats().unwrap();
+        assert_eq!(again.primary_rays, 40 * 30);
+        assert_eq!(again.reflection_rays, mirror_stats.reflection_rays);
+
+        // Each render counts only its own rays, even beside other renders on the tracer
+        let rays = |stats: RenderStats| RenderStats { elapsed: Duration::ZERO, ..stats };
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                for _ in 0..4 {
+                    mirror.render_heatmap().unwrap();
+                }
+            });
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    for _ in 0..4 {
+                        assert_eq!(rays(mirror.render_with_stats().unwrap().1), rays(mirror_stats));
+                    }
+                });
+            }
+        });
     }
 
     #[test]
-    fn test_raytracer_tp62_3() {
-        test_file("test_file/jalon6/tp62-3");
+    fn test_anisotropic_highlight_stretches_along_tangent() {
+        // Extent in pixels (width, height) of the bright part of a specular-only sphere,
+        // lit from the camera so the highlight sits at the center
+        let highlight_extent = |anisotropy: &str| {
+            let scene = format!(
+                "size 100 100
+camera 0 0 5 0 0 0 0 1 0 45
+point 0 0 5 1 1 1
+diffuse 0 0 0
+specular 1 1 1
+shininess 200
+anisotropy {anisotropy}
+sphere 0 0 0 1
+"
+            );
+            let image = RayTracer::new(ParsedConfigState::new().load_config_str(&scene).unwrap())
+                .render()
+                .unwrap();
+            let bright: Vec<(u32, u32)> = (0..image.height)
+                .flat_map(|y| (0..image.width).map(move |x| (x, y)))
+                .filter(|&(x, y)| (image.data[(y * image.width + x) as usize] >> 16) & 0xFF > 64)
+                .collect();
+            let span = |coord: fn(&(u32, u32)) -> u32| {
+                bright.iter().map(coord).max().unwrap() - bright.iter().map(coord).min().unwrap() + 1
+            };
+            (span(|p| p.0), span(|p| p.1))
+        };
+
+        let (iso_w, iso_h) = highlight_extent("0");
+        assert!(iso_w.abs_diff(iso_h) <= 1, "isotropic highlight {iso_w}x{iso_h}");
+
+        // Sphere tangents run along the lines of latitude: horizontal at the center
+        let (aniso_w, aniso_h) = highlight_extent("0.9");
+        assert!(aniso_w >= 2 * aniso_h, "anisotropic highlight {aniso_w}x{aniso_h}");
+        assert!(aniso_h.abs_diff(iso_h) <= 1);
     }
 
     #[test]
-    fn test_raytracer_tp62_4() {
-        test_file("test_file/jalon6/tp62-4");
+    fn test_normal_aov_encodes_sphere_normals() {
+        let scene = "size 41 41
+camera 0 0 5 0 0 0 0 1 0 45
+sphere 0 0 0 1
+";
+        let ray_tracer = RayTracer::new(ParsedConfigState::new().load_config_str(scene).unwrap());
+        let normals = ray_tracer.render_normal_aov().unwrap();
+        let blue = |x: u32, y: u32| normals.data[(y * normals.width + x) as usize] & 0xFF;
+
+        assert_eq!(normals.data[0], NORMAL_AOV_BACKGROUND);
+        // The center faces the camera: n = (0, 0, 1) encodes full blue
+        assert!(blue(20, 20) >= 250);
+        // At the silhouette the normal is perpendicular to the view, so z is near 0
+        // and blue near the 128 midpoint, nothing like the center
+        let edge = (0..41).find(|&x| normals.data[(20 * 41 + x) as usize] != NORMAL_AOV_BACKGROUND).unwrap();
+        assert!(blue(edge, 20) < 180, "edge blue {}", blue(edge, 20));
     }
 
     #[test]
-    fn test_raytracer_tp62_5() {
-        test_file("test_file/jalon6/tp62-5");
+    fn test_deep_recursion_between_facing_mirrors_is_bounded() {
+        let scene = |maxdepth: u32| {
+            format!(
+                "size 8 8
+camera 0 0 0 0 0 -1 0 1 0 30
+maxdepth {maxdepth}
+diffuse 0.1 0.1 0.1
+specular 1 1 1
+plane 0 0 -1 0 0 1
+plane 0 0 1 0 0 -1
+"
+            )
+        };
+        assert_eq!(
+            ParsedConfigState::new().load_config_str(&scene(100_000)).err(),
+            Some(RayTracerError::Parse {
+                line: 3,
+                msg: format!("maxdepth must be at most {MAX_DEPTH}")
+            })
+        );
+
+        // Every primary ray bounces between the mirrors for the full depth
+        let ray_tracer = RayTracer::new(ParsedConfigState::new().load_config_str(&scene(MAX_DEPTH)).unwrap());
+        let (_, stats) = ray_tracer.render_with_stats().unwrap();
+        assert_eq!(stats.reflection_rays, 64 * (MAX_DEPTH as u64 - 1));
+
+        let mut config = ParsedConfigState::new().load_config_str(&scene(1)).unwrap();
+        config.maxdepth = 100_000;
+        assert!(matches!(RayTracer::new(config).render(), Err(RayTracerError::Render(_))));
     }
 
     #[test]
-    fn test_raytracer_tp63() {
-        test_file("test_file/jalon6/tp63");
+    fn test_light_passes_sum_to_beauty() {
+        let scene = |lights: &str| {
+            format!(
+                "size 40 30
+camera 0 1 6 0 0 0 0 1 0 45
+maxdepth 3
+ambient 0.05 0.05 0.05
+{lights}
+diffuse 0.3 0.2 0.1
+specular 0.2 0.2 0.2
+shininess 20
+sphere 0 0 0 1
+diffuse 0.2 0.3 0.3
+specular 0 0 0
+plane 0 -1 0 0 1 0
+"
+            )
+        };
+        let render = |lights: &str| {
+            RayTracer::new(ParsedConfigState::new().load_config_str(&scene(lights)).unwrap())
+        };
+
+        let lit = render("point 3 4 3 0.5 0.3 0.2\ndirectional -1 1 0.5 0.2 0.3 0.4");
+        let beauty = lit.render().unwrap();
+        let ambient_only = render("").render().unwrap();
+        let passes = lit.render_light_passes().unwrap();
+        assert_eq!(passes.len(), 2);
+        assert_eq!((passes[0].0, passes[1].0), (0, 1));
+        assert_ne!(passes[0].1.data, passes[1].1.data);
+
+        let channel = |pixel: u32, shift: u32| ((pixel >> shift) & 0xFF) as i32;
+        for i in 0..beauty.data.len() {
+            for shift in [0, 8, 16] {
+                let sum = channel(passes[0].1.data[i], shift)
+                    + channel(passes[1].1.data[i], shift)
+                    + channel(ambient_only.data[i], shift);
+                let expected = channel(beauty.data[i], shift);
+                assert!((sum - expected).abs() <= 2, "pixel {i}: {sum} vs {expected}");
+            }
+        }
     }
 
     #[test]
-    fn test_raytracer_tp64() {
-        test_file("test_file/jalon6/tp64");
+    fn test_plane_aabb_fitted_to_scene_bounds() {
+        let scene = "size 20 20
+camera 0 4 8 0 0 0 0 1 0 45
+sphere -1 0 0 1
+sphere 2 1 0 0.5
+plane 0 -2 0 0 1 0
+";
+        let config = ParsedConfigState::new().load_config_str(scene).unwrap();
+        let surface_area = |objects: &[Shape]| {
+            let (min, max) = objects
+                .iter()
+                .map(|object| object.aabb())
+                .map(|aabb| (aabb.min.coords, aabb.max.coords))
+                .reduce(|(min_a, max_a), (min_b, max_b)| (min_a.inf(&min_b), max_a.sup(&max_b)))
+                .unwrap();
+            let size = max - min;
+            2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+        };
+        let unfitted = surface_area(config.get_scene_objects());
+        let ray_tracer = RayTracer::new(config);
+        let fitted = surface_area(ray_tracer.config().get_scene_objects());
+
+        // The root box, and with it the SAH cost of every node above the plane, now
+        // scales with the scene rather than with the 1e10 placeholder
+        assert!(fitted < 1e3, "fitted root area {fitted}");
+        assert!(unfitted / fitted > 1e15);
+
+        // The floor is still hit far outside its fitted box
+        let far = Ray::new(Vector3::new(0.0, 4.0, 8.0), Vector3::new(0.0, -6.0, -500.0).normalize());
+        let hit = ray_tracer.closest_intersection(&far).unwrap();
+        assert!((hit.point.y + 2.0).abs() < 1e-3 && hit.point.z < -400.0);
+    }
+
+    #[test]
+    fn test_rough_reflection_is_blurrier() {
+        // A striped, self-lit sphere seen only through its reflection in a mirror floor
+        let scene = |roughness: &str| {
+            format!(
+                "size 80 60
+camera 0 1 6 0 -1 0 0 1 0 30
+maxdepth 2
+material_ambient 0.9 0.9 0.9
+bands 12 0 0 0 0.9 0.9 0.9
+sphere 0 1.5 0 1
+material_ambient none
+bands none
+diffuse 0 0 0
+specular 0.9 0.9 0.9
+shininess 500
+roughness {roughness}
+plane 0 -1 0 0 1 0
+"
+            )
+        };
+        // Sum of squared differences between vertically adjacent pixels: stripes in the
+        // reflection are horizontal, so blurring them lowers this
+        let edge_energy = |roughness: &str| {
+            let ray_tracer = RayTracer::new(ParsedConfigState::new().load_config_str(&scene(roughness)).unwrap());
+            let image = ray_tracer.render().unwrap();
+            let ids = ray_tracer.render_object_ids().unwrap();
+            let width = image.width as usize;
+            let green = |i: usize| ((image.data[i] >> 8) & 0xFF) as f32;
+            (width..image.data.len())
+                .filter(|&i| ids[i] == 1 && ids[i - width] == 1)
+                .map(|i| (green(i) - green(i - width)).powi(2))
+                .sum::<f32>()
+        };
+
+        let smooth = edge_energy("0");
+        let rough = edge_energy("0.3");
+        assert!(smooth > 0.0);
+        assert!(rough < smooth * 0.6, "rough {rough} vs smooth {smooth}");
+
+        // Deterministic: the same render twice gives the same pixels
+        assert_eq!(edge_energy("0.3"), rough);
+    }
+
+    #[test]
+    fn test_bvh_shadows_match_brute_force() {
+        // Overlapping shadows from two lights, cast by spheres, a triangle and a mesh
+        // instance onto a floor and onto each other
+        let scene = "size 80 60
+camera 0 5 9 0 0 0 0 1 0 45
+ambient 0.05 0.05 0.05
+point 2 6 2 0.5 0.5 0.5
+point -3 5 1 0.4 0.4 0.4
+directional 0.3 1 0.2 0.2 0.2 0.2
+diffuse 0.7 0.3 0.3
+sphere 0 1 0 1
+sphere 1.5 0.5 1 0.5
+sphere -1.2 2 -0.5 0.6
+maxverts 3
+vertex -2 1.5 1
+vertex -1 1.5 2
+vertex -2 2.5 2
+diffuse 0.3 0.7 0.3
+tri 0 1 2
+mesh shard
+tri 0 1 2
+endmesh
+instance shard translate 2 1 -1
+diffuse 0.6 0.6 0.6
+plane 0 -0.01 0 0 1 0
+";
+        let render = |brute_force: bool| {
+            let mut ray_tracer = RayTracer::new(ParsedConfigState::new().load_config_str(scene).unwrap());
+            if brute_force {
+                ray_tracer = ray_tracer.with_brute_force_shadows();
+            }
+            ray_tracer.render().unwrap()
+        };
+
+        let bvh = render(false);
+        let brute_force = render(true);
+        let (diff, _) = Image::compare(&bvh, &brute_force).unwrap();
+        assert_eq!(diff, 0);
+        assert_eq!(bvh.data, brute_force.data);
+    }
+
+    #[test]
+    fn test_shadow_traversal_stops_at_point_lights() {
+        // A lit floor with a row of spheres behind the light, on the floor-to-light line
+        let mut scene = String::from(
+            "size 40 30\ncamera 0 2 8 0 0 0 0 1 0 45\npoint 0 3 0 1 1 1\ndiffuse 0.8 0.8 0.8\n\
+             plane 0 0 0 0 1 0\nsphere 2 1 2 0.5\n",
+        );
+        for i in 0..32 {
+            scene.push_str(&format!("sphere 0 {} 0 0.4\n", 5 + i));
+        }
+        let ray_tracer = RayTracer::new(ParsedConfigState::new().load_config_str(&scene).unwrap());
+
+        let shadow_ray = Ray::new(Vector3::new(0.0, 1e-6, 0.0), Vector3::new(0.0, 1.0, 0.0));
+        let full = ray_tracer.candidates(&shadow_ray).len();
+        let limited = ray_tracer.candidates_within(&shadow_ray, 3.0).len();
+        println!("Shadow intersection tests: {} unlimited, {} limited to the light", full, limited);
+        assert!(full >= 33);
+        assert!(limited < full / 4, "{} candidates within the light distance", limited);
+
+        // An occluder just short of the light is still found
+        let occluder = ray_tracer.candidates_within(&Ray::new(Vector3::zeros(), Vector3::new(0.0, 1.0, 0.0)), 4.6);
+        assert!(occluder.iter().any(|object| matches!(object, Shape::Sphere { center, .. } if center.y == 5.0)));
+
+        let brute_force = RayTracer::new(ParsedConfigState::new().load_config_str(&scene).unwrap())
+            .with_brute_force_shadows();
+        assert_eq!(ray_tracer.render().unwrap().data, brute_force.render().unwrap().data);
+    }
+
+    #[test]
+    fn test_hdr_framebuffer_keeps_over_bright_values() {
+        let config = ParsedConfigState::new()
+            .load_config_str(
+                "size 16 12\ncamera 0 0 5 0 0 0 0 1 0 45\ndiffuse 0.8 0.8 0.8\nsphere 0 0 0 1\n\
+                 directional 0 0 1 1 1 1\ndirectional 0.1 0 1 1 1 1\ndirectional -0.1 0 1 1 1 1\n",
+            )
+            .unwrap();
+        let ray_tracer = RayTracer::new(config);
+        let (image, framebuffer) = ray_tracer.render_hdr().unwrap();
+
+        assert_eq!(framebuffer.len(), image.data.len());
+        assert_eq!(image, ray_tracer.render().unwrap());
+        let center = (6 * 16 + 8) as usize;
+        assert!(framebuffer[center].min() > 1.0, "{:?}", framebuffer[center]);
+        assert_eq!(image.data[center] & 0xFF_FFFF, 0xFF_FFFF);
+        assert!(framebuffer.iter().all(|color| color.iter().all(|c| c.is_finite())));
+    }
+
+    #[test]
+    fn test_linear_sidecar_encodes_to_display_image() {
+        let dir = std::env::temp_dir().join(format!("raytracer_linear_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let scene_path = dir.join("scene.test");
+        std::fs::write(
+            &scene_path,
+            "size 24 16\noutput out.png\nsave_linear out.exr\ncamera 0 0 5 0 0 0 0 1 0 45\n\
+             ambient 0.1 0.1 0.1\ngrade 0 0 0 2.2 2.2 2.2 1 1 1\n\
+             point 2 3 4 0.9 0.8 0.7\ndiffuse 0.6 0.4 0.2\nspecular 0.5 0.5 0.5\nsphere 0 0 0 1.5\n",
+        )
+        .unwrap();
+        let config = ParsedConfigState::new().load_config_file(scene_path.to_str().unwrap()).unwrap();
+        let linear_path = config.linear_output.clone().unwrap();
+        assert_eq!(Path::new(&linear_path), dir.join("out.exr"));
+        let ray_tracer = RayTracer::new(config);
+
+        let (image, linear, _) = ray_tracer.render_linear().unwrap();
+        assert_eq!(image, ray_tracer.render().unwrap());
+        save_image(&image, ray_tracer.get_output_path()).unwrap();
+        let pixels: Vec<[f32; 3]> = linear.iter().map(|color| [color.x, color.y, color.z]).collect();
+        crate::imgcomparator::save_linear_image(24, 16, &pixels, &linear_path).unwrap();
+
+        // Grading (here a 2.2 gamma) and quantizing the sidecar reproduces the PNG
+        let png = file_to_image(ray_tracer.get_output_path()).unwrap();
+        let sidecar = image::open(&linear_path).unwrap().into_rgb32f();
+        let config = ray_tracer.config();
+        for (pixel, (expected, encoded)) in sidecar.pixels().zip(&png.data).enumerate() {
+            let color = Vector3::new(expected[0], expected[1], expected[2]);
+            let (r, g, b) = crate::imgcomparator::extract_rgb(pack_color(&config.clamp_mode.apply(config.grade.apply(color))));
+            let (pr, pg, pb) = crate::imgcomparator::extract_rgb(*encoded);
+            assert!(
+                r.abs_diff(pr) <= 1 && g.abs_diff(pg) <= 1 && b.abs_diff(pb) <= 1,
+                "pixel {pixel}: {:?} vs {:?}",
+                (r, g, b),
+                (pr, pg, pb)
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // The display image is the full render, anti-aliased and denoised alike
+        let config = ParsedConfigState::new()
+            .load_config_str("size 24 16\ncamera 0 0 5 0 0 0 0 1 0 45\nedge_aa 4\ndenoise 1\nsphere 0 0 0 1.5\n")
+            .unwrap();
+        let ray_tracer = RayTracer::new(config);
+        let (image, linear, _) = ray_tracer.render_linear().unwrap();
+        assert_eq!(image, ray_tracer.render().unwrap());
+        // Without a `grade`, the HDR framebuffer is the linear one
+        assert_eq!(ray_tracer.render_hdr().unwrap(), (image, linear));
+    }
+
+    #[test]
+    fn test_progressive_passes_converge() {
+        // Hard-edged spheres: the passes only differ along their silhouettes
+        let config = ParsedConfigState::new()
+            .load_config_str(
+                "size 48 36\ncamera 0 0 6 0 0 0 0 1 0 45\nambient 0.9 0.9 0.9\ndiffuse 0.1 0.1 0.1\nsphere -1 0 0 1\nsphere 1.2 0.3 -1 0.8\n",
+            )
+            .unwrap();
+        let ray_tracer = RayTracer::new(config);
+
+        let mut passes = Vec::new();
+        let converged = ray_tracer
+            .render_progressive(64, |image| {
+                if passes.len() < 2 {
+                    passes.push(Image::new(image.width, image.height, image.data.clone()));
+                }
+            })
+            .unwrap();
+
+        assert_eq!(passes[0], ray_tracer.render().unwrap());
+        let psnr_1 = Image::psnr(&passes[0], &converged).unwrap();
+        let psnr_2 = Image::psnr(&passes[1], &converged).unwrap();
+        assert!(psnr_2 > psnr_1, "pass 1: {psnr_1} dB, pass 2: {psnr_2} dB");
+    }
+
+    #[test]
+    fn test_albedo_pass_is_unlit_diffuse() {
+        let scene = "size 40 40
+camera 0 0 5 0 0 0 0 1 0 45
+ambient 0.1 0.1 0.1
+point 2 2 5 1 1 1
+diffuse 0.8 0 0
+specular 0.5 0.5 0.5
+shininess 30
+sphere 0 0 0 1
+";
+        let ray_tracer = RayTracer::new(ParsedConfigState::new().load_config_str(scene).unwrap());
+        let albedo = ray_tracer.render_albedo().unwrap();
+        let ids = ray_tracer.render_object_ids().unwrap();
+        let red = pack_color(&Vector3::new(0.8, 0.0, 0.0));
+        let black = pack_color(&Vector3::zeros());
+
+        assert!(ids.contains(&0));
+        for (pixel, id) in albedo.data.iter().zip(&ids) {
+            let expected = if *id == NO_OBJECT { black } else { red };
+            assert_eq!(*pixel, expected);
+        }
+    }
+
+    #[test]
+    fn test_lights_behind_surface_cast_no_shadow_rays() {
+        // Seen from z = 10, the sphere and the wall behind it all face +z, away from
+        // lights at z = -10 or shining along -z
+        let scene = |lights: &str| {
+            format!(
+                "size 40 30
+camera 0 0 10 0 0 0 0 1 0 45
+ambient 0.1 0.1 0.1
+{lights}
+diffuse 0.5 0.5 0.5
+specular 0.3 0.3 0.3
+shininess 20
+sphere 0 0 0 1
+plane 0 0 -2 0 0 1
+"
+            )
+        };
+        let render = |lights: &str| {
+            RayTracer::new(ParsedConfigState::new().load_config_str(&scene(lights)).unwrap())
+                .render_with_stats()
+                .unwrap()
+        };
+
+        let (unlit, _) = render("");
+        let (behind, behind_stats) = render("point 0 0 -10 1 1 1");
+        assert_eq!(behind_stats.shadow_rays, 0);
+        assert_eq!(behind.data, unlit.data);
+
+        // Adding the back light to a front-lit scene costs no extra shadow rays
+        let (front, front_stats) = render("point 0 5 10 1 1 1");
+        let (both, both_stats) = render("point 0 5 10 1 1 1\npoint 0 0 -10 1 1 1");
+        assert!(front_stats.shadow_rays > 0);
+        assert_eq!(both_stats.shadow_rays, front_stats.shadow_rays);
+        assert_eq!(both.data, front.data);
+
+        // Same for directional lights
+        let (behind, behind_stats) = render("directional 0 0 -1 1 1 1");
+        assert_eq!(behind_stats.shadow_rays, 0);
+        assert_eq!(behind.data, unlit.data);
+
+        let (front, front_stats) = render("directional 0 1 1 1 1 1");
+        let (both, both_stats) = render("directional 0 1 1 1 1 1\ndirectional 0 0 -1 1 1 1");
+        assert!(front_stats.shadow_rays > 0);
+        assert_eq!(both_stats.shadow_rays, front_stats.shadow_rays);
+        assert_eq!(both.data, front.data);
+    }
+
+    #[test]
+    fn test_horizontal_fov_spans_image_width() {
+        // With a 90 degree horizontal fov the view is exactly 10 units wide on each side
+        // at distance 10, so a sphere at x = 9 sits near the right edge of the image
+        let scene = |axis: &str| {
+            format!(
+                "size 80 20
+camera 0 0 0 0 0 -1 0 1 0 90
+fov_axis {axis}
+directional 0 0 1 1 1 1
+diffuse 1 1 1
+sphere 9 0 -10 1
+"
+            )
+        };
+        let horizontal = RayTracer::new(ParsedConfigState::new().load_config_str(&scene("horizontal")).unwrap())
+            .render()
+            .unwrap();
+        let vertical = RayTracer::new(ParsedConfigState::new().load_config_str(&scene("vertical")).unwrap())
+            .render()
+            .unwrap();
+
+        // A vertical fov of 90 degrees on a 4:1 image spans 40 units on each side instead
+        assert_ne!(horizontal.data[9 * 80 + 76] & 0xFFFFFF, 0);
+        assert_eq!(horizontal.data[9 * 80 + 48] & 0xFFFFFF, 0);
+        assert_eq!(vertical.data[9 * 80 + 76] & 0xFFFFFF, 0);
+        assert_ne!(vertical.data[9 * 80 + 48] & 0xFFFFFF, 0);
     }
 
     /// Benchmark test to demonstrate BVH performance improvement.
@@ -319,7 +3778,7 @@ mod tests {
         let scene_file = "test_file/jalon6/tp64.test";
         let mut parsed_config = ParsedConfigState::new();
         let config = parsed_config
-            .load_config_file(&scene_file)
+            .load_config_file(scene_file)
             .expect("Failed to load configuration");
         
         let object_count = config.get_scene_objects().len();
@@ -350,13 +3809,18 @@ mod tests {
         let scene_file = format!("{path}.test");
         let expected_image_file = format!("{path}.png");
         let mut parsed_config = ParsedConfigState::new();
-        let config = parsed_config
+        parsed_config.set_cache_dir(Some(std::env::temp_dir().join("raytracer_scene_cache")));
+        let mut config = parsed_config
             .load_config_file(&scene_file)
             .expect("Failed to load configuration");
-        let ray_tracer = RayTracer::new(config);
-        let generated_image = ray_tracer.render().expect("Failed to render image");
         let expected_image =
             file_to_image(&expected_image_file).expect("Failed to load expected image");
+        if !parsed_config.size_was_set() {
+            config.width = expected_image.width;
+            config.height = expected_image.height;
+        }
+        let ray_tracer = RayTracer::new(config);
+        let generated_image = ray_tracer.render().expect("Failed to render image");
         let (diff, img) =
             Image::compare(&generated_image, &expected_image).expect("Failed to compare images");
         if SAVE_DIFF_IMAGES {
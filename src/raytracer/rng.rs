@@ -0,0 +1,39 @@
+use nalgebra::Vector3;
+
+/// Small deterministic pseudo-random generator (SplitMix64).
+///
+/// Stochastic features seed one generator per shading point or pixel from its own
+/// inputs, so results never depend on thread scheduling.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    /// Seeds a generator from the exact bit patterns of a point and extra values.
+    pub fn from_point(point: Vector3<f32>, extra: u64) -> Self {
+        let mut rng = Rng::new(extra);
+        for component in point.iter() {
+            rng.state ^= component.to_bits() as u64;
+            rng.next_u64();
+        }
+        rng
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform value in `[0, 1)`.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
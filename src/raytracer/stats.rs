@@ -0,0 +1,151 @@
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::ops::{Add, Sub};
+use std::time::Duration;
+
+/// Ray counts and timing gathered during one render.
+///
+/// Serializable so CI can record it per commit (see `--stats`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct RenderStats {
+    /// Camera rays, one per pixel sample
+    pub primary_rays: u64,
+    /// Occlusion rays cast towards lights
+    pub shadow_rays: u64,
+    /// Secondary rays spawned by reflective surfaces
+    pub reflection_rays: u64,
+    /// Secondary rays spawned through transparent surfaces
+    #[serde(default)]
+    pub refraction_rays: u64,
+    /// Wall-clock time spent rendering
+    pub elapsed: Duration,
+    /// Effective random seed, enough to reproduce the render exactly
+    #[serde(default)]
+    pub seed: u64,
+}
+
+impl RenderStats {
+    pub fn total_rays(&self) -> u64 {
+        self.primary_rays + self.shadow_rays + self.reflection_rays + self.refraction_rays
+    }
+}
+
+/// Rays of each kind cast while tracing part of a render.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct RayCount {
+    pub primary: u64,
+    pub shadow: u64,
+    pub reflection: u64,
+    pub refraction: u64,
+}
+
+impl RayCount {
+    const ZERO: RayCount = RayCount {
+        primary: 0,
+        shadow: 0,
+        reflection: 0,
+        refraction: 0,
+    };
+
+    pub fn secondary(&self) -> u64 {
+        self.shadow + self.reflection + self.refraction
+    }
+
+    pub fn stats(&self, elapsed: Duration, seed: u64) -> RenderStats {
+        RenderStats {
+            primary_rays: self.primary,
+            shadow_rays: self.shadow,
+            reflection_rays: self.reflection,
+            refraction_rays: self.refraction,
+            elapsed,
+            seed,
+        }
+    }
+}
+
+impl Add for RayCount {
+    type Output = RayCount;
+
+    fn add(self, other: RayCount) -> RayCount {
+        RayCount {
+            primary: self.primary + other.primary,
+            shadow: self.shadow + other.shadow,
+            reflection: self.reflection + other.reflection,
+            refraction: self.refraction + other.refraction,
+        }
+    }
+}
+
+impl Sub for RayCount {
+    type Output = RayCount;
+
+    fn sub(self, other: RayCount) -> RayCount {
+        RayCount {
+            primary: self.primary - other.primary,
+            shadow: self.shadow - other.shadow,
+            reflection: self.reflection - other.reflection,
+            refraction: self.refraction - other.refraction,
+        }
+    }
+}
+
+thread_local! {
+    /// Rays cast on this thread. Each render totals the rays of its own pixels from the
+    /// difference across them (see `count_rays`), so renders running at the same time
+    /// on one tracer do not mix their counts.
+    static THREAD_RAYS: Cell<RayCount> = const { Cell::new(RayCount::ZERO) };
+}
+
+/// Runs `trace` and returns its result along with the rays it cast. The count is per
+/// thread, so it stays exact while other pixels and other renders trace in parallel.
+pub(crate) fn count_rays<T>(trace: impl FnOnce() -> T) -> (T, RayCount) {
+    let before = THREAD_RAYS.with(Cell::get);
+    let result = trace();
+    (result, THREAD_RAYS.with(Cell::get) - before)
+}
+
+/// Runs `trace` and returns its result along with the secondary rays it cast.
+pub(crate) fn count_secondary_rays<T>(trace: impl FnOnce() -> T) -> (T, u64) {
+    let (result, rays) = count_rays(trace);
+    (result, rays.secondary())
+}
+
+fn add_rays(rays: RayCount) {
+    THREAD_RAYS.with(|count| count.set(count.get() + rays));
+}
+
+pub(crate) fn add_primary_rays(count: u64) {
+    add_rays(RayCount { primary: count, ..RayCount::ZERO });
+}
+
+pub(crate) fn add_shadow_ray() {
+    add_rays(RayCount { shadow: 1, ..RayCount::ZERO });
+}
+
+pub(crate) fn add_reflection_ray() {
+    add_rays(RayCount { reflection: 1, ..RayCount::ZERO });
+}
+
+pub(crate) fn add_refraction_ray() {
+    add_rays(RayCount { refraction: 1, ..RayCount::ZERO });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_stats_json_roundtrip() {
+        let stats = RenderStats {
+            primary_rays: 307_200,
+            shadow_rays: 1_234_567,
+            reflection_rays: 42,
+            refraction_rays: 7,
+            elapsed: Duration::from_micros(1_500_250),
+            seed: 42,
+        };
+        let json = serde_json::to_string(&stats).unwrap();
+        let parsed: RenderStats = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, stats);
+    }
+}
@@ -0,0 +1,46 @@
+//! Uses the crate the way a downstream user would: only through its public API
+
+use nalgebra::Vector3;
+use raytracer_rust::imgcomparator::{file_to_image, save_image};
+use raytracer_rust::{Camera, Image, Light, ParsedConfigState, RayTracer, Shape};
+
+const SCENE: &str = "\
+size 32 24
+output public_api.png
+camera 0 0 5 0 0 0 0 1 0 45
+ambient 0.1 0.1 0.1
+diffuse 0.9 0 0
+sphere 0 0 0 1
+directional 0 0 1 1 1 1
+";
+
+#[test]
+fn test_load_and_render_tiny_scene() {
+    let config = ParsedConfigState::new().load_config_str(SCENE).unwrap();
+    assert!(matches!(config.get_scene_objects()[0], Shape::Sphere { .. }));
+    assert!(matches!(config.get_lights()[0], Light::Directional { .. }));
+
+    let ray_tracer = RayTracer::new(config);
+    let image = ray_tracer.render().unwrap();
+    assert_eq!((image.width, image.height), (32, 24));
+
+    let center = image.data[(12 * 32 + 16) as usize];
+    let corner = image.data[0];
+    assert!((center >> 16) & 0xFF > 200, "center {center:06x}");
+    assert_eq!(corner & 0xFF_FFFF, 0);
+
+    let path = std::env::temp_dir().join("raytracer_rust_public_api.png");
+    let path = path.to_str().unwrap();
+    save_image(&image, path).unwrap();
+    let reloaded = file_to_image(path).unwrap();
+    assert_eq!(Image::compare(&image, &reloaded).unwrap().0, 0);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_render_with_custom_camera() {
+    let ray_tracer = RayTracer::new(ParsedConfigState::new().load_config_str(SCENE).unwrap());
+    let away = Camera::new(Vector3::new(0.0, 0.0, 5.0), Vector3::new(0.0, 0.0, 10.0), Vector3::y(), 45.0);
+    let image = ray_tracer.render_to_image_with_camera(&away, 16, 12);
+    assert!(image.data.iter().all(|&p| p & 0xFF_FFFF == 0));
+}